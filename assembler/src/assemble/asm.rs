@@ -5,17 +5,20 @@ use crate::{Instruction, class, error::ParseError, op};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum InstructionType {
-    AluBinary,
+    AluBinary(i16),
     AluUnary,
     LoadImm,
+    LoadMapFd,
     LoadAbs,
     LoadInd,
     LoadReg,
     StoreImm,
     StoreReg,
     JumpUnconditional,
+    JumpLong,
     JumpConditional,
     Call,
+    LocalCall,
     Endian(i64),
     NoOperand,
 }
@@ -37,8 +40,11 @@ fn make_instruction_map() -> HashMap<String, (InstructionType, u8)> {
         // Miscellaneous.
         entry("exit", InstructionType::NoOperand, op::EXIT);
         entry("ja", InstructionType::JumpUnconditional, op::JA);
+        entry("gotol", InstructionType::JumpLong, op::JA32);
         entry("call", InstructionType::Call, op::CALL);
+        entry("lcall", InstructionType::LocalCall, op::CALL);
         entry("lddw", InstructionType::LoadImm, op::LDDW);
+        entry("ldmapfd", InstructionType::LoadMapFd, op::LDDW);
 
         // AluUnary.
         entry("neg", InstructionType::AluUnary, op::NEG64);
@@ -46,20 +52,23 @@ fn make_instruction_map() -> HashMap<String, (InstructionType, u8)> {
         entry("neg64", InstructionType::AluUnary, op::NEG64);
 
         // AluBinary.
-        for (&name, &opc) in alu_binary_ops.iter() {
+        for (&name, &(opc, off)) in alu_binary_ops.iter() {
             entry(
                 name,
-                InstructionType::AluBinary,
+                InstructionType::AluBinary(off),
                 class::EBPF_CLS_ALU64 | opc,
             );
-            entry(
-                &format!("{}32", name),
-                InstructionType::AluBinary,
-                class::EBPF_CLS_ALU | opc,
-            );
+            // the low 32 bits are never extended from 32 bits
+            if off != 32 {
+                entry(
+                    &format!("{}32", name),
+                    InstructionType::AluBinary(off),
+                    class::EBPF_CLS_ALU | opc,
+                );
+            }
             entry(
                 &format!("{}64", name),
-                InstructionType::AluBinary,
+                InstructionType::AluBinary(off),
                 class::EBPF_CLS_ALU64 | opc,
             );
         }
@@ -81,6 +90,14 @@ fn make_instruction_map() -> HashMap<String, (InstructionType, u8)> {
                 InstructionType::LoadReg,
                 op::EBPF_MEM | class::EBPF_CLS_LDX | size,
             );
+            // no 64 bits to extend a double word to
+            if size != op::EBPF_SIZE_DW {
+                entry(
+                    &format!("ldxs{}", suffix),
+                    InstructionType::LoadReg,
+                    op::EBPF_MEMSX | class::EBPF_CLS_LDX | size,
+                );
+            }
             entry(
                 &format!("st{}", suffix),
                 InstructionType::StoreImm,
@@ -100,6 +117,11 @@ fn make_instruction_map() -> HashMap<String, (InstructionType, u8)> {
                 InstructionType::JumpConditional,
                 class::EBPF_CLS_JMP | condition,
             );
+            entry(
+                &format!("{}32", name),
+                InstructionType::JumpConditional,
+                class::EBPF_CLS_JMP32 | condition,
+            );
         }
 
         // Endian.
@@ -114,6 +136,11 @@ fn make_instruction_map() -> HashMap<String, (InstructionType, u8)> {
                 InstructionType::Endian(size),
                 op::LE,
             );
+            entry(
+                &format!("bswap{}", size),
+                InstructionType::Endian(size),
+                op::BSWAP,
+            );
         }
     }
 
@@ -141,7 +168,7 @@ pub(crate) fn assemble(src: &str) -> Result<Vec<Instruction>, ParseError> {
                     Err(msg) => panic!("{}", msg),
                 }
                 // Special case for lddw.
-                if let InstructionType::LoadImm = inst_type {
+                if let InstructionType::LoadImm | InstructionType::LoadMapFd = inst_type {
                     if let Some(operands) = &raw.operands {
                         if let Operand::Integer(imm) = operands[1] {
                             let insn = insn(0, 0, 0, 0, imm >> 32).unwrap();
@@ -180,17 +207,20 @@ fn encode(
     let (a, b, c) = (operands_tuple(operands)).unwrap();
     match (inst_type, a, b, c) {
         (
-            InstructionType::AluBinary,
+            InstructionType::AluBinary(off),
             Operand::Register(dst),
             Operand::Register(src),
             Operand::Nil,
-        ) => insn(opc | op::EBPF_SRC_REG, dst, src, 0, 0),
+        ) => insn(opc | op::EBPF_SRC_REG, dst, src, off as i64, 0),
+        // sign extension only applies to a register
         (
-            InstructionType::AluBinary,
+            InstructionType::AluBinary(off),
             Operand::Register(dst),
             Operand::Integer(imm),
             Operand::Nil,
-        ) => insn(opc | op::EBPF_SRC_IMM, dst, 0, 0, imm),
+        ) if off == 0 || off == op::EBPF_OFF_SIGNED => {
+            insn(opc | op::EBPF_SRC_IMM, dst, 0, off as i64, imm)
+        }
         (InstructionType::AluUnary, Operand::Register(dst), Operand::Nil, Operand::Nil) => {
             insn(opc, dst, 0, 0, 0)
         }
@@ -224,6 +254,9 @@ fn encode(
         (InstructionType::JumpUnconditional, Operand::Integer(off), Operand::Nil, Operand::Nil) => {
             insn(opc, 0, 0, off, 0)
         }
+        (InstructionType::JumpLong, Operand::Integer(imm), Operand::Nil, Operand::Nil) => {
+            insn(opc, 0, 0, 0, imm)
+        }
         (
             InstructionType::JumpConditional,
             Operand::Register(dst),
@@ -239,6 +272,9 @@ fn encode(
         (InstructionType::Call, Operand::Integer(imm), Operand::Nil, Operand::Nil) => {
             insn(opc, 0, 0, 0, imm)
         }
+        (InstructionType::LocalCall, Operand::Integer(imm), Operand::Nil, Operand::Nil) => {
+            insn(opc, 0, op::EBPF_PSEUDO_CALL as i64, 0, imm)
+        }
         (InstructionType::Endian(size), Operand::Register(dst), Operand::Nil, Operand::Nil) => {
             insn(opc, dst, 0, 0, size)
         }
@@ -252,6 +288,12 @@ fn encode(
                 insn(opc, dst, 0, 0, (imm << 32) >> 32)
             }
         }
+        (
+            InstructionType::LoadMapFd,
+            Operand::Register(dst),
+            Operand::Integer(fd),
+            Operand::Nil,
+        ) => insn(opc, dst, op::EBPF_PSEUDO_MAP_FD as i64, 0, fd),
         _ => {
             dbg!(inst_type, a, b, c);
             todo!()
@@ -281,7 +323,7 @@ fn insn(op: u8, dst: i64, src: i64, off: i64, imm: i64) -> Result<Instruction, P
 
 #[cfg(test)]
 mod tests {
-    use crate::Instructions;
+    use crate::{Instruction, Instructions, op};
 
     #[test]
     fn t1() {
@@ -311,4 +353,57 @@ mod tests {
         let prog = "lddw r0, 0x10000000c";
         println!("{:?}", Instructions::from_asm(prog).unwrap());
     }
+
+    #[test]
+    fn test_ldmapfd() {
+        let prog = "ldmapfd r1, 3";
+        let v: Vec<Instruction> = Instructions::from_asm(prog).unwrap().into();
+        assert_eq!(v.len(), 2);
+        assert_eq!(v[0].op, op::LDDW);
+        assert_eq!(v[0].src_reg(), op::EBPF_PSEUDO_MAP_FD);
+        assert_eq!(v[0].dst_reg(), 1);
+        assert_eq!(v[0].imm, 3);
+        assert_eq!(format!("{:?}", v[0]), "ldmapfd r1, 3");
+    }
+
+    #[test]
+    fn test_lcall() {
+        let v: Vec<Instruction> = Instructions::from_asm("lcall +2").unwrap().into();
+        assert_eq!(v[0].op, op::CALL);
+        assert_eq!(v[0].src_reg(), op::EBPF_PSEUDO_CALL);
+        assert_eq!(v[0].imm, 2);
+        assert_eq!(format!("{:?}", v[0]), "lcall +2");
+    }
+
+    #[test]
+    fn test_isa_v4() {
+        let prog = "sdiv r1, -3
+smod r1, r2
+movsx832 r1, r2
+movsx32 r1, r2
+ldxsh r1, [r2+4]
+bswap64 r1
+gotol +70000";
+        let v: Vec<Instruction> = Instructions::from_asm(prog).unwrap().into();
+        assert_eq!((v[0].op, v[0].offset), (op::DIV64_IMM, op::EBPF_OFF_SIGNED));
+        assert_eq!((v[1].op, v[1].offset), (op::MOD64_REG, op::EBPF_OFF_SIGNED));
+        assert_eq!((v[2].op, v[2].offset), (op::MOV_REG, 8));
+        assert_eq!((v[3].op, v[3].offset), (op::MOV64_REG, 32));
+        assert_eq!(v[4].op, op::LDXSH);
+        assert_eq!(v[5].op, op::BSWAP);
+        assert_eq!((v[6].op, v[6].imm), (op::JA32, 70000));
+        let text: Vec<String> = v.iter().map(|ins| format!("{:?}", ins)).collect();
+        assert_eq!(text, prog.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_jmp32() {
+        let v: Vec<Instruction> = Instructions::from_asm("jlt32 r1, 5, +1\njsgt32 r1, r2, -1")
+            .unwrap()
+            .into();
+        assert_eq!(v[0].op, op::JLT32_IMM);
+        assert_eq!(v[1].op, op::JSGT32_REG);
+        assert_eq!(format!("{:?}", v[0]), "jlt32 r1, 5, +1");
+        assert_eq!(format!("{:?}", v[1]), "jsgt32 r1, r2, -1");
+    }
 }
@@ -14,7 +14,7 @@ pub fn locate_function<'a>(elf: &Elf<'a>, target_name: &str) -> Result<Range<usi
     let offset = (hdr.sh_offset + f.st_value) as usize;
     let size = f.st_size as usize;
 
-    return Ok(offset..(offset + size));
+    Ok(offset..(offset + size))
 }
 
 pub fn lookup_section<'a>(elf: &Elf<'a>, target_name: &str) -> Result<usize, ElfError> {
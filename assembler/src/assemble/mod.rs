@@ -1,4 +1,4 @@
-mod elf;
+pub(crate) mod elf;
 
 pub mod asm;
 mod asm_parser;
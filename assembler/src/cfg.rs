@@ -0,0 +1,422 @@
+use std::fmt::Write;
+
+use crate::{Instruction, class, op};
+
+/// instructions always run one after the other, from `start` up to `end`
+/// excluded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    /// blocks control may go to once the block ran
+    pub succs: Vec<usize>,
+    pub preds: Vec<usize>,
+    /// first blocks of the functions `lcall` goes to
+    pub callees: Vec<usize>,
+}
+
+/// control flow graph of a program, each function being its own graph
+/// starting at the block of its first instruction
+#[derive(Debug, Clone)]
+pub struct Cfg<'a> {
+    insns: &'a [Instruction],
+    blocks: Vec<BasicBlock>,
+    // block of each instruction
+    block_of: Vec<usize>,
+    funcs: Vec<usize>,
+    idom: Vec<Option<usize>>,
+    reachable: Vec<bool>,
+}
+
+impl<'a> Cfg<'a> {
+    pub fn new(insns: &'a [Instruction]) -> Self {
+        let len = insns.len();
+        let mut leaders = vec![false; len + 1];
+        let mut funcs = vec![0];
+        leaders[0] = true;
+        for (pc, ins) in Slots::new(insns) {
+            if !is_jmp(ins) {
+                continue;
+            }
+            if let Some(target) = local_call(pc, ins, len) {
+                leaders[target] = true;
+                funcs.push(target);
+            }
+            if ins.op == op::EXIT || jump_target(pc, ins, len).is_some() {
+                leaders[next_pc(pc, ins)] = true;
+            }
+            if let Some(target) = jump_target(pc, ins, len) {
+                leaders[target] = true;
+            }
+        }
+        funcs.sort_unstable();
+        funcs.dedup();
+
+        let mut blocks = Vec::new();
+        let mut block_of = vec![0; len];
+        for (pc, _) in Slots::new(insns) {
+            if leaders[pc] {
+                blocks.push(BasicBlock {
+                    start: pc,
+                    end: pc,
+                    succs: Vec::new(),
+                    preds: Vec::new(),
+                    callees: Vec::new(),
+                });
+            }
+            let block = blocks.len() - 1;
+            blocks[block].end = next_pc(pc, &insns[pc]).min(len);
+            block_of[pc..blocks[block].end].fill(block);
+        }
+
+        for block in 0..blocks.len() {
+            let mut succs = Vec::new();
+            let mut callees = Vec::new();
+            for (pc, ins) in Slots::new(insns).skip_while(|&(pc, _)| pc < blocks[block].start) {
+                if pc >= blocks[block].end {
+                    break;
+                }
+                if let Some(target) = local_call(pc, ins, len) {
+                    callees.push(block_of[target]);
+                }
+            }
+            let last = last_pc(insns, &blocks[block]);
+            let ins = &insns[last];
+            let falls_through = !matches!(ins.op, op::EXIT | op::JA | op::JA32);
+            if falls_through && blocks[block].end < len {
+                succs.push(block_of[blocks[block].end]);
+            }
+            if let Some(target) = jump_target(last, ins, len) {
+                succs.push(block_of[target]);
+            }
+            succs.dedup();
+            for &succ in &succs {
+                blocks[succ].preds.push(block);
+            }
+            blocks[block].succs = succs;
+            blocks[block].callees = callees;
+        }
+        let funcs = funcs.into_iter().map(|pc| block_of[pc]).collect();
+
+        let mut cfg = Self {
+            insns,
+            blocks,
+            block_of,
+            funcs,
+            idom: Vec::new(),
+            reachable: Vec::new(),
+        };
+        cfg.idom = cfg.compute_dominators();
+        cfg.reachable = cfg.compute_reachable();
+        cfg
+    }
+
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+
+    /// block holding the instruction at `pc`
+    pub fn block_of(&self, pc: usize) -> usize {
+        self.block_of[pc]
+    }
+
+    pub fn successors(&self, block: usize) -> &[usize] {
+        &self.blocks[block].succs
+    }
+
+    pub fn predecessors(&self, block: usize) -> &[usize] {
+        &self.blocks[block].preds
+    }
+
+    /// first block of each function, the program itself first
+    pub fn functions(&self) -> &[usize] {
+        &self.funcs
+    }
+
+    /// closest block every path from the start of the function to `block`
+    /// goes through, `None` for the first block of a function and for
+    /// blocks no path reaches
+    pub fn immediate_dominator(&self, block: usize) -> Option<usize> {
+        self.idom[block]
+    }
+
+    /// whether every path from the start of the function to `block` goes
+    /// through `dom`, a block dominating itself
+    pub fn dominates(&self, dom: usize, mut block: usize) -> bool {
+        loop {
+            if block == dom {
+                return true;
+            }
+            match self.idom[block] {
+                Some(idom) => block = idom,
+                None => return false,
+            }
+        }
+    }
+
+    /// blocks no execution of the program runs
+    pub fn unreachable_blocks(&self) -> Vec<usize> {
+        (0..self.blocks.len())
+            .filter(|&block| !self.reachable[block])
+            .collect()
+    }
+
+    /// graphviz graph with the instructions of each block, jumps taken are
+    /// labelled, calls dashed and unreachable blocks greyed
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n");
+        dot.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for (pc, ins) in Slots::new(self.insns).skip_while(|&(pc, _)| pc < block.start) {
+                if pc >= block.end {
+                    break;
+                }
+                let text = format!("{:?}", ins)
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"");
+                let _ = write!(label, "{}: {}\\l", pc, text);
+            }
+            let style = if self.reachable[i] {
+                ""
+            } else {
+                ", style=filled, fillcolor=lightgrey"
+            };
+            let _ = writeln!(dot, "    b{} [label=\"{}\"{}];", i, label, style);
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            let last = last_pc(self.insns, block);
+            let target = jump_target(last, &self.insns[last], self.insns.len());
+            let conditional = !matches!(self.insns[last].op, op::JA | op::JA32);
+            for &succ in &block.succs {
+                let taken = target.is_some_and(|t| self.block_of[t] == succ);
+                if taken && conditional {
+                    let _ = writeln!(dot, "    b{} -> b{} [label=\"taken\"];", i, succ);
+                } else {
+                    let _ = writeln!(dot, "    b{} -> b{};", i, succ);
+                }
+            }
+            for &callee in &block.callees {
+                let _ = writeln!(dot, "    b{} -> b{} [style=dashed];", i, callee);
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // iterative algorithm of Cooper, Harvey and Kennedy, with a virtual root
+    // above the first block of every function
+    fn compute_dominators(&self) -> Vec<Option<usize>> {
+        let n = self.blocks.len();
+        let root = n;
+        let mut postorder = Vec::with_capacity(n + 1);
+        let mut visited = vec![false; n + 1];
+        // (block, next successor to visit)
+        let mut stack = vec![(root, 0)];
+        visited[root] = true;
+        while let Some((block, next)) = stack.pop() {
+            let succs = if block == root {
+                &self.funcs
+            } else {
+                &self.blocks[block].succs
+            };
+            match succs.get(next) {
+                Some(&succ) => {
+                    stack.push((block, next + 1));
+                    if !visited[succ] {
+                        visited[succ] = true;
+                        stack.push((succ, 0));
+                    }
+                }
+                None => postorder.push(block),
+            }
+        }
+        let mut order = vec![usize::MAX; n + 1];
+        for (i, &block) in postorder.iter().enumerate() {
+            order[block] = i;
+        }
+
+        let mut idom = vec![None; n + 1];
+        idom[root] = Some(root);
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while order[a] < order[b] {
+                    a = idom[a].unwrap();
+                }
+                while order[b] < order[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in postorder.iter().rev().skip(1) {
+                let preds = if self.funcs.contains(&block) {
+                    vec![root]
+                } else {
+                    self.blocks[block].preds.clone()
+                };
+                let mut new_idom = None;
+                for pred in preds {
+                    if idom[pred].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(other) => intersect(&idom, pred, other),
+                    });
+                }
+                if new_idom.is_some() && idom[block] != new_idom {
+                    idom[block] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom.truncate(n);
+        idom.into_iter()
+            .map(|idom| idom.filter(|&idom| idom != root))
+            .collect()
+    }
+
+    // from the start of the program, through jumps and calls
+    fn compute_reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut stack = vec![0];
+        while let Some(block) = stack.pop() {
+            if std::mem::replace(&mut reachable[block], true) {
+                continue;
+            }
+            let block = &self.blocks[block];
+            stack.extend(block.succs.iter().chain(block.callees.iter()));
+        }
+        reachable
+    }
+}
+
+// pc and instruction of each instruction, skipping the second slot of lddw
+struct Slots<'a> {
+    insns: &'a [Instruction],
+    pc: usize,
+}
+
+impl<'a> Slots<'a> {
+    fn new(insns: &'a [Instruction]) -> Self {
+        Self { insns, pc: 0 }
+    }
+}
+
+impl<'a> Iterator for Slots<'a> {
+    type Item = (usize, &'a Instruction);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pc = self.pc;
+        let ins = self.insns.get(pc)?;
+        self.pc = next_pc(pc, ins);
+        Some((pc, ins))
+    }
+}
+
+fn next_pc(pc: usize, ins: &Instruction) -> usize {
+    if ins.op == op::LDDW { pc + 2 } else { pc + 1 }
+}
+
+fn last_pc(insns: &[Instruction], block: &BasicBlock) -> usize {
+    Slots::new(insns)
+        .skip_while(|&(pc, _)| pc < block.start)
+        .take_while(|&(pc, _)| pc < block.end)
+        .last()
+        .map_or(block.start, |(pc, _)| pc)
+}
+
+fn is_jmp(ins: &Instruction) -> bool {
+    matches!(ins.class(), class::EBPF_CLS_JMP | class::EBPF_CLS_JMP32)
+}
+
+// target of a jump inside the program
+fn jump_target(pc: usize, ins: &Instruction, len: usize) -> Option<usize> {
+    if !is_jmp(ins) || ins.op == op::CALL || ins.op == op::EXIT {
+        return None;
+    }
+    let target = pc as i64 + ins.jump_offset() + 1;
+    (0..len as i64).contains(&target).then_some(target as usize)
+}
+
+fn local_call(pc: usize, ins: &Instruction, len: usize) -> Option<usize> {
+    if ins.op != op::CALL || ins.src_reg() != op::EBPF_PSEUDO_CALL {
+        return None;
+    }
+    let target = pc as i64 + ins.imm + 1;
+    (0..len as i64).contains(&target).then_some(target as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Instructions;
+
+    #[test]
+    fn test_blocks() {
+        let prog = "mov r0, 0
+jeq r1, 0, +3
+lddw r0, 0x100000000
+exit
+mov r0, 1
+exit";
+        let insns = Instructions::from_asm(prog).unwrap();
+        let cfg = insns.cfg();
+        let spans: Vec<_> = cfg.blocks().iter().map(|b| (b.start, b.end)).collect();
+        assert_eq!(spans, [(0, 2), (2, 5), (5, 7)]);
+        assert_eq!(cfg.successors(0), [1, 2]);
+        assert_eq!(cfg.predecessors(2), [0]);
+        assert!(cfg.successors(1).is_empty());
+        assert_eq!(cfg.block_of(3), 1);
+        assert_eq!(cfg.immediate_dominator(2), Some(0));
+        assert!(cfg.unreachable_blocks().is_empty());
+    }
+
+    #[test]
+    fn test_dominators() {
+        // diamond then a loop back to the join
+        let prog = "jeq r1, 0, +2
+mov r0, 1
+ja +1
+mov r0, 2
+add r0, 1
+jlt r0, 10, -2
+exit
+mov r0, 3
+exit";
+        let insns = Instructions::from_asm(prog).unwrap();
+        let cfg = insns.cfg();
+        let join = cfg.block_of(4);
+        assert_eq!(cfg.immediate_dominator(cfg.block_of(1)), Some(0));
+        assert_eq!(cfg.immediate_dominator(cfg.block_of(3)), Some(0));
+        assert_eq!(cfg.immediate_dominator(join), Some(0));
+        assert!(cfg.dominates(join, cfg.block_of(6)));
+        assert!(!cfg.dominates(cfg.block_of(1), join));
+        assert_eq!(cfg.unreachable_blocks(), [cfg.block_of(7)]);
+        assert_eq!(cfg.immediate_dominator(cfg.block_of(7)), None);
+    }
+
+    #[test]
+    fn test_functions() {
+        let prog = "lcall +2
+exit
+exit
+mov r0, 1
+exit";
+        let insns = Instructions::from_asm(prog).unwrap();
+        let cfg = insns.cfg();
+        assert_eq!(cfg.functions(), [0, cfg.block_of(3)]);
+        assert_eq!(cfg.blocks()[0].callees, [cfg.block_of(3)]);
+        assert_eq!(cfg.unreachable_blocks(), [cfg.block_of(2)]);
+        assert_eq!(cfg.immediate_dominator(cfg.block_of(3)), None);
+
+        let dot = cfg.to_dot();
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains("b0 [label=\"0: lcall +2\\l1: exit\\l\"];"));
+        assert!(dot.contains("b0 -> b2 [style=dashed];"));
+        assert!(dot.contains("b1 [label=\"2: exit\\l\", style=filled, fillcolor=lightgrey];"));
+    }
+}
@@ -5,12 +5,15 @@ pub mod class {
     pub const EBPF_CLS_STX: u8 = 3;
     pub const EBPF_CLS_ALU: u8 = 4;
     pub const EBPF_CLS_JMP: u8 = 5;
-    pub const EBPF_CLS_RET: u8 = 6;
+    pub const EBPF_CLS_JMP32: u8 = 6;
     pub const EBPF_CLS_ALU64: u8 = 7;
 }
 
 pub mod alu {
+    pub const DIV: u8 = 3;
     pub const NEG: u8 = 8;
+    pub const MOD: u8 = 9;
+    pub const MOV: u8 = 11;
     pub const END: u8 = 13;
 }
 
@@ -25,6 +28,8 @@ pub mod op {
     pub const EBPF_IND: u8 = 0x40;
     pub const EBPF_MEM: u8 = 0x60;
     pub const EBPF_XADD: u8 = 0xc0;
+    // sign extending load
+    pub const EBPF_MEMSX: u8 = 0x80;
 
     pub const EBPF_ADD: u8 = 0x00;
     pub const EBPF_SUB: u8 = 0x10;
@@ -70,6 +75,16 @@ pub mod op {
 
     pub const EBPF_MODE_IMM: u8 = 0x00;
     pub const EBPF_MODE_MEM: u8 = 0x60;
+    pub const EBPF_MODE_MEMSX: u8 = 0x80;
+
+    // offset of a `div` or `mod` that is signed, `sdiv` and `smod`
+    pub const EBPF_OFF_SIGNED: i16 = 1;
+
+    // src register of a `lddw` whose imm is a map fd rather than a constant
+    pub const EBPF_PSEUDO_MAP_FD: u8 = 0x01;
+    // src register of a `call` to a function of the program, its imm is the
+    // offset of the function from the next instruction
+    pub const EBPF_PSEUDO_CALL: u8 = 0x01;
 
     // real opcodes
 
@@ -101,6 +116,8 @@ pub mod op {
 
     pub const LE: u8 = EBPF_CLS_ALU | EBPF_SRC_IMM | EBPF_END;
     pub const BE: u8 = EBPF_CLS_ALU | EBPF_SRC_REG | EBPF_END;
+    // byte swap whatever the endianness of the host
+    pub const BSWAP: u8 = EBPF_CLS_ALU64 | EBPF_SRC_IMM | EBPF_END;
 
     pub const ADD64_IMM: u8 = EBPF_CLS_ALU64 | EBPF_SRC_IMM | EBPF_ADD;
     pub const ADD64_REG: u8 = EBPF_CLS_ALU64 | EBPF_SRC_REG | EBPF_ADD;
@@ -132,6 +149,9 @@ pub mod op {
     pub const LDXH: u8 = EBPF_CLS_LDX | EBPF_MODE_MEM | EBPF_SIZE_H;
     pub const LDXB: u8 = EBPF_CLS_LDX | EBPF_MODE_MEM | EBPF_SIZE_B;
     pub const LDXDW: u8 = EBPF_CLS_LDX | EBPF_MODE_MEM | EBPF_SIZE_DW;
+    pub const LDXSW: u8 = EBPF_CLS_LDX | EBPF_MODE_MEMSX | EBPF_SIZE_W;
+    pub const LDXSH: u8 = EBPF_CLS_LDX | EBPF_MODE_MEMSX | EBPF_SIZE_H;
+    pub const LDXSB: u8 = EBPF_CLS_LDX | EBPF_MODE_MEMSX | EBPF_SIZE_B;
     pub const STW: u8 = EBPF_CLS_ST | EBPF_MODE_MEM | EBPF_SIZE_W;
     pub const STH: u8 = EBPF_CLS_ST | EBPF_MODE_MEM | EBPF_SIZE_H;
     pub const STB: u8 = EBPF_CLS_ST | EBPF_MODE_MEM | EBPF_SIZE_B;
@@ -143,6 +163,8 @@ pub mod op {
     pub const LDDW: u8 = EBPF_CLS_LD | EBPF_MODE_IMM | EBPF_SIZE_DW;
 
     pub const JA: u8 = EBPF_CLS_JMP | EBPF_JA;
+    // `gotol`, the offset is in imm
+    pub const JA32: u8 = EBPF_CLS_JMP32 | EBPF_JA;
     pub const JEQ_IMM: u8 = EBPF_CLS_JMP | EBPF_SRC_IMM | EBPF_JEQ;
     pub const JEQ_REG: u8 = EBPF_CLS_JMP | EBPF_SRC_REG | EBPF_JEQ;
     pub const JGT_IMM: u8 = EBPF_CLS_JMP | EBPF_SRC_IMM | EBPF_JGT;
@@ -169,6 +191,30 @@ pub mod op {
     pub const JSLT_REG: u8 = EBPF_CLS_JMP | EBPF_SRC_REG | EBPF_JSLT;
     pub const JSLE_IMM: u8 = EBPF_CLS_JMP | EBPF_SRC_IMM | EBPF_JSLE;
     pub const JSLE_REG: u8 = EBPF_CLS_JMP | EBPF_SRC_REG | EBPF_JSLE;
+
+    // same conditions, comparing the low 32 bits of the operands
+    pub const JEQ32_IMM: u8 = EBPF_CLS_JMP32 | EBPF_SRC_IMM | EBPF_JEQ;
+    pub const JEQ32_REG: u8 = EBPF_CLS_JMP32 | EBPF_SRC_REG | EBPF_JEQ;
+    pub const JGT32_IMM: u8 = EBPF_CLS_JMP32 | EBPF_SRC_IMM | EBPF_JGT;
+    pub const JGT32_REG: u8 = EBPF_CLS_JMP32 | EBPF_SRC_REG | EBPF_JGT;
+    pub const JGE32_IMM: u8 = EBPF_CLS_JMP32 | EBPF_SRC_IMM | EBPF_JGE;
+    pub const JGE32_REG: u8 = EBPF_CLS_JMP32 | EBPF_SRC_REG | EBPF_JGE;
+    pub const JSET32_IMM: u8 = EBPF_CLS_JMP32 | EBPF_SRC_IMM | EBPF_JSET;
+    pub const JSET32_REG: u8 = EBPF_CLS_JMP32 | EBPF_SRC_REG | EBPF_JSET;
+    pub const JNE32_IMM: u8 = EBPF_CLS_JMP32 | EBPF_SRC_IMM | EBPF_JNE;
+    pub const JNE32_REG: u8 = EBPF_CLS_JMP32 | EBPF_SRC_REG | EBPF_JNE;
+    pub const JSGT32_IMM: u8 = EBPF_CLS_JMP32 | EBPF_SRC_IMM | EBPF_JSGT;
+    pub const JSGT32_REG: u8 = EBPF_CLS_JMP32 | EBPF_SRC_REG | EBPF_JSGT;
+    pub const JSGE32_IMM: u8 = EBPF_CLS_JMP32 | EBPF_SRC_IMM | EBPF_JSGE;
+    pub const JSGE32_REG: u8 = EBPF_CLS_JMP32 | EBPF_SRC_REG | EBPF_JSGE;
+    pub const JLT32_IMM: u8 = EBPF_CLS_JMP32 | EBPF_SRC_IMM | EBPF_JLT;
+    pub const JLT32_REG: u8 = EBPF_CLS_JMP32 | EBPF_SRC_REG | EBPF_JLT;
+    pub const JLE32_IMM: u8 = EBPF_CLS_JMP32 | EBPF_SRC_IMM | EBPF_JLE;
+    pub const JLE32_REG: u8 = EBPF_CLS_JMP32 | EBPF_SRC_REG | EBPF_JLE;
+    pub const JSLT32_IMM: u8 = EBPF_CLS_JMP32 | EBPF_SRC_IMM | EBPF_JSLT;
+    pub const JSLT32_REG: u8 = EBPF_CLS_JMP32 | EBPF_SRC_REG | EBPF_JSLT;
+    pub const JSLE32_IMM: u8 = EBPF_CLS_JMP32 | EBPF_SRC_IMM | EBPF_JSLE;
+    pub const JSLE32_REG: u8 = EBPF_CLS_JMP32 | EBPF_SRC_REG | EBPF_JSLE;
 }
 
 pub const DEFAULT_STACK_SIZE: usize = 4096;
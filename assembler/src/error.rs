@@ -1,5 +1,16 @@
 use thiserror::Error;
 
+use crate::IsaVersion;
+
+/// an instruction newer than the isa version the program targets
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("insn {pc} needs isa {required}, the program targets {isa}")]
+pub struct IsaError {
+    pub pc: usize,
+    pub required: IsaVersion,
+    pub isa: IsaVersion,
+}
+
 #[derive(Error, Debug)]
 pub enum ElfError {
     #[error("platform not support")]
@@ -12,6 +23,8 @@ pub enum ElfError {
     SectionNotFound(String),
     #[error("section not found")]
     FunctionNotFound(String),
+    #[error(transparent)]
+    Isa(#[from] IsaError),
     #[error("unknown data store error")]
     Unknown,
 }
@@ -28,4 +41,18 @@ pub enum ParseError {
     InvalidOffset(i64),
     #[error("invalid immediate")]
     InvalidImmediate(i64),
+    #[error(transparent)]
+    Isa(#[from] IsaError),
+}
+
+#[derive(Error, Debug)]
+pub enum JitError {
+    #[error("unknown helper function")]
+    UnknownHelper(i64),
+    #[error("make exec error")]
+    MakeExec,
+    #[error("bpf to bpf call at insn {0} is not supported")]
+    LocalCall(usize),
+    #[error(transparent)]
+    Isa(#[from] IsaError),
 }
@@ -1,12 +1,13 @@
-use std::mem;
-
+use goblin::elf::Elf;
 use nom::AsBytes;
 
 use crate::{
     alu,
-    assemble::asm::assemble,
+    assemble::{asm::assemble, elf::locate_function},
+    cfg::Cfg,
     class,
-    error::ParseError,
+    error::{ElfError, IsaError, ParseError},
+    isa::IsaVersion,
     utils::{memory, reg},
 };
 
@@ -58,36 +59,33 @@ impl Instruction {
     pub fn source(&self) -> u8 {
         (self.op >> 3) & 0x1 // 0x00001000
     }
+
+    // for jmp/jmp32, `gotol` keeps its offset in imm
+    #[inline(always)]
+    pub fn jump_offset(&self) -> i64 {
+        if self.op == crate::op::JA32 {
+            self.imm
+        } else {
+            self.offset as i64
+        }
+    }
 }
 
 /// from bytes, notice that we should handle lddw here, which means
 /// we should perceive the next instruction's raw content
 impl From<&[u8]> for Instruction {
     fn from(bytes: &[u8]) -> Self {
-        let mut op = 0;
-        let mut regs = 0;
-        let mut offset = 0;
-        // if current instruction is `lddx`, we should also load the next instruction's imm
-        // FIXME: not sure if we should load next the instruction when facing lddx
-        let mut imm = 0;
-
-        unsafe {
-            let (mut src, _): (usize, usize) = mem::transmute(bytes);
-            std::ptr::copy(src as *const u8, &mut op as *mut _ as _, 1);
-            src += 1;
-            std::ptr::copy(src as *const u8, &mut regs as *mut _ as _, 1);
-            src += 1;
-            std::ptr::copy(src as *const u8, &mut offset as *mut _ as _, 2);
-            src += 2;
-            std::ptr::copy(src as *const u8, &mut imm as *mut _ as _, 4);
-            src += 8;
-
-            if op == 0x18 {
-                assert!(bytes.len() >= 16);
-                let mut tmp = 0;
-                std::ptr::copy(src as *const u8, &mut tmp as *mut _ as _, 4);
-                imm |= (tmp as i64) << 32;
-            }
+        assert!(bytes.len() >= INS_SIZE);
+        let op = bytes[0];
+        let regs = bytes[1];
+        let offset = i16::from_le_bytes([bytes[2], bytes[3]]);
+        let mut imm = i32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as i64;
+
+        // lddw keeps the whole 64 bits immediate in its first slot
+        if op == LDDW {
+            assert!(bytes.len() >= 2 * INS_SIZE);
+            let high = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+            imm = ((imm as u32 as u64) | ((high as u64) << 32)) as i64;
         }
 
         Self {
@@ -112,9 +110,33 @@ impl std::fmt::Debug for Instruction {
 
             match opcode {
                 alu::END => {
-                    let opcode_name = if source == 1 { "be" } else { "le" };
+                    let opcode_name = if cls == class::EBPF_CLS_ALU64 {
+                        "bswap"
+                    } else if source == 1 {
+                        "be"
+                    } else {
+                        "le"
+                    };
                     write!(f, "{}{} {}", opcode_name, self.imm, reg(self.dst_reg()))
                 }
+                alu::DIV | alu::MOD if self.offset == crate::op::EBPF_OFF_SIGNED => {
+                    if source == 0 {
+                        write!(f, "s{} {}, {}", opcode_name, reg(dst), self.imm)
+                    } else {
+                        write!(f, "s{} {}, {}", opcode_name, reg(dst), reg(src))
+                    }
+                }
+                alu::MOV if self.offset != 0 => {
+                    let suffix = if cls == class::EBPF_CLS_ALU { "32" } else { "" };
+                    write!(
+                        f,
+                        "movsx{}{} {}, {}",
+                        self.offset,
+                        suffix,
+                        reg(dst),
+                        reg(src)
+                    )
+                }
                 alu::NEG => {
                     write!(f, "{} {}", opcode_name, reg(dst))
                 }
@@ -126,20 +148,28 @@ impl std::fmt::Debug for Instruction {
                     }
                 }
             }
-        } else if cls == class::EBPF_CLS_JMP {
+        } else if cls == class::EBPF_CLS_JMP || cls == class::EBPF_CLS_JMP32 {
             let source = self.source(); // 0x00001000
             let opcode = self.opcode(); // 0x11110000
             let opcode_name = *crate::JMP_OPCODES_TO_NAME.get(&opcode).unwrap();
+            let suffix = if cls == class::EBPF_CLS_JMP32 {
+                "32"
+            } else {
+                ""
+            };
             match opcode_name {
                 "exit" => write!(f, "{}", opcode_name),
+                "call" if src == crate::op::EBPF_PSEUDO_CALL => write!(f, "lcall {:+}", self.imm),
                 "call" => write!(f, "{} {}", opcode_name, self.imm),
-                "ja" => write!(f, "{} {}", opcode_name, self.offset),
+                "ja" if cls == class::EBPF_CLS_JMP32 => write!(f, "gotol {:+}", self.imm),
+                "ja" => write!(f, "{} {:+}", opcode_name, self.offset),
                 _ => {
                     if source == 0 {
                         write!(
                             f,
-                            "{} {},{}, {}",
+                            "{}{} {}, {}, {:+}",
                             opcode_name,
+                            suffix,
                             reg(dst),
                             self.imm,
                             self.offset
@@ -147,10 +177,11 @@ impl std::fmt::Debug for Instruction {
                     } else {
                         write!(
                             f,
-                            "{} {},{}, {}",
+                            "{}{} {}, {}, {:+}",
                             opcode_name,
+                            suffix,
                             reg(dst),
-                            src as i64,
+                            reg(src),
                             self.offset
                         )
                     }
@@ -166,7 +197,9 @@ impl std::fmt::Debug for Instruction {
             let class_name = *crate::CLASS.get(&cls).unwrap();
 
             // handle special cases: lddx
-            if self.op == 0x18 {
+            if self.op == 0x18 && src == crate::op::EBPF_PSEUDO_MAP_FD {
+                return write!(f, "ldmapfd {}, {}", reg(dst), self.imm);
+            } else if self.op == 0x18 {
                 return write!(f, "{}{} {}, {}", class_name, size_name, reg(dst), self.imm);
             } else if self.op == 0x0 {
                 // section instruction of lddw
@@ -175,6 +208,11 @@ impl std::fmt::Debug for Instruction {
 
             match cls {
                 class::EBPF_CLS_LDX => {
+                    let class_name = if self.op & 0xe0 == crate::op::EBPF_MODE_MEMSX {
+                        "ldxs"
+                    } else {
+                        class_name
+                    };
                     write!(
                         f,
                         "{}{} {}, {}",
@@ -226,6 +264,39 @@ impl Instructions {
         let inner = assemble(text)?;
         Ok(Self { inner })
     }
+
+    /// instructions of the function `name` in the relocatable object `buffer`
+    pub fn from_elf(buffer: &[u8], name: &str) -> Result<Self, ElfError> {
+        let elf = Elf::parse(buffer).map_err(|_| ElfError::PlatFormNotSupport)?;
+        let range = locate_function(&elf, name)?;
+        let bytes = buffer.get(range).ok_or(ElfError::NoTextSection)?;
+        Ok(Self::from(bytes))
+    }
+
+    /// `from_asm`, rejecting the instructions newer than `isa`
+    pub fn from_asm_with_isa(text: &str, isa: IsaVersion) -> Result<Self, ParseError> {
+        let instructions = Self::from_asm(text)?;
+        isa.check(&instructions.inner)?;
+        Ok(instructions)
+    }
+
+    /// `from_elf`, rejecting the instructions newer than `isa`
+    pub fn from_elf_with_isa(buffer: &[u8], name: &str, isa: IsaVersion) -> Result<Self, ElfError> {
+        let instructions = Self::from_elf(buffer, name)?;
+        isa.check(&instructions.inner)?;
+        Ok(instructions)
+    }
+
+    /// decode `bytes`, rejecting the instructions newer than `isa`
+    pub fn decode(bytes: &[u8], isa: IsaVersion) -> Result<Self, IsaError> {
+        let instructions = Self::from(bytes);
+        isa.check(&instructions.inner)?;
+        Ok(instructions)
+    }
+
+    pub fn cfg(&self) -> Cfg<'_> {
+        Cfg::new(&self.inner)
+    }
 }
 
 impl From<Instructions> for Vec<Instruction> {
@@ -245,7 +316,10 @@ impl From<&[u8]> for Instructions {
         while i < bytes.len() {
             let r = Instruction::from(&bytes[i..]);
             inner.push(r);
+            // same second slot as the assembler emits, so jump offsets and
+            // pcs keep counting 8 bytes slots
             if r.op == LDDW {
+                inner.push(Instruction::new(0, 0, 0, r.imm >> 32));
                 i += INS_SIZE;
             }
             i += INS_SIZE;
@@ -319,6 +393,23 @@ mod tests {
         // let a=1819043144;
         // let b=
         // println!("instruction:{}", r);
+
+        let inner: Vec<Instruction> = instructions.into();
+        assert_eq!(inner.len(), 13);
+        assert_eq!(inner[2].imm, 8022916924116329800);
+        assert_eq!(inner[3].imm, 8022916924116329800 >> 32);
+        assert_eq!(inner[8].imm, -16);
+    }
+
+    #[test]
+    fn test_from_elf() {
+        let buffer = std::fs::read("../data/hello_kern.o").unwrap();
+        let instructions = Instructions::from_elf(&buffer, "bpf_prog").unwrap();
+        assert_eq!(Vec::<Instruction>::from(instructions).len(), 13);
+        assert!(matches!(
+            Instructions::from_elf(&buffer, "missing"),
+            Err(ElfError::FunctionNotFound(_))
+        ));
     }
 
     #[test]
@@ -0,0 +1,137 @@
+use std::{fmt, str::FromStr};
+
+use crate::{Instruction, alu, class, error::IsaError, op};
+
+/// instruction set a program targets, as clang `-mcpu`, each version adds
+/// instructions to the previous one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IsaVersion {
+    V1,
+    /// `jlt`, `jle`, `jslt` and `jsle`
+    V2,
+    /// the jmp32 class
+    V3,
+    /// `sdiv`, `smod`, `movsx`, `ldxs`, `bswap` and `gotol`
+    #[default]
+    V4,
+}
+
+impl IsaVersion {
+    /// first version with `ins`
+    pub fn of(ins: &Instruction) -> IsaVersion {
+        match ins.class() {
+            class::EBPF_CLS_JMP32 if ins.op == op::JA32 => IsaVersion::V4,
+            class::EBPF_CLS_JMP32 => IsaVersion::V3,
+            class::EBPF_CLS_JMP => match ins.op & op::ALU_OP_MASK {
+                op::EBPF_JLT | op::EBPF_JLE | op::EBPF_JSLT | op::EBPF_JSLE => IsaVersion::V2,
+                _ => IsaVersion::V1,
+            },
+            class::EBPF_CLS_ALU | class::EBPF_CLS_ALU64 => match ins.opcode() {
+                alu::DIV | alu::MOD | alu::MOV if ins.offset != 0 => IsaVersion::V4,
+                _ if ins.op == op::BSWAP => IsaVersion::V4,
+                _ => IsaVersion::V1,
+            },
+            class::EBPF_CLS_LDX if ins.op & 0xe0 == op::EBPF_MODE_MEMSX => IsaVersion::V4,
+            _ => IsaVersion::V1,
+        }
+    }
+
+    /// the first instruction of `insns` newer than this version, if any
+    pub fn check(self, insns: &[Instruction]) -> Result<(), IsaError> {
+        for (pc, ins) in insns.iter().enumerate() {
+            let required = IsaVersion::of(ins);
+            if required > self {
+                return Err(IsaError {
+                    pc,
+                    required,
+                    isa: self,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for IsaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = match self {
+            IsaVersion::V1 => 1,
+            IsaVersion::V2 => 2,
+            IsaVersion::V3 => 3,
+            IsaVersion::V4 => 4,
+        };
+        write!(f, "v{}", version)
+    }
+}
+
+impl FromStr for IsaVersion {
+    type Err = String;
+
+    /// `v1` to `v4`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(IsaVersion::V1),
+            "v2" => Ok(IsaVersion::V2),
+            "v3" => Ok(IsaVersion::V3),
+            "v4" => Ok(IsaVersion::V4),
+            _ => Err(format!("unknown isa version {}, expected v1 to v4", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Instruction, Instructions, IsaVersion, JitError, JitOptions, error::ParseError, translate,
+    };
+
+    #[test]
+    fn test_versions() {
+        let versions = [
+            ("mov r0, 0", IsaVersion::V1),
+            ("lddw r0, 0x100000000", IsaVersion::V1),
+            ("jgt r0, 1, +0", IsaVersion::V1),
+            ("jlt r0, 1, +0", IsaVersion::V2),
+            ("jgt32 r0, 1, +0", IsaVersion::V3),
+            ("sdiv r0, 3", IsaVersion::V4),
+            ("movsx8 r0, r1", IsaVersion::V4),
+            ("ldxsb r0, [r1]", IsaVersion::V4),
+            ("bswap16 r0", IsaVersion::V4),
+            ("be16 r0", IsaVersion::V1),
+            ("gotol +0", IsaVersion::V4),
+        ];
+        for (prog, version) in versions {
+            let insns: Vec<Instruction> = Instructions::from_asm(prog).unwrap().into();
+            assert_eq!(IsaVersion::of(&insns[0]), version, "{prog}");
+        }
+        assert!("v5".parse::<IsaVersion>().is_err());
+        assert_eq!("v2".parse::<IsaVersion>(), Ok(IsaVersion::V2));
+    }
+
+    #[test]
+    fn test_reject_newer() {
+        let prog = "mov r0, 0\njlt r0, 1, +0\nexit";
+        let err = Instructions::from_asm_with_isa(prog, IsaVersion::V1).unwrap_err();
+        assert!(matches!(
+            err,
+            ParseError::Isa(e) if e.pc == 1 && e.required == IsaVersion::V2
+        ));
+        assert!(Instructions::from_asm_with_isa(prog, IsaVersion::V2).is_ok());
+
+        // jlt32 r0, 1, +0
+        let bytes = [0xa6, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+        let err = Instructions::decode(&bytes, IsaVersion::V2).unwrap_err();
+        assert_eq!((err.pc, err.required), (0, IsaVersion::V3));
+        let insns: Vec<Instruction> = Instructions::decode(&bytes, IsaVersion::V3).unwrap().into();
+        assert_eq!(format!("{:?}", insns[0]), "jlt32 r0, 1, +0");
+
+        let options = JitOptions {
+            isa: IsaVersion::V2,
+            ..Default::default()
+        };
+        assert!(matches!(
+            translate(&insns, &options),
+            Err(JitError::Isa(e)) if e.required == IsaVersion::V3
+        ));
+    }
+}
@@ -0,0 +1,64 @@
+use libc::{
+    MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE, mmap, mprotect,
+    munmap,
+};
+
+use crate::JitError;
+
+const PAGE_SIZE: usize = 4096;
+
+fn page_align(n: usize) -> usize {
+    (n + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1)
+}
+
+/// executable copy of the jited code, the pages are made read-only once the
+/// code has been copied in
+#[derive(Debug)]
+pub struct JitMemory {
+    base: *mut u8,
+    size: usize,
+}
+
+// the mapping is never written after `new` returns
+unsafe impl Send for JitMemory {}
+unsafe impl Sync for JitMemory {}
+
+impl JitMemory {
+    pub fn new(code: &[u8]) -> Result<Self, JitError> {
+        let size = page_align(code.len().max(1));
+        unsafe {
+            let base = mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if base == MAP_FAILED {
+                return Err(JitError::MakeExec);
+            }
+            std::ptr::copy_nonoverlapping(code.as_ptr(), base as *mut u8, code.len());
+            if mprotect(base, size, PROT_READ | PROT_EXEC) != 0 {
+                munmap(base, size);
+                return Err(JitError::MakeExec);
+            }
+            Ok(Self {
+                base: base as *mut u8,
+                size,
+            })
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.base
+    }
+}
+
+impl Drop for JitMemory {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.base as _, self.size);
+        }
+    }
+}
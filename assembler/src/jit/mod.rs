@@ -1,13 +1,21 @@
+mod memory;
 mod translator;
 pub mod utils;
 
+use std::collections::HashMap;
+
 use bytes::{BufMut, BytesMut};
+pub use memory::JitMemory;
 pub use translator::{
-    R8, R9, R10, R11, R12, R13, R14, R15, RBP, RBX, RCX, RDI, RDX, RSI, RSP, translate,
+    R8, R9, R10, R11, R12, R13, R14, R15, RBP, RBX, RCX, RDI, RDX, RSI, RSP, TAIL_CALL_OFFSET,
+    translate,
 };
 
 use self::translator::RAX;
-use crate::ebpf::DEFAULT_INS_NUM;
+use crate::{
+    IsaVersion,
+    ebpf::{DEFAULT_INS_NUM, DEFAULT_STACK_SIZE},
+};
 
 #[derive(Debug, Clone)]
 pub enum OperandSize {
@@ -17,6 +25,59 @@ pub enum OperandSize {
     S64,
 }
 
+/// native function reached by `call imm`, r1-r5 are passed as the first five
+/// arguments and `context` as the sixth one
+#[derive(Debug, Clone, Copy)]
+pub struct ExternalCall {
+    pub func: usize,
+    pub context: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct JitOptions {
+    pub helpers: HashMap<u32, ExternalCall>,
+    /// the helpers also get the pc of the call as a seventh argument and
+    /// return a second word in rdx, the program exits when it is not 0
+    pub helper_faults: bool,
+    /// `func(map, index, count) -> i64` resolving `bpf_tail_call`, see
+    /// `emit_tail_call`
+    pub tail_call: Option<usize>,
+    /// `func(addr, size, pc, stack_top, flags) -> u64` called before the
+    /// loads and stores that are not in `safe_accesses`, returns the host
+    /// address they access, 0 ends the program, bit 0 of `flags` is set for
+    /// stores and bit 1 for safe accesses
+    pub bounds_check: Option<usize>,
+    /// loads and stores known to stay in bounds, indexed by pc
+    pub safe_accesses: Vec<bool>,
+    /// `func(pc)` called when a divisor is zero, the program exits right
+    /// after, without it a division by zero gives 0 and a modulo by zero
+    /// leaves the dividend as the kernel does
+    pub div_by_zero: Option<usize>,
+    /// instructions newer than this version are rejected
+    pub isa: IsaVersion,
+    /// bytes of stack below r10
+    pub stack_size: usize,
+    /// guest address of the stack, r10 points to its top instead of the
+    /// native stack and every load and store goes through `bounds_check`
+    pub stack_vaddr: Option<u64>,
+}
+
+impl Default for JitOptions {
+    fn default() -> Self {
+        Self {
+            helpers: HashMap::new(),
+            helper_faults: false,
+            tail_call: None,
+            bounds_check: None,
+            safe_accesses: Vec::new(),
+            div_by_zero: None,
+            isa: IsaVersion::default(),
+            stack_size: DEFAULT_STACK_SIZE,
+            stack_vaddr: None,
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Default)]
 pub struct Jmp {
@@ -30,6 +91,7 @@ pub struct JitBuilder {
     pub buffer: BytesMut,
     pc_locations: Vec<usize>,
     exit_location: usize,
+    div_by_zero_location: usize,
     jumps: Vec<Jmp>,
     offset: usize,
 }
@@ -47,6 +109,7 @@ impl JitBuilder {
             buffer: BytesMut::new(),
             pc_locations: Vec::with_capacity(DEFAULT_INS_NUM),
             exit_location: 0,
+            div_by_zero_location: 0,
             jumps: Vec::with_capacity(DEFAULT_INS_NUM),
             offset: 0,
         }
@@ -231,9 +294,41 @@ impl JitBuilder {
         self.emit_modrm_and_displacement(dst, src, offset);
     }
 
+    /// load extending the sign of the value to 64 bits
+    #[inline(always)]
+    pub fn emit_load_sx(&mut self, size: OperandSize, src: i32, dst: i32, offset: i32) {
+        self.emit_rex(1, bit01(dst & 8), 0, bit01(src & 8));
+        self.emit_movsx_opcode(size);
+        self.emit_modrm_and_displacement(dst, src, offset);
+    }
+
+    /// `movsx`, to 64 bits with `w` or else to 32 bits
+    #[inline(always)]
+    pub fn emit_movsx(&mut self, size: OperandSize, w: i32, src: i32, dst: i32) {
+        // always a rex, without it the low bytes of rsi and rdi are dh and bh
+        self.emit_rex(w, bit01(dst & 8), 0, bit01(src & 8));
+        self.emit_movsx_opcode(size);
+        self.emit_modrm_reg2reg(dst, src);
+    }
+
+    #[inline(always)]
+    fn emit_movsx_opcode(&mut self, size: OperandSize) {
+        match size {
+            OperandSize::S8 => {
+                self.emit1(0x0f);
+                self.emit1(0xbe);
+            }
+            OperandSize::S16 => {
+                self.emit1(0x0f);
+                self.emit1(0xbf);
+            }
+            // movsxd
+            OperandSize::S32 | OperandSize::S64 => self.emit1(0x63),
+        }
+    }
+
     #[inline(always)]
     pub fn emit_load_imm(&mut self, dst: i32, imm: i64) {
-        dbg!(imm >= i32::MIN as i64 && imm >= i32::MAX as i64);
         if imm >= i32::MIN as i64 && imm <= i32::MAX as i64 {
             self.emit_alu64_imm32(0xc7, 0, dst, imm as i32);
         } else {
@@ -276,8 +371,8 @@ impl JitBuilder {
             self.emit1(0x66);
         }
         let a = match size {
-            OperandSize::S64 => 0,
-            _ => 1,
+            OperandSize::S64 => 1,
+            _ => 0,
         };
         self.emit_basic_rex(a, 0, dst);
         let b = match size {
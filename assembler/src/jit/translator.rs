@@ -1,4 +1,8 @@
-use crate::{Instruction, JitBuilder, class::EBPF_CLS_ALU64, ebpf::DEFAULT_STACK_SIZE, op::*};
+use crate::{
+    Instruction, JitBuilder, JitError, JitOptions,
+    class::{EBPF_CLS_ALU64, EBPF_CLS_LDX, EBPF_CLS_ST, EBPF_CLS_STX},
+    op::*,
+};
 
 pub const RAX: i32 = 0;
 pub const RCX: i32 = 1;
@@ -22,19 +26,40 @@ pub const REGISTER_MAP: [i32; 11] = [RAX, RDI, RSI, RDX, R9, R8, RBX, R13, R14,
 const TARGET_PC_EXIT: i32 = -1;
 const TARGET_PC_DIV_BY_ZERO: i32 = -2;
 
-pub fn translate(inner: &[Instruction]) -> Vec<u8> {
+// helper id of `bpf_tail_call`
+const BPF_FUNC_TAIL_CALL: i64 = 12;
+
+/// offset of the first instruction after the prologue, a tail call jumps
+/// there so that the frame and the tail call counter in r12 are reused
+pub const TAIL_CALL_OFFSET: usize = 30;
+
+pub fn translate(inner: &[Instruction], options: &JitOptions) -> Result<Vec<u8>, JitError> {
+    options.isa.check(inner)?;
     let mut builder = JitBuilder::new();
 
     // save stack frame
     builder.emit_push(RBP);
     builder.emit_mov(RSP, map_register(10));
-    builder.emit_alu64_imm32(0x81, 5, RSP, DEFAULT_STACK_SIZE as i32);
+    // rounded up so that rsp stays 16 bytes aligned
+    let stack_size = options.stack_size.next_multiple_of(16);
+    builder.emit_alu64_imm32(0x81, 5, RSP, stack_size as i32);
 
     // save registers
     builder.emit_push(RBX);
     builder.emit_push(R13);
     builder.emit_push(R14);
     builder.emit_push(R15);
+    builder.emit_push(R12);
+    // keep rsp 16 bytes aligned for helper calls
+    builder.emit_alu64_imm32(0x81, 5, RSP, 8);
+
+    // tail call counter
+    builder.emit_alu32(0x31, R12, R12);
+    assert_eq!(builder.offset, TAIL_CALL_OFFSET);
+
+    if let Some(vaddr) = options.stack_vaddr {
+        builder.emit_load_imm(map_register(10), (vaddr + options.stack_size as u64) as i64);
+    }
 
     if map_register(1) != RDI {
         builder.emit_mov(RDI, map_register(1));
@@ -48,7 +73,22 @@ pub fn translate(inner: &[Instruction]) -> Vec<u8> {
         let dst = map_register(ins.dst_reg() as i32);
         let src = map_register(ins.src_reg() as i32);
 
-        let target_pc = index + ins.offset as usize + 1;
+        let target_pc = index as i64 + ins.jump_offset() + 1;
+
+        // address of a load or a store, the host one the bounds check
+        // returned if there is one
+        let (mut base, mut offset) = match ins.class() {
+            EBPF_CLS_LDX => (src, ins.offset as i32),
+            _ => (dst, ins.offset as i32),
+        };
+        let safe = options.safe_accesses.get(index).is_some_and(|&safe| safe);
+        if let Some(func) = options.bounds_check
+            && (!safe || options.stack_vaddr.is_some())
+            && matches!(ins.class(), EBPF_CLS_LDX | EBPF_CLS_ST | EBPF_CLS_STX)
+        {
+            emit_bounds_check(&mut builder, func, base, ins, index, safe);
+            (base, offset) = (R11, 0);
+        }
 
         match ins.op {
             ADD_IMM => {
@@ -64,14 +104,7 @@ pub fn translate(inner: &[Instruction]) -> Vec<u8> {
                 builder.emit_alu32(0x29, src, dst);
             }
             MUL_IMM | MUL_REG | DIV_IMM | DIV_REG | MOD_IMM | MOD_REG => {
-                muldivmod(
-                    &mut builder,
-                    ins.op,
-                    src,
-                    dst,
-                    ins.imm as i32,
-                    target_pc as i64,
-                );
+                muldivmod(&mut builder, ins, src, dst, index, options.div_by_zero);
             }
             OR_IMM => {
                 builder.emit_alu32_imm32(0x81, 1, dst, ins.imm as i32);
@@ -90,6 +123,7 @@ pub fn translate(inner: &[Instruction]) -> Vec<u8> {
                 builder.emit1(ins.imm as u8);
             }
             LSH_REG => {
+                builder.emit_mov(src, RCX);
                 builder.emit_alu32(0xd3, 4, dst);
             }
             RSH_IMM => {
@@ -98,6 +132,7 @@ pub fn translate(inner: &[Instruction]) -> Vec<u8> {
                 // builder.emit_alu32_imm32(0xc1, 5, dst, ins.imm as i32);
             }
             RSH_REG => {
+                builder.emit_mov(src, RCX);
                 builder.emit_alu32(0xd3, 5, dst);
             }
             NEG32 => {
@@ -112,22 +147,36 @@ pub fn translate(inner: &[Instruction]) -> Vec<u8> {
             MOV_IMM => {
                 builder.emit_alu32_imm32(0xc7, 0, dst, ins.imm as i32);
             }
+            MOV_REG if ins.offset != 0 => {
+                builder.emit_movsx(sx_size(ins.offset), 0, src, dst);
+            }
             MOV_REG => {
-                builder.emit_mov(src, dst);
+                builder.emit_alu32(0x89, src, dst);
             }
             ARSH_IMM => {
-                builder.emit_alu32_imm32(0xc1, 7, dst, ins.imm as i32);
+                builder.emit_alu32(0xc1, 7, dst);
+                builder.emit1(ins.imm as u8);
             }
             ARSH_REG => {
                 builder.emit_mov(src, RCX);
                 builder.emit_alu32(0xd3, 7, dst);
             }
-            // TODO: LE and BE
-            LE => {
-                todo!()
-            }
-            BE => {
-                todo!()
+            // x86 is little endian already, only the width changes
+            LE => match ins.imm {
+                16 => builder.emit_alu32_imm32(0x81, 4, dst, 0xffff),
+                32 => builder.emit_alu32(0x89, dst, dst),
+                _ => {}
+            },
+            BE | BSWAP => {
+                /* bswap dst */
+                builder.emit_basic_rex((ins.imm == 64) as i32, 0, dst);
+                builder.emit1(0x0f);
+                builder.emit1(0xc8 | (dst & 7) as u8);
+                if ins.imm == 16 {
+                    /* shr dst,16 */
+                    builder.emit_alu32(0xc1, 5, dst);
+                    builder.emit1(16);
+                }
             }
             ADD64_IMM => {
                 builder.emit_alu64_imm32(0x81, 0, dst, ins.imm as i32);
@@ -142,14 +191,7 @@ pub fn translate(inner: &[Instruction]) -> Vec<u8> {
                 builder.emit_alu64(0x29, src, dst);
             }
             MUL64_IMM | MUL64_REG | DIV64_IMM | DIV64_REG | MOD64_IMM | MOD64_REG => {
-                muldivmod(
-                    &mut builder,
-                    ins.op,
-                    src,
-                    dst,
-                    ins.imm as i32,
-                    target_pc as i64,
-                );
+                muldivmod(&mut builder, ins, src, dst, index, options.div_by_zero);
             }
             OR64_IMM => {
                 builder.emit_alu64_imm32(0x81, 1, dst, ins.imm as i32);
@@ -185,19 +227,23 @@ pub fn translate(inner: &[Instruction]) -> Vec<u8> {
                 builder.emit_alu64(0xf7, 3, dst);
             }
             XOR64_IMM => {
-                builder.emit_alu64_imm32(0x31, 6, dst, ins.imm as i32);
+                builder.emit_alu64_imm32(0x81, 6, dst, ins.imm as i32);
             }
             XOR64_REG => {
-                builder.emit_alu64(0x09, src, dst);
+                builder.emit_alu64(0x31, src, dst);
             }
             MOV64_IMM => {
                 builder.emit_alu64_imm32(0xc7, 0, dst, ins.imm as i32);
             }
+            MOV64_REG if ins.offset != 0 => {
+                builder.emit_movsx(sx_size(ins.offset), 1, src, dst);
+            }
             MOV64_REG => {
                 builder.emit_mov(src, dst);
             }
             ARSH64_IMM => {
-                builder.emit_alu64_imm32(0xc1, 7, dst, ins.imm as i32);
+                builder.emit_alu64(0xc1, 7, dst);
+                builder.emit1(ins.imm as u8);
             }
             ARSH64_REG => {
                 builder.emit_mov(src, RCX);
@@ -208,62 +254,51 @@ pub fn translate(inner: &[Instruction]) -> Vec<u8> {
                 builder.emit_load_imm(dst, ins.imm);
             }
             LDXW => {
-                builder.emit_load(crate::OperandSize::S32, src, dst, ins.offset as i32);
+                builder.emit_load(crate::OperandSize::S32, base, dst, offset);
             }
             LDXH => {
-                builder.emit_load(crate::OperandSize::S16, src, dst, ins.offset as i32);
+                builder.emit_load(crate::OperandSize::S16, base, dst, offset);
             }
             LDXB => {
-                builder.emit_load(crate::OperandSize::S8, src, dst, ins.offset as i32);
+                builder.emit_load(crate::OperandSize::S8, base, dst, offset);
             }
             LDXDW => {
-                builder.emit_load(crate::OperandSize::S64, src, dst, ins.offset as i32);
+                builder.emit_load(crate::OperandSize::S64, base, dst, offset);
+            }
+            LDXSW => {
+                builder.emit_load_sx(crate::OperandSize::S32, base, dst, offset);
+            }
+            LDXSH => {
+                builder.emit_load_sx(crate::OperandSize::S16, base, dst, offset);
+            }
+            LDXSB => {
+                builder.emit_load_sx(crate::OperandSize::S8, base, dst, offset);
             }
             STW => {
-                builder.emit_store_imm32(
-                    crate::OperandSize::S32,
-                    dst,
-                    ins.offset as i32,
-                    ins.imm as i32,
-                );
+                builder.emit_store_imm32(crate::OperandSize::S32, base, offset, ins.imm as i32);
             }
             STH => {
-                builder.emit_store_imm32(
-                    crate::OperandSize::S16,
-                    dst,
-                    ins.offset as i32,
-                    ins.imm as i32,
-                );
+                builder.emit_store_imm32(crate::OperandSize::S16, base, offset, ins.imm as i32);
             }
             STB => {
-                builder.emit_store_imm32(
-                    crate::OperandSize::S8,
-                    dst,
-                    ins.offset as i32,
-                    ins.imm as i32,
-                );
+                builder.emit_store_imm32(crate::OperandSize::S8, base, offset, ins.imm as i32);
             }
             STDW => {
-                builder.emit_store_imm32(
-                    crate::OperandSize::S64,
-                    dst,
-                    ins.offset as i32,
-                    ins.imm as i32,
-                );
+                builder.emit_store_imm32(crate::OperandSize::S64, base, offset, ins.imm as i32);
             }
             STXW => {
-                builder.emit_store(crate::OperandSize::S32, src, dst, ins.offset as i32);
+                builder.emit_store(crate::OperandSize::S32, src, base, offset);
             }
             STXH => {
-                builder.emit_store(crate::OperandSize::S16, src, dst, ins.offset as i32);
+                builder.emit_store(crate::OperandSize::S16, src, base, offset);
             }
             STXB => {
-                builder.emit_store(crate::OperandSize::S8, src, dst, ins.offset as i32);
+                builder.emit_store(crate::OperandSize::S8, src, base, offset);
             }
             STXDW => {
-                builder.emit_store(crate::OperandSize::S64, src, dst, ins.offset as i32);
+                builder.emit_store(crate::OperandSize::S64, src, base, offset);
             }
-            JA => {
+            JA | JA32 => {
                 builder.emit_jmp(target_pc as i32);
             }
             JEQ_IMM => {
@@ -319,7 +354,7 @@ pub fn translate(inner: &[Instruction]) -> Vec<u8> {
                 builder.emit_jcc(0x85, target_pc as i32);
             }
             JNE_REG => {
-                builder.emit_alu64(0x85, src, dst);
+                builder.emit_cmp(src, dst);
                 builder.emit_jcc(0x85, target_pc as i32);
             }
             JSGT_IMM => {
@@ -354,88 +389,323 @@ pub fn translate(inner: &[Instruction]) -> Vec<u8> {
                 builder.emit_cmp(src, dst);
                 builder.emit_jcc(0x8e, target_pc as i32);
             }
-            // TODO: call functions(maybe jit)
+            // same jumps comparing the low 32 bits
+            JEQ32_IMM => {
+                builder.emit_alu32_imm32(0x81, 7, dst, ins.imm as i32);
+                builder.emit_jcc(0x84, target_pc as i32);
+            }
+            JEQ32_REG => {
+                builder.emit_alu32(0x39, src, dst);
+                builder.emit_jcc(0x84, target_pc as i32);
+            }
+            JGT32_IMM => {
+                builder.emit_alu32_imm32(0x81, 7, dst, ins.imm as i32);
+                builder.emit_jcc(0x87, target_pc as i32);
+            }
+            JGT32_REG => {
+                builder.emit_alu32(0x39, src, dst);
+                builder.emit_jcc(0x87, target_pc as i32);
+            }
+            JGE32_IMM => {
+                builder.emit_alu32_imm32(0x81, 7, dst, ins.imm as i32);
+                builder.emit_jcc(0x83, target_pc as i32);
+            }
+            JGE32_REG => {
+                builder.emit_alu32(0x39, src, dst);
+                builder.emit_jcc(0x83, target_pc as i32);
+            }
+            JLT32_IMM => {
+                builder.emit_alu32_imm32(0x81, 7, dst, ins.imm as i32);
+                builder.emit_jcc(0x82, target_pc as i32);
+            }
+            JLT32_REG => {
+                builder.emit_alu32(0x39, src, dst);
+                builder.emit_jcc(0x82, target_pc as i32);
+            }
+            JLE32_IMM => {
+                builder.emit_alu32_imm32(0x81, 7, dst, ins.imm as i32);
+                builder.emit_jcc(0x86, target_pc as i32);
+            }
+            JLE32_REG => {
+                builder.emit_alu32(0x39, src, dst);
+                builder.emit_jcc(0x86, target_pc as i32);
+            }
+            JSET32_IMM => {
+                builder.emit_alu32_imm32(0xf7, 0, dst, ins.imm as i32);
+                builder.emit_jcc(0x85, target_pc as i32);
+            }
+            JSET32_REG => {
+                builder.emit_alu32(0x85, src, dst);
+                builder.emit_jcc(0x85, target_pc as i32);
+            }
+            JNE32_IMM => {
+                builder.emit_alu32_imm32(0x81, 7, dst, ins.imm as i32);
+                builder.emit_jcc(0x85, target_pc as i32);
+            }
+            JNE32_REG => {
+                builder.emit_alu32(0x39, src, dst);
+                builder.emit_jcc(0x85, target_pc as i32);
+            }
+            JSGT32_IMM => {
+                builder.emit_alu32_imm32(0x81, 7, dst, ins.imm as i32);
+                builder.emit_jcc(0x8f, target_pc as i32);
+            }
+            JSGT32_REG => {
+                builder.emit_alu32(0x39, src, dst);
+                builder.emit_jcc(0x8f, target_pc as i32);
+            }
+            JSGE32_IMM => {
+                builder.emit_alu32_imm32(0x81, 7, dst, ins.imm as i32);
+                builder.emit_jcc(0x8d, target_pc as i32);
+            }
+            JSGE32_REG => {
+                builder.emit_alu32(0x39, src, dst);
+                builder.emit_jcc(0x8d, target_pc as i32);
+            }
+            JSLT32_IMM => {
+                builder.emit_alu32_imm32(0x81, 7, dst, ins.imm as i32);
+                builder.emit_jcc(0x8c, target_pc as i32);
+            }
+            JSLT32_REG => {
+                builder.emit_alu32(0x39, src, dst);
+                builder.emit_jcc(0x8c, target_pc as i32);
+            }
+            JSLE32_IMM => {
+                builder.emit_alu32_imm32(0x81, 7, dst, ins.imm as i32);
+                builder.emit_jcc(0x8e, target_pc as i32);
+            }
+            JSLE32_REG => {
+                builder.emit_alu32(0x39, src, dst);
+                builder.emit_jcc(0x8e, target_pc as i32);
+            }
+            CALL if ins.src_reg() == EBPF_PSEUDO_CALL => return Err(JitError::LocalCall(index)),
+            CALL if ins.imm == BPF_FUNC_TAIL_CALL => {
+                let func = options.tail_call.ok_or(JitError::UnknownHelper(ins.imm))?;
+                emit_tail_call(&mut builder, func, index as i32 + 1);
+            }
             CALL => {
+                let call = options
+                    .helpers
+                    .get(&(ins.imm as u32))
+                    .ok_or(JitError::UnknownHelper(ins.imm))?;
+                // r4 lives in r9, which carries the helper context instead
                 builder.emit_mov(R9, RCX);
-                // FIXME: call extern functions
-                todo!()
-            }
-            EXIT => {
-                if index != num_ins - 1 {
-                    builder.emit_jmp(TARGET_PC_EXIT);
+                builder.emit_load_imm(R9, call.context as i64);
+                if options.helper_faults {
+                    // the pc goes on the stack, which stays 16 bytes aligned
+                    builder.emit_load_imm(R11, index as i64);
+                    builder.emit_alu64_imm32(0x81, 5, RSP, 8);
+                    builder.emit_push(R11);
+                    builder.emit_call(call.func as *const u8);
+                    builder.emit_alu64_imm32(0x81, 0, RSP, 16);
+                    /* test rdx,rdx; jnz exit */
+                    builder.emit_alu64(0x85, RDX, RDX);
+                    builder.emit_jcc(0x85, TARGET_PC_EXIT);
+                } else {
+                    builder.emit_call(call.func as *const u8);
                 }
             }
+            EXIT if index != num_ins - 1 => {
+                builder.emit_jmp(TARGET_PC_EXIT);
+            }
             _ => {}
         }
     }
 
+    // a failed tail call may continue right past the last instruction
+    builder.pc_locations.push(builder.offset);
     builder.exit_location = builder.offset;
 
+    builder.emit_alu64_imm32(0x81, 0, RSP, 8);
+    builder.emit_pop(R12);
     builder.emit_pop(R15);
     builder.emit_pop(R14);
     builder.emit_pop(R13);
     builder.emit_pop(RBX);
-    builder.emit1(0xc9);
+    if options.stack_vaddr.is_some() {
+        // rbp holds the guest r10, the frame is popped by hand
+        builder.emit_alu64_imm32(0x81, 0, RSP, stack_size as i32);
+        builder.emit_pop(RBP);
+    } else {
+        builder.emit1(0xc9); /* leave */
+    }
     builder.emit1(0xc3); /* ret */
 
+    // reached with the pc of the division in rcx
+    if let Some(func) = options.div_by_zero {
+        builder.div_by_zero_location = builder.offset;
+        builder.emit_mov(RCX, RDI);
+        builder.emit_call(func as *const u8);
+        builder.emit_jmp(TARGET_PC_EXIT);
+    }
+
     let content = &builder.buffer[..];
     let mut content: Vec<u8> = content.into();
 
-    dbg!(builder.jumps.clone());
-    dbg!(builder.pc_locations.clone());
-
     for jump in builder.jumps.iter() {
         let target_location = if jump.target_pc == TARGET_PC_EXIT {
             builder.exit_location
         } else if jump.target_pc == TARGET_PC_DIV_BY_ZERO {
-            unimplemented!("todo: div by zero check");
+            builder.div_by_zero_location
         } else {
             builder.pc_locations[jump.target_pc as usize]
         };
 
-        dbg!(target_location, jump);
-
-        let relative = target_location - jump.offset_location - std::mem::size_of::<i32>();
+        let relative = target_location as i64
+            - jump.offset_location as i64
+            - std::mem::size_of::<i32>() as i64;
 
         unsafe {
             let p = content.as_mut_ptr();
             let offset_ptr = (p as usize + jump.offset_location) as *mut i32;
-            let content: &[u8] = std::mem::transmute((offset_ptr, 10));
-            println!("{:X?}", content);
-            *offset_ptr = relative as i32;
-            println!("{:X?}", content);
+            offset_ptr.write_unaligned(relative as i32);
         }
     }
 
-    content
+    Ok(content)
+}
+
+/// `func(map, index, count)` returns the address of the target program, or a
+/// negative errno to fall through to `next_pc` with r0 set to it
+fn emit_tail_call(builder: &mut JitBuilder, func: usize, next_pc: i32) {
+    // r1 must survive the call, r2-r5 are scratch after it anyway
+    builder.emit_push(RDI);
+    builder.emit_alu64_imm32(0x81, 5, RSP, 8);
+    builder.emit_mov(RSI, RDI);
+    builder.emit_mov(RDX, RSI);
+    builder.emit_mov(R12, RDX);
+    builder.emit_call(func as *const u8);
+    builder.emit_alu64_imm32(0x81, 0, RSP, 8);
+    builder.emit_pop(RDI);
+
+    /* test rax,rax; js next_pc */
+    builder.emit_alu64(0x85, RAX, RAX);
+    builder.emit_jcc(0x88, next_pc);
+
+    builder.emit_alu64_imm32(0x81, 0, R12, 1);
+    /* jmp rax */
+    builder.emit_alu32(0xff, 4, RAX);
+}
+
+/// `func(addr, size, pc, stack_top, flags)` checks the access of `ins`
+/// through `base` and returns the host address to access in r11, the program
+/// exits when it returns 0
+fn emit_bounds_check(
+    builder: &mut JitBuilder,
+    func: usize,
+    base: i32,
+    ins: &Instruction,
+    pc: usize,
+    safe: bool,
+) {
+    // r0-r5 live in caller saved registers, 6 pushes keep rsp aligned
+    const SAVED: [i32; 6] = [RAX, RDI, RSI, RDX, R9, R8];
+    let size = match ins.op & 0x18 {
+        EBPF_SIZE_B => 1,
+        EBPF_SIZE_H => 2,
+        EBPF_SIZE_W => 4,
+        _ => 8,
+    };
+
+    for reg in SAVED {
+        builder.emit_push(reg);
+    }
+    builder.emit_mov(base, RDI);
+    builder.emit_alu64_imm32(0x81, 0, RDI, ins.offset as i32);
+    builder.emit_load_imm(RSI, size);
+    builder.emit_load_imm(RDX, pc as i64);
+    builder.emit_mov(RBP, RCX);
+    let write = (ins.class() != EBPF_CLS_LDX) as i64;
+    builder.emit_load_imm(R8, write | (safe as i64) << 1);
+    builder.emit_call(func as *const u8);
+    builder.emit_mov(RAX, R11);
+    for reg in SAVED.iter().rev() {
+        builder.emit_pop(*reg);
+    }
+
+    /* test r11,r11; jz exit */
+    builder.emit_alu64(0x85, R11, R11);
+    builder.emit_jcc(0x84, TARGET_PC_EXIT);
 }
 
 fn map_register(reg: i32) -> i32 {
     REGISTER_MAP[reg as usize]
 }
 
-#[allow(unused_variables)]
-fn muldivmod(builder: &mut JitBuilder, opcode: u8, src: i32, dst: i32, imm: i32, pc: i64) {
+fn muldivmod(
+    builder: &mut JitBuilder,
+    ins: &Instruction,
+    src: i32,
+    dst: i32,
+    pc: usize,
+    div_by_zero: Option<usize>,
+) {
     // MUL_IMM | MUL_REG | DIV_IMM | DIV_REG | MOD_IMM | MOD_REG
+    let opcode = ins.op;
     let mul_res = (opcode & ALU_OP_MASK) == (MUL_IMM & ALU_OP_MASK);
     let div_res = (opcode & ALU_OP_MASK) == (DIV_IMM & ALU_OP_MASK);
     let mod_res = (opcode & ALU_OP_MASK) == (MOD_IMM & ALU_OP_MASK);
     let is64 = (opcode & CLS_MASK) == EBPF_CLS_ALU64;
+    let by_reg = opcode & EBPF_SRC_REG != 0;
+    let signed = (div_res || mod_res) && ins.offset == EBPF_OFF_SIGNED;
 
-    // TODO: divide by zero check
-    // if div_res || mod_res {
-    //     builder.emit_load_imm(RCX, pc);
-
-    //     /* test src,src */
-    //     if is64 {
-    //         builder.emit_alu64(0x85, src, src);
-    //     } else {
-    //         builder.emit_alu32(0x85, src, src);
-    //     }
+    if (div_res || mod_res) && !by_reg && ins.imm == 0 {
+        match div_by_zero {
+            Some(_) => {
+                builder.emit_load_imm(RCX, pc as i64);
+                builder.emit_jmp(TARGET_PC_DIV_BY_ZERO);
+            }
+            None => emit_div_by_zero_result(builder, dst, mod_res, is64),
+        }
+        return;
+    }
+    if (div_res || mod_res) && by_reg {
+        /* test src,src */
+        if is64 {
+            builder.emit_alu64(0x85, src, src);
+        } else {
+            builder.emit_alu32(0x85, src, src);
+        }
+        match div_by_zero {
+            Some(_) => {
+                // mov does not change the flags
+                builder.emit_load_imm(RCX, pc as i64);
+                /* jz div_by_zero */
+                builder.emit_jcc(0x84, TARGET_PC_DIV_BY_ZERO);
+            }
+            None => {
+                /* jnz div, over the result for a zero divisor */
+                builder.emit1(0x75);
+                let patch = builder.offset;
+                builder.emit1(0);
+                emit_div_by_zero_result(builder, dst, mod_res, is64);
+                builder.emit_jmp(pc as i32 + 1);
+                builder.buffer[patch] = (builder.offset - patch - 1) as u8;
+            }
+        }
+    }
 
-    //     /* jz div_by_zero */
-    //     builder.emit_jcc(0x84, TARGET_PC_DIV_BY_ZERO);
-    // }
+    // idiv faults on the smallest number divided by -1, whose quotient is
+    // itself once wrapped and whose remainder is 0
+    if signed && !by_reg && ins.imm == -1 {
+        emit_minus_one_result(builder, dst, mod_res, is64);
+        return;
+    }
+    if signed && by_reg {
+        /* cmp src,-1 */
+        if is64 {
+            builder.emit_alu64_imm32(0x81, 7, src, -1);
+        } else {
+            builder.emit_alu32_imm32(0x81, 7, src, -1);
+        }
+        /* jne div */
+        builder.emit1(0x75);
+        let patch = builder.offset;
+        builder.emit1(0);
+        emit_minus_one_result(builder, dst, mod_res, is64);
+        builder.emit_jmp(pc as i32 + 1);
+        builder.buffer[patch] = (builder.offset - patch - 1) as u8;
+    }
 
     if dst != RAX {
         builder.emit_push(RAX);
@@ -443,15 +713,21 @@ fn muldivmod(builder: &mut JitBuilder, opcode: u8, src: i32, dst: i32, imm: i32,
     if dst != RDX {
         builder.emit_push(RDX);
     }
-    if imm != 0 {
-        builder.emit_load_imm(RCX, imm as i64);
-    } else {
+    if by_reg {
         builder.emit_mov(src, RCX);
+    } else {
+        builder.emit_load_imm(RCX, ins.imm);
     }
 
     builder.emit_mov(dst, RAX);
 
-    if div_res || mod_res {
+    if signed {
+        /* cdq or cqo */
+        if is64 {
+            builder.emit_rex(1, 0, 0, 0);
+        }
+        builder.emit1(0x99);
+    } else if div_res || mod_res {
         /* xor %edx,%edx */
         builder.emit_alu32(0x31, RDX, RDX);
     }
@@ -460,8 +736,13 @@ fn muldivmod(builder: &mut JitBuilder, opcode: u8, src: i32, dst: i32, imm: i32,
         builder.emit_rex(1, 0, 0, 0);
     }
 
-    /* mul %ecx or div %ecx */
-    builder.emit_alu32(0xf7, if mul_res { 4 } else { 6 }, RCX);
+    /* mul %ecx, div %ecx or idiv %ecx */
+    let ext = match (mul_res, signed) {
+        (true, _) => 4,
+        (false, false) => 6,
+        (false, true) => 7,
+    };
+    builder.emit_alu32(0xf7, ext, RCX);
 
     if dst != RDX {
         if mod_res {
@@ -477,6 +758,37 @@ fn muldivmod(builder: &mut JitBuilder, opcode: u8, src: i32, dst: i32, imm: i32,
     }
 }
 
+// as the kernel, dividing by zero gives 0 and the modulo leaves dst as is
+fn emit_div_by_zero_result(builder: &mut JitBuilder, dst: i32, mod_res: bool, is64: bool) {
+    if !mod_res {
+        /* xor dst,dst */
+        builder.emit_alu32(0x31, dst, dst);
+    } else if !is64 {
+        /* mov dst,dst clears the upper half */
+        builder.emit_alu32(0x89, dst, dst);
+    }
+}
+
+// dst divided by -1, the negation for a division and 0 for a modulo
+fn emit_minus_one_result(builder: &mut JitBuilder, dst: i32, mod_res: bool, is64: bool) {
+    match (mod_res, is64) {
+        /* xor dst,dst */
+        (true, _) => builder.emit_alu32(0x31, dst, dst),
+        /* neg dst */
+        (false, true) => builder.emit_alu64(0xf7, 3, dst),
+        (false, false) => builder.emit_alu32(0xf7, 3, dst),
+    }
+}
+
+// width of the value a `movsx` extends
+fn sx_size(offset: i16) -> crate::OperandSize {
+    match offset {
+        8 => crate::OperandSize::S8,
+        16 => crate::OperandSize::S16,
+        _ => crate::OperandSize::S32,
+    }
+}
+
 #[allow(unused_variables, dead_code)]
 fn muldivmod_nop(builder: &mut JitBuilder, opcode: u8, src: i32, dst: i32, imm: i32, pc: i64) {}
 
@@ -484,7 +796,7 @@ fn muldivmod_nop(builder: &mut JitBuilder, opcode: u8, src: i32, dst: i32, imm:
 mod tests {
     use super::translate;
     use crate::{
-        Instruction,
+        Instruction, JitOptions,
         jit::utils::{display, test_utils::load_data},
     };
 
@@ -492,7 +804,7 @@ mod tests {
     fn test_translate(prog_name: &str) {
         let (instructions, res) = load_data(prog_name);
         let v: Vec<Instruction> = instructions.into();
-        let r = translate(&v);
+        let r = translate(&v, &JitOptions::default()).unwrap();
         display(&r);
         println!("----\nres:{:?}\n\n", res);
     }
@@ -509,7 +821,7 @@ mod tests {
     fn test_suite(prog_name: &str, memory: (*const u8, usize)) {
         let (instructions, res) = load_data(prog_name);
         let v: Vec<Instruction> = instructions.into();
-        let r = translate(&v);
+        let r = translate(&v, &JitOptions::default()).unwrap();
         display(&r);
         let size = page_align(r.len());
         unsafe {
@@ -584,6 +896,34 @@ mod tests {
         test_suite("jslt_imm", null_mem());
     }
 
+    #[test]
+    fn test_isa_v4() {
+        for name in [
+            "sdiv32_imm",
+            "sdiv32_reg",
+            "sdiv64_imm",
+            "sdiv64_reg",
+            "smod32_imm",
+            "smod32_reg",
+            "smod64_imm",
+            "smod64_reg",
+            "movsx32_reg",
+            "movsx64_reg",
+            "bswap16",
+            "bswap32",
+            "bswap64",
+            "gotol",
+        ] {
+            test_suite(name, null_mem());
+        }
+
+        let raw: [u8; 8] = [0xaa, 0xbb, 0x11, 0x22, 0x33, 0x44, 0xcc, 0xdd];
+        let mem = unsafe { std::mem::transmute::<&[u8], (*const u8, usize)>(raw.as_slice()) };
+        test_suite("ldxsb", mem);
+        test_suite("ldxsh", mem);
+        test_suite("ldxsw", mem);
+    }
+
     #[test]
     fn test_memory() {
         let raw: [u8; 8] = [0xaa, 0xbb, 0x11, 0x22, 0x33, 0x44, 0xcc, 0xdd];
@@ -1,8 +1,10 @@
 #[allow(dead_code)]
 mod assemble;
+mod cfg;
 mod ebpf;
 mod error;
 mod instruction;
+mod isa;
 mod jit;
 pub mod utils;
 
@@ -10,9 +12,11 @@ use std::collections::HashMap;
 
 // pub use assemble::*;
 pub use assemble::{ident, instruction, instructions, integer, operand, operands, register};
+pub use cfg::{BasicBlock, Cfg};
 pub use ebpf::{alu, class, op};
-pub use error::ElfError;
+pub use error::{ElfError, IsaError, JitError};
 pub use instruction::{Instruction, Instructions};
+pub use isa::IsaVersion;
 pub use jit::*;
 
 lazy_static::lazy_static! {
@@ -27,6 +31,7 @@ lazy_static::lazy_static! {
         m.insert(3, "stx");
         m.insert(4, "alu");
         m.insert(5, "jmp");
+        m.insert(6, "jmp32");
         m.insert(7, "alu64");
         m
     };
@@ -95,21 +100,27 @@ lazy_static::lazy_static! {
 
     // =====> from name to code ====
 
-    // binary operations
-    pub static ref ALU_BINARY_OPS: HashMap<&'static str,u8> = {
+    // binary operations, with the offset that tells the signed and sign
+    // extending ones apart
+    pub static ref ALU_BINARY_OPS: HashMap<&'static str, (u8, i16)> = {
         let mut m = HashMap::new();
-        m.insert("add",op::EBPF_ADD);
-        m.insert("sub",op::EBPF_SUB);
-        m.insert("mul",op::EBPF_MUL);
-        m.insert("div",op::EBPF_DIV);
-        m.insert("or",op::EBPF_OR);
-        m.insert("and",op::EBPF_AND);
-        m.insert("lsh",op::EBPF_LSH);
-        m.insert("rsh",op::EBPF_RSH);
-        m.insert("mod",op::EBPF_MOD);
-        m.insert("xor",op::EBPF_XOR);
-        m.insert("mov",op::EBPF_MOV);
-        m.insert("arsh",op::EBPF_ARSH);
+        m.insert("add", (op::EBPF_ADD, 0));
+        m.insert("sub", (op::EBPF_SUB, 0));
+        m.insert("mul", (op::EBPF_MUL, 0));
+        m.insert("div", (op::EBPF_DIV, 0));
+        m.insert("sdiv", (op::EBPF_DIV, op::EBPF_OFF_SIGNED));
+        m.insert("or", (op::EBPF_OR, 0));
+        m.insert("and", (op::EBPF_AND, 0));
+        m.insert("lsh", (op::EBPF_LSH, 0));
+        m.insert("rsh", (op::EBPF_RSH, 0));
+        m.insert("mod", (op::EBPF_MOD, 0));
+        m.insert("smod", (op::EBPF_MOD, op::EBPF_OFF_SIGNED));
+        m.insert("xor", (op::EBPF_XOR, 0));
+        m.insert("mov", (op::EBPF_MOV, 0));
+        m.insert("movsx8", (op::EBPF_MOV, 8));
+        m.insert("movsx16", (op::EBPF_MOV, 16));
+        m.insert("movsx32", (op::EBPF_MOV, 32));
+        m.insert("arsh", (op::EBPF_ARSH, 0));
         m
     };
 
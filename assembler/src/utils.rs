@@ -6,8 +6,8 @@ pub fn reg(reg: u8) -> String {
 // memory format
 pub fn memory(base: &str, off: i16) -> String {
     if off != 0 {
-        format!("{}{:+}", base, off)
+        format!("[{}{:+}]", base, off)
     } else {
-        base.to_string()
+        format!("[{}]", base)
     }
 }
@@ -0,0 +1 @@
+0x8877
\ No newline at end of file
@@ -0,0 +1 @@
+0x88776655
\ No newline at end of file
@@ -0,0 +1 @@
+0x8877665544332211
\ No newline at end of file
@@ -0,0 +1 @@
+-86
\ No newline at end of file
@@ -0,0 +1 @@
+-8756
\ No newline at end of file
@@ -0,0 +1 @@
+-573815757
\ No newline at end of file
@@ -0,0 +1 @@
+0xffff7f80
\ No newline at end of file
@@ -0,0 +1 @@
+-2147516544
\ No newline at end of file
@@ -0,0 +1 @@
+0xfffffffd
\ No newline at end of file
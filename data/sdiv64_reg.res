@@ -0,0 +1 @@
+0x8000000000000000
\ No newline at end of file
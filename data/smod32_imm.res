@@ -0,0 +1 @@
+0xffffffff
\ No newline at end of file
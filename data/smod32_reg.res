@@ -0,0 +1 @@
+0x1
\ No newline at end of file
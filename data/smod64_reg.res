@@ -0,0 +1 @@
+0x0
\ No newline at end of file
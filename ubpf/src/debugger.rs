@@ -0,0 +1,242 @@
+use std::{collections::BTreeSet, marker::PhantomData, sync::Arc};
+
+use crate::{
+    error::VmError,
+    memory,
+    runtime::{Execution, InterpreterState, Program, RunState, VirtualMachine},
+};
+
+/// why a debugged program stopped
+#[derive(Debug)]
+pub enum StopReason {
+    /// ran the one instruction `step` asked for
+    Step,
+    /// about to run the instruction at this pc
+    Breakpoint(usize),
+    /// the program exited with this r0
+    Exit(i64),
+    /// the program failed, or is not running anymore
+    Error(VmError),
+}
+
+/// a run of a program through the interpreter, one instruction at a time,
+/// whose registers and memory can be looked at and changed between them
+#[derive(Debug)]
+pub struct Debugger<'a> {
+    prog: Arc<Program>,
+    execution: Execution,
+    state: InterpreterState,
+    // stack, context, then the map values it looked up, as the program sees
+    // them
+    run: RunState,
+    breakpoints: BTreeSet<usize>,
+    // set once the program exited or failed
+    finished: bool,
+    _ctx: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> Debugger<'a> {
+    /// stopped before the first instruction of `prog`, with `ctx` in r1 and
+    /// its length in r2, `prog` must not be jited
+    pub fn new(prog: Arc<Program>, ctx: &'a mut [u8]) -> Result<Self, VmError> {
+        if prog.is_jited() {
+            return Err(VmError::InvalidConfig(
+                "only interpreted programs can be debugged",
+            ));
+        }
+        let mut execution = Execution::new();
+        let (ptr, len) = (ctx.as_mut_ptr(), ctx.len());
+        execution.start(&prog, ptr, len)?;
+        let regions = execution.memory(&prog, ptr, len, &[]);
+        let run = RunState::new(&prog, regions, 0, len);
+        Ok(Self {
+            prog,
+            execution,
+            state: InterpreterState::default(),
+            run,
+            breakpoints: BTreeSet::new(),
+            finished: false,
+            _ctx: PhantomData,
+        })
+    }
+
+    /// run the next instruction
+    pub fn step(&mut self) -> StopReason {
+        if self.finished {
+            return StopReason::Error(VmError::NotRunning);
+        }
+        let res = self
+            .run
+            .enter(|| self.execution.step_interpreter(&self.prog, &mut self.state));
+        match res {
+            Ok(None) => StopReason::Step,
+            Ok(Some(r0)) => {
+                self.finished = true;
+                StopReason::Exit(r0)
+            }
+            Err(e) => {
+                self.finished = true;
+                StopReason::Error(e)
+            }
+        }
+    }
+
+    /// run until a breakpoint or the end of the program
+    pub fn cont(&mut self) -> StopReason {
+        self.run_until(None)
+    }
+
+    /// same as `cont`, also stops before the instruction at `pc`
+    pub fn continue_until(&mut self, pc: usize) -> StopReason {
+        self.run_until(Some(pc))
+    }
+
+    fn run_until(&mut self, until: Option<usize>) -> StopReason {
+        // at least one instruction, to get past the breakpoint it stopped at
+        loop {
+            match self.step() {
+                StopReason::Step => {}
+                stop => return stop,
+            }
+            let pc = self.pc();
+            if until == Some(pc) || self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint(pc);
+            }
+        }
+    }
+
+    /// stop before the instruction at `pc`, false if it already did
+    pub fn add_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.insert(pc)
+    }
+
+    /// false if there was no breakpoint at `pc`
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// whether the program can still run, it did not exit nor fail
+    pub fn is_running(&self) -> bool {
+        !self.finished
+    }
+
+    /// pc of the next instruction, in the program last tail called if any
+    pub fn pc(&self) -> usize {
+        self.execution.pc as usize
+    }
+
+    /// value of r0 to r10, panics for other registers
+    pub fn reg(&self, reg: usize) -> u64 {
+        assert!(reg <= 10, "no register r{reg}");
+        self.execution.regs[reg] as u64
+    }
+
+    pub fn set_reg(&mut self, reg: usize, value: u64) {
+        assert!(reg <= 10, "no register r{reg}");
+        self.execution.regs[reg] = value as i64;
+    }
+
+    /// the whole stack, r10 points past its end
+    pub fn stack(&self) -> &[u8] {
+        &self.execution.stack
+    }
+
+    pub fn stack_mut(&mut self) -> &mut [u8] {
+        &mut self.execution.stack
+    }
+
+    /// `len` bytes of the stack or the context at `addr`, as the program sees
+    /// them
+    pub fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>, VmError> {
+        let host = memory::translate(addr, len, false, self.pc(), self.run.regions())?;
+        let mem = unsafe { std::slice::from_raw_parts(host as *const u8, len) };
+        Ok(mem.to_vec())
+    }
+
+    /// write `data` to the stack or the context at `addr`
+    pub fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<(), VmError> {
+        let host = memory::translate(addr, data.len(), true, self.pc(), self.run.regions())?;
+        let mem = unsafe { std::slice::from_raw_parts_mut(host as *mut u8, data.len()) };
+        mem.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl VirtualMachine {
+    /// debug the interpreted program on `ctx`, see `Debugger::new`
+    pub fn debug<'a>(&mut self, ctx: &'a mut [u8]) -> Result<Debugger<'a>, VmError> {
+        let prog = self.load_program(false)?;
+        Debugger::new(prog, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assembler::Instructions;
+
+    use super::*;
+    use crate::{memory::STACK_VADDR, runtime::VmConfig};
+
+    #[test]
+    fn test_debugger() {
+        // sums the two words of the context through the stack
+        let prog = "ldxw r2, [r1]
+ldxw r3, [r1+4]
+stxw [r10-4], r2
+add r3, r2
+mov r0, r3
+stxw [r1], r0
+exit";
+        let instructions = Instructions::from_asm(prog).unwrap();
+        let mut vm = VirtualMachine::new(instructions.into());
+        let mut ctx = [0u8; 8];
+        ctx[..4].copy_from_slice(&3u32.to_ne_bytes());
+        ctx[4..].copy_from_slice(&4u32.to_ne_bytes());
+        let ctx_addr = ctx.as_ptr() as u64;
+        let mut dbg = vm.debug(&mut ctx).unwrap();
+
+        assert!(matches!(dbg.step(), StopReason::Step));
+        assert_eq!((dbg.pc(), dbg.reg(2)), (1, 3));
+        assert!(dbg.add_breakpoint(3));
+        assert!(!dbg.add_breakpoint(3));
+        assert!(matches!(dbg.cont(), StopReason::Breakpoint(3)));
+        let stack_len = dbg.stack().len();
+        assert_eq!(dbg.stack()[stack_len - 4..], 3u32.to_ne_bytes());
+
+        // change what the program sees before it adds it up
+        dbg.set_reg(2, 10);
+        dbg.write_memory(ctx_addr + 4, &5u32.to_ne_bytes()).unwrap();
+        assert_eq!(
+            dbg.read_memory(ctx_addr, 8).unwrap()[4..],
+            5u32.to_ne_bytes()
+        );
+        assert!(dbg.read_memory(ctx_addr + 6, 4).is_err());
+        assert!(matches!(dbg.continue_until(5), StopReason::Breakpoint(5)));
+        assert_eq!(dbg.reg(0), 14);
+
+        assert!(dbg.remove_breakpoint(3));
+        assert!(matches!(dbg.cont(), StopReason::Exit(14)));
+        assert!(!dbg.is_running());
+        assert!(matches!(dbg.step(), StopReason::Error(VmError::NotRunning)));
+        drop(dbg);
+        assert_eq!(ctx[..4], 14u32.to_ne_bytes());
+
+        // stops at the faulting instruction
+        let instructions = Instructions::from_asm("ldxdw r0, [r1+8]\nexit").unwrap();
+        let config = VmConfig::new().with_guest_addresses(true);
+        let mut vm = VirtualMachine::with_config(instructions.into(), config).unwrap();
+        vm.set_bound_check(true);
+        let mut ctx = [0u8; 8];
+        let mut dbg = vm.debug(&mut ctx).unwrap();
+        assert_eq!(dbg.reg(10), STACK_VADDR + config.stack_size() as u64);
+        assert!(matches!(
+            dbg.cont(),
+            StopReason::Error(VmError::OutOfBounds { pc: 0, .. })
+        ));
+        assert_eq!(dbg.pc(), 1);
+    }
+}
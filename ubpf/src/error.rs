@@ -1,11 +1,176 @@
+use assembler::{IsaError, IsaVersion, JitError};
 use thiserror::Error;
 
+use crate::verifier::RegType;
+
 #[derive(Error, Debug)]
 pub enum VmError {
-    #[error("div zero")]
-    DivZero,
+    #[error("division by zero at pc {0}")]
+    DivZero(usize),
     #[error("virtual memory set failed, out of boundary")]
     MemOutOfBound,
+    #[error("out of bounds memory access at pc {pc}, {size} bytes at {addr:#x}")]
+    OutOfBounds { pc: usize, addr: u64, size: usize },
+    #[error("{} not allowed at pc {pc}, {size} bytes at {addr:#x}", if *write { "write" } else { "read" })]
+    AccessViolation {
+        pc: usize,
+        addr: u64,
+        size: usize,
+        write: bool,
+    },
+    #[error("invalid memory region at {0:#x}, empty or overlapping another one")]
+    InvalidRegion(u64),
+    #[error("unknown opcode {op:#04x} at pc {pc}")]
+    UnknownOpcode { pc: usize, op: u8 },
+    #[error("unknown helper function {0}")]
+    UnknownHelper(i64),
+    #[error("too many nested calls at pc {0}")]
+    CallStackOverflow(usize),
+    #[error("prog array {0} holds programs with another stack layout")]
+    ProgArrayMismatch(u32),
+    #[error("map fd {0} not found")]
+    MapNotFound(i64),
+    #[error("invalid vm config: {0}")]
+    InvalidConfig(&'static str),
+    #[error("context of {len} bytes is over the {max} bytes limit")]
+    CtxTooLarge { len: usize, max: usize },
+    #[error("context of {len} bytes is shorter than the {min} bytes it was verified for")]
+    CtxTooSmall { len: usize, min: usize },
+    #[error("the program is not running")]
+    NotRunning,
+    #[error("failed to allocate a packet buffer")]
+    PacketAlloc,
+    #[error("jit compile failed: {0}")]
+    Jit(#[from] JitError),
+    #[error(transparent)]
+    Isa(#[from] IsaError),
+    #[error("capture file error: {0}")]
+    Pcap(#[from] PcapError),
     #[error("unknown virtual machine error")]
     Unknown,
 }
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    #[error("key not found")]
+    NotFound,
+    #[error("key already exists")]
+    Exists,
+    #[error("map is full")]
+    Full,
+    #[error("no space left in map")]
+    NoSpace,
+    #[error("invalid argument")]
+    InvalidArgument,
+}
+
+impl MapError {
+    /// errno the kernel reports for the same condition
+    pub fn errno(&self) -> i64 {
+        match self {
+            MapError::NotFound => 2,
+            MapError::Exists => 17,
+            MapError::Full => 7,
+            MapError::NoSpace => 28,
+            MapError::InvalidArgument => 22,
+        }
+    }
+}
+
+/// why a helper failed, the program gets the negated errno
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HelperError {
+    #[error("invalid argument")]
+    InvalidArgument,
+}
+
+impl HelperError {
+    pub fn errno(&self) -> i64 {
+        match self {
+            HelperError::InvalidArgument => 22,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PcapError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a pcap or pcapng file")]
+    UnknownFormat,
+    #[error("truncated capture file")]
+    Truncated,
+    #[error("invalid block at offset {0}")]
+    InvalidBlock(usize),
+    #[error("packet references unknown interface {0}")]
+    UnknownInterface(u32),
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VerifierError {
+    #[error("{pc}: unknown opcode {op:#04x}")]
+    UnknownOpcode { pc: usize, op: u8 },
+    #[error("{pc}: instruction needs isa {required}, newer than {isa}")]
+    UnsupportedInsn {
+        pc: usize,
+        required: IsaVersion,
+        isa: IsaVersion,
+    },
+    #[error("{pc}: invalid offset {off} for the opcode")]
+    InvalidOffset { pc: usize, off: i16 },
+    #[error("{pc}: invalid register r{reg}")]
+    InvalidRegister { pc: usize, reg: u8 },
+    #[error("{pc}: frame pointer is read only")]
+    ReadOnlyFramePointer { pc: usize },
+    #[error("{pc}: invalid lddw, second half missing")]
+    InvalidLddw { pc: usize },
+    #[error("{pc}: jump out of range to {target}")]
+    JumpOutOfRange { pc: usize, target: i64 },
+    #[error("back-edge from insn {from} to {to}")]
+    BackEdge { from: usize, to: usize },
+    #[error("unreachable insn {pc}")]
+    Unreachable { pc: usize },
+    #[error("last insn is not an exit or jmp")]
+    FallThrough,
+    #[error("{pc}: R{reg} !read_ok")]
+    UninitRegister { pc: usize, reg: u8 },
+    #[error("{pc}: R{reg} invalid mem access '{ty}'")]
+    InvalidMemAccess { pc: usize, reg: u8, ty: RegType },
+    #[error("{pc}: invalid access to {ty}, off={off} size={size}")]
+    OutOfBounds {
+        pc: usize,
+        ty: RegType,
+        off: i64,
+        size: usize,
+    },
+    #[error("{pc}: R{reg} pointer arithmetic on {ty} prohibited")]
+    PointerArithmetic { pc: usize, reg: u8, ty: RegType },
+    #[error("{pc}: R{reg} leaks addr")]
+    PointerLeak { pc: usize, reg: u8 },
+    #[error("{pc}: unknown func {id}")]
+    UnknownHelper { pc: usize, id: i64 },
+    #[error("{pc}: R{reg} type={ty} is not a valid helper argument")]
+    InvalidHelperArg { pc: usize, reg: u8, ty: RegType },
+    #[error("{pc}: fd {fd} is not pointing to valid bpf_map")]
+    InvalidMapFd { pc: usize, fd: i64 },
+    #[error("{pc}: recursive call")]
+    RecursiveCall { pc: usize },
+    #[error("{pc}: the call stack of {frames} frames is too deep")]
+    CallStackTooDeep { pc: usize, frames: usize },
+    #[error(
+        "function at insn {func} uses {depth} bytes of stack, over the {limit} bytes frame of a function making calls"
+    )]
+    StackTooDeep {
+        func: usize,
+        depth: usize,
+        limit: usize,
+    },
+    #[error("{pc}: invalid read from stack off {off} size {size}")]
+    InvalidStackRead { pc: usize, off: i64, size: usize },
+    #[error("infinite loop detected at insn {pc}")]
+    InfiniteLoop { pc: usize },
+    #[error("loop at insn {pc} is not proven to end")]
+    LoopTooComplex { pc: usize },
+    #[error("program is too large, processed {0} insn")]
+    TooComplex(usize),
+}
@@ -0,0 +1,432 @@
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, ToSocketAddrs},
+};
+
+use crate::{
+    debugger::{Debugger, StopReason},
+    error::VmError,
+};
+
+// gdb's bpf registers, r0 to r10 then pc, which counts bytes and not
+// instructions
+const NUM_GDB_REGS: usize = 12;
+const PC_REG: usize = 11;
+const INS_SIZE: u64 = 8;
+
+const SIGABRT: u8 = 6;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<architecture>bpf</architecture>
+<feature name="org.gnu.gdb.bpf.core">
+<reg name="r0" bitsize="64" type="int64"/>
+<reg name="r1" bitsize="64" type="int64"/>
+<reg name="r2" bitsize="64" type="int64"/>
+<reg name="r3" bitsize="64" type="int64"/>
+<reg name="r4" bitsize="64" type="int64"/>
+<reg name="r5" bitsize="64" type="int64"/>
+<reg name="r6" bitsize="64" type="int64"/>
+<reg name="r7" bitsize="64" type="int64"/>
+<reg name="r8" bitsize="64" type="int64"/>
+<reg name="r9" bitsize="64" type="int64"/>
+<reg name="r10" bitsize="64" type="data_ptr"/>
+<reg name="pc" bitsize="64" type="code_ptr"/>
+</feature>
+</target>
+"#;
+
+/// wait for a debugger on `addr`, a local port, and serve `dbg` to it
+pub fn serve_gdb_tcp<A: ToSocketAddrs>(dbg: &mut Debugger, addr: A) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    serve_gdb(dbg, stream.try_clone()?, stream)
+}
+
+/// answer the gdb remote serial protocol packets read from `input` until the
+/// debugger detaches, kills the program or goes away, the program only runs
+/// when asked to so it cannot be interrupted
+pub fn serve_gdb<R: Read, W: Write>(dbg: &mut Debugger, input: R, output: W) -> io::Result<()> {
+    let mut session = Session {
+        dbg,
+        input: BufReader::new(input),
+        output,
+        ack: true,
+        signal: SIGTRAP,
+        done: false,
+    };
+    while !session.done
+        && let Some(packet) = session.recv()?
+    {
+        if let Some(reply) = session.handle(&packet)? {
+            session.send(&reply)?;
+        }
+    }
+    Ok(())
+}
+
+struct Session<'d, 'a, R, W> {
+    dbg: &'d mut Debugger<'a>,
+    input: BufReader<R>,
+    output: W,
+    // until the debugger asks for no acks
+    ack: bool,
+    // of the last stop, a fault ends the program with it
+    signal: u8,
+    // detached or killed
+    done: bool,
+}
+
+impl<R: Read, W: Write> Session<'_, '_, R, W> {
+    /// data of the next valid packet, none once the input is closed
+    fn recv(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0u8];
+        loop {
+            if self.input.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            // skips the acks, and the interrupts as the program is stopped
+            if byte[0] != b'$' {
+                continue;
+            }
+            let mut data = Vec::new();
+            self.input.read_until(b'#', &mut data)?;
+            if data.pop() != Some(b'#') {
+                return Ok(None);
+            }
+            let mut sum = [0u8; 2];
+            self.input.read_exact(&mut sum)?;
+            let valid = from_hex(&sum).is_some_and(|sum| sum == [checksum(&data)]);
+            if self.ack {
+                self.output.write_all(if valid { b"+" } else { b"-" })?;
+                self.output.flush()?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    // never resent, the transports are reliable
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        write!(self.output, "${}#{:02x}", data, checksum(data.as_bytes()))?;
+        self.output.flush()
+    }
+
+    /// reply to `packet`, if it gets one
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let Some(cmd) = packet.chars().next() else {
+            return Ok(Some(String::new()));
+        };
+        let args = &packet[cmd.len_utf8()..];
+        let reply = match cmd {
+            '?' => format!("S{:02x}", self.signal),
+            'g' => {
+                let regs: Vec<u8> = (0..NUM_GDB_REGS)
+                    .flat_map(|reg| self.reg(reg).to_le_bytes())
+                    .collect();
+                to_hex(&regs)
+            }
+            'G' => match from_hex(args.as_bytes()) {
+                Some(regs) if regs.len() == NUM_GDB_REGS * 8 => {
+                    // pc cannot be changed, it is left as is
+                    for (reg, value) in regs.chunks(8).take(PC_REG).enumerate() {
+                        self.dbg
+                            .set_reg(reg, u64::from_le_bytes(value.try_into().unwrap()));
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            'p' => match usize::from_str_radix(args, 16) {
+                Ok(reg) if reg < NUM_GDB_REGS => to_hex(&self.reg(reg).to_le_bytes()),
+                _ => "E01".to_string(),
+            },
+            'P' => {
+                let reg = args.split_once('=').and_then(|(reg, value)| {
+                    let reg = usize::from_str_radix(reg, 16).ok()?;
+                    let value: [u8; 8] = from_hex(value.as_bytes())?.try_into().ok()?;
+                    Some((reg, u64::from_le_bytes(value)))
+                });
+                match reg {
+                    Some((reg, value)) if reg < PC_REG => {
+                        self.dbg.set_reg(reg, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            'm' => match parse_range(args) {
+                Some((addr, len)) => match self.dbg.read_memory(addr, len) {
+                    Ok(data) => to_hex(&data),
+                    Err(_) => "E14".to_string(),
+                },
+                None => "E01".to_string(),
+            },
+            'M' => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    Some((addr, from_hex(data.as_bytes()).filter(|d| d.len() == len)?))
+                });
+                match write {
+                    Some((addr, data)) => match self.dbg.write_memory(addr, &data) {
+                        Ok(()) => "OK".to_string(),
+                        Err(_) => "E14".to_string(),
+                    },
+                    None => "E01".to_string(),
+                }
+            }
+            // resuming at another address is not supported
+            's' => {
+                let stop = self.dbg.step();
+                self.stop_reply(stop)?
+            }
+            'c' => {
+                let stop = self.dbg.cont();
+                self.stop_reply(stop)?
+            }
+            // software and hardware breakpoints are the same
+            'Z' | 'z' => {
+                let bp = args.split(',').collect::<Vec<_>>();
+                match bp[..] {
+                    ["0" | "1", addr, _] => match u64::from_str_radix(addr, 16) {
+                        Ok(addr) => {
+                            let pc = (addr / INS_SIZE) as usize;
+                            match cmd {
+                                'Z' => self.dbg.add_breakpoint(pc),
+                                _ => self.dbg.remove_breakpoint(pc),
+                            };
+                            "OK".to_string()
+                        }
+                        Err(_) => "E01".to_string(),
+                    },
+                    _ => String::new(),
+                }
+            }
+            'H' | 'T' => "OK".to_string(),
+            'D' => {
+                self.done = true;
+                "OK".to_string()
+            }
+            'k' => {
+                self.done = true;
+                return Ok(None);
+            }
+            _ => self.query(packet)?,
+        };
+        Ok(Some(reply))
+    }
+
+    /// reply to the q, Q and v packets, empty for the unsupported ones
+    fn query(&mut self, packet: &str) -> io::Result<String> {
+        if packet.starts_with("qSupported") {
+            return Ok("PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string());
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = parse_range(range) else {
+                return Ok("E01".to_string());
+            };
+            let rest = TARGET_XML.get(offset as usize..).unwrap_or_default();
+            return Ok(match rest.len() <= len {
+                true => format!("l{rest}"),
+                false => format!("m{}", &rest[..len]),
+            });
+        }
+        if let Some(reg) = packet.strip_prefix("qRegisterInfo") {
+            return Ok(match usize::from_str_radix(reg, 16) {
+                Ok(reg) if reg < NUM_GDB_REGS => register_info(reg),
+                _ => "E45".to_string(),
+            });
+        }
+        let reply = match packet {
+            // this one was acked already
+            "QStartNoAckMode" => {
+                self.ack = false;
+                "OK".to_string()
+            }
+            "qHostInfo" => format!("triple:{};endian:little;ptrsize:8;", to_hex(b"bpf")),
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "vKill;1" => {
+                self.done = true;
+                "OK".to_string()
+            }
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn reg(&self, reg: usize) -> u64 {
+        match reg {
+            PC_REG => self.dbg.pc() as u64 * INS_SIZE,
+            _ => self.dbg.reg(reg),
+        }
+    }
+
+    /// stop reply packet for `stop`, the faults are also printed by gdb
+    fn stop_reply(&mut self, stop: StopReason) -> io::Result<String> {
+        let reply = match stop {
+            StopReason::Step | StopReason::Breakpoint(_) => {
+                self.signal = SIGTRAP;
+                format!("S{SIGTRAP:02x}")
+            }
+            StopReason::Exit(r0) => format!("W{:02x}", r0 as u8),
+            // the program is gone since its last fault
+            StopReason::Error(VmError::NotRunning) => format!("X{:02x}", self.signal),
+            StopReason::Error(e) => {
+                self.signal = match e {
+                    VmError::DivZero(_) => SIGFPE,
+                    VmError::OutOfBounds { .. } | VmError::AccessViolation { .. } => SIGSEGV,
+                    _ => SIGABRT,
+                };
+                self.send(&format!("O{}", to_hex(format!("{e}\n").as_bytes())))?;
+                format!("S{:02x}", self.signal)
+            }
+        };
+        Ok(reply)
+    }
+}
+
+/// reply to lldb's `qRegisterInfo` for `reg`
+fn register_info(reg: usize) -> String {
+    let name = match reg {
+        PC_REG => "pc".to_string(),
+        _ => format!("r{reg}"),
+    };
+    let mut info = format!(
+        "name:{name};bitsize:64;offset:{};encoding:uint;format:hex;set:General Purpose Registers;",
+        reg * 8
+    );
+    match reg {
+        PC_REG => info.push_str("generic:pc;"),
+        10 => info.push_str("generic:fp;"),
+        1..=5 => write!(info, "generic:arg{reg};").unwrap(),
+        _ => {}
+    }
+    info
+}
+
+/// `addr,len` in hex
+fn parse_range(range: &str) -> Option<(u64, usize)> {
+    let (addr, len) = range.split_once(',')?;
+    let addr = u64::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    Some((addr, len))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().fold(String::new(), |mut hex, b| {
+        write!(hex, "{b:02x}").unwrap();
+        hex
+    })
+}
+
+fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use assembler::Instructions;
+
+    use super::*;
+    use crate::runtime::VirtualMachine;
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum(data.as_bytes()))
+    }
+
+    #[test]
+    fn test_serve_gdb() {
+        let prog = "ldxw r2, [r1]
+ldxw r3, [r1+4]
+add r3, r2
+mov r0, r3
+div r0, r4
+exit";
+        let instructions = Instructions::from_asm(prog).unwrap();
+        let mut vm = VirtualMachine::new(instructions.into());
+        let mut ctx = [3, 0, 0, 0, 4, 0, 0, 0];
+        let ctx_addr = ctx.as_ptr() as u64;
+        let mut dbg = vm.debug(&mut ctx).unwrap();
+
+        let requests = [
+            "qSupported:swbreak+".to_string(),
+            "QStartNoAckMode".to_string(),
+            "?".to_string(),
+            "Z0,18,8".to_string(),
+            format!("M{:x},4:05000000", ctx_addr + 4),
+            "c".to_string(),
+            "p3".to_string(),
+            "pb".to_string(),
+            format!("m{ctx_addr:x},8"),
+            "m0,8".to_string(),
+            "P4=0200000000000000".to_string(),
+            "s".to_string(),
+            "g".to_string(),
+            "c".to_string(),
+            "k".to_string(),
+            // never read
+            "?".to_string(),
+        ];
+        let input = requests.iter().map(|r| packet(r)).collect::<String>();
+        let mut output = Vec::new();
+        serve_gdb(&mut dbg, input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        // acks up to the one of QStartNoAckMode
+        let packets: Vec<_> = output.split('$').collect();
+        assert_eq!(packets[0], "+");
+        assert!(packets[1].ends_with('+'));
+        assert!(packets[2..].iter().all(|p| !p.ends_with('+')));
+        let replies: Vec<_> = packets[1..]
+            .iter()
+            .map(|p| p.rsplit_once('#').unwrap().0)
+            .collect();
+        let mut regs = [0u64; 12];
+        regs[0] = 8;
+        regs[1] = ctx_addr;
+        regs[2] = 3;
+        regs[3] = 8;
+        regs[4] = 2;
+        regs[10] = dbg.reg(10);
+        regs[11] = 4 * 8;
+        let regs = regs.map(|r| to_hex(&r.to_le_bytes())).concat();
+        assert_eq!(
+            replies,
+            [
+                "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+",
+                "OK",
+                "S05",
+                "OK",
+                "OK",
+                "S05",
+                "0800000000000000",
+                "1800000000000000",
+                "0300000005000000",
+                "E14",
+                "OK",
+                "S05",
+                &regs,
+                "W04",
+            ]
+        );
+        assert!(!dbg.is_running());
+    }
+}
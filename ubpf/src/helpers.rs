@@ -0,0 +1,205 @@
+use std::fmt::Debug;
+
+use crate::{
+    error::VmError,
+    memory,
+    runtime::{enter_tail_call, run_map, tail_call_entry, tail_call_target, with_regions},
+};
+
+// helper ids, same numbering as `enum bpf_func_id` in the kernel
+pub const BPF_FUNC_MAP_LOOKUP_ELEM: u32 = 1;
+pub const BPF_FUNC_MAP_UPDATE_ELEM: u32 = 2;
+pub const BPF_FUNC_MAP_DELETE_ELEM: u32 = 3;
+pub const BPF_FUNC_KTIME_GET_NS: u32 = 5;
+pub const BPF_FUNC_TRACE_PRINTK: u32 = 6;
+pub const BPF_FUNC_GET_PRANDOM_U32: u32 = 7;
+pub const BPF_FUNC_GET_SMP_PROCESSOR_ID: u32 = 8;
+pub const BPF_FUNC_TAIL_CALL: u32 = 12;
+pub const BPF_FUNC_XDP_ADJUST_HEAD: u32 = 44;
+pub const BPF_FUNC_XDP_ADJUST_META: u32 = 54;
+pub const BPF_FUNC_XDP_ADJUST_TAIL: u32 = 65;
+
+pub type HelperFn = dyn Fn(u64, u64, u64, u64, u64) -> u64 + Send + Sync;
+
+/// what the verifier accepts in an argument register, as the kernel's
+/// `enum bpf_arg_type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArgType {
+    /// not an argument, the register is not read
+    DontCare,
+    /// any initialized register
+    Anything,
+    /// a number
+    Scalar,
+    /// map loaded by `ldmapfd`
+    ConstMapPtr,
+    /// initialized memory of the key size of the map of the previous
+    /// `ConstMapPtr`
+    PtrToMapKey,
+    /// initialized memory of the value size of that map
+    PtrToMapValue,
+    /// initialized memory of as many bytes as the next argument
+    PtrToMem,
+    /// bounded number of bytes of the previous `PtrToMem`
+    ConstSize,
+    PtrToCtx,
+}
+
+/// what the verifier knows r0 holds after the call
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RetType {
+    Integer,
+    /// pointer to a value of the map of the `ConstMapPtr` argument, or 0
+    MapValueOrNull,
+}
+
+/// arguments and return value of a helper, for the verifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HelperProto {
+    pub ret: RetType,
+    /// r1-r5
+    pub args: [ArgType; 5],
+}
+
+impl HelperProto {
+    /// `args` are the first arguments, the others are `DontCare`
+    pub const fn new(ret: RetType, args: &[ArgType]) -> Self {
+        let mut all = [ArgType::DontCare; 5];
+        let mut i = 0;
+        while i < args.len() {
+            all[i] = args[i];
+            i += 1;
+        }
+        Self { ret, args: all }
+    }
+}
+
+pub const MAP_LOOKUP_ELEM_PROTO: HelperProto = HelperProto::new(
+    RetType::MapValueOrNull,
+    &[ArgType::ConstMapPtr, ArgType::PtrToMapKey],
+);
+pub const MAP_UPDATE_ELEM_PROTO: HelperProto = HelperProto::new(
+    RetType::Integer,
+    &[
+        ArgType::ConstMapPtr,
+        ArgType::PtrToMapKey,
+        ArgType::PtrToMapValue,
+        ArgType::Scalar,
+    ],
+);
+pub const MAP_DELETE_ELEM_PROTO: HelperProto = HelperProto::new(
+    RetType::Integer,
+    &[ArgType::ConstMapPtr, ArgType::PtrToMapKey],
+);
+pub const TAIL_CALL_PROTO: HelperProto = HelperProto::new(
+    RetType::Integer,
+    &[ArgType::PtrToCtx, ArgType::ConstMapPtr, ArgType::Scalar],
+);
+
+/// function reachable from a program through `call imm`, it gets r1-r5 and
+/// its return value is written to r0
+pub struct Helper {
+    func: Box<HelperFn>,
+    proto: HelperProto,
+}
+
+impl Helper {
+    pub fn new<F>(proto: HelperProto, func: F) -> Self
+    where
+        F: Fn(u64, u64, u64, u64, u64) -> u64 + Send + Sync + 'static,
+    {
+        Self {
+            func: Box::new(func),
+            proto,
+        }
+    }
+
+    pub fn proto(&self) -> &HelperProto {
+        &self.proto
+    }
+
+    #[inline(always)]
+    pub fn call(&self, r1: u64, r2: u64, r3: u64, r4: u64, r5: u64) -> u64 {
+        (self.func)(r1, r2, r3, r4, r5)
+    }
+
+    /// `call` at `pc` from a program that sees guest addresses, the pointer
+    /// arguments are translated to host addresses first, in the memory of the
+    /// run on this thread, which must hold all the bytes the helper may access
+    pub(crate) fn call_guest(&self, args: [u64; 5], pc: usize) -> Result<u64, VmError> {
+        let [r1, r2, r3, r4, r5] = self.host_args(args, pc)?;
+        Ok(self.call(r1, r2, r3, r4, r5))
+    }
+
+    fn host_args(&self, args: [u64; 5], pc: usize) -> Result<[u64; 5], VmError> {
+        let types = self.proto.args;
+        let mut host = args;
+        let mut map = None;
+        for i in 0..args.len() {
+            let size = match types[i] {
+                ArgType::ConstMapPtr => {
+                    map = run_map(args[i]);
+                    continue;
+                }
+                ArgType::PtrToMapKey => map.as_ref().map_or(1, |m| m.def().key_size as usize),
+                ArgType::PtrToMapValue => map.as_ref().map_or(1, |m| m.def().value_size as usize),
+                ArgType::PtrToMem => match types.get(i + 1) {
+                    Some(ArgType::ConstSize) => args[i + 1] as usize,
+                    _ => 1,
+                },
+                ArgType::PtrToCtx => 1,
+                _ => continue,
+            };
+            // not borrowed during the call, the helper may look up a map value
+            host[i] = with_regions(|regions| memory::to_host(args[i], size, false, pc, regions))?;
+        }
+        Ok(host)
+    }
+}
+
+impl Debug for Helper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Helper")
+    }
+}
+
+/// entry point of helper calls from jited code, `helper` is the
+/// `context` of the `ExternalCall` registered for the call
+pub(crate) extern "C" fn helper_trampoline(
+    r1: u64,
+    r2: u64,
+    r3: u64,
+    r4: u64,
+    r5: u64,
+    helper: *const Helper,
+) -> u64 {
+    let helper = unsafe { &*helper };
+    helper.call(r1, r2, r3, r4, r5)
+}
+
+/// `bpf_tail_call` from jited code, returns the address to jump to or a
+/// negative errno when execution continues after the call
+pub(crate) extern "C" fn tail_call_trampoline(map: u64, index: u64, count: u64) -> i64 {
+    let target = tail_call_target(map, index, count)
+        .and_then(|prog| tail_call_entry(&prog).map(|entry| (prog, entry)));
+    match target {
+        Ok((prog, entry)) => {
+            enter_tail_call(&prog);
+            entry
+        }
+        Err(e) => -e.errno(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trampoline() {
+        let proto = HelperProto::new(RetType::Integer, &[ArgType::Anything; 5]);
+        let helper = Helper::new(proto, |a, b, c, d, e| a + b + c + d + e);
+        let r = helper_trampoline(1, 2, 3, 4, 5, &helper as *const _);
+        assert_eq!(r, 15);
+    }
+}
@@ -1,9 +1,33 @@
+use std::{fs, path::PathBuf, time::Instant};
+
+use assembler::{Instructions, IsaVersion};
 use structopt::StructOpt;
 
+mod debugger;
 mod error;
+mod gdb;
+mod helpers;
+mod maps;
+mod memory;
+mod pcap;
+mod replay;
 mod runtime;
+mod std_helpers;
+mod threaded;
 mod utils;
+mod verifier;
+mod xdp;
+pub use debugger::*;
+pub use error::*;
+pub use gdb::*;
+pub use helpers::*;
+pub use maps::*;
+pub use pcap::*;
+pub use replay::*;
 pub use runtime::*;
+pub use std_helpers::*;
+pub use verifier::*;
+pub use xdp::*;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "example", about = "An example of StructOpt usage.")]
@@ -12,9 +36,237 @@ struct Opt {
     // short and long flags (-d, --debug) will be deduced from the field's name
     #[structopt(short, long)]
     debug: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Run a program over the packets of a pcap or pcapng file
+    Replay {
+        /// ELF object or assembly file
+        #[structopt(parse(from_os_str))]
+        program: PathBuf,
+        /// Capture file to read the packets from
+        #[structopt(parse(from_os_str))]
+        pcap: PathBuf,
+        /// Function to load from an ELF object
+        #[structopt(short, long, default_value = "bpf_prog")]
+        function: String,
+        /// Write the packets with a non zero verdict to this pcap file
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+        #[structopt(long)]
+        jit: bool,
+        /// Interpret the program decoded into threaded code
+        #[structopt(long)]
+        threaded: bool,
+        /// Run the program as an xdp program
+        #[structopt(long)]
+        xdp: bool,
+        /// Instruction set version the program targets, v1 to v4
+        #[structopt(long, default_value = "v4")]
+        isa: IsaVersion,
+    },
+    /// Check a program with the verifier and print its log
+    Verify {
+        /// ELF object or assembly file
+        #[structopt(parse(from_os_str))]
+        program: PathBuf,
+        /// Function to load from an ELF object
+        #[structopt(short, long, default_value = "bpf_prog")]
+        function: String,
+        /// 1 logs the instructions walked, 2 the register states too
+        #[structopt(short, long, default_value = "1")]
+        log_level: u32,
+        /// Instruction set version the program targets, v1 to v4
+        #[structopt(long, default_value = "v4")]
+        isa: IsaVersion,
+    },
+    /// Time the runs of a program in each execution mode, with the vm
+    /// memory as context
+    Bench {
+        /// ELF object or assembly file
+        #[structopt(parse(from_os_str))]
+        program: PathBuf,
+        /// Function to load from an ELF object
+        #[structopt(short, long, default_value = "bpf_prog")]
+        function: String,
+        /// Runs per mode
+        #[structopt(short, long, default_value = "10000")]
+        runs: u32,
+        /// Instruction set version the program targets, v1 to v4
+        #[structopt(long, default_value = "v4")]
+        isa: IsaVersion,
+    },
+    /// Print the control flow graph of a program in graphviz dot format
+    Cfg {
+        /// ELF object or assembly file
+        #[structopt(parse(from_os_str))]
+        program: PathBuf,
+        /// Function to load from an ELF object
+        #[structopt(short, long, default_value = "bpf_prog")]
+        function: String,
+    },
+    /// Run a program in the interpreter under gdb, which talks to it over
+    /// stdio (`target remote | ubpf gdb ...`) or a local port
+    Gdb {
+        /// ELF object or assembly file
+        #[structopt(parse(from_os_str))]
+        program: PathBuf,
+        /// Function to load from an ELF object
+        #[structopt(short, long, default_value = "bpf_prog")]
+        function: String,
+        /// File to use as the context, none by default
+        #[structopt(short, long, parse(from_os_str))]
+        input: Option<PathBuf>,
+        /// Wait for gdb on this port of localhost instead of stdio
+        #[structopt(short, long)]
+        port: Option<u16>,
+        /// Instruction set version the program targets, v1 to v4
+        #[structopt(long, default_value = "v4")]
+        isa: IsaVersion,
+    },
 }
 
-fn main() {
+fn load_program(
+    path: &PathBuf,
+    function: &str,
+    isa: IsaVersion,
+) -> Result<Instructions, Box<dyn std::error::Error>> {
+    let content = fs::read(path)?;
+    if content.starts_with(b"\x7fELF") {
+        Ok(Instructions::from_elf_with_isa(&content, function, isa)?)
+    } else {
+        Ok(Instructions::from_asm_with_isa(
+            &String::from_utf8(content)?,
+            isa,
+        )?)
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Opt::from_args();
-    println!("{:?}", opt.debug);
+    match opt.command {
+        Some(Command::Replay {
+            program,
+            pcap,
+            function,
+            output,
+            jit,
+            threaded,
+            xdp,
+            isa,
+        }) => {
+            let mut vm = VirtualMachine::new(load_program(&program, &function, isa)?.into());
+            vm.set_isa(isa)?;
+            vm.set_threaded(threaded);
+            vm.register_std_helpers(StdHelpers::new());
+            let capture = Capture::from_file(pcap)?;
+            let options = ReplayOptions {
+                jit,
+                context: if xdp {
+                    ReplayContext::Xdp
+                } else {
+                    ReplayContext::Packet
+                },
+                ..Default::default()
+            };
+
+            let stats = match output {
+                Some(path) => {
+                    let link_type = capture
+                        .packets
+                        .first()
+                        .map_or(LINKTYPE_ETHERNET, |p| p.link_type);
+                    let file = std::io::BufWriter::new(fs::File::create(path)?);
+                    let mut writer = PcapWriter::new(file, link_type)?;
+                    vm.replay(&capture, &options, Some(&mut writer))?
+                }
+                None => vm.replay::<fs::File>(&capture, &options, None)?,
+            };
+
+            println!("packets: {} ({} bytes)", stats.packets, stats.bytes);
+            println!("matched: {}", stats.matched);
+            for (verdict, count) in stats.verdicts.iter() {
+                match xdp {
+                    true => println!("{:?}: {}", XdpAction::from(*verdict), count),
+                    false => println!("{}: {}", verdict, count),
+                }
+            }
+        }
+        Some(Command::Verify {
+            program,
+            function,
+            log_level,
+            isa,
+        }) => {
+            let mut vm = VirtualMachine::new(load_program(&program, &function, isa)?.into());
+            vm.set_isa(isa)?;
+            vm.register_std_helpers(StdHelpers::new());
+            let options = VerifierOptions {
+                log_level,
+                isa,
+                ..Default::default()
+            };
+            let (res, log) = vm.verify_with_log(&options);
+            print!("{}", log);
+            res?;
+        }
+        Some(Command::Bench {
+            program,
+            function,
+            runs,
+            isa,
+        }) => {
+            let mut vm = VirtualMachine::new(load_program(&program, &function, isa)?.into());
+            vm.set_isa(isa)?;
+            vm.register_std_helpers(StdHelpers::new());
+            for (mode, jit, threaded) in [
+                ("interpreter", false, false),
+                ("threaded", false, true),
+                ("jit", true, false),
+            ] {
+                vm.set_threaded(threaded);
+                // decodes or compiles the program outside of the timing
+                let res = vm.exec(jit)?;
+                let start = Instant::now();
+                for _ in 0..runs {
+                    vm.exec(jit)?;
+                }
+                let elapsed = start.elapsed() / runs.max(1);
+                println!("{}: {:?} per run, r0 = {}", mode, elapsed, res);
+            }
+        }
+        Some(Command::Cfg { program, function }) => {
+            print!(
+                "{}",
+                load_program(&program, &function, IsaVersion::default())?
+                    .cfg()
+                    .to_dot()
+            );
+        }
+        Some(Command::Gdb {
+            program,
+            function,
+            input,
+            port,
+            isa,
+        }) => {
+            let mut vm = VirtualMachine::new(load_program(&program, &function, isa)?.into());
+            vm.set_isa(isa)?;
+            vm.register_std_helpers(StdHelpers::new());
+            let mut ctx = match input {
+                Some(path) => fs::read(path)?,
+                None => Vec::new(),
+            };
+            let mut dbg = vm.debug(&mut ctx)?;
+            match port {
+                Some(port) => serve_gdb_tcp(&mut dbg, ("127.0.0.1", port))?,
+                None => serve_gdb(&mut dbg, std::io::stdin(), std::io::stdout())?,
+            }
+        }
+        None => println!("{:?}", opt.debug),
+    }
+    Ok(())
 }
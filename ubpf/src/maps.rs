@@ -0,0 +1,642 @@
+use std::{
+    cell::UnsafeCell,
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    error::MapError,
+    runtime::{Program, VmConfig, hold_map_value, run_map},
+};
+
+// flags of `bpf_map_update_elem`
+pub const BPF_ANY: u64 = 0;
+pub const BPF_NOEXIST: u64 = 1;
+pub const BPF_EXIST: u64 = 2;
+
+/// map types, same numbering as `enum bpf_map_type` in the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapType {
+    Hash = 1,
+    Array = 2,
+    ProgArray = 3,
+    LruHash = 9,
+    LpmTrie = 11,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapDef {
+    pub map_type: MapType,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+}
+
+impl MapDef {
+    pub fn new(map_type: MapType, key_size: u32, value_size: u32, max_entries: u32) -> Self {
+        Self {
+            map_type,
+            key_size,
+            value_size,
+            max_entries,
+        }
+    }
+}
+
+/// value of an entry, the runs that looked it up hold it until they end so
+/// that deleting or evicting the entry does not free it under them
+pub(crate) struct MapValue(Box<[UnsafeCell<u8>]>);
+
+// programs and the host read and write values at the same time, as they do
+// in the kernel
+unsafe impl Sync for MapValue {}
+
+impl MapValue {
+    fn new(value: &[u8]) -> Arc<Self> {
+        Arc::new(Self(value.iter().map(|&b| UnsafeCell::new(b)).collect()))
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        UnsafeCell::raw_get(self.0.as_ptr())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn read(&self) -> Vec<u8> {
+        unsafe { std::slice::from_raw_parts(self.as_ptr(), self.len()) }.to_vec()
+    }
+
+    fn write(&self, value: &[u8]) {
+        let len = value.len().min(self.len());
+        unsafe { std::ptr::copy_nonoverlapping(value.as_ptr(), self.as_ptr(), len) };
+    }
+}
+
+impl Debug for MapValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MapValue({} bytes)", self.len())
+    }
+}
+
+trait MapStorage: Debug + Send {
+    fn lookup(&mut self, key: &[u8]) -> Option<Arc<MapValue>>;
+    fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<(), MapError>;
+    fn delete(&mut self, key: &[u8]) -> Result<(), MapError>;
+
+    fn program(&self, _index: u32) -> Option<Arc<Program>> {
+        None
+    }
+
+    fn set_program(&mut self, _index: u32, _prog: Arc<Program>) -> Result<(), MapError> {
+        Err(MapError::InvalidArgument)
+    }
+
+    /// whether a program running with `config` may tail call into the map
+    fn claim(&mut self, _config: &VmConfig) -> Result<(), MapError> {
+        Ok(())
+    }
+}
+
+/// a map shared between the host and programs, programs reach it through
+/// `ldmapfd` and the map helpers
+#[derive(Debug)]
+pub struct BpfMap {
+    def: MapDef,
+    storage: Mutex<Box<dyn MapStorage>>,
+}
+
+impl BpfMap {
+    pub fn new(def: MapDef) -> Result<Self, MapError> {
+        if def.key_size == 0 || def.value_size == 0 || def.max_entries == 0 {
+            return Err(MapError::InvalidArgument);
+        }
+
+        let storage: Box<dyn MapStorage> = match def.map_type {
+            MapType::Hash => Box::new(HashStorage::new(def)),
+            MapType::Array => {
+                if def.key_size != 4 {
+                    return Err(MapError::InvalidArgument);
+                }
+                Box::new(ArrayStorage::new(def))
+            }
+            MapType::ProgArray => {
+                if def.key_size != 4 || def.value_size != 4 {
+                    return Err(MapError::InvalidArgument);
+                }
+                Box::new(ProgArrayStorage::new(def))
+            }
+            MapType::LruHash => Box::new(LruHashStorage::new(def)),
+            MapType::LpmTrie => {
+                // struct bpf_lpm_trie_key { __u32 prefixlen; __u8 data[]; }
+                if def.key_size <= 4 || def.key_size > 4 + 256 {
+                    return Err(MapError::InvalidArgument);
+                }
+                Box::new(LpmTrieStorage::new(def))
+            }
+        };
+
+        Ok(Self {
+            def,
+            storage: Mutex::new(storage),
+        })
+    }
+
+    pub fn def(&self) -> &MapDef {
+        &self.def
+    }
+
+    pub fn lookup(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if key.len() != self.def.key_size as usize {
+            return None;
+        }
+        // copied before an update can get to the value
+        let mut storage = self.storage.lock().unwrap();
+        Some(storage.lookup(key)?.read())
+    }
+
+    pub fn update(&self, key: &[u8], value: &[u8], flags: u64) -> Result<(), MapError> {
+        if key.len() != self.def.key_size as usize || value.len() != self.def.value_size as usize {
+            return Err(MapError::InvalidArgument);
+        }
+        if flags > BPF_EXIST {
+            return Err(MapError::InvalidArgument);
+        }
+        self.storage.lock().unwrap().update(key, value, flags)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<(), MapError> {
+        if key.len() != self.def.key_size as usize {
+            return Err(MapError::InvalidArgument);
+        }
+        self.storage.lock().unwrap().delete(key)
+    }
+
+    /// store `prog` at `index` of a prog array, replacing the previous one,
+    /// it must have the stack layout of the other programs of the array
+    pub fn set_program(&self, index: u32, prog: Arc<Program>) -> Result<(), MapError> {
+        self.storage.lock().unwrap().set_program(index, prog)
+    }
+
+    /// refuse programs whose stack another program of the prog array could
+    /// not run on, the first one sets the layout
+    pub(crate) fn claim(&self, config: &VmConfig) -> Result<(), MapError> {
+        self.storage.lock().unwrap().claim(config)
+    }
+
+    /// program `bpf_tail_call` jumps to for `index`
+    pub fn program(&self, index: u32) -> Option<Arc<Program>> {
+        self.storage.lock().unwrap().program(index)
+    }
+
+    pub(crate) fn lookup_value(&self, key: &[u8]) -> Option<Arc<MapValue>> {
+        if key.len() != self.def.key_size as usize {
+            return None;
+        }
+        self.storage.lock().unwrap().lookup(key)
+    }
+}
+
+#[derive(Debug)]
+struct HashStorage {
+    def: MapDef,
+    entries: HashMap<Vec<u8>, Arc<MapValue>>,
+}
+
+impl HashStorage {
+    fn new(def: MapDef) -> Self {
+        Self {
+            def,
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl MapStorage for HashStorage {
+    fn lookup(&mut self, key: &[u8]) -> Option<Arc<MapValue>> {
+        self.entries.get(key).cloned()
+    }
+
+    fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<(), MapError> {
+        match self.entries.get_mut(key) {
+            Some(_) if flags == BPF_NOEXIST => Err(MapError::Exists),
+            Some(old) => {
+                old.write(value);
+                Ok(())
+            }
+            None if flags == BPF_EXIST => Err(MapError::NotFound),
+            None => {
+                if self.entries.len() >= self.def.max_entries as usize {
+                    return Err(MapError::Full);
+                }
+                self.entries.insert(key.to_vec(), MapValue::new(value));
+                Ok(())
+            }
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), MapError> {
+        self.entries
+            .remove(key)
+            .map(|_| ())
+            .ok_or(MapError::NotFound)
+    }
+}
+
+#[derive(Debug)]
+struct ArrayStorage {
+    values: Vec<Arc<MapValue>>,
+}
+
+impl ArrayStorage {
+    fn new(def: MapDef) -> Self {
+        let zero = vec![0; def.value_size as usize];
+        Self {
+            values: (0..def.max_entries).map(|_| MapValue::new(&zero)).collect(),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Arc<MapValue>> {
+        let index = u32::from_ne_bytes(key.try_into().ok()?);
+        self.values.get(index as usize)
+    }
+}
+
+impl MapStorage for ArrayStorage {
+    fn lookup(&mut self, key: &[u8]) -> Option<Arc<MapValue>> {
+        self.get(key).cloned()
+    }
+
+    fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<(), MapError> {
+        // every element of an array always exists
+        if flags == BPF_NOEXIST {
+            return Err(MapError::Exists);
+        }
+        self.get(key).ok_or(MapError::Full)?.write(value);
+        Ok(())
+    }
+
+    fn delete(&mut self, _key: &[u8]) -> Result<(), MapError> {
+        Err(MapError::InvalidArgument)
+    }
+}
+
+/// programs for `bpf_tail_call`, they cannot be read or written as plain
+/// values, only through `set_program` and `delete`
+#[derive(Debug)]
+struct ProgArrayStorage {
+    programs: Vec<Option<Arc<Program>>>,
+    // of the programs stored and of their callers, a tail call reuses the
+    // stack of the caller
+    owner: Option<VmConfig>,
+}
+
+impl ProgArrayStorage {
+    fn new(def: MapDef) -> Self {
+        Self {
+            programs: vec![None; def.max_entries as usize],
+            owner: None,
+        }
+    }
+}
+
+impl MapStorage for ProgArrayStorage {
+    fn lookup(&mut self, _key: &[u8]) -> Option<Arc<MapValue>> {
+        None
+    }
+
+    fn update(&mut self, _key: &[u8], _value: &[u8], _flags: u64) -> Result<(), MapError> {
+        Err(MapError::InvalidArgument)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), MapError> {
+        let index = u32::from_ne_bytes(key.try_into().map_err(|_| MapError::InvalidArgument)?);
+        let slot = self
+            .programs
+            .get_mut(index as usize)
+            .ok_or(MapError::InvalidArgument)?;
+        slot.take().map(|_| ()).ok_or(MapError::NotFound)
+    }
+
+    fn program(&self, index: u32) -> Option<Arc<Program>> {
+        self.programs.get(index as usize)?.clone()
+    }
+
+    fn set_program(&mut self, index: u32, prog: Arc<Program>) -> Result<(), MapError> {
+        let slot = self
+            .programs
+            .get_mut(index as usize)
+            .ok_or(MapError::Full)?;
+        match self.owner {
+            Some(owner) if !owner.same_stack(prog.config()) => Err(MapError::InvalidArgument),
+            _ => {
+                self.owner = Some(*prog.config());
+                *slot = Some(prog);
+                Ok(())
+            }
+        }
+    }
+
+    fn claim(&mut self, config: &VmConfig) -> Result<(), MapError> {
+        match self.owner {
+            Some(owner) if !owner.same_stack(config) => Err(MapError::InvalidArgument),
+            _ => {
+                self.owner = Some(*config);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LruEntry {
+    value: Arc<MapValue>,
+    stamp: u64,
+}
+
+/// hash map evicting the least recently used entry when an update would
+/// exceed `max_entries`, both lookups and updates count as a use
+#[derive(Debug)]
+struct LruHashStorage {
+    def: MapDef,
+    entries: HashMap<Vec<u8>, LruEntry>,
+    // stamp -> key, the first entry is the least recently used one
+    order: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+}
+
+impl LruHashStorage {
+    fn new(def: MapDef) -> Self {
+        Self {
+            def,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn touch(&mut self, key: &[u8]) {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            let old = std::mem::replace(&mut entry.stamp, self.clock);
+            self.order.remove(&old);
+            self.order.insert(self.clock, key.to_vec());
+        }
+    }
+
+    fn evict(&mut self) {
+        if let Some((_, key)) = self.order.pop_first() {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+impl MapStorage for LruHashStorage {
+    fn lookup(&mut self, key: &[u8]) -> Option<Arc<MapValue>> {
+        self.touch(key);
+        self.entries.get(key).map(|e| e.value.clone())
+    }
+
+    fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<(), MapError> {
+        match self.entries.get_mut(key) {
+            Some(_) if flags == BPF_NOEXIST => Err(MapError::Exists),
+            Some(old) => {
+                old.value.write(value);
+                self.touch(key);
+                Ok(())
+            }
+            None if flags == BPF_EXIST => Err(MapError::NotFound),
+            None => {
+                if self.entries.len() >= self.def.max_entries as usize {
+                    self.evict();
+                }
+                self.clock += 1;
+                let entry = LruEntry {
+                    value: MapValue::new(value),
+                    stamp: self.clock,
+                };
+                self.entries.insert(key.to_vec(), entry);
+                self.order.insert(self.clock, key.to_vec());
+                Ok(())
+            }
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), MapError> {
+        let entry = self.entries.remove(key).ok_or(MapError::NotFound)?;
+        self.order.remove(&entry.stamp);
+        Ok(())
+    }
+}
+
+/// longest prefix match map, keys are laid out like
+/// `struct bpf_lpm_trie_key`: a native endian u32 prefix length followed by
+/// the data in network order
+#[derive(Debug)]
+struct LpmTrieStorage {
+    def: MapDef,
+    // (prefixlen, data with the bits past prefixlen cleared) -> value
+    entries: HashMap<(u32, Vec<u8>), Arc<MapValue>>,
+}
+
+impl LpmTrieStorage {
+    fn new(def: MapDef) -> Self {
+        Self {
+            def,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn max_prefixlen(&self) -> u32 {
+        (self.def.key_size - 4) * 8
+    }
+
+    fn split_key(&self, key: &[u8]) -> Option<(u32, Vec<u8>)> {
+        let prefixlen = u32::from_ne_bytes(key[..4].try_into().ok()?);
+        if prefixlen > self.max_prefixlen() {
+            return None;
+        }
+        Some((prefixlen, mask_prefix(&key[4..], prefixlen)))
+    }
+}
+
+fn mask_prefix(data: &[u8], prefixlen: u32) -> Vec<u8> {
+    data.iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            let bits = prefixlen.saturating_sub(i as u32 * 8).min(8);
+            if bits == 0 {
+                0
+            } else {
+                b & (0xffu8 << (8 - bits))
+            }
+        })
+        .collect()
+}
+
+impl MapStorage for LpmTrieStorage {
+    fn lookup(&mut self, key: &[u8]) -> Option<Arc<MapValue>> {
+        let prefixlen = u32::from_ne_bytes(key[..4].try_into().ok()?);
+        let prefixlen = prefixlen.min(self.max_prefixlen());
+        for len in (0..=prefixlen).rev() {
+            let data = mask_prefix(&key[4..], len);
+            if let Some(value) = self.entries.get(&(len, data)) {
+                return Some(value.clone());
+            }
+        }
+        None
+    }
+
+    fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<(), MapError> {
+        let key = self.split_key(key).ok_or(MapError::InvalidArgument)?;
+        match self.entries.get_mut(&key) {
+            Some(_) if flags == BPF_NOEXIST => Err(MapError::Exists),
+            Some(old) => {
+                old.write(value);
+                Ok(())
+            }
+            None if flags == BPF_EXIST => Err(MapError::NotFound),
+            None => {
+                if self.entries.len() >= self.def.max_entries as usize {
+                    return Err(MapError::NoSpace);
+                }
+                self.entries.insert(key, MapValue::new(value));
+                Ok(())
+            }
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), MapError> {
+        let key = self.split_key(key).ok_or(MapError::InvalidArgument)?;
+        self.entries
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(MapError::NotFound)
+    }
+}
+
+#[inline]
+fn errno_result(r: Result<(), MapError>) -> u64 {
+    match r {
+        Ok(()) => 0,
+        Err(e) => (-e.errno()) as u64,
+    }
+}
+
+/// `void *bpf_map_lookup_elem(struct bpf_map *map, const void *key)`
+pub(crate) fn map_lookup_elem(map: u64, key: u64, _: u64, _: u64, _: u64) -> u64 {
+    let Some(map) = run_map(map) else {
+        return 0;
+    };
+    let key = unsafe { std::slice::from_raw_parts(key as *const u8, map.def.key_size as usize) };
+    map.lookup_value(key).map_or(0, hold_map_value)
+}
+
+/// `long bpf_map_update_elem(struct bpf_map *map, const void *key, const void *value, u64 flags)`
+pub(crate) fn map_update_elem(map: u64, key: u64, value: u64, flags: u64, _: u64) -> u64 {
+    let Some(map) = run_map(map) else {
+        return errno_result(Err(MapError::InvalidArgument));
+    };
+    let key = unsafe { std::slice::from_raw_parts(key as *const u8, map.def.key_size as usize) };
+    let value =
+        unsafe { std::slice::from_raw_parts(value as *const u8, map.def.value_size as usize) };
+    errno_result(map.update(key, value, flags))
+}
+
+/// `long bpf_map_delete_elem(struct bpf_map *map, const void *key)`
+pub(crate) fn map_delete_elem(map: u64, key: u64, _: u64, _: u64, _: u64) -> u64 {
+    let Some(map) = run_map(map) else {
+        return errno_result(Err(MapError::InvalidArgument));
+    };
+    let key = unsafe { std::slice::from_raw_parts(key as *const u8, map.def.key_size as usize) };
+    errno_result(map.delete(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lpm_key(prefixlen: u32, data: [u8; 4]) -> Vec<u8> {
+        let mut key = prefixlen.to_ne_bytes().to_vec();
+        key.extend_from_slice(&data);
+        key
+    }
+
+    #[test]
+    fn test_hash() {
+        let map = BpfMap::new(MapDef::new(MapType::Hash, 4, 8, 2)).unwrap();
+        map.update(&[1; 4], &[1; 8], BPF_ANY).unwrap();
+        assert_eq!(
+            map.update(&[1; 4], &[2; 8], BPF_NOEXIST),
+            Err(MapError::Exists)
+        );
+        assert_eq!(
+            map.update(&[2; 4], &[2; 8], BPF_EXIST),
+            Err(MapError::NotFound)
+        );
+        map.update(&[2; 4], &[2; 8], BPF_ANY).unwrap();
+        assert_eq!(map.update(&[3; 4], &[3; 8], BPF_ANY), Err(MapError::Full));
+        assert_eq!(map.lookup(&[1; 4]), Some(vec![1; 8]));
+        map.delete(&[1; 4]).unwrap();
+        assert_eq!(map.lookup(&[1; 4]), None);
+        assert_eq!(map.delete(&[1; 4]), Err(MapError::NotFound));
+    }
+
+    #[test]
+    fn test_array() {
+        let map = BpfMap::new(MapDef::new(MapType::Array, 4, 4, 2)).unwrap();
+        assert_eq!(map.lookup(&1u32.to_ne_bytes()), Some(vec![0; 4]));
+        map.update(&1u32.to_ne_bytes(), &[7; 4], BPF_ANY).unwrap();
+        assert_eq!(map.lookup(&1u32.to_ne_bytes()), Some(vec![7; 4]));
+        assert_eq!(map.lookup(&2u32.to_ne_bytes()), None);
+        assert_eq!(
+            map.delete(&1u32.to_ne_bytes()),
+            Err(MapError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_lru_hash_eviction() {
+        let map = BpfMap::new(MapDef::new(MapType::LruHash, 1, 1, 2)).unwrap();
+        map.update(&[1], &[1], BPF_ANY).unwrap();
+        map.update(&[2], &[2], BPF_ANY).unwrap();
+        // 1 becomes the most recently used entry, so 2 gets evicted
+        assert_eq!(map.lookup(&[1]), Some(vec![1]));
+        map.update(&[3], &[3], BPF_ANY).unwrap();
+        assert_eq!(map.lookup(&[2]), None);
+        assert_eq!(map.lookup(&[1]), Some(vec![1]));
+        assert_eq!(map.lookup(&[3]), Some(vec![3]));
+
+        map.delete(&[1]).unwrap();
+        map.update(&[4], &[4], BPF_NOEXIST).unwrap();
+        assert_eq!(map.lookup(&[3]), Some(vec![3]));
+        assert_eq!(map.lookup(&[4]), Some(vec![4]));
+    }
+
+    #[test]
+    fn test_lpm_trie() {
+        let map = BpfMap::new(MapDef::new(MapType::LpmTrie, 8, 1, 8)).unwrap();
+        map.update(&lpm_key(8, [10, 0, 0, 0]), &[8], BPF_ANY)
+            .unwrap();
+        map.update(&lpm_key(24, [10, 1, 2, 0]), &[24], BPF_ANY)
+            .unwrap();
+        map.update(&lpm_key(0, [0, 0, 0, 0]), &[0], BPF_ANY)
+            .unwrap();
+
+        assert_eq!(map.lookup(&lpm_key(32, [10, 1, 2, 3])), Some(vec![24]));
+        assert_eq!(map.lookup(&lpm_key(32, [10, 1, 3, 3])), Some(vec![8]));
+        assert_eq!(map.lookup(&lpm_key(32, [192, 168, 0, 1])), Some(vec![0]));
+        // the prefix length of the lookup key bounds the match
+        assert_eq!(map.lookup(&lpm_key(16, [10, 1, 2, 3])), Some(vec![8]));
+
+        // host bits past the prefix are ignored
+        map.delete(&lpm_key(24, [10, 1, 2, 99])).unwrap();
+        assert_eq!(map.lookup(&lpm_key(32, [10, 1, 2, 3])), Some(vec![8]));
+        assert_eq!(
+            map.update(&lpm_key(33, [0; 4]), &[1], BPF_ANY),
+            Err(MapError::InvalidArgument)
+        );
+    }
+}
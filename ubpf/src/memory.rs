@@ -0,0 +1,221 @@
+use std::marker::PhantomData;
+
+use crate::error::VmError;
+
+// where the stack, the context, the maps and the map values looked up are
+// for programs that see guest addresses, map n is at MAP_VADDR + n
+pub const STACK_VADDR: u64 = 0x1_0000_0000;
+pub const INPUT_VADDR: u64 = 0x4_0000_0000;
+pub const MAP_VADDR: u64 = 0x8_0000_0000;
+pub const MAP_VALUE_VADDR: u64 = 0x9_0000_0000;
+
+/// what a program may do with a region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Perm {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+}
+
+impl Perm {
+    fn allows(self, write: bool) -> bool {
+        match self {
+            Perm::ReadOnly => !write,
+            Perm::WriteOnly => write,
+            Perm::ReadWrite => true,
+        }
+    }
+}
+
+/// `len` bytes the program sees at `vaddr` and that live at `host`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MemoryRegion {
+    pub vaddr: u64,
+    pub host: u64,
+    pub len: u64,
+    pub perm: Perm,
+}
+
+impl MemoryRegion {
+    /// host memory the program accesses through its host address
+    pub fn host(ptr: *mut u8, len: usize) -> Self {
+        Self::guest(ptr as u64, ptr, len)
+    }
+
+    /// host memory the program accesses at `vaddr`
+    pub fn guest(vaddr: u64, ptr: *mut u8, len: usize) -> Self {
+        Self {
+            vaddr,
+            host: ptr as u64,
+            len: len as u64,
+            perm: Perm::ReadWrite,
+        }
+    }
+
+    fn contains(&self, addr: u64, size: usize) -> bool {
+        addr >= self.vaddr
+            && addr
+                .checked_add(size as u64)
+                .is_some_and(|end| end <= self.vaddr + self.len)
+    }
+}
+
+/// host address of the `size` bytes at `addr` the instruction at `pc` reads
+/// or writes, if one of `regions` holds them all and allows it
+pub(crate) fn translate(
+    addr: u64,
+    size: usize,
+    write: bool,
+    pc: usize,
+    regions: &[MemoryRegion],
+) -> Result<u64, VmError> {
+    match regions.iter().find(|r| r.contains(addr, size)) {
+        Some(r) if r.perm.allows(write) => Ok(r.host + (addr - r.vaddr)),
+        Some(_) => Err(VmError::AccessViolation {
+            pc,
+            addr,
+            size,
+            write,
+        }),
+        None => Err(VmError::OutOfBounds { pc, addr, size }),
+    }
+}
+
+/// host address of the `size` bytes at `addr` a program that sees guest
+/// addresses accesses without the bounds check, one of `regions` must hold
+/// them
+pub(crate) fn to_host(
+    addr: u64,
+    size: usize,
+    write: bool,
+    pc: usize,
+    regions: &[MemoryRegion],
+) -> Result<u64, VmError> {
+    match regions.iter().find(|r| r.contains(addr, size)) {
+        Some(r) => Ok(r.host + (addr - r.vaddr)),
+        None => Err(VmError::AccessViolation {
+            pc,
+            addr,
+            size,
+            write,
+        }),
+    }
+}
+
+/// host address of `addr` and how many of the `max` bytes from there the
+/// region holding it has, if one does and may be read
+pub(crate) fn host_bytes(addr: u64, max: usize, regions: &[MemoryRegion]) -> Option<(u64, usize)> {
+    let r = regions
+        .iter()
+        .find(|r| r.contains(addr, 1) && r.perm.allows(false))?;
+    let len = (r.vaddr + r.len - addr).min(max as u64);
+    Some((r.host + (addr - r.vaddr), len as usize))
+}
+
+/// host buffers a program accesses at the guest addresses they are mapped
+/// to, along with its context and stack
+#[derive(Debug, Default)]
+pub struct MemoryMap<'a> {
+    regions: Vec<MemoryRegion>,
+    _mem: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> MemoryMap<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// make `mem` accessible at `vaddr` as `perm` allows, regions may not
+    /// overlap
+    pub fn map(&mut self, vaddr: u64, mem: &'a mut [u8], perm: Perm) -> Result<(), VmError> {
+        self.insert(vaddr, mem.as_mut_ptr(), mem.len(), perm)
+    }
+
+    /// make `mem` readable at `vaddr`
+    pub fn map_readonly(&mut self, vaddr: u64, mem: &'a [u8]) -> Result<(), VmError> {
+        // never written through, writes are rejected before
+        self.insert(vaddr, mem.as_ptr() as *mut u8, mem.len(), Perm::ReadOnly)
+    }
+
+    fn insert(&mut self, vaddr: u64, ptr: *mut u8, len: usize, perm: Perm) -> Result<(), VmError> {
+        let end = vaddr
+            .checked_add(len as u64)
+            .filter(|_| len > 0)
+            .ok_or(VmError::InvalidRegion(vaddr))?;
+        if self
+            .regions
+            .iter()
+            .any(|r| vaddr < r.vaddr + r.len && r.vaddr < end)
+        {
+            return Err(VmError::InvalidRegion(vaddr));
+        }
+        self.regions.push(MemoryRegion {
+            vaddr,
+            host: ptr as u64,
+            len: len as u64,
+            perm,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        let input = [1u8, 2, 3, 4];
+        let mut output = [0u8; 4];
+        let (mut other, mut last) = ([0u8; 4], [0u8; 4]);
+        let mut map = MemoryMap::new();
+        map.map_readonly(0x1000, &input).unwrap();
+        map.map(0x2000, &mut output, Perm::ReadWrite).unwrap();
+        assert!(matches!(
+            map.map(0x1002, &mut other, Perm::ReadWrite),
+            Err(VmError::InvalidRegion(0x1002))
+        ));
+        assert!(matches!(
+            map.map(u64::MAX - 1, &mut last, Perm::ReadWrite),
+            Err(VmError::InvalidRegion(_))
+        ));
+
+        let regions = map.regions();
+        let host = translate(0x1002, 2, false, 0, regions).unwrap();
+        assert_eq!(host, input.as_ptr() as u64 + 2);
+        assert!(matches!(
+            translate(0x1002, 2, true, 3, regions),
+            Err(VmError::AccessViolation {
+                pc: 3,
+                write: true,
+                ..
+            })
+        ));
+        assert!(matches!(
+            translate(0x1003, 2, false, 3, regions),
+            Err(VmError::OutOfBounds {
+                pc: 3,
+                addr: 0x1003,
+                size: 2
+            })
+        ));
+        assert!(translate(0x2000, 4, true, 0, regions).is_ok());
+        assert_eq!(
+            to_host(0x1003, 1, true, 0, regions).unwrap(),
+            input.as_ptr() as u64 + 3
+        );
+        assert!(matches!(
+            to_host(0x1003, 2, false, 3, regions),
+            Err(VmError::AccessViolation {
+                pc: 3,
+                addr: 0x1003,
+                size: 2,
+                write: false
+            })
+        ));
+    }
+}
@@ -0,0 +1,402 @@
+use std::io::Write;
+
+use crate::error::PcapError;
+
+// magic numbers of the classic format, as read in the file byte order
+const PCAP_MAGIC_USEC: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NSEC: u32 = 0xa1b2_3c4d;
+const PCAP_HEADER_LEN: usize = 24;
+const PCAP_RECORD_LEN: usize = 16;
+
+// pcapng block types
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const BLOCK_INTERFACE: u32 = 0x0000_0001;
+const BLOCK_PACKET: u32 = 0x0000_0002;
+const BLOCK_SIMPLE_PACKET: u32 = 0x0000_0003;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const OPTION_END: u16 = 0;
+const OPTION_IF_TSRESOL: u16 = 9;
+
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const DEFAULT_SNAPLEN: u32 = 262_144;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcapPacket {
+    /// nanoseconds since the epoch
+    pub timestamp: u64,
+    /// length on the wire, `data` may have been truncated to the snaplen
+    pub orig_len: u32,
+    pub link_type: u32,
+    pub data: Vec<u8>,
+}
+
+/// packets of a pcap or pcapng file
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    pub packets: Vec<PcapPacket>,
+}
+
+impl Capture {
+    /// parse a classic pcap file (any byte order, micro or nanoseconds) or a
+    /// pcapng file
+    pub fn parse(buffer: &[u8]) -> Result<Self, PcapError> {
+        let magic = buffer.get(..4).ok_or(PcapError::UnknownFormat)?;
+        let magic = u32::from_le_bytes(magic.try_into().unwrap());
+        match magic {
+            BLOCK_SECTION_HEADER => parse_pcapng(buffer),
+            PCAP_MAGIC_USEC | PCAP_MAGIC_NSEC => parse_pcap(buffer, Endian::Little),
+            m if m.swap_bytes() == PCAP_MAGIC_USEC || m.swap_bytes() == PCAP_MAGIC_NSEC => {
+                parse_pcap(buffer, Endian::Big)
+            }
+            _ => Err(PcapError::UnknownFormat),
+        }
+    }
+
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self, PcapError> {
+        Self::parse(&std::fs::read(path)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Endian {
+    Little,
+    Big,
+}
+
+// bounds checked reads in the byte order of the file
+struct Cursor<'a> {
+    buffer: &'a [u8],
+    endian: Endian,
+}
+
+impl Cursor<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], PcapError> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.buffer.get(offset..end))
+            .ok_or(PcapError::Truncated)
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, PcapError> {
+        let b = self.bytes(offset, 2)?.try_into().unwrap();
+        Ok(match self.endian {
+            Endian::Little => u16::from_le_bytes(b),
+            Endian::Big => u16::from_be_bytes(b),
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, PcapError> {
+        let b = self.bytes(offset, 4)?.try_into().unwrap();
+        Ok(match self.endian {
+            Endian::Little => u32::from_le_bytes(b),
+            Endian::Big => u32::from_be_bytes(b),
+        })
+    }
+}
+
+fn parse_pcap(buffer: &[u8], endian: Endian) -> Result<Capture, PcapError> {
+    let cursor = Cursor { buffer, endian };
+    let nsec = cursor.u32(0)? == PCAP_MAGIC_NSEC;
+    let link_type = cursor.u32(20)?;
+
+    let mut packets = Vec::new();
+    let mut offset = PCAP_HEADER_LEN;
+    while offset < buffer.len() {
+        let sec = cursor.u32(offset)? as u64;
+        let frac = cursor.u32(offset + 4)? as u64;
+        let incl_len = cursor.u32(offset + 8)? as usize;
+        let orig_len = cursor.u32(offset + 12)?;
+        let data = cursor.bytes(offset + PCAP_RECORD_LEN, incl_len)?;
+        packets.push(PcapPacket {
+            timestamp: sec * 1_000_000_000 + if nsec { frac } else { frac * 1000 },
+            orig_len,
+            link_type,
+            data: data.to_vec(),
+        });
+        offset += PCAP_RECORD_LEN + incl_len;
+    }
+    Ok(Capture { packets })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    link_type: u32,
+    snaplen: u32,
+    // timestamp units per second
+    ticks: u64,
+}
+
+fn parse_pcapng(buffer: &[u8]) -> Result<Capture, PcapError> {
+    let mut cursor = Cursor {
+        buffer,
+        endian: Endian::Little,
+    };
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut packets = Vec::new();
+
+    let mut offset = 0;
+    while offset < buffer.len() {
+        let block_type = cursor.u32(offset)?;
+        if block_type == BLOCK_SECTION_HEADER {
+            // every section has its own byte order and interfaces
+            let magic = cursor.u32(offset + 8)?;
+            cursor.endian = match magic {
+                BYTE_ORDER_MAGIC => cursor.endian,
+                m if m.swap_bytes() == BYTE_ORDER_MAGIC => match cursor.endian {
+                    Endian::Little => Endian::Big,
+                    Endian::Big => Endian::Little,
+                },
+                _ => return Err(PcapError::InvalidBlock(offset)),
+            };
+            interfaces.clear();
+        }
+
+        let len = cursor.u32(offset + 4)? as usize;
+        if len < 12 || !len.is_multiple_of(4) || cursor.u32(offset + len - 4)? as usize != len {
+            return Err(PcapError::InvalidBlock(offset));
+        }
+        let body = offset + 8;
+        let body_end = offset + len - 4;
+
+        let interface = |id: u32| {
+            interfaces
+                .get(id as usize)
+                .copied()
+                .ok_or(PcapError::UnknownInterface(id))
+        };
+        let packet = |iface: Interface, ts: u64, caplen: usize, orig_len: u32, data: usize| {
+            if data + caplen > body_end {
+                return Err(PcapError::InvalidBlock(offset));
+            }
+            Ok(PcapPacket {
+                timestamp: (ts as u128 * 1_000_000_000 / iface.ticks as u128) as u64,
+                orig_len,
+                link_type: iface.link_type,
+                data: cursor.bytes(data, caplen)?.to_vec(),
+            })
+        };
+
+        match block_type {
+            BLOCK_INTERFACE => {
+                let link_type = cursor.u16(body)? as u32;
+                let snaplen = cursor.u32(body + 4)?;
+                let ticks = interface_ticks(&cursor, body + 8, body_end)?;
+                interfaces.push(Interface {
+                    link_type,
+                    snaplen,
+                    ticks,
+                });
+            }
+            BLOCK_ENHANCED_PACKET => {
+                let iface = interface(cursor.u32(body)?)?;
+                let ts = ((cursor.u32(body + 4)? as u64) << 32) | cursor.u32(body + 8)? as u64;
+                let caplen = cursor.u32(body + 12)? as usize;
+                let orig_len = cursor.u32(body + 16)?;
+                packets.push(packet(iface, ts, caplen, orig_len, body + 20)?);
+            }
+            BLOCK_PACKET => {
+                let iface = interface(cursor.u16(body)? as u32)?;
+                let ts = ((cursor.u32(body + 4)? as u64) << 32) | cursor.u32(body + 8)? as u64;
+                let caplen = cursor.u32(body + 12)? as usize;
+                let orig_len = cursor.u32(body + 16)?;
+                packets.push(packet(iface, ts, caplen, orig_len, body + 20)?);
+            }
+            BLOCK_SIMPLE_PACKET => {
+                // no timestamp and the captured length is implied
+                let iface = interface(0)?;
+                let orig_len = cursor.u32(body)?;
+                let room = (body_end - body)
+                    .checked_sub(4)
+                    .ok_or(PcapError::Truncated)?;
+                let mut caplen = (orig_len as usize).min(room);
+                if iface.snaplen != 0 {
+                    caplen = caplen.min(iface.snaplen as usize);
+                }
+                packets.push(packet(iface, 0, caplen, orig_len, body + 4)?);
+            }
+            // section headers were handled above, the rest is not needed
+            _ => {}
+        }
+        offset += len;
+    }
+    Ok(Capture { packets })
+}
+
+// resolution of the timestamps from the `if_tsresol` option, microseconds
+// when it is missing
+fn interface_ticks(cursor: &Cursor, mut offset: usize, end: usize) -> Result<u64, PcapError> {
+    let mut ticks = 1_000_000;
+    while offset + 4 <= end {
+        let code = cursor.u16(offset)?;
+        let len = cursor.u16(offset + 2)? as usize;
+        if code == OPTION_END {
+            break;
+        }
+        if code == OPTION_IF_TSRESOL && len >= 1 {
+            let resol = cursor.bytes(offset + 4, 1)?[0];
+            let exp = (resol & 0x7f) as u32;
+            ticks = if resol & 0x80 != 0 {
+                2u64.checked_pow(exp)
+            } else {
+                10u64.checked_pow(exp)
+            }
+            .ok_or(PcapError::InvalidBlock(offset))?;
+        }
+        offset += 4 + len.div_ceil(4) * 4;
+    }
+    Ok(ticks)
+}
+
+/// writes a classic pcap file with nanosecond timestamps
+pub struct PcapWriter<W: Write> {
+    output: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut output: W, link_type: u32) -> Result<Self, PcapError> {
+        let mut header = Vec::with_capacity(PCAP_HEADER_LEN);
+        header.extend_from_slice(&PCAP_MAGIC_NSEC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // thiszone and sigfigs
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&DEFAULT_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&link_type.to_le_bytes());
+        output.write_all(&header)?;
+        Ok(Self { output })
+    }
+
+    pub fn write_packet(&mut self, packet: &PcapPacket) -> Result<(), PcapError> {
+        let sec = (packet.timestamp / 1_000_000_000) as u32;
+        let nsec = (packet.timestamp % 1_000_000_000) as u32;
+        let mut record = Vec::with_capacity(PCAP_RECORD_LEN);
+        record.extend_from_slice(&sec.to_le_bytes());
+        record.extend_from_slice(&nsec.to_le_bytes());
+        record.extend_from_slice(&(packet.data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet.orig_len.max(packet.data.len() as u32).to_le_bytes());
+        self.output.write_all(&record)?;
+        self.output.write_all(&packet.data)?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(timestamp: u64, data: &[u8]) -> PcapPacket {
+        PcapPacket {
+            timestamp,
+            orig_len: data.len() as u32,
+            link_type: LINKTYPE_ETHERNET,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_pcap_roundtrip() {
+        let packets = vec![
+            packet(1_500_000_000_123_456_789, &[1, 2, 3]),
+            packet(7, &[4; 60]),
+        ];
+        let mut writer = PcapWriter::new(Vec::new(), LINKTYPE_ETHERNET).unwrap();
+        for p in packets.iter() {
+            writer.write_packet(p).unwrap();
+        }
+        let buffer = writer.into_inner();
+
+        assert_eq!(Capture::parse(&buffer).unwrap().packets, packets);
+        assert!(matches!(
+            Capture::parse(&buffer[..buffer.len() - 1]),
+            Err(PcapError::Truncated)
+        ));
+        assert!(matches!(
+            Capture::parse(&[0; 24]),
+            Err(PcapError::UnknownFormat)
+        ));
+    }
+
+    #[test]
+    fn test_pcap_big_endian_usec() {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&PCAP_MAGIC_USEC.to_be_bytes());
+        buffer.extend_from_slice(&[0, 2, 0, 4]);
+        buffer.extend_from_slice(&[0; 8]);
+        buffer.extend_from_slice(&65535u32.to_be_bytes());
+        buffer.extend_from_slice(&LINKTYPE_ETHERNET.to_be_bytes());
+        for v in [2u32, 5, 2, 100] {
+            buffer.extend_from_slice(&v.to_be_bytes());
+        }
+        buffer.extend_from_slice(&[9, 8]);
+
+        let capture = Capture::parse(&buffer).unwrap();
+        let mut expected = packet(2_000_005_000, &[9, 8]);
+        expected.orig_len = 100;
+        assert_eq!(capture.packets, vec![expected]);
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let len = (12 + body.len().div_ceil(4) * 4) as u32;
+        let mut b = Vec::new();
+        b.extend_from_slice(&block_type.to_le_bytes());
+        b.extend_from_slice(&len.to_le_bytes());
+        b.extend_from_slice(body);
+        b.resize(len as usize - 4, 0);
+        b.extend_from_slice(&len.to_le_bytes());
+        b
+    }
+
+    #[test]
+    fn test_pcapng() {
+        let mut shb = BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        shb.extend_from_slice(&[1, 0, 0, 0]);
+        shb.extend_from_slice(&u64::MAX.to_le_bytes());
+
+        // ethernet with nanosecond timestamps
+        let mut idb = vec![1, 0, 0, 0];
+        idb.extend_from_slice(&4u32.to_le_bytes());
+        idb.extend_from_slice(&[9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut epb = 0u32.to_le_bytes().to_vec();
+        epb.extend_from_slice(&1u32.to_le_bytes());
+        epb.extend_from_slice(&2u32.to_le_bytes());
+        epb.extend_from_slice(&3u32.to_le_bytes());
+        epb.extend_from_slice(&5u32.to_le_bytes());
+        epb.extend_from_slice(&[1, 2, 3]);
+
+        let mut spb = 6u32.to_le_bytes().to_vec();
+        spb.extend_from_slice(&[1, 2, 3, 4, 5, 6, 0, 0]);
+
+        let mut buffer = block(BLOCK_SECTION_HEADER, &shb);
+        buffer.extend(block(BLOCK_INTERFACE, &idb));
+        buffer.extend(block(0x0000_0005, &[0; 8]));
+        buffer.extend(block(BLOCK_ENHANCED_PACKET, &epb));
+        buffer.extend(block(BLOCK_SIMPLE_PACKET, &spb));
+
+        let capture = Capture::parse(&buffer).unwrap();
+        let mut first = packet((1 << 32) | 2, &[1, 2, 3]);
+        first.orig_len = 5;
+        let mut second = packet(0, &[1, 2, 3, 4]);
+        second.orig_len = 6;
+        assert_eq!(capture.packets, vec![first, second]);
+
+        // a packet before any interface description
+        let mut buffer = block(BLOCK_SECTION_HEADER, &shb);
+        buffer.extend(block(BLOCK_ENHANCED_PACKET, &epb));
+        assert!(matches!(
+            Capture::parse(&buffer),
+            Err(PcapError::UnknownInterface(0))
+        ));
+
+        // a simple packet block too short for its length field
+        let mut buffer = block(BLOCK_SECTION_HEADER, &shb);
+        buffer.extend(block(BLOCK_INTERFACE, &idb));
+        buffer.extend(block(BLOCK_SIMPLE_PACKET, &[]));
+        assert!(matches!(Capture::parse(&buffer), Err(PcapError::Truncated)));
+    }
+}
@@ -0,0 +1,195 @@
+use std::{collections::BTreeMap, io::Write};
+
+use crate::{
+    error::VmError,
+    pcap::{Capture, PcapPacket, PcapWriter},
+    runtime::VirtualMachine,
+    xdp::XdpPacket,
+};
+
+/// what the program gets in r1 for each packet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReplayContext {
+    /// the packet bytes, copied to the vm memory, with their length in r2
+    #[default]
+    Packet,
+    /// an `xdp_md` pointing to the packet, see `VirtualMachine::exec_xdp`
+    Xdp,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayOptions {
+    pub jit: bool,
+    pub context: ReplayContext,
+    /// packets whose verdict matches are written to the output, by default
+    /// everything but 0 (drop for a socket filter, XDP_ABORTED for xdp)
+    pub matches: fn(i64) -> bool,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            jit: false,
+            context: ReplayContext::Packet,
+            matches: |verdict| verdict != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub packets: u64,
+    pub bytes: u64,
+    pub matched: u64,
+    /// number of packets per return value of the program
+    pub verdicts: BTreeMap<i64, u64>,
+}
+
+impl VirtualMachine {
+    /// run the program once per packet of `capture` and count the verdicts,
+    /// matching packets are written to `output`, as modified by the program
+    /// in xdp mode
+    pub fn replay<W: Write>(
+        &mut self,
+        capture: &Capture,
+        options: &ReplayOptions,
+        mut output: Option<&mut PcapWriter<W>>,
+    ) -> Result<ReplayStats, VmError> {
+        let mut stats = ReplayStats::default();
+        for packet in capture.packets.iter() {
+            let (verdict, data) = match options.context {
+                ReplayContext::Packet => {
+                    self.set_mem(0, packet.data.len(), &packet.data)?;
+                    let ctx = self.mem_ptr();
+                    let verdict = self.exec_raw(ctx, packet.data.len(), &[], options.jit)?;
+                    (verdict, None)
+                }
+                ReplayContext::Xdp => {
+                    let mut xdp = XdpPacket::new(&packet.data)?;
+                    let verdict = self.exec_xdp(&mut xdp, options.jit)? as i64;
+                    (verdict, Some(xdp.data().to_vec()))
+                }
+            };
+
+            stats.packets += 1;
+            stats.bytes += packet.data.len() as u64;
+            *stats.verdicts.entry(verdict).or_default() += 1;
+            if !(options.matches)(verdict) {
+                continue;
+            }
+            stats.matched += 1;
+            if let Some(output) = output.as_mut() {
+                match data {
+                    Some(data) => output.write_packet(&PcapPacket {
+                        orig_len: data.len() as u32,
+                        data,
+                        ..packet.clone()
+                    })?,
+                    None => output.write_packet(packet)?,
+                }
+            }
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assembler::Instructions;
+
+    use super::*;
+    use crate::{pcap::LINKTYPE_ETHERNET, xdp::XdpAction};
+
+    fn capture() -> Capture {
+        // 3 small packets and one far larger than the initial vm memory
+        let sizes = [30, 64, 100, 9000];
+        let packets = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| {
+                let mut data = vec![0u8; len];
+                data[0] = i as u8;
+                data[len - 1] = 0xee;
+                PcapPacket {
+                    timestamp: i as u64,
+                    orig_len: len as u32,
+                    link_type: LINKTYPE_ETHERNET,
+                    data,
+                }
+            })
+            .collect();
+        Capture { packets }
+    }
+
+    #[test]
+    fn test_replay_filter() {
+        // accept packets whose last byte is 0xee and that are longer than
+        // 60 bytes, with the length as verdict
+        let prog = "mov r0, 0
+jle r2, 60, +5
+mov r3, r1
+add r3, r2
+ldxb r4, [r3-1]
+jne r4, 0xee, +1
+mov r0, r2
+exit";
+        let capture = capture();
+        for jit in [false, true] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            let mut writer = PcapWriter::new(Vec::new(), LINKTYPE_ETHERNET).unwrap();
+            let options = ReplayOptions {
+                jit,
+                ..Default::default()
+            };
+            let stats = runtime
+                .replay(&capture, &options, Some(&mut writer))
+                .unwrap();
+
+            assert_eq!(stats.packets, 4);
+            assert_eq!(stats.bytes, 30 + 64 + 100 + 9000);
+            assert_eq!(stats.matched, 3);
+            assert_eq!(
+                stats.verdicts,
+                BTreeMap::from([(0, 1), (64, 1), (100, 1), (9000, 1)])
+            );
+            let written = Capture::parse(&writer.into_inner()).unwrap();
+            assert_eq!(written.packets, capture.packets[1..]);
+        }
+    }
+
+    #[test]
+    fn test_replay_xdp() {
+        // drop packets starting with 1, strip 14 bytes from the others
+        let prog = "mov r6, r1
+ldxw r2, [r1]
+ldxb r3, [r2]
+mov r0, 1
+jeq r3, 1, +4
+mov r1, r6
+mov r2, 14
+call 44
+mov r0, 2
+exit";
+        let capture = capture();
+        for jit in [false, true] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            let mut writer = PcapWriter::new(Vec::new(), LINKTYPE_ETHERNET).unwrap();
+            let options = ReplayOptions {
+                jit,
+                context: ReplayContext::Xdp,
+                matches: |verdict| XdpAction::from(verdict) == XdpAction::Pass,
+            };
+            let stats = runtime
+                .replay(&capture, &options, Some(&mut writer))
+                .unwrap();
+
+            assert_eq!(stats.matched, 3);
+            assert_eq!(stats.verdicts, BTreeMap::from([(1, 1), (2, 3)]));
+            let written = Capture::parse(&writer.into_inner()).unwrap();
+            let lens: Vec<usize> = written.packets.iter().map(|p| p.data.len()).collect();
+            assert_eq!(lens, vec![30 - 14, 100 - 14, 9000 - 14]);
+        }
+    }
+}
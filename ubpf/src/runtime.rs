@@ -1,520 +1,1741 @@
-use assembler::{Instruction, translate};
-
-use crate::error::VmError;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    sync::Arc,
+};
+
+use assembler::{
+    ExternalCall, Instruction, IsaVersion, JitMemory, JitOptions, TAIL_CALL_OFFSET,
+    class::{EBPF_CLS_LDX, EBPF_CLS_ST, EBPF_CLS_STX},
+    op::{EBPF_PSEUDO_MAP_FD, EBPF_SIZE_B, EBPF_SIZE_H, EBPF_SIZE_W, LDDW},
+    translate,
+};
+
+use crate::{
+    error::{MapError, VmError},
+    helpers::{
+        BPF_FUNC_MAP_DELETE_ELEM, BPF_FUNC_MAP_LOOKUP_ELEM, BPF_FUNC_MAP_UPDATE_ELEM,
+        BPF_FUNC_TAIL_CALL, Helper, HelperProto, MAP_DELETE_ELEM_PROTO, MAP_LOOKUP_ELEM_PROTO,
+        MAP_UPDATE_ELEM_PROTO, TAIL_CALL_PROTO, helper_trampoline, tail_call_trampoline,
+    },
+    maps::{BpfMap, MapType, MapValue, map_delete_elem, map_lookup_elem, map_update_elem},
+    memory::{self, INPUT_VADDR, MAP_VADDR, MAP_VALUE_VADDR, MemoryMap, MemoryRegion, STACK_VADDR},
+    threaded::{self, ThreadedProgram},
+};
 
 #[allow(dead_code)]
 const MB: usize = 1024 * 1024;
 const KB: usize = 1024;
 
-const STACK_SIZE: usize = 4 * KB;
-const MEM_SIZE: usize = 4 * KB;
-const NUM_REGS: usize = 16;
+// defaults of `VmConfig`
+pub(crate) const STACK_SIZE: usize = 4 * KB;
+pub(crate) const MEM_SIZE: usize = 4 * KB;
+pub(crate) const NUM_REGS: usize = 16;
+
+// same limit as the kernel's MAX_TAIL_CALL_CNT
+pub const MAX_TAIL_CALL_CNT: u64 = 33;
+// same limit as the kernel's MAX_CALL_FRAMES, each function called by
+// `lcall` gets a frame of the stack below the one of its caller
+pub const MAX_CALL_FRAMES: usize = 8;
+#[cfg(test)]
+pub(crate) const STACK_FRAME_SIZE: usize = STACK_SIZE / MAX_CALL_FRAMES;
+
+/// sizes a vm runs its programs with, the interpreters, the jited code and
+/// the verifier all assume the same ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmConfig {
+    stack_size: usize,
+    max_call_frames: usize,
+    mem_size: usize,
+    max_ctx_size: usize,
+    guest_addresses: bool,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            stack_size: STACK_SIZE,
+            max_call_frames: MAX_CALL_FRAMES,
+            mem_size: MEM_SIZE,
+            max_ctx_size: usize::MAX,
+            guest_addresses: false,
+        }
+    }
+}
+
+impl VmConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// bytes of stack below r10, shared by the frames of `lcall`
+    pub fn with_stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// frames of the deepest `lcall` chain, the outermost function included
+    pub fn with_max_call_frames(mut self, frames: usize) -> Self {
+        self.max_call_frames = frames;
+        self
+    }
+
+    /// initial size of the vm memory, `set_mem` grows it as needed
+    pub fn with_mem_size(mut self, size: usize) -> Self {
+        self.mem_size = size;
+        self
+    }
+
+    /// largest context a program runs on, the vm memory included
+    pub fn with_max_ctx_size(mut self, size: usize) -> Self {
+        self.max_ctx_size = size;
+        self
+    }
+
+    /// r1 and r10 hold the guest addresses `INPUT_VADDR` and `STACK_VADDR`
+    /// plus the stack size instead of host ones, every access is translated,
+    /// so that runs do not depend on where the host memory is
+    pub fn with_guest_addresses(mut self, enable: bool) -> Self {
+        self.guest_addresses = enable;
+        self
+    }
+
+    pub fn stack_size(&self) -> usize {
+        self.stack_size
+    }
+
+    pub fn max_call_frames(&self) -> usize {
+        self.max_call_frames
+    }
+
+    /// stack of each function called by `lcall`
+    pub fn frame_size(&self) -> usize {
+        self.stack_size / self.max_call_frames
+    }
+
+    pub fn mem_size(&self) -> usize {
+        self.mem_size
+    }
+
+    pub fn max_ctx_size(&self) -> usize {
+        self.max_ctx_size
+    }
 
-type Regs = [i64; NUM_REGS];
-type Stack = [u8; STACK_SIZE];
-type Mem = [u8; MEM_SIZE];
+    pub fn guest_addresses(&self) -> bool {
+        self.guest_addresses
+    }
 
+    /// whether programs run with `other` have the same stack, which a tail
+    /// call between them requires
+    pub(crate) fn same_stack(&self, other: &VmConfig) -> bool {
+        self.stack_size == other.stack_size
+            && self.max_call_frames == other.max_call_frames
+            && self.guest_addresses == other.guest_addresses
+    }
+
+    fn validate(&self) -> Result<(), VmError> {
+        if self.max_call_frames == 0 {
+            return Err(VmError::InvalidConfig("no call frame"));
+        }
+        if self.stack_size == 0 || !self.stack_size.is_multiple_of(8 * self.max_call_frames) {
+            return Err(VmError::InvalidConfig(
+                "the stack is not split in frames of a multiple of 8 bytes",
+            ));
+        }
+        // the jit reserves the stack with an imm32
+        if self.stack_size > i32::MAX as usize {
+            return Err(VmError::InvalidConfig("the stack is too large"));
+        }
+        if self.mem_size > self.max_ctx_size {
+            return Err(VmError::InvalidConfig(
+                "the vm memory is over the context limit",
+            ));
+        }
+        if self.guest_addresses && self.stack_size as u64 > INPUT_VADDR - STACK_VADDR {
+            return Err(VmError::InvalidConfig(
+                "the stack overlaps the guest context",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// what a division or a modulo by zero does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DivByZero {
+    /// the program stops with `VmError::DivZero`
+    #[default]
+    Error,
+    /// as the kernel, the quotient is 0 and the remainder is the dividend
+    Kernel,
+}
+
+impl DivByZero {
+    pub(crate) fn check(self, pc: i64) -> Result<(), VmError> {
+        match self {
+            DivByZero::Error => Err(VmError::DivZero(pc as usize)),
+            DivByZero::Kernel => Ok(()),
+        }
+    }
+}
+
+pub(crate) type Regs = [i64; NUM_REGS];
+
+/// loads a program with its helpers, maps and options into a `Program`, and
+/// runs it over its own memory
 #[derive(Debug, Clone)]
 pub struct VirtualMachine {
     instructions: Vec<Instruction>,
-    pc: i64,
+    config: VmConfig,
     memory_bound_check: bool,
-    regs: Regs,
-    stack: Stack,
-    virtual_mem: Box<Mem>,
-    jit_fn: Option<Vec<u8>>,
+    // loads and stores the verifier proved in bounds, indexed by pc, for a
+    // context of at least `verified_ctx_size` bytes
+    safe_accesses: Vec<bool>,
+    verified_ctx_size: usize,
+    div_by_zero: DivByZero,
+    isa: IsaVersion,
+    threaded: bool,
+    virtual_mem: Vec<u8>,
+    helpers: HashMap<u32, Arc<Helper>>,
+    maps: Vec<Arc<BpfMap>>,
+    jit_fn: Option<Arc<JitMemory>>,
+    // last loaded program, until a helper, a map or an option changes
+    program: Option<Arc<Program>>,
+    execution: Execution,
+}
+
+/// a program with its map fds resolved and the options it was loaded with,
+/// what a prog array holds, executions on several threads can run it at once
+#[derive(Debug)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    helpers: HashMap<u32, Arc<Helper>>,
+    // keeps the maps `instructions` points to alive, the map helpers only
+    // accept these
+    maps: Vec<Arc<BpfMap>>,
+    pub(crate) config: VmConfig,
+    safe_accesses: Vec<bool>,
+    // runs on shorter contexts are refused, the safe accesses assume it
+    verified_ctx_size: usize,
+    pub(crate) memory_bound_check: bool,
+    div_by_zero: DivByZero,
+    isa: IsaVersion,
+    // interpreted over `decoded` instead of the instructions
+    threaded: bool,
+    jit_fn: Option<Arc<JitMemory>>,
+    pub(crate) decoded: ThreadedProgram,
+}
+
+impl Program {
+    /// version of the instruction set the program was loaded for
+    pub fn isa(&self) -> IsaVersion {
+        self.isa
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    /// whether runs go through the jited code
+    pub fn is_jited(&self) -> bool {
+        self.jit_fn.is_some()
+    }
+}
+
+/// where an interpreted run is besides its registers and its stack
+#[derive(Debug, Default)]
+pub(crate) struct InterpreterState {
+    // program a tail call jumped to, if any
+    tail_prog: Option<Arc<Program>>,
+    tail_call_cnt: u64,
+    // return pc, r6-r9 and r10 of the callers of the current function
+    frames: Vec<(i64, [i64; 5])>,
+}
+
+/// registers and stack of the runs of programs, one per thread running them
+#[derive(Debug, Clone)]
+pub struct Execution {
+    pub(crate) pc: i64,
+    pub(crate) regs: Regs,
+    // sized for the last program run
+    pub(crate) stack: Vec<u8>,
+}
+
+impl Default for Execution {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// what a run holds besides its registers and its stack, the one of the
+/// program running on a thread is reachable from its helpers and trampolines
+#[derive(Debug, Default)]
+pub(crate) struct RunState {
+    // the program running, the last one tail called if any
+    prog: Option<Arc<Program>>,
+    // memory the program may access, the map values it looked up included,
+    // and the size of the stack jited code passes, which is not one of them
+    regions: Vec<MemoryRegion>,
+    native_stack: usize,
+    // the map values, kept alive until the run ends, and the addresses the
+    // program accesses them at
+    values: Vec<(Arc<MapValue>, u64)>,
+    // length of the context, the programs tail called may not be verified
+    // for a longer one
+    ctx_len: usize,
+}
+
+impl RunState {
+    pub(crate) fn new(
+        prog: &Arc<Program>,
+        regions: Vec<MemoryRegion>,
+        native_stack: usize,
+        ctx_len: usize,
+    ) -> Self {
+        Self {
+            prog: Some(prog.clone()),
+            regions,
+            native_stack,
+            values: Vec::new(),
+            ctx_len,
+        }
+    }
+
+    pub(crate) fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
+    /// run `f` as the run on this thread, a helper may run another program on
+    /// the same thread
+    pub(crate) fn enter<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let outer = RUN.replace(std::mem::take(self));
+        let res = f();
+        *self = RUN.replace(outer);
+        res
+    }
+}
+
+thread_local! {
+    static RUN: RefCell<RunState> = RefCell::default();
+    // why the jited program running on this thread stopped early, if it did
+    static JIT_FAULT: Cell<Option<VmError>> = const { Cell::new(None) };
+}
+
+/// the map `handle` stands for, if it is one of the maps of the program
+/// running on this thread
+pub(crate) fn run_map(handle: u64) -> Option<Arc<BpfMap>> {
+    RUN.with_borrow(|run| {
+        let prog = run.prog.as_ref()?;
+        if prog.config.guest_addresses {
+            let fd = handle.checked_sub(MAP_VADDR)?;
+            return prog.maps.get(usize::try_from(fd).ok()?).cloned();
+        }
+        prog.maps
+            .iter()
+            .find(|map| Arc::as_ptr(map) as u64 == handle)
+            .cloned()
+    })
+}
+
+/// make `prog` the program running on this thread after a tail call
+pub(crate) fn enter_tail_call(prog: &Arc<Program>) {
+    RUN.with_borrow_mut(|run| run.prog = Some(prog.clone()));
+}
+
+/// `f` over the memory the program running on this thread may access
+pub(crate) fn with_regions<R>(f: impl FnOnce(&[MemoryRegion]) -> R) -> R {
+    RUN.with_borrow(|run| f(&run.regions))
+}
+
+/// the string at `addr` in the memory the program running on this thread
+/// may access, up to its nul, `max` bytes or the end of the region holding it
+pub(crate) fn run_string(addr: u64, max: usize) -> Option<Vec<u8>> {
+    with_regions(|regions| {
+        let (host, len) = memory::host_bytes(addr, max, regions)?;
+        let bytes = unsafe { std::slice::from_raw_parts(host as *const u8, len) };
+        let end = bytes.iter().position(|&c| c == 0).unwrap_or(len);
+        Some(bytes[..end].to_vec())
+    })
+}
+
+/// keep `value` alive and accessible until the run on this thread ends,
+/// returns the address the program accesses it at
+pub(crate) fn hold_map_value(value: Arc<MapValue>) -> u64 {
+    RUN.with_borrow_mut(|run| {
+        if let Some((_, addr)) = run.values.iter().find(|(v, _)| Arc::ptr_eq(v, &value)) {
+            return *addr;
+        }
+        let guest = run.prog.as_ref().is_some_and(|p| p.config.guest_addresses);
+        let region = match run.values.last() {
+            // after the last value, 8 bytes aligned
+            Some((last, addr)) if guest => {
+                let vaddr = (addr + last.len() as u64).next_multiple_of(8);
+                MemoryRegion::guest(vaddr, value.as_ptr(), value.len())
+            }
+            None if guest => MemoryRegion::guest(MAP_VALUE_VADDR, value.as_ptr(), value.len()),
+            _ => MemoryRegion::host(value.as_ptr(), value.len()),
+        };
+        run.regions.push(region);
+        run.values.push((value, region.vaddr));
+        region.vaddr
+    })
+}
+
+/// base register and size of a load or a store
+fn memory_access(ins: &Instruction) -> Option<(u8, usize)> {
+    let size = match ins.op & 0x18 {
+        EBPF_SIZE_B => 1,
+        EBPF_SIZE_H => 2,
+        EBPF_SIZE_W => 4,
+        _ => 8,
+    };
+    match ins.class() {
+        EBPF_CLS_LDX => Some((ins.src_reg(), size)),
+        EBPF_CLS_ST | EBPF_CLS_STX => Some((ins.dst_reg(), size)),
+        _ => None,
+    }
+}
+
+/// called by jited code before the accesses it could not prove in bounds,
+/// returns the host address to access, 0 ends the program
+pub(crate) extern "C" fn bounds_check_trampoline(
+    addr: u64,
+    size: u64,
+    pc: u64,
+    stack_top: u64,
+    flags: u64,
+) -> u64 {
+    let res = RUN.with_borrow(|run| {
+        let regions = &run.regions;
+        // no native stack when the program sees guest addresses, it is one
+        // of the regions
+        let stack_bottom = (stack_top - run.native_stack as u64) as *mut u8;
+        let stack = MemoryRegion::host(stack_bottom, run.native_stack);
+        let (size, write, pc) = (size as usize, flags & 1 != 0, pc as usize);
+        if flags & 2 != 0 {
+            return memory::to_host(addr, size, write, pc, regions);
+        }
+        memory::translate(addr, size, write, pc, &[stack])
+            .or_else(|_| memory::translate(addr, size, write, pc, regions))
+    });
+    res.unwrap_or_else(|e| {
+        JIT_FAULT.set(Some(e));
+        0
+    })
+}
+
+/// `bounds_check_trampoline` for the programs that see guest addresses and
+/// run without the bounds check, every access is translated
+pub(crate) extern "C" fn guest_access_trampoline(
+    addr: u64,
+    size: u64,
+    pc: u64,
+    _stack_top: u64,
+    flags: u64,
+) -> u64 {
+    let res = RUN.with_borrow(|run| {
+        let (size, write, pc) = (size as usize, flags & 1 != 0, pc as usize);
+        memory::to_host(addr, size, write, pc, &run.regions)
+    });
+    res.unwrap_or_else(|e| {
+        JIT_FAULT.set(Some(e));
+        0
+    })
+}
+
+/// r0 and whether the program stops, returned in rax and rdx
+#[repr(C)]
+pub(crate) struct GuestCall {
+    r0: u64,
+    fault: u64,
+}
+
+/// `helper_trampoline` for the jited programs that see guest addresses, `pc`
+/// is the one of the call
+pub(crate) extern "C" fn guest_helper_trampoline(
+    r1: u64,
+    r2: u64,
+    r3: u64,
+    r4: u64,
+    r5: u64,
+    helper: *const Helper,
+    pc: u64,
+) -> GuestCall {
+    let helper = unsafe { &*helper };
+    match helper.call_guest([r1, r2, r3, r4, r5], pc as usize) {
+        Ok(r0) => GuestCall { r0, fault: 0 },
+        Err(e) => {
+            JIT_FAULT.set(Some(e));
+            GuestCall { r0: 0, fault: 1 }
+        }
+    }
+}
+
+/// called by jited code when a divisor is zero, the program exits next
+pub(crate) extern "C" fn div_by_zero_trampoline(pc: u64) {
+    JIT_FAULT.set(Some(VmError::DivZero(pc as usize)));
+}
+
+/// resolve `bpf_tail_call(ctx, map, index)` after `count` tail calls
+pub(crate) fn tail_call_target(map: u64, index: u64, count: u64) -> Result<Arc<Program>, MapError> {
+    let map = run_map(map)
+        .filter(|map| map.def().map_type == MapType::ProgArray)
+        .ok_or(MapError::InvalidArgument)?;
+    if count >= MAX_TAIL_CALL_CNT {
+        return Err(MapError::Full);
+    }
+    let index = u32::try_from(index).map_err(|_| MapError::NotFound)?;
+    let prog = map.program(index).ok_or(MapError::NotFound)?;
+    // it relies on the context it was verified for and on the accesses of
+    // the run being checked or not as its own
+    let fits = RUN.with_borrow(|run| {
+        run.prog.as_ref().is_some_and(|caller| {
+            prog.verified_ctx_size <= run.ctx_len
+                && prog.memory_bound_check == caller.memory_bound_check
+        })
+    });
+    match fits {
+        true => Ok(prog),
+        false => Err(MapError::InvalidArgument),
+    }
+}
+
+/// address jited code jumps to for a tail call into `prog`
+pub(crate) fn tail_call_entry(prog: &Program) -> Result<i64, MapError> {
+    let jit_fn = prog.jit_fn.as_ref().ok_or(MapError::InvalidArgument)?;
+    Ok(jit_fn.as_ptr() as i64 + TAIL_CALL_OFFSET as i64)
 }
 
 impl VirtualMachine {
     pub fn new(instructions: Vec<Instruction>) -> Self {
-        Self {
+        Self::build(instructions, VmConfig::default())
+    }
+
+    /// a vm whose programs run with the stack, call depth and memory sizes
+    /// of `config`
+    pub fn with_config(instructions: Vec<Instruction>, config: VmConfig) -> Result<Self, VmError> {
+        config.validate()?;
+        Ok(Self::build(instructions, config))
+    }
+
+    fn build(instructions: Vec<Instruction>, config: VmConfig) -> Self {
+        let mut vm = Self {
             instructions,
-            pc: 0,
+            config,
             memory_bound_check: true,
-            regs: [0; NUM_REGS],
-            stack: [0; STACK_SIZE],
-            virtual_mem: Box::new([0; MEM_SIZE]),
+            safe_accesses: Vec::new(),
+            verified_ctx_size: 0,
+            div_by_zero: DivByZero::default(),
+            isa: IsaVersion::default(),
+            threaded: false,
+            virtual_mem: vec![0; config.mem_size],
+            helpers: HashMap::new(),
+            maps: Vec::new(),
             jit_fn: None,
+            program: None,
+            execution: Execution::new(),
+        };
+        vm.register_helper(
+            BPF_FUNC_MAP_LOOKUP_ELEM,
+            MAP_LOOKUP_ELEM_PROTO,
+            map_lookup_elem,
+        );
+        vm.register_helper(
+            BPF_FUNC_MAP_UPDATE_ELEM,
+            MAP_UPDATE_ELEM_PROTO,
+            map_update_elem,
+        );
+        vm.register_helper(
+            BPF_FUNC_MAP_DELETE_ELEM,
+            MAP_DELETE_ELEM_PROTO,
+            map_delete_elem,
+        );
+        vm
+    }
+
+    /// make `func` callable through `call id`, replacing any helper already
+    /// registered with the same id, `verify` checks calls against `proto`
+    pub fn register_helper<F>(&mut self, id: u32, proto: HelperProto, func: F)
+    where
+        F: Fn(u64, u64, u64, u64, u64) -> u64 + Send + Sync + 'static,
+    {
+        self.helpers.insert(id, Arc::new(Helper::new(proto, func)));
+        self.discard_proofs();
+    }
+
+    pub fn has_helper(&self, id: u32) -> bool {
+        self.helpers.contains_key(&id)
+    }
+
+    /// prototypes of every helper a program may call, the tail call included
+    pub fn helper_protos(&self) -> HashMap<u32, HelperProto> {
+        let mut protos: HashMap<_, _> = self
+            .helpers
+            .iter()
+            .map(|(&id, helper)| (id, *helper.proto()))
+            .collect();
+        protos.insert(BPF_FUNC_TAIL_CALL, TAIL_CALL_PROTO);
+        protos
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    /// check at runtime that loads and stores stay in the context or the
+    /// stack, except those `verify` proved in bounds, on by default
+    pub fn set_bound_check(&mut self, enable: bool) {
+        self.memory_bound_check = enable;
+        self.discard_compiled();
+    }
+
+    /// whether a division or a modulo by zero stops the program
+    pub fn set_div_by_zero(&mut self, mode: DivByZero) {
+        self.div_by_zero = mode;
+        self.discard_compiled();
+    }
+
+    /// restrict the program to the instructions of `isa`, which fails if it
+    /// already uses newer ones
+    pub fn set_isa(&mut self, isa: IsaVersion) -> Result<(), VmError> {
+        isa.check(&self.instructions)?;
+        self.isa = isa;
+        self.discard_compiled();
+        Ok(())
+    }
+
+    pub fn isa(&self) -> IsaVersion {
+        self.isa
+    }
+
+    pub(crate) fn set_safe_accesses(&mut self, safe_accesses: Vec<bool>, ctx_size: usize) {
+        self.safe_accesses = safe_accesses;
+        self.verified_ctx_size = ctx_size;
+        self.discard_compiled();
+    }
+
+    /// attach `map` to the vm, the returned fd is what `ldmapfd` expects
+    pub fn register_map(&mut self, map: BpfMap) -> u32 {
+        self.register_shared_map(Arc::new(map))
+    }
+
+    /// same as `register_map`, for a map that is also used by other vms
+    pub fn register_shared_map(&mut self, map: Arc<BpfMap>) -> u32 {
+        self.maps.push(map);
+        self.discard_proofs();
+        (self.maps.len() - 1) as u32
+    }
+
+    pub fn map(&self, fd: u32) -> Option<&Arc<BpfMap>> {
+        self.maps.get(fd as usize)
+    }
+
+    pub fn maps(&self) -> &[Arc<BpfMap>] {
+        &self.maps
+    }
+
+    // the jited code and the loaded program depend on the helpers, the maps
+    // and the options
+    fn discard_compiled(&mut self) {
+        self.jit_fn = None;
+        self.program = None;
+    }
+
+    // what the verifier proved holds for the helpers and the maps it saw, the
+    // program has to be verified again
+    fn discard_proofs(&mut self) {
+        self.safe_accesses.clear();
+        self.verified_ctx_size = 0;
+        self.discard_compiled();
+    }
+
+    /// run the interpreter over the program decoded once into threaded code,
+    /// with its jumps resolved and a handler per instruction, instead of
+    /// matching each instruction as it comes
+    pub fn set_threaded(&mut self, enable: bool) {
+        self.threaded = enable;
+        self.program = None;
+    }
+
+    /// the handle of the map `fd`, its guest address when the program sees
+    /// guest addresses
+    fn map_address(&self, fd: i64) -> Result<i64, VmError> {
+        let map = usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.maps.get(fd))
+            .ok_or(VmError::MapNotFound(fd))?;
+        Ok(match self.config.guest_addresses {
+            true => (MAP_VADDR + fd as u64) as i64,
+            false => Arc::as_ptr(map) as i64,
+        })
+    }
+
+    /// copy `size` bytes of `content` to `start` in the vm memory, which
+    /// grows when the copy does not fit, up to the context size limit
+    pub fn set_mem(&mut self, start: usize, size: usize, content: &[u8]) -> Result<(), VmError> {
+        let content = content.get(..size).ok_or(VmError::MemOutOfBound)?;
+        let end = start.checked_add(size).ok_or(VmError::MemOutOfBound)?;
+        if end > self.config.max_ctx_size {
+            return Err(VmError::MemOutOfBound);
+        }
+        if end > self.virtual_mem.len() {
+            self.virtual_mem.resize(end, 0);
+        }
+        self.virtual_mem[start..end].copy_from_slice(content);
+        Ok(())
+    }
+
+    pub fn mem(&self) -> &[u8] {
+        &self.virtual_mem
+    }
+
+    pub(crate) fn mem_ptr(&mut self) -> *mut u8 {
+        self.virtual_mem.as_mut_ptr()
+    }
+
+    pub fn exec(&mut self, jit_enable: bool) -> Result<i64, VmError> {
+        let ctx = self.virtual_mem.as_mut_ptr();
+        self.exec_raw(ctx, self.virtual_mem.len(), &[], jit_enable)
+    }
+
+    /// run the program on `mem` instead of the vm memory, r1 points to it and
+    /// r2 holds its length
+    pub fn exec_on(&mut self, mem: &mut [u8], jit_enable: bool) -> Result<i64, VmError> {
+        self.exec_with_ctx(mem, &mut [], jit_enable)
+    }
+
+    /// instructions with the map fds of `ldmapfd` replaced by the map addresses
+    fn relocate(&self) -> Result<Vec<Instruction>, VmError> {
+        let mut instructions = self.instructions.clone();
+        for ins in instructions.iter_mut() {
+            if ins.op == LDDW && ins.src_reg() == EBPF_PSEUDO_MAP_FD {
+                ins.imm = self.map_address(ins.imm)?;
+                ins.regs &= 0x0f;
+            }
         }
+        Ok(instructions)
+    }
+
+    /// snapshot of the program, its helpers, maps and options, that
+    /// executions run and other programs tail call into once stored in a prog
+    /// array, `jit` must be set for jited callers
+    pub fn load_program(&mut self, jit: bool) -> Result<Arc<Program>, VmError> {
+        if let Some(prog) = &self.program
+            && prog.is_jited() == jit
+        {
+            return Ok(prog.clone());
+        }
+        for (fd, map) in self.maps.iter().enumerate() {
+            map.claim(&self.config)
+                .map_err(|_| VmError::ProgArrayMismatch(fd as u32))?;
+        }
+        if jit && self.jit_fn.is_none() {
+            self.compile()?;
+        }
+        let instructions = self.relocate()?;
+        let decoded = ThreadedProgram::decode(&instructions, &self.helpers, &self.safe_accesses);
+        let prog = Arc::new(Program {
+            instructions,
+            helpers: self.helpers.clone(),
+            maps: self.maps.clone(),
+            config: self.config,
+            safe_accesses: self.safe_accesses.clone(),
+            verified_ctx_size: self.verified_ctx_size,
+            memory_bound_check: self.memory_bound_check,
+            div_by_zero: self.div_by_zero,
+            isa: self.isa,
+            threaded: self.threaded,
+            jit_fn: if jit { self.jit_fn.clone() } else { None },
+            decoded,
+        });
+        self.program = Some(prog.clone());
+        Ok(prog)
+    }
+
+    /// translate the program, the result is cached until a helper or a map
+    /// gets registered
+    pub fn compile(&mut self) -> Result<(), VmError> {
+        // the jited code embeds the map addresses instead of their fds
+        let instructions = self.relocate()?;
+
+        let mut options = JitOptions {
+            tail_call: Some(tail_call_trampoline as *const () as usize),
+            isa: self.isa,
+            stack_size: self.config.stack_size,
+            ..Default::default()
+        };
+        if self.memory_bound_check {
+            options.bounds_check = Some(bounds_check_trampoline as *const () as usize);
+            options.safe_accesses = self.safe_accesses.clone();
+        }
+        let mut helper_fn = helper_trampoline as *const () as usize;
+        if self.config.guest_addresses {
+            // every access is translated
+            options.stack_vaddr = Some(STACK_VADDR);
+            if !self.memory_bound_check {
+                options.bounds_check = Some(guest_access_trampoline as *const () as usize);
+                options.safe_accesses = self.safe_accesses.clone();
+            }
+            helper_fn = guest_helper_trampoline as *const () as usize;
+            options.helper_faults = true;
+        }
+        if self.div_by_zero == DivByZero::Error {
+            options.div_by_zero = Some(div_by_zero_trampoline as *const () as usize);
+        }
+        for (&id, helper) in self.helpers.iter() {
+            let call = ExternalCall {
+                func: helper_fn,
+                context: Arc::as_ptr(helper) as usize,
+            };
+            options.helpers.insert(id, call);
+        }
+
+        let code = translate(&instructions, &options)?;
+        self.jit_fn = Some(Arc::new(JitMemory::new(&code)?));
+        Ok(())
+    }
+
+    pub fn exec_jit(&mut self) -> Result<i64, VmError> {
+        self.exec(true)
+    }
+
+    pub fn exec_interpretor(&mut self) -> Result<i64, VmError> {
+        self.exec(false)
     }
 
-    fn reset(&mut self) {
+    /// same as `exec_on` with `ctx`, the program may also access `regions`,
+    /// whose addresses it gets from the context or from helpers
+    pub fn exec_with_ctx(
+        &mut self,
+        ctx: &mut [u8],
+        regions: &mut [&mut [u8]],
+        jit_enable: bool,
+    ) -> Result<i64, VmError> {
+        let prog = self.load_program(jit_enable)?;
+        self.execution.run_with_regions(&prog, ctx, regions)
+    }
+
+    /// same as `exec_on` with `ctx`, the program may also access the regions
+    /// of `map` at their guest addresses, as their permissions allow, which
+    /// turns the bounds check on
+    pub fn exec_with_map(
+        &mut self,
+        ctx: &mut [u8],
+        map: &mut MemoryMap,
+        jit_enable: bool,
+    ) -> Result<i64, VmError> {
+        if !self.memory_bound_check {
+            self.set_bound_check(true);
+        }
+        let prog = self.load_program(jit_enable)?;
+        self.execution.run_mapped(&prog, ctx, map)
+    }
+
+    pub(crate) fn exec_raw(
+        &mut self,
+        ctx: *mut u8,
+        len: usize,
+        regions: &[MemoryRegion],
+        jit_enable: bool,
+    ) -> Result<i64, VmError> {
+        let prog = self.load_program(jit_enable)?;
+        self.execution.run_raw(&prog, ctx, len, regions)
+    }
+}
+
+impl Execution {
+    pub fn new() -> Self {
+        Self {
+            pc: 0,
+            regs: [0; NUM_REGS],
+            stack: Vec::new(),
+        }
+    }
+
+    fn reset(&mut self, ctx: *mut u8, len: usize, config: &VmConfig) {
         self.pc = 0;
         self.regs = [0; NUM_REGS];
-        self.stack = [0; STACK_SIZE];
+        self.stack.clear();
+        self.stack.resize(config.stack_size, 0);
+
+        // same arguments the jited function gets
+        let (ctx, stack_bottom) = match config.guest_addresses {
+            true => (INPUT_VADDR as i64, STACK_VADDR as i64),
+            false => (ctx as i64, self.stack.as_ptr() as i64),
+        };
+        self.regs[1] = ctx;
+        self.regs[2] = len as i64;
+        self.regs[10] = stack_bottom + config.stack_size as i64;
+    }
 
-        self.regs[1] = self.virtual_mem.as_ptr() as i64;
-        let stack_bottom = &self.stack as *const _ as i64;
-        self.regs[10] = stack_bottom + std::mem::size_of::<Stack>() as i64;
+    /// run `prog` with `ctx` in r1 and its length in r2, through its jited
+    /// code if it was loaded with it, `ctx` may not be shorter than the
+    /// context `prog` was verified for
+    pub fn run(&mut self, prog: &Arc<Program>, ctx: &mut [u8]) -> Result<i64, VmError> {
+        self.run_with_regions(prog, ctx, &mut [])
     }
 
-    pub fn set_mem(&mut self, start: usize, size: usize, content: &[u8]) -> Result<(), VmError> {
-        if start >= self.virtual_mem.len() || start + size > self.virtual_mem.len() {
-            return Err(VmError::MemOutOfBound);
+    /// same as `run`, the program may also access `regions`
+    pub fn run_with_regions(
+        &mut self,
+        prog: &Arc<Program>,
+        ctx: &mut [u8],
+        regions: &mut [&mut [u8]],
+    ) -> Result<i64, VmError> {
+        let regions: Vec<_> = regions
+            .iter_mut()
+            .map(|r| MemoryRegion::host(r.as_mut_ptr(), r.len()))
+            .collect();
+        self.run_raw(prog, ctx.as_mut_ptr(), ctx.len(), &regions)
+    }
+
+    /// same as `run`, the program may also access the regions of `map` at
+    /// their guest addresses, `prog` must be loaded with the bounds check and
+    /// the regions end below `MAP_VADDR` when it sees guest addresses
+    pub fn run_mapped(
+        &mut self,
+        prog: &Arc<Program>,
+        ctx: &mut [u8],
+        map: &mut MemoryMap,
+    ) -> Result<i64, VmError> {
+        if !prog.memory_bound_check {
+            return Err(VmError::InvalidConfig(
+                "a memory map needs the bounds check",
+            ));
         }
+        // the maps and the map values are above
+        if prog.config.guest_addresses
+            && let Some(r) = map.regions().iter().find(|r| r.vaddr + r.len > MAP_VADDR)
+        {
+            return Err(VmError::InvalidRegion(r.vaddr));
+        }
+        self.run_raw(prog, ctx.as_mut_ptr(), ctx.len(), map.regions())
+    }
+
+    pub(crate) fn run_raw(
+        &mut self,
+        prog: &Arc<Program>,
+        ctx: *mut u8,
+        len: usize,
+        regions: &[MemoryRegion],
+    ) -> Result<i64, VmError> {
+        self.start(prog, ctx, len)?;
+        let config = &prog.config;
+        // what the accesses are checked or translated against and the helpers
+        // read strings from, the jited code passes its own stack unless the
+        // program sees guest addresses
+        let mut all = self.memory(prog, ctx, len, regions);
+        let mut native_stack = config.stack_size;
+        if config.guest_addresses {
+            native_stack = 0;
+        } else if prog.jit_fn.is_some() {
+            all.remove(0);
+        }
+        let mut run = RunState::new(prog, all, native_stack, len);
+        match &prog.jit_fn {
+            Some(jit_fn) => {
+                let ctx = self.regs[1] as *mut u8;
+                run.enter(|| Self::run_jit(jit_fn, ctx, len))
+            }
+            None if prog.threaded => {
+                run.enter(|| threaded::run(prog, &mut self.regs, prog.div_by_zero))
+            }
+            None => run.enter(|| self.run_interpreter(prog)),
+        }
+    }
 
-        let dst = self.virtual_mem.as_mut_ptr();
-        unsafe {
-            let (raw, _size): (*const u8, usize) = std::mem::transmute(content);
-            std::ptr::copy(raw, dst, size);
-            Ok(())
+    /// check the context against the limits and get ready to run `prog` on it
+    pub(crate) fn start(
+        &mut self,
+        prog: &Program,
+        ctx: *mut u8,
+        len: usize,
+    ) -> Result<(), VmError> {
+        let config = &prog.config;
+        if len > config.max_ctx_size {
+            return Err(VmError::CtxTooLarge {
+                len,
+                max: config.max_ctx_size,
+            });
         }
+        if len < prog.verified_ctx_size {
+            return Err(VmError::CtxTooSmall {
+                len,
+                min: prog.verified_ctx_size,
+            });
+        }
+        self.reset(ctx, len, config);
+        Ok(())
     }
 
-    pub fn exec(&mut self, jit_enable: bool) -> Result<i64, VmError> {
-        if jit_enable {
-            self.exec_jit()
+    /// the stack, the context and `regions`, at the addresses `prog` sees them
+    pub(crate) fn memory(
+        &mut self,
+        prog: &Program,
+        ctx: *mut u8,
+        len: usize,
+        regions: &[MemoryRegion],
+    ) -> Vec<MemoryRegion> {
+        let config = &prog.config;
+        let stack = self.stack.as_mut_ptr();
+        let mut all = Vec::with_capacity(regions.len() + 2);
+        if config.guest_addresses {
+            all.push(MemoryRegion::guest(STACK_VADDR, stack, config.stack_size));
+            all.push(MemoryRegion::guest(INPUT_VADDR, ctx, len));
         } else {
-            self.exec_interpretor()
+            all.push(MemoryRegion::host(stack, config.stack_size));
+            all.push(MemoryRegion::host(ctx, len));
         }
+        all.extend_from_slice(regions);
+        all
     }
 
-    pub fn exec_jit(&mut self) -> Result<i64, VmError> {
-        let jited_instructions = translate(&self.instructions);
-        self.jit_fn = Some(jited_instructions);
+    fn run_jit(jit_fn: &JitMemory, ctx: *mut u8, len: usize) -> Result<i64, VmError> {
+        let f: extern "C" fn(*mut u8, usize) -> i64 =
+            unsafe { std::mem::transmute(jit_fn.as_ptr()) };
+        let res = f(ctx, len);
+        match JIT_FAULT.take() {
+            Some(e) => Err(e),
+            None => Ok(res),
+        }
+    }
 
-        todo!()
+    fn run_interpreter(&mut self, prog: &Program) -> Result<i64, VmError> {
+        let mut state = InterpreterState::default();
+        loop {
+            if let Some(r0) = self.step_interpreter(prog, &mut state)? {
+                return Ok(r0);
+            }
+        }
     }
 
-    pub fn exec_interpretor(&mut self) -> Result<i64, VmError> {
+    /// run the instruction at pc, r0 once the program exited, the run must
+    /// be the one of this thread
+    pub(crate) fn step_interpreter(
+        &mut self,
+        prog: &Program,
+        state: &mut InterpreterState,
+    ) -> Result<Option<i64>, VmError> {
         use assembler::op::*;
-        self.reset();
 
         let reg = &mut self.regs;
+        let guest = prog.config.guest_addresses;
+        let InterpreterState {
+            tail_prog,
+            tail_call_cnt,
+            frames,
+        } = state;
+
+        let (instructions, helpers, safe_accesses) = match tail_prog {
+            Some(prog) => (&prog.instructions, &prog.helpers, &prog.safe_accesses),
+            None => (&prog.instructions, &prog.helpers, &prog.safe_accesses),
+        };
+
+        let cur_pc = self.pc;
+        let ins = instructions[cur_pc as usize];
+        self.pc += 1;
+
+        // host address of a checked or translated load or store
+        let mut host = None;
+        if (prog.memory_bound_check || guest)
+            && let Some((base, size)) = memory_access(&ins)
+        {
+            let addr = reg[base as usize].wrapping_add(ins.offset as i64) as u64;
+            let write = ins.class() != EBPF_CLS_LDX;
+            let safe = safe_accesses.get(cur_pc as usize).is_some_and(|&s| s);
+            let pc = cur_pc as usize;
+            host = with_regions(|regions| {
+                if prog.memory_bound_check && !safe {
+                    memory::translate(addr, size, write, pc, regions).map(Some)
+                } else if guest {
+                    memory::to_host(addr, size, write, pc, regions).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })?
+            .map(|host| host as i64);
+        }
 
-        loop {
-            let cur_pc = self.pc;
-            dbg!(cur_pc);
-            let ins = &self.instructions[cur_pc as usize];
-            self.pc += 1;
-
-            match ins.op {
-                // the only instruction that advance pc
-                LDDW => {
-                    let new_ins = self.instructions[self.pc as usize];
-                    self.pc += 1;
-                    let imm_high = new_ins.imm << 32;
-                    reg[ins.dst_reg() as usize] = ins.imm | imm_high;
-                }
-                ADD_IMM => {
-                    reg[ins.dst_reg() as usize] += ins.imm;
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                ADD_REG => {
-                    reg[ins.dst_reg() as usize] += reg[ins.src_reg() as usize];
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                SUB_IMM => {
-                    reg[ins.dst_reg() as usize] -= ins.imm;
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                SUB_REG => {
-                    reg[ins.dst_reg() as usize] -= reg[ins.src_reg() as usize];
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                MUL_IMM => {
-                    reg[ins.dst_reg() as usize] *= ins.imm;
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                MUL_REG => {
-                    reg[ins.dst_reg() as usize] *= reg[ins.src_reg() as usize];
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                DIV_IMM => {
-                    dbg!(reg[ins.dst_reg() as usize], ins.imm);
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
+        match ins.op {
+            // the only instruction that advance pc
+            LDDW => {
+                let new_ins = instructions[self.pc as usize];
+                self.pc += 1;
+                let imm_high = new_ins.imm << 32;
+                reg[ins.dst_reg() as usize] = ins.imm | imm_high;
+            }
+            ADD_IMM => {
+                reg[ins.dst_reg() as usize] = reg[ins.dst_reg() as usize].wrapping_add(ins.imm);
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            ADD_REG => {
+                reg[ins.dst_reg() as usize] =
+                    reg[ins.dst_reg() as usize].wrapping_add(reg[ins.src_reg() as usize]);
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            SUB_IMM => {
+                reg[ins.dst_reg() as usize] = reg[ins.dst_reg() as usize].wrapping_sub(ins.imm);
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            SUB_REG => {
+                reg[ins.dst_reg() as usize] =
+                    reg[ins.dst_reg() as usize].wrapping_sub(reg[ins.src_reg() as usize]);
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            MUL_IMM => {
+                reg[ins.dst_reg() as usize] = reg[ins.dst_reg() as usize].wrapping_mul(ins.imm);
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            MUL_REG => {
+                reg[ins.dst_reg() as usize] =
+                    reg[ins.dst_reg() as usize].wrapping_mul(reg[ins.src_reg() as usize]);
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            DIV_IMM | DIV_REG | MOD_IMM | MOD_REG if ins.offset == EBPF_OFF_SIGNED => {
+                let a = reg[ins.dst_reg() as usize] as i32;
+                let b = if ins.op & EBPF_SRC_REG != 0 {
+                    reg[ins.src_reg() as usize] as i32
+                } else {
+                    ins.imm as i32
+                };
+                let r = if b == 0 {
+                    prog.div_by_zero.check(cur_pc)?;
+                    if ins.op & ALU_OP_MASK == EBPF_DIV {
+                        0
+                    } else {
+                        a
+                    }
+                } else if ins.op & ALU_OP_MASK == EBPF_DIV {
+                    a.wrapping_div(b)
+                } else {
+                    a.wrapping_rem(b)
+                };
+                reg[ins.dst_reg() as usize] = r as u32 as i64;
+            }
+            DIV_IMM => {
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+                if ins.imm & U32_MASK == 0 {
+                    prog.div_by_zero.check(cur_pc)?;
+                    reg[ins.dst_reg() as usize] = 0;
+                } else {
                     reg[ins.dst_reg() as usize] /= ins.imm & U32_MASK;
-                    // reg[ins.dst_reg() as usize] &= U32_MASK;
                 }
-                DIV_REG => {
-                    if reg[ins.src_reg() as usize] == 0 {
-                        return Err(VmError::DivZero);
-                    }
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
+                // reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            DIV_REG => {
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+                if reg[ins.src_reg() as usize] & U32_MASK == 0 {
+                    prog.div_by_zero.check(cur_pc)?;
+                    reg[ins.dst_reg() as usize] = 0;
+                } else {
                     reg[ins.dst_reg() as usize] /= reg[ins.src_reg() as usize] & U32_MASK;
                 }
-                OR_IMM => {
-                    reg[ins.dst_reg() as usize] |= ins.imm;
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                OR_REG => {
-                    reg[ins.dst_reg() as usize] |= reg[ins.src_reg() as usize];
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                AND_IMM => {
-                    reg[ins.dst_reg() as usize] &= ins.imm;
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                AND_REG => {
-                    reg[ins.dst_reg() as usize] &= reg[ins.src_reg() as usize];
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                LSH_IMM => {
-                    let old = reg[ins.dst_reg() as usize] & U32_MASK;
-                    reg[ins.dst_reg() as usize] = old << ins.imm;
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                LSH_REG => {
-                    let old = reg[ins.dst_reg() as usize] & U32_MASK;
-                    reg[ins.dst_reg() as usize] = old << reg[ins.src_reg() as usize];
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                RSH_IMM => {
-                    let old = reg[ins.dst_reg() as usize] & U32_MASK;
-                    reg[ins.dst_reg() as usize] = old >> ins.imm;
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                RSH_REG => {
-                    let old = reg[ins.dst_reg() as usize] & U32_MASK;
-                    reg[ins.dst_reg() as usize] = old >> reg[ins.src_reg() as usize];
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                NEG32 => {
-                    reg[ins.dst_reg() as usize] = -reg[ins.dst_reg() as usize];
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                MOD_IMM => {
-                    let a = reg[ins.dst_reg() as usize] & U32_MASK;
-                    let b = ins.imm & U32_MASK;
-                    let r = a % b;
-                    reg[ins.dst_reg() as usize] = r & U32_MASK;
-                }
-                MOD_REG => {
-                    let a = reg[ins.dst_reg() as usize] & U32_MASK;
-                    let b = reg[ins.src_reg() as usize] & U32_MASK;
-                    let r = a % b;
-                    reg[ins.dst_reg() as usize] = r & U32_MASK;
-                }
-                XOR_IMM => {
-                    reg[ins.dst_reg() as usize] ^= ins.imm;
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                XOR_REG => {
-                    reg[ins.dst_reg() as usize] ^= reg[ins.src_reg() as usize];
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                MOV_IMM => {
-                    reg[ins.dst_reg() as usize] = ins.imm;
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                MOV_REG => {
-                    reg[ins.dst_reg() as usize] = reg[ins.src_reg() as usize];
-                    reg[ins.dst_reg() as usize] &= U32_MASK;
-                }
-                ARSH_IMM => {
-                    let a = (reg[ins.dst_reg() as usize] & U32_MASK) >> ins.imm;
-                    reg[ins.dst_reg() as usize] = a & U32_MASK;
-                }
-                ARSH_REG => {
-                    let a = (reg[ins.dst_reg() as usize] & U32_MASK) >> ins.src_reg();
-                    reg[ins.dst_reg() as usize] = a & U32_MASK;
-                }
-                // TODO: LE and BE
-                LE => {
-                    todo!()
-                }
-                BE => {
-                    todo!()
-                }
-                ADD64_IMM => {
-                    reg[ins.dst_reg() as usize] += ins.imm;
-                }
-                ADD64_REG => {
-                    reg[ins.dst_reg() as usize] += reg[ins.src_reg() as usize];
-                }
-                SUB64_IMM => {
-                    reg[ins.dst_reg() as usize] -= ins.imm;
-                }
-                SUB64_REG => {
-                    reg[ins.dst_reg() as usize] -= reg[ins.src_reg() as usize];
-                }
-                MUL64_IMM => {
-                    reg[ins.dst_reg() as usize] *= ins.imm;
-                }
-                MUL64_REG => {
-                    reg[ins.dst_reg() as usize] *= reg[ins.src_reg() as usize];
-                }
-                DIV64_IMM => {
-                    reg[ins.dst_reg() as usize] /= ins.imm;
-                }
-                DIV64_REG => {
-                    if reg[ins.src_reg() as usize] == 0 {
-                        return Err(VmError::DivZero);
+            }
+            OR_IMM => {
+                reg[ins.dst_reg() as usize] |= ins.imm;
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            OR_REG => {
+                reg[ins.dst_reg() as usize] |= reg[ins.src_reg() as usize];
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            AND_IMM => {
+                reg[ins.dst_reg() as usize] &= ins.imm;
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            AND_REG => {
+                reg[ins.dst_reg() as usize] &= reg[ins.src_reg() as usize];
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            LSH_IMM => {
+                let old = reg[ins.dst_reg() as usize] & U32_MASK;
+                reg[ins.dst_reg() as usize] = old << (ins.imm & 31);
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            LSH_REG => {
+                let old = reg[ins.dst_reg() as usize] & U32_MASK;
+                reg[ins.dst_reg() as usize] = old << (reg[ins.src_reg() as usize] & 31);
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            RSH_IMM => {
+                let old = reg[ins.dst_reg() as usize] & U32_MASK;
+                reg[ins.dst_reg() as usize] = old >> (ins.imm & 31);
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            RSH_REG => {
+                let old = reg[ins.dst_reg() as usize] & U32_MASK;
+                reg[ins.dst_reg() as usize] = old >> (reg[ins.src_reg() as usize] & 31);
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            NEG32 => {
+                reg[ins.dst_reg() as usize] = reg[ins.dst_reg() as usize].wrapping_neg();
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            MOD_IMM => {
+                let a = reg[ins.dst_reg() as usize] & U32_MASK;
+                let b = ins.imm & U32_MASK;
+                if b == 0 {
+                    prog.div_by_zero.check(cur_pc)?;
+                }
+                let r = a.checked_rem(b).unwrap_or(a);
+                reg[ins.dst_reg() as usize] = r & U32_MASK;
+            }
+            MOD_REG => {
+                let a = reg[ins.dst_reg() as usize] & U32_MASK;
+                let b = reg[ins.src_reg() as usize] & U32_MASK;
+                if b == 0 {
+                    prog.div_by_zero.check(cur_pc)?;
+                }
+                let r = a.checked_rem(b).unwrap_or(a);
+                reg[ins.dst_reg() as usize] = r & U32_MASK;
+            }
+            XOR_IMM => {
+                reg[ins.dst_reg() as usize] ^= ins.imm;
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            XOR_REG => {
+                reg[ins.dst_reg() as usize] ^= reg[ins.src_reg() as usize];
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            MOV_IMM => {
+                reg[ins.dst_reg() as usize] = ins.imm;
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            MOV_REG if ins.offset != 0 => {
+                let src = reg[ins.src_reg() as usize];
+                let r = if ins.offset == 8 {
+                    src as i8 as i32
+                } else {
+                    src as i16 as i32
+                };
+                reg[ins.dst_reg() as usize] = r as u32 as i64;
+            }
+            MOV_REG => {
+                reg[ins.dst_reg() as usize] = reg[ins.src_reg() as usize];
+                reg[ins.dst_reg() as usize] &= U32_MASK;
+            }
+            ARSH_IMM => {
+                let a = (reg[ins.dst_reg() as usize] as i32) >> (ins.imm & 31);
+                reg[ins.dst_reg() as usize] = a as i64 & U32_MASK;
+            }
+            ARSH_REG => {
+                let a = (reg[ins.dst_reg() as usize] as i32) >> (reg[ins.src_reg() as usize] & 31);
+                reg[ins.dst_reg() as usize] = a as i64 & U32_MASK;
+            }
+            // the host is little endian
+            LE => {
+                reg[ins.dst_reg() as usize] = match ins.imm {
+                    16 => reg[ins.dst_reg() as usize] as u16 as i64,
+                    32 => reg[ins.dst_reg() as usize] as u32 as i64,
+                    _ => reg[ins.dst_reg() as usize],
+                };
+            }
+            BE | BSWAP => {
+                reg[ins.dst_reg() as usize] = match ins.imm {
+                    16 => (reg[ins.dst_reg() as usize] as u16).swap_bytes() as i64,
+                    32 => (reg[ins.dst_reg() as usize] as u32).swap_bytes() as i64,
+                    _ => reg[ins.dst_reg() as usize].swap_bytes(),
+                };
+            }
+            ADD64_IMM => {
+                reg[ins.dst_reg() as usize] = reg[ins.dst_reg() as usize].wrapping_add(ins.imm);
+            }
+            ADD64_REG => {
+                reg[ins.dst_reg() as usize] =
+                    reg[ins.dst_reg() as usize].wrapping_add(reg[ins.src_reg() as usize]);
+            }
+            SUB64_IMM => {
+                reg[ins.dst_reg() as usize] = reg[ins.dst_reg() as usize].wrapping_sub(ins.imm);
+            }
+            SUB64_REG => {
+                reg[ins.dst_reg() as usize] =
+                    reg[ins.dst_reg() as usize].wrapping_sub(reg[ins.src_reg() as usize]);
+            }
+            MUL64_IMM => {
+                reg[ins.dst_reg() as usize] = reg[ins.dst_reg() as usize].wrapping_mul(ins.imm);
+            }
+            MUL64_REG => {
+                reg[ins.dst_reg() as usize] =
+                    reg[ins.dst_reg() as usize].wrapping_mul(reg[ins.src_reg() as usize]);
+            }
+            DIV64_IMM | DIV64_REG | MOD64_IMM | MOD64_REG if ins.offset == EBPF_OFF_SIGNED => {
+                let a = reg[ins.dst_reg() as usize];
+                let b = if ins.op & EBPF_SRC_REG != 0 {
+                    reg[ins.src_reg() as usize]
+                } else {
+                    ins.imm
+                };
+                reg[ins.dst_reg() as usize] = if b == 0 {
+                    prog.div_by_zero.check(cur_pc)?;
+                    if ins.op & ALU_OP_MASK == EBPF_DIV {
+                        0
+                    } else {
+                        a
                     }
-                    reg[ins.dst_reg() as usize] /= reg[ins.src_reg() as usize];
-                }
-                OR64_IMM => {
-                    reg[ins.dst_reg() as usize] |= ins.imm;
-                }
-                OR64_REG => {
-                    reg[ins.dst_reg() as usize] |= reg[ins.src_reg() as usize];
-                }
-                AND64_IMM => {
-                    reg[ins.dst_reg() as usize] &= ins.imm;
-                }
-                AND64_REG => {
-                    reg[ins.dst_reg() as usize] &= reg[ins.src_reg() as usize];
+                } else if ins.op & ALU_OP_MASK == EBPF_DIV {
+                    a.wrapping_div(b)
+                } else {
+                    a.wrapping_rem(b)
+                };
+            }
+            DIV64_IMM => {
+                if ins.imm == 0 {
+                    prog.div_by_zero.check(cur_pc)?;
+                    reg[ins.dst_reg() as usize] = 0;
+                } else {
+                    reg[ins.dst_reg() as usize] =
+                        (reg[ins.dst_reg() as usize] as u64 / ins.imm as u64) as i64;
                 }
-                LSH64_IMM => {
-                    reg[ins.dst_reg() as usize] <<= ins.imm;
+            }
+            DIV64_REG => {
+                if reg[ins.src_reg() as usize] == 0 {
+                    prog.div_by_zero.check(cur_pc)?;
+                    reg[ins.dst_reg() as usize] = 0;
+                } else {
+                    reg[ins.dst_reg() as usize] = (reg[ins.dst_reg() as usize] as u64
+                        / reg[ins.src_reg() as usize] as u64)
+                        as i64;
                 }
-                LSH64_REG => {
-                    reg[ins.dst_reg() as usize] <<= reg[ins.src_reg() as usize];
+            }
+            OR64_IMM => {
+                reg[ins.dst_reg() as usize] |= ins.imm;
+            }
+            OR64_REG => {
+                reg[ins.dst_reg() as usize] |= reg[ins.src_reg() as usize];
+            }
+            AND64_IMM => {
+                reg[ins.dst_reg() as usize] &= ins.imm;
+            }
+            AND64_REG => {
+                reg[ins.dst_reg() as usize] &= reg[ins.src_reg() as usize];
+            }
+            LSH64_IMM => {
+                reg[ins.dst_reg() as usize] =
+                    reg[ins.dst_reg() as usize].wrapping_shl(ins.imm as u32);
+            }
+            LSH64_REG => {
+                reg[ins.dst_reg() as usize] =
+                    reg[ins.dst_reg() as usize].wrapping_shl(reg[ins.src_reg() as usize] as u32);
+            }
+            RSH64_IMM => {
+                reg[ins.dst_reg() as usize] =
+                    (reg[ins.dst_reg() as usize] as u64).wrapping_shr(ins.imm as u32) as i64;
+            }
+            RSH64_REG => {
+                reg[ins.dst_reg() as usize] = (reg[ins.dst_reg() as usize] as u64)
+                    .wrapping_shr(reg[ins.src_reg() as usize] as u32)
+                    as i64;
+            }
+            NEG64 => {
+                reg[ins.dst_reg() as usize] = reg[ins.dst_reg() as usize].wrapping_neg();
+            }
+            MOD64_IMM => {
+                if ins.imm == 0 {
+                    prog.div_by_zero.check(cur_pc)?;
+                } else {
+                    reg[ins.dst_reg() as usize] =
+                        (reg[ins.dst_reg() as usize] as u64 % ins.imm as u64) as i64;
                 }
-                RSH64_IMM => {
-                    reg[ins.dst_reg() as usize] >>= ins.imm;
+            }
+            MOD64_REG => {
+                if reg[ins.src_reg() as usize] == 0 {
+                    prog.div_by_zero.check(cur_pc)?;
+                } else {
+                    reg[ins.dst_reg() as usize] = (reg[ins.dst_reg() as usize] as u64
+                        % reg[ins.src_reg() as usize] as u64)
+                        as i64;
                 }
-                RSH64_REG => {
-                    reg[ins.dst_reg() as usize] >>= reg[ins.src_reg() as usize];
+            }
+            XOR64_IMM => {
+                reg[ins.dst_reg() as usize] ^= ins.imm;
+            }
+            XOR64_REG => {
+                reg[ins.dst_reg() as usize] ^= reg[ins.src_reg() as usize];
+            }
+            MOV64_IMM => {
+                reg[ins.dst_reg() as usize] = ins.imm;
+            }
+            MOV64_REG if ins.offset != 0 => {
+                let src = reg[ins.src_reg() as usize];
+                reg[ins.dst_reg() as usize] = match ins.offset {
+                    8 => src as i8 as i64,
+                    16 => src as i16 as i64,
+                    _ => src as i32 as i64,
+                };
+            }
+            MOV64_REG => {
+                reg[ins.dst_reg() as usize] = reg[ins.src_reg() as usize];
+            }
+            ARSH64_IMM => {
+                let old = reg[ins.dst_reg() as usize];
+                reg[ins.dst_reg() as usize] = old.wrapping_shr(ins.imm as u32);
+            }
+            ARSH64_REG => {
+                let old = reg[ins.dst_reg() as usize];
+                reg[ins.dst_reg() as usize] = old.wrapping_shr(reg[ins.src_reg() as usize] as u32);
+            }
+            // load/store operations
+            LDXW => {
+                // println!("ldxw");
+                let addr = host.unwrap_or(reg[ins.src_reg() as usize] + ins.offset as i64);
+                // dbg!(unsafe { *(addr as *const i32) as i64 } & 0xffffffff);
+                reg[ins.dst_reg() as usize] =
+                    unsafe { (addr as *const u32).read_unaligned() as i64 };
+            }
+            LDXH => {
+                // println!("ldxh");
+                let addr = host.unwrap_or(reg[ins.src_reg() as usize] + ins.offset as i64);
+                // dbg!(unsafe { *(addr as *const i16) as i64 } & 0xffff);
+                reg[ins.dst_reg() as usize] =
+                    unsafe { (addr as *const u16).read_unaligned() as i64 };
+            }
+            LDXB => {
+                // println!("ldxb");
+                let addr = host.unwrap_or(reg[ins.src_reg() as usize] + ins.offset as i64);
+                // dbg!(unsafe { *(addr as *const i8) as i64 } & 0xff);
+                reg[ins.dst_reg() as usize] =
+                    unsafe { (addr as *const u8).read_unaligned() as i64 };
+            }
+            LDXDW => {
+                // println!("ldxdw");
+                let addr = host.unwrap_or(reg[ins.src_reg() as usize] + ins.offset as i64);
+                // dbg!(unsafe { *(addr as *const i64) as i64 });
+                reg[ins.dst_reg() as usize] = unsafe { (addr as *const i64).read_unaligned() };
+            }
+            LDXSW => {
+                let addr = host.unwrap_or(reg[ins.src_reg() as usize] + ins.offset as i64);
+                reg[ins.dst_reg() as usize] =
+                    unsafe { (addr as *const i32).read_unaligned() as i64 };
+            }
+            LDXSH => {
+                let addr = host.unwrap_or(reg[ins.src_reg() as usize] + ins.offset as i64);
+                reg[ins.dst_reg() as usize] =
+                    unsafe { (addr as *const i16).read_unaligned() as i64 };
+            }
+            LDXSB => {
+                let addr = host.unwrap_or(reg[ins.src_reg() as usize] + ins.offset as i64);
+                reg[ins.dst_reg() as usize] =
+                    unsafe { (addr as *const i8).read_unaligned() as i64 };
+            }
+            STW => {
+                let addr = host.unwrap_or(reg[ins.dst_reg() as usize] + ins.offset as i64);
+                unsafe { (addr as *mut i32).write_unaligned(ins.imm as i32) };
+            }
+            STH => {
+                let addr = host.unwrap_or(reg[ins.dst_reg() as usize] + ins.offset as i64);
+                unsafe { (addr as *mut i16).write_unaligned(ins.imm as i16) };
+            }
+            STB => {
+                let addr = host.unwrap_or(reg[ins.dst_reg() as usize] + ins.offset as i64);
+                unsafe { (addr as *mut i8).write_unaligned(ins.imm as i8) };
+            }
+            STDW => {
+                let addr = host.unwrap_or(reg[ins.dst_reg() as usize] + ins.offset as i64);
+                unsafe { (addr as *mut i64).write_unaligned(ins.imm) };
+            }
+            STXW => {
+                let addr = host.unwrap_or(reg[ins.dst_reg() as usize] + ins.offset as i64);
+                unsafe { (addr as *mut i32).write_unaligned(reg[ins.src_reg() as usize] as i32) };
+            }
+            STXH => {
+                let addr = host.unwrap_or(reg[ins.dst_reg() as usize] + ins.offset as i64);
+                unsafe { (addr as *mut i16).write_unaligned(reg[ins.src_reg() as usize] as i16) };
+            }
+            STXB => {
+                let addr = host.unwrap_or(reg[ins.dst_reg() as usize] + ins.offset as i64);
+                unsafe { (addr as *mut i8).write_unaligned(reg[ins.src_reg() as usize] as i8) };
+            }
+            STXDW => {
+                let addr = host.unwrap_or(reg[ins.dst_reg() as usize] + ins.offset as i64);
+                unsafe { (addr as *mut i64).write_unaligned(reg[ins.src_reg() as usize]) };
+            }
+            JA => {
+                self.pc += ins.offset as i64;
+            }
+            JA32 => {
+                self.pc += ins.imm;
+            }
+            JEQ_IMM => {
+                if reg[ins.dst_reg() as usize] as u64 == ins.imm as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                NEG64 => {
-                    reg[ins.dst_reg() as usize] = -reg[ins.dst_reg() as usize];
+            }
+            JEQ_REG => {
+                if reg[ins.dst_reg() as usize] as u64 == reg[ins.src_reg() as usize] as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                MOD64_IMM => {
-                    reg[ins.dst_reg() as usize] %= ins.imm;
+            }
+            JGT_IMM => {
+                if reg[ins.dst_reg() as usize] as u64 > ins.imm as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                MOD64_REG => {
-                    reg[ins.dst_reg() as usize] %= reg[ins.src_reg() as usize];
+            }
+            JGT_REG => {
+                if reg[ins.dst_reg() as usize] as u64 > reg[ins.src_reg() as usize] as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                XOR64_IMM => {
-                    reg[ins.dst_reg() as usize] ^= ins.imm;
+            }
+            JGE_IMM => {
+                if reg[ins.dst_reg() as usize] as u64 >= ins.imm as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                XOR64_REG => {
-                    reg[ins.dst_reg() as usize] ^= reg[ins.src_reg() as usize];
+            }
+            JGE_REG => {
+                if reg[ins.dst_reg() as usize] as u64 >= reg[ins.src_reg() as usize] as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                MOV64_IMM => {
-                    reg[ins.dst_reg() as usize] = ins.imm;
+            }
+            JSET_IMM => {
+                if reg[ins.dst_reg() as usize] as u64 & ins.imm as u64 != 0 {
+                    self.pc += ins.offset as i64;
                 }
-                MOV64_REG => {
-                    reg[ins.dst_reg() as usize] = reg[ins.src_reg() as usize];
+            }
+            JSET_REG => {
+                if reg[ins.dst_reg() as usize] as u64 & reg[ins.src_reg() as usize] as u64 != 0 {
+                    self.pc += ins.offset as i64;
                 }
-                ARSH64_IMM => {
-                    let old = reg[ins.dst_reg() as usize];
-                    reg[ins.dst_reg() as usize] = old >> ins.imm;
+            }
+            JNE_IMM => {
+                if reg[ins.dst_reg() as usize] as u64 != ins.imm as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                ARSH64_REG => {
-                    let old = reg[ins.dst_reg() as usize];
-                    reg[ins.dst_reg() as usize] = old >> reg[ins.src_reg() as usize];
+            }
+            JNE_REG => {
+                if reg[ins.dst_reg() as usize] as u64 != reg[ins.src_reg() as usize] as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                // load/store operations
-                LDXW => {
-                    // println!("ldxw");
-                    let addr = reg[ins.src_reg() as usize] + ins.offset as i64;
-                    // dbg!(unsafe { *(addr as *const i32) as i64 } & 0xffffffff);
-                    reg[ins.dst_reg() as usize] = unsafe { *(addr as *const i32) as i64 };
+            }
+            JSGT_IMM => {
+                if reg[ins.dst_reg() as usize] > ins.imm {
+                    self.pc += ins.offset as i64;
                 }
-                LDXH => {
-                    // println!("ldxh");
-                    let addr = reg[ins.src_reg() as usize] + ins.offset as i64;
-                    // dbg!(unsafe { *(addr as *const i16) as i64 } & 0xffff);
-                    reg[ins.dst_reg() as usize] = unsafe { *(addr as *const i16) as i64 };
+            }
+            JSGT_REG => {
+                if reg[ins.dst_reg() as usize] > reg[ins.src_reg() as usize] {
+                    self.pc += ins.offset as i64;
                 }
-                LDXB => {
-                    // println!("ldxb");
-                    let addr = reg[ins.src_reg() as usize] + ins.offset as i64;
-                    // dbg!(unsafe { *(addr as *const i8) as i64 } & 0xff);
-                    reg[ins.dst_reg() as usize] = unsafe { *(addr as *const i8) as i64 };
+            }
+            JSGE_IMM => {
+                if reg[ins.dst_reg() as usize] >= ins.imm {
+                    self.pc += ins.offset as i64;
                 }
-                LDXDW => {
-                    // println!("ldxdw");
-                    let addr = reg[ins.src_reg() as usize] + ins.offset as i64;
-                    // dbg!(unsafe { *(addr as *const i64) as i64 });
-                    reg[ins.dst_reg() as usize] = unsafe { *(addr as *const i64) };
+            }
+            JSGE_REG => {
+                if reg[ins.dst_reg() as usize] >= reg[ins.src_reg() as usize] {
+                    self.pc += ins.offset as i64;
                 }
-                STW => {
-                    let addr = reg[ins.dst_reg() as usize] + ins.offset as i64;
-                    unsafe { *(addr as *mut i32) = ins.imm as i32 };
+            }
+            JLT_IMM => {
+                if (reg[ins.dst_reg() as usize] as u64) < ins.imm as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                STH => {
-                    let addr = reg[ins.dst_reg() as usize] + ins.offset as i64;
-                    unsafe { *(addr as *mut i16) = ins.imm as i16 };
+            }
+            JLT_REG => {
+                if (reg[ins.dst_reg() as usize] as u64) < reg[ins.src_reg() as usize] as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                STB => {
-                    let addr = reg[ins.dst_reg() as usize] + ins.offset as i64;
-                    unsafe { *(addr as *mut i8) = ins.imm as i8 };
+            }
+            JLE_IMM => {
+                if (reg[ins.dst_reg() as usize] as u64) <= ins.imm as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                STDW => {
-                    let addr = reg[ins.dst_reg() as usize] + ins.offset as i64;
-                    unsafe { *(addr as *mut i64) = ins.imm };
+            }
+            JLE_REG => {
+                if (reg[ins.dst_reg() as usize] as u64) <= reg[ins.src_reg() as usize] as u64 {
+                    self.pc += ins.offset as i64;
                 }
-                STXW => {
-                    let addr = reg[ins.dst_reg() as usize] + ins.offset as i64;
-                    unsafe { *(addr as *mut i32) = reg[ins.src_reg() as usize] as i32 };
+            }
+            JSLT_IMM => {
+                if (reg[ins.dst_reg() as usize]) < ins.imm {
+                    self.pc += ins.offset as i64;
                 }
-                STXH => {
-                    let addr = reg[ins.dst_reg() as usize] + ins.offset as i64;
-                    unsafe { *(addr as *mut i16) = reg[ins.src_reg() as usize] as i16 };
+            }
+            JSLT_REG => {
+                if (reg[ins.dst_reg() as usize]) < reg[ins.src_reg() as usize] {
+                    self.pc += ins.offset as i64;
                 }
-                STXB => {
-                    let addr = reg[ins.dst_reg() as usize] + ins.offset as i64;
-                    unsafe { *(addr as *mut i8) = reg[ins.src_reg() as usize] as i8 };
+            }
+            JSLE_IMM => {
+                if (reg[ins.dst_reg() as usize]) <= ins.imm {
+                    self.pc += ins.offset as i64;
                 }
-                STXDW => {
-                    let addr = reg[ins.dst_reg() as usize] + ins.offset as i64;
-                    unsafe { *(addr as *mut i64) = reg[ins.src_reg() as usize] };
+            }
+            JSLE_REG => {
+                if (reg[ins.dst_reg() as usize]) <= reg[ins.src_reg() as usize] {
+                    self.pc += ins.offset as i64;
                 }
-                JA => {
+            }
+            JEQ32_IMM => {
+                if reg[ins.dst_reg() as usize] as u32 == ins.imm as u32 {
                     self.pc += ins.offset as i64;
                 }
-                JEQ_IMM => {
-                    if reg[ins.dst_reg() as usize] == ins.imm {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JEQ32_REG => {
+                if reg[ins.dst_reg() as usize] as u32 == reg[ins.src_reg() as usize] as u32 {
+                    self.pc += ins.offset as i64;
                 }
-                JEQ_REG => {
-                    if reg[ins.dst_reg() as usize] == reg[ins.src_reg() as usize] {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JGT32_IMM => {
+                if reg[ins.dst_reg() as usize] as u32 > ins.imm as u32 {
+                    self.pc += ins.offset as i64;
                 }
-                JGT_IMM => {
-                    if reg[ins.dst_reg() as usize] as u64 > (ins.imm & U32_MASK) as u64 {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JGT32_REG => {
+                if reg[ins.dst_reg() as usize] as u32 > reg[ins.src_reg() as usize] as u32 {
+                    self.pc += ins.offset as i64;
                 }
-                JGT_REG => {
-                    if reg[ins.dst_reg() as usize] as u64
-                        > (reg[ins.src_reg() as usize] & U32_MASK) as u64
-                    {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JGE32_IMM => {
+                if reg[ins.dst_reg() as usize] as u32 >= ins.imm as u32 {
+                    self.pc += ins.offset as i64;
                 }
-                JGE_IMM => {
-                    if reg[ins.dst_reg() as usize] as u64 >= (ins.imm & U32_MASK) as u64 {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JGE32_REG => {
+                if reg[ins.dst_reg() as usize] as u32 >= reg[ins.src_reg() as usize] as u32 {
+                    self.pc += ins.offset as i64;
                 }
-                JGE_REG => {
-                    if reg[ins.dst_reg() as usize] as u64
-                        >= (reg[ins.src_reg() as usize] & U32_MASK) as u64
-                    {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JSET32_IMM => {
+                if reg[ins.dst_reg() as usize] as u32 & ins.imm as u32 != 0 {
+                    self.pc += ins.offset as i64;
                 }
-                JSET_REG => {
-                    let a = sign_extend(reg[ins.dst_reg() as usize]);
-                    let b = sign_extend(reg[ins.src_reg() as usize]);
-                    if a & b != 0 {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JSET32_REG => {
+                if reg[ins.dst_reg() as usize] as u32 & reg[ins.src_reg() as usize] as u32 != 0 {
+                    self.pc += ins.offset as i64;
                 }
-                JSET_IMM => {
-                    let a = sign_extend(reg[ins.dst_reg() as usize]);
-                    let b = sign_extend(ins.imm);
-                    if a & b != 0 {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JNE32_IMM => {
+                if reg[ins.dst_reg() as usize] as u32 != ins.imm as u32 {
+                    self.pc += ins.offset as i64;
                 }
-                JSGT_IMM => {
-                    let a = sign_extend(reg[ins.dst_reg() as usize]);
-                    let b = sign_extend(ins.imm);
-                    if a > b {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JNE32_REG => {
+                if reg[ins.dst_reg() as usize] as u32 != reg[ins.src_reg() as usize] as u32 {
+                    self.pc += ins.offset as i64;
                 }
-                JSGT_REG => {
-                    let a = sign_extend(reg[ins.dst_reg() as usize]);
-                    let b = sign_extend(reg[ins.src_reg() as usize]);
-                    if a > b {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JSGT32_IMM => {
+                if reg[ins.dst_reg() as usize] as i32 > ins.imm as i32 {
+                    self.pc += ins.offset as i64;
                 }
-                JSGE_IMM => {
-                    let a = sign_extend(reg[ins.dst_reg() as usize]);
-                    let b = sign_extend(ins.imm);
-                    if a >= b {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JSGT32_REG => {
+                if reg[ins.dst_reg() as usize] as i32 > reg[ins.src_reg() as usize] as i32 {
+                    self.pc += ins.offset as i64;
                 }
-                JSGE_REG => {
-                    dbg!(reg[ins.dst_reg() as usize], reg[ins.src_reg() as usize]);
-                    let a = sign_extend(reg[ins.dst_reg() as usize]);
-                    let b = sign_extend(reg[ins.src_reg() as usize]);
-                    if a >= b {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JSGE32_IMM => {
+                if reg[ins.dst_reg() as usize] as i32 >= ins.imm as i32 {
+                    self.pc += ins.offset as i64;
                 }
-                JNE_IMM => {
-                    if reg[ins.dst_reg() as usize] != ins.imm {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JSGE32_REG => {
+                if reg[ins.dst_reg() as usize] as i32 >= reg[ins.src_reg() as usize] as i32 {
+                    self.pc += ins.offset as i64;
                 }
-                JNE_REG => {
-                    if reg[ins.dst_reg() as usize] != reg[ins.src_reg() as usize] {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JLT32_IMM => {
+                if (reg[ins.dst_reg() as usize] as u32) < ins.imm as u32 {
+                    self.pc += ins.offset as i64;
                 }
-                JLT_IMM => {
-                    if (reg[ins.dst_reg() as usize] as u64) < (ins.imm & U32_MASK) as u64 {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JLT32_REG => {
+                if (reg[ins.dst_reg() as usize] as u32) < reg[ins.src_reg() as usize] as u32 {
+                    self.pc += ins.offset as i64;
                 }
-                JLT_REG => {
-                    if (reg[ins.dst_reg() as usize] as u64)
-                        < (reg[ins.src_reg() as usize] & U32_MASK) as u64
-                    {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JLE32_IMM => {
+                if (reg[ins.dst_reg() as usize] as u32) <= ins.imm as u32 {
+                    self.pc += ins.offset as i64;
                 }
-                JLE_IMM => {
-                    if (reg[ins.dst_reg() as usize] as u64) <= (ins.imm & U32_MASK) as u64 {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JLE32_REG => {
+                if (reg[ins.dst_reg() as usize] as u32) <= reg[ins.src_reg() as usize] as u32 {
+                    self.pc += ins.offset as i64;
                 }
-                JLE_REG => {
-                    if (reg[ins.dst_reg() as usize] as u64)
-                        <= (reg[ins.src_reg() as usize] & U32_MASK) as u64
-                    {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JSLT32_IMM => {
+                if (reg[ins.dst_reg() as usize] as i32) < ins.imm as i32 {
+                    self.pc += ins.offset as i64;
                 }
-                JSLT_IMM => {
-                    let a = sign_extend(reg[ins.dst_reg() as usize]);
-                    let b = sign_extend(ins.imm);
-                    dbg!(a, b);
-                    if a < b {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JSLT32_REG => {
+                if (reg[ins.dst_reg() as usize] as i32) < reg[ins.src_reg() as usize] as i32 {
+                    self.pc += ins.offset as i64;
                 }
-                JSLT_REG => {
-                    let a = sign_extend(reg[ins.dst_reg() as usize]);
-                    let b = sign_extend(reg[ins.src_reg() as usize]);
-                    dbg!(a, b);
-                    if a < b {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JSLE32_IMM => {
+                if (reg[ins.dst_reg() as usize] as i32) <= ins.imm as i32 {
+                    self.pc += ins.offset as i64;
                 }
-                JSLE_IMM => {
-                    let a = sign_extend(reg[ins.dst_reg() as usize]);
-                    let b = sign_extend(ins.imm);
-                    if a <= b {
-                        self.pc += ins.offset as i64;
-                    }
+            }
+            JSLE32_REG => {
+                if (reg[ins.dst_reg() as usize] as i32) <= reg[ins.src_reg() as usize] as i32 {
+                    self.pc += ins.offset as i64;
                 }
-                JSLE_REG => {
-                    let a = sign_extend(reg[ins.dst_reg() as usize]);
-                    let b = sign_extend(reg[ins.src_reg() as usize]);
-                    if a <= b {
-                        self.pc += ins.offset as i64;
+            }
+            CALL if ins.src_reg() == EBPF_PSEUDO_CALL => {
+                if frames.len() + 1 >= prog.config.max_call_frames {
+                    return Err(VmError::CallStackOverflow(cur_pc as usize));
+                }
+                let saved = [reg[6], reg[7], reg[8], reg[9], reg[10]];
+                frames.push((self.pc, saved));
+                reg[10] -= prog.config.frame_size() as i64;
+                self.pc += ins.imm;
+            }
+            CALL if ins.imm == BPF_FUNC_TAIL_CALL as i64 => {
+                match tail_call_target(reg[2] as u64, reg[3] as u64, *tail_call_cnt) {
+                    Ok(prog) => {
+                        // r1 and the stack are handed over as they are,
+                        // the new program never returns to the callers
+                        *tail_call_cnt += 1;
+                        enter_tail_call(&prog);
+                        *tail_prog = Some(prog);
+                        self.pc = 0;
+                        if let Some((_, saved)) = frames.first() {
+                            reg[10] = saved[4];
+                        }
+                        frames.clear();
                     }
-                }
-                // TODO: call functions(maybe jit)
-                CALL => {
-                    todo!("not implemented")
-                }
-                EXIT => return Ok(self.regs[0]),
-                _ => {
-                    dbg!(ins);
-                    // virtual machine show abort here
-                    unreachable!()
+                    Err(e) => reg[0] = -e.errno(),
                 }
             }
+            CALL => {
+                let helper = helpers
+                    .get(&(ins.imm as u32))
+                    .ok_or(VmError::UnknownHelper(ins.imm))?;
+                let args = [reg[1], reg[2], reg[3], reg[4], reg[5]].map(|r| r as u64);
+                reg[0] = if guest {
+                    helper.call_guest(args, cur_pc as usize)?
+                } else {
+                    let [r1, r2, r3, r4, r5] = args;
+                    helper.call(r1, r2, r3, r4, r5)
+                } as i64;
+            }
+            EXIT => match frames.pop() {
+                Some((pc, saved)) => {
+                    reg[6..=10].copy_from_slice(&saved);
+                    self.pc = pc;
+                }
+                None => return Ok(Some(self.regs[0])),
+            },
+            op => {
+                return Err(VmError::UnknownOpcode {
+                    pc: cur_pc as usize,
+                    op,
+                });
+            }
         }
-    }
-
-    #[inline(always)]
-    pub fn bound_check(&self) {
-        if !self.memory_bound_check {
-        }
+        Ok(None)
     }
 }
 
@@ -527,42 +1748,21 @@ pub fn sign_extend(origin: i64) -> i64 {
     origin & 0x00000000ffffffff
 }
 
-/*
- *
-#define BOUNDS_CHECK_LOAD(size) \
-    do { \
-        if (!bounds_check(vm, (void *)reg[inst.src] + inst.offset, size, "load", cur_pc, mem, mem_len, stack)) { \
-            return UINT64_MAX; \
-        } \
-    } while (0)
-
-static bool
-bounds_check(const struct ubpf_vm *vm, void *addr, int size, const char *type, uint16_t cur_pc, void *mem, size_t mem_len, void *stack)
-{
-    if (!vm->bounds_check_enabled)
-        return true;
-    if (mem && (addr >= mem && (addr + size) <= (mem + mem_len))) {
-        /* Context access */
-        return true;
-    } else if (addr >= stack && (addr + size) <= (stack + STACK_SIZE)) {
-        /* Stack access */
-        return true;
-    } else {
-        fprintf(stderr, "uBPF error: out of bounds memory %s at PC %u, addr %p, size %d\n", type, cur_pc, addr, size);
-        fprintf(stderr, "mem %p/%zd stack %p/%d\n", mem, mem_len, stack, STACK_SIZE);
-        return false;
-    }
-}
-
- */
-
 // pub fn compile()
 
 #[cfg(test)]
 mod tests {
 
+    use assembler::{Instructions, JitError};
+
     use super::*;
-    use crate::utils::test_utils;
+    use crate::{
+        helpers::RetType,
+        maps::{BPF_ANY, MapDef, MapType},
+        memory::Perm,
+        utils::test_utils,
+        verifier::VerifierOptions,
+    };
 
     #[test]
     fn test_sign_extend() {
@@ -780,6 +1980,38 @@ mod tests {
         println!("{:?},{:?}\n\n-------", r, res);
     }
 
+    #[test]
+    fn test_isa_v4() {
+        let names = [
+            "sdiv32_imm",
+            "sdiv32_reg",
+            "sdiv64_imm",
+            "sdiv64_reg",
+            "smod32_imm",
+            "smod32_reg",
+            "smod64_imm",
+            "smod64_reg",
+            "movsx32_reg",
+            "movsx64_reg",
+            "ldxsb",
+            "ldxsh",
+            "ldxsw",
+            "bswap16",
+            "bswap32",
+            "bswap64",
+            "gotol",
+        ];
+        let mem: [u8; 8] = [0xaa, 0xbb, 0x11, 0x22, 0x33, 0x44, 0xcc, 0xdd];
+        for name in names {
+            for jit in [false, true] {
+                let (instructions, res) = test_utils::load_data(name);
+                let mut runtime = VirtualMachine::new(instructions.into());
+                runtime.set_mem(0, mem.len(), mem.as_slice()).unwrap();
+                assert_eq!(runtime.exec(jit).unwrap(), res, "{name}, jit: {jit}");
+            }
+        }
+    }
+
     #[test]
     fn test_jmp() {
         let (instructions, res) = test_utils::load_data("ja");
@@ -901,4 +2133,910 @@ mod tests {
         let r = runtime.exec(false);
         println!("{:?},{:?}\n\n-------", r, res);
     }
+
+    #[test]
+    fn test_lru_hash_helpers() {
+        // update keys 1, 2, 3 in that order, then look up key 3
+        let prog = "stdw [r10-16], 100
+mov r6, 1
+stxw [r10-4], r6
+ldmapfd r1, 0
+mov r2, r10
+add r2, -4
+mov r3, r10
+add r3, -16
+mov r4, 0
+call 2
+add r6, 1
+jle r6, 3, -11
+ldmapfd r1, 0
+mov r2, r10
+add r2, -4
+call 1
+jeq r0, 0, +2
+ldxdw r0, [r0]
+exit
+mov r0, 0
+exit";
+        for jit in [false, true] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            let map = BpfMap::new(MapDef::new(MapType::LruHash, 4, 8, 2)).unwrap();
+            let fd = runtime.register_map(map);
+            assert_eq!(runtime.exec(jit).unwrap(), 100);
+
+            let map = runtime.map(fd).unwrap();
+            assert_eq!(map.lookup(&1u32.to_ne_bytes()), None);
+            assert_eq!(
+                map.lookup(&2u32.to_ne_bytes()),
+                Some(100u64.to_ne_bytes().to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_lpm_trie_helpers() {
+        // look up 10.1.2.3/32
+        let prog = "stw [r10-8], 32
+stw [r10-4], 0x0302010a
+ldmapfd r1, 0
+mov r2, r10
+add r2, -8
+call 1
+mov r1, r0
+mov r0, 0
+jeq r1, 0, +1
+ldxb r0, [r1]
+exit";
+        let mut key = 8u32.to_ne_bytes().to_vec();
+        key.extend_from_slice(&[10, 0, 0, 0]);
+        let mut longer_key = 24u32.to_ne_bytes().to_vec();
+        longer_key.extend_from_slice(&[10, 1, 2, 0]);
+
+        for jit in [false, true] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            let map = BpfMap::new(MapDef::new(MapType::LpmTrie, 8, 1, 16)).unwrap();
+            map.update(&key, &[8], BPF_ANY).unwrap();
+            runtime.register_map(map);
+            assert_eq!(runtime.exec(jit).unwrap(), 8);
+
+            runtime
+                .map(0)
+                .unwrap()
+                .update(&longer_key, &[24], BPF_ANY)
+                .unwrap();
+            assert_eq!(runtime.exec(jit).unwrap(), 24);
+        }
+    }
+
+    #[test]
+    fn test_deleted_map_value() {
+        // the value looked up stays valid after its entry is deleted and
+        // another one takes its place
+        let prog = "stw [r10-4], 0
+stdw [r10-16], 5
+ldmapfd r1, 0
+mov r2, r10
+add r2, -4
+call 1
+jeq r0, 0, +16
+mov r6, r0
+ldmapfd r1, 0
+mov r2, r10
+add r2, -4
+call 3
+ldmapfd r1, 0
+mov r2, r10
+add r2, -4
+mov r3, r10
+add r3, -16
+mov r4, 0
+call 2
+ldxdw r0, [r6]
+exit
+mov r0, 0
+exit";
+        for jit in [false, true] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            let map = BpfMap::new(MapDef::new(MapType::Hash, 4, 8, 1)).unwrap();
+            map.update(&0u32.to_ne_bytes(), &1u64.to_ne_bytes(), BPF_ANY)
+                .unwrap();
+            runtime.register_map(map);
+            runtime.verify(&VerifierOptions::default()).unwrap();
+            assert_eq!(runtime.exec(jit).unwrap(), 1);
+            assert_eq!(
+                runtime.map(0).unwrap().lookup(&0u32.to_ne_bytes()),
+                Some(5u64.to_ne_bytes().to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_foreign_map_handle() {
+        // maps of another vm, whose addresses are read from ctx, are not
+        // accepted by the map helpers nor by the tail call
+        let prog = "mov r6, r1
+stw [r10-4], 0
+ldxdw r1, [r6]
+mov r2, r10
+add r2, -4
+call 1
+mov r7, r0
+ldxdw r1, [r6]
+mov r2, r10
+add r2, -4
+call 3
+add r7, r0
+mov r1, r6
+ldxdw r2, [r6+8]
+mov r3, 0
+call 12
+add r0, r7
+exit";
+        for (jit, threaded) in [(false, false), (false, true), (true, false)] {
+            let mut other =
+                VirtualMachine::new(Instructions::from_asm("mov r0, 1\nexit").unwrap().into());
+            let hash = BpfMap::new(MapDef::new(MapType::Hash, 4, 8, 1)).unwrap();
+            hash.update(&0u32.to_ne_bytes(), &[1; 8], BPF_ANY).unwrap();
+            other.register_map(hash);
+            other.register_map(BpfMap::new(MapDef::new(MapType::ProgArray, 4, 4, 1)).unwrap());
+            let target = other.load_program(jit).unwrap();
+            other.map(1).unwrap().set_program(0, target).unwrap();
+
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.set_threaded(threaded);
+            let mut ctx = [0u8; 16];
+            ctx[..8].copy_from_slice(&(Arc::as_ptr(other.map(0).unwrap()) as u64).to_ne_bytes());
+            ctx[8..].copy_from_slice(&(Arc::as_ptr(other.map(1).unwrap()) as u64).to_ne_bytes());
+            let einval = -MapError::InvalidArgument.errno();
+            assert_eq!(runtime.exec_on(&mut ctx, jit).unwrap(), 2 * einval);
+            assert!(other.map(0).unwrap().lookup(&0u32.to_ne_bytes()).is_some());
+            other.map(1).unwrap().delete(&0u32.to_ne_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_unknown_map_and_helper() {
+        let instructions = Instructions::from_asm("ldmapfd r1, 3\nexit").unwrap();
+        let mut runtime = VirtualMachine::new(instructions.into());
+        assert!(matches!(runtime.exec(false), Err(VmError::MapNotFound(3))));
+        assert!(matches!(runtime.exec(true), Err(VmError::MapNotFound(3))));
+
+        let instructions = Instructions::from_asm("call 1000\nexit").unwrap();
+        let mut runtime = VirtualMachine::new(instructions.into());
+        assert!(matches!(
+            runtime.exec(false),
+            Err(VmError::UnknownHelper(1000))
+        ));
+        assert!(matches!(
+            runtime.exec(true),
+            Err(VmError::Jit(JitError::UnknownHelper(1000)))
+        ));
+
+        let mut instructions: Vec<Instruction> =
+            Instructions::from_asm("mov r0, 0\nexit").unwrap().into();
+        instructions[0].op = 0xff;
+        let mut runtime = VirtualMachine::new(instructions);
+        assert!(matches!(
+            runtime.exec(false),
+            Err(VmError::UnknownOpcode { pc: 0, op: 0xff })
+        ));
+    }
+
+    #[test]
+    fn test_tail_call_limit() {
+        // counts the runs in ctx and tail calls itself until the limit
+        let prog = "ldxdw r6, [r1]
+add r6, 1
+stxdw [r1], r6
+ldmapfd r2, 0
+mov r3, 0
+call 12
+mov r0, r6
+exit";
+        for jit in [false, true] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.register_map(BpfMap::new(MapDef::new(MapType::ProgArray, 4, 4, 1)).unwrap());
+            let prog = runtime.load_program(jit).unwrap();
+            runtime.map(0).unwrap().set_program(0, prog).unwrap();
+            runtime.set_mem(0, 8, &[0; 8]).unwrap();
+
+            assert_eq!(runtime.exec(jit).unwrap(), MAX_TAIL_CALL_CNT as i64 + 1);
+            // break the cycle between the program and its prog array
+            runtime.map(0).unwrap().delete(&0u32.to_ne_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_tail_call_chain() {
+        let prog = "mov r6, r1
+ldmapfd r2, 0
+ldxb r3, [r6]
+call 12
+exit";
+        for jit in [false, true] {
+            let progs = Arc::new(BpfMap::new(MapDef::new(MapType::ProgArray, 4, 4, 4)).unwrap());
+
+            let target = Instructions::from_asm("ldxb r0, [r1+1]\nexit").unwrap();
+            let mut target = VirtualMachine::new(target.into());
+            progs
+                .set_program(1, target.load_program(jit).unwrap())
+                .unwrap();
+
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.register_shared_map(progs);
+
+            // r1 is handed over to the target
+            runtime.set_mem(0, 2, &[1, 42]).unwrap();
+            assert_eq!(runtime.exec(jit).unwrap(), 42);
+
+            // empty slot and out of range index fall through
+            runtime.set_mem(0, 1, &[0]).unwrap();
+            assert_eq!(runtime.exec(jit).unwrap(), -MapError::NotFound.errno());
+            runtime.set_mem(0, 1, &[7]).unwrap();
+            assert_eq!(runtime.exec(jit).unwrap(), -MapError::NotFound.errno());
+        }
+    }
+
+    #[test]
+    fn test_tail_call_stack_layout() {
+        let vm = |prog: &str, config| {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            VirtualMachine::with_config(instructions.into(), config).unwrap()
+        };
+        let prog = "ldmapfd r2, 0\nmov r3, 0\ncall 12\nexit";
+        let larger = VmConfig::new().with_stack_size(2 * STACK_SIZE);
+        for jit in [false, true] {
+            let progs = Arc::new(BpfMap::new(MapDef::new(MapType::ProgArray, 4, 4, 2)).unwrap());
+            let mut caller = vm(prog, VmConfig::new());
+            caller.register_shared_map(progs.clone());
+            caller.load_program(jit).unwrap();
+
+            // its frame would not fit in the one of the caller
+            let mut target = vm("mov r0, 1\nexit", larger);
+            let target = target.load_program(jit).unwrap();
+            assert_eq!(progs.set_program(0, target), Err(MapError::InvalidArgument));
+            let mut other = vm(prog, larger);
+            other.register_shared_map(progs.clone());
+            assert!(matches!(
+                other.load_program(jit),
+                Err(VmError::ProgArrayMismatch(0))
+            ));
+
+            // only the stack has to match
+            let mut target = vm("mov r0, 1\nexit", VmConfig::new().with_mem_size(64));
+            let target = target.load_program(jit).unwrap();
+            progs.set_program(0, target).unwrap();
+            assert_eq!(caller.exec(jit).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn test_tail_call_proofs() {
+        let vm = |prog: &str, threaded| {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut vm = VirtualMachine::new(instructions.into());
+            vm.set_threaded(threaded);
+            vm
+        };
+        let prog = "ldmapfd r2, 0\nmov r3, 0\ncall 12\nexit";
+        let einval = -MapError::InvalidArgument.errno();
+        for (jit, threaded) in [(false, false), (false, true), (true, false)] {
+            let progs = Arc::new(BpfMap::new(MapDef::new(MapType::ProgArray, 4, 4, 1)).unwrap());
+            let mut caller = vm(prog, threaded);
+            caller.register_shared_map(progs.clone());
+            let mut ctx = [0u8; 8];
+
+            // the store is only proven for a context of 4096 bytes
+            let target = |ctx_size, bound_check| {
+                let mut target = vm("stb [r1+4000], 1\nmov r0, 1\nexit", threaded);
+                target.set_bound_check(bound_check);
+                let options = VerifierOptions {
+                    ctx_size,
+                    ..Default::default()
+                };
+                target.verify(&options).unwrap();
+                target.load_program(jit).unwrap()
+            };
+            progs.set_program(0, target(4096, true)).unwrap();
+            assert_eq!(caller.exec_on(&mut ctx, jit).unwrap(), einval);
+
+            // nor run without the bounds check from a checked caller
+            let mut ctx = [0u8; 4096];
+            progs.set_program(0, target(4096, false)).unwrap();
+            assert_eq!(caller.exec_on(&mut ctx, jit).unwrap(), einval);
+            assert_eq!(ctx[4000], 0);
+
+            progs.set_program(0, target(4096, true)).unwrap();
+            assert_eq!(caller.exec_on(&mut ctx, jit).unwrap(), 1);
+            assert_eq!(ctx[4000], 1);
+        }
+    }
+
+    #[test]
+    fn test_local_call() {
+        // the callee writes 7 to the stack of its caller, r6 survives
+        let prog = "mov r6, 35
+mov r1, r10
+add r1, -8
+lcall +3
+ldxdw r0, [r10-8]
+add r0, r6
+exit
+stdw [r1], 7
+mov r6, 0
+stdw [r10-8], 1
+mov r0, 0
+exit";
+        let instructions = Instructions::from_asm(prog).unwrap();
+        let mut runtime = VirtualMachine::new(instructions.into());
+        runtime.verify(&VerifierOptions::default()).unwrap();
+        assert_eq!(runtime.exec(false).unwrap(), 42);
+        assert!(matches!(
+            runtime.exec(true),
+            Err(VmError::Jit(JitError::LocalCall(3)))
+        ));
+
+        // each call takes a frame of the stack
+        let prog = "lcall -1\nexit";
+        let instructions = Instructions::from_asm(prog).unwrap();
+        let mut runtime = VirtualMachine::new(instructions.into());
+        assert!(matches!(
+            runtime.exec(false),
+            Err(VmError::CallStackOverflow(0))
+        ));
+    }
+
+    #[test]
+    fn test_bound_check() {
+        // a byte of the context indexes it, past its 16 bytes when > 15
+        let prog = "ldxb r2, [r1]
+add r1, r2
+stb [r10-1], 0
+ldxb r0, [r1]
+exit";
+        let options = VerifierOptions {
+            ctx_size: 16,
+            runtime_checks: true,
+            ..Default::default()
+        };
+        for (jit, verified) in [(false, false), (true, false), (false, true), (true, true)] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            // on by default
+            let mut runtime = VirtualMachine::new(instructions.into());
+            if verified {
+                runtime.verify(&options).unwrap();
+            }
+
+            let mut ctx = [0u8; 16];
+            ctx[0] = 5;
+            ctx[5] = 42;
+            let res = runtime.exec_on(&mut ctx, jit);
+            assert_eq!(res.unwrap(), 42);
+
+            ctx[0] = 16;
+            let res = runtime.exec_on(&mut ctx, jit);
+            assert!(matches!(
+                res,
+                Err(VmError::OutOfBounds { pc: 3, size: 1, addr }) if addr == ctx.as_ptr() as u64 + 16
+            ));
+        }
+
+        // proven in bounds of the context size the verifier was given only
+        let instructions = Instructions::from_asm("ldxdw r0, [r1+2000]\nexit").unwrap();
+        let mut runtime = VirtualMachine::new(instructions.into());
+        runtime.verify(&VerifierOptions::default()).unwrap();
+        for jit in [false, true] {
+            assert!(matches!(
+                runtime.exec_on(&mut [0u8; 8], jit),
+                Err(VmError::CtxTooSmall {
+                    len: 8,
+                    min: MEM_SIZE
+                })
+            ));
+            assert_eq!(runtime.exec_on(&mut [0u8; MEM_SIZE], jit).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_shared_program() {
+        // sums the context into a hash map and returns the context plus 1
+        let prog = "ldxdw r6, [r1]
+stxdw [r10-8], r6
+ldmapfd r1, 0
+mov r2, r10
+add r2, -8
+mov r3, r10
+add r3, -8
+mov r4, 0
+call 2
+mov r0, r6
+add r0, 1
+exit";
+        for (jit, threaded) in [(false, false), (false, true), (true, false)] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut vm = VirtualMachine::new(instructions.into());
+            vm.register_map(BpfMap::new(MapDef::new(MapType::Hash, 8, 8, 16)).unwrap());
+            vm.set_threaded(threaded);
+            let prog = vm.load_program(jit).unwrap();
+            assert_eq!(prog.is_jited(), jit);
+
+            std::thread::scope(|s| {
+                for i in 0..4u64 {
+                    let prog = prog.clone();
+                    s.spawn(move || {
+                        let mut execution = Execution::new();
+                        for _ in 0..100 {
+                            let mut ctx = i.to_ne_bytes();
+                            assert_eq!(execution.run(&prog, &mut ctx).unwrap(), i as i64 + 1);
+                        }
+                    });
+                }
+            });
+            let map = vm.map(0).unwrap();
+            for i in 0..4u64 {
+                assert_eq!(map.lookup(&i.to_ne_bytes()), Some(i.to_ne_bytes().to_vec()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_caller_memory() {
+        // writes to the first byte and returns the last one, found through r2
+        let prog = "stb [r1], 9\nadd r1, r2\nldxb r0, [r1-1]\nexit";
+        // reads the region the context points to
+        let region_prog = "ldxdw r2, [r1]\nldxb r0, [r2+3]\nexit";
+        for (jit, threaded) in [(false, false), (false, true), (true, false)] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.set_bound_check(true);
+            runtime.set_threaded(threaded);
+            let mut mem = vec![0u8; 3 * MEM_SIZE];
+            mem[3 * MEM_SIZE - 1] = 42;
+            assert_eq!(runtime.exec_on(&mut mem, jit).unwrap(), 42);
+            assert_eq!(mem[0], 9);
+
+            let instructions = Instructions::from_asm(region_prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.set_bound_check(true);
+            runtime.set_threaded(threaded);
+            let mut region = [1, 2, 3, 4];
+            let mut ctx = (region.as_ptr() as u64).to_ne_bytes();
+            let res = runtime.exec_with_ctx(&mut ctx, &mut [&mut region], jit);
+            assert_eq!(res.unwrap(), 4);
+            assert!(matches!(
+                runtime.exec_on(&mut ctx, jit),
+                Err(VmError::OutOfBounds { pc: 1, size: 1, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_verified_ctx_size() {
+        let options = VerifierOptions {
+            ctx_size: 16,
+            ..Default::default()
+        };
+        let short = |res| matches!(res, Err(VmError::CtxTooSmall { len: 8, min: 16 }));
+        for (jit, threaded) in [(false, false), (false, true), (true, false)] {
+            let instructions = Instructions::from_asm("ldxdw r0, [r1+8]\nexit").unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.set_threaded(threaded);
+            runtime.verify(&options).unwrap();
+
+            assert!(short(runtime.exec_on(&mut [0; 8], jit)));
+            assert!(short(runtime.exec_with_ctx(&mut [0; 8], &mut [], jit)));
+            let res = runtime.exec_with_map(&mut [0; 8], &mut MemoryMap::new(), jit);
+            assert!(short(res));
+            let prog = runtime.load_program(jit).unwrap();
+            let mut execution = Execution::new();
+            assert!(short(execution.run(&prog, &mut [0; 8])));
+            assert!(short(execution.run_with_regions(
+                &prog,
+                &mut [0; 8],
+                &mut []
+            )));
+            let res = execution.run_mapped(&prog, &mut [0; 8], &mut MemoryMap::new());
+            assert!(short(res));
+
+            let mut ctx = [0u8; 16];
+            ctx[8] = 7;
+            assert_eq!(execution.run(&prog, &mut ctx).unwrap(), 7);
+
+            // the proofs do not hold for helpers and maps the verifier did
+            // not see, the accesses are checked again
+            let unproven = |res| matches!(res, Err(VmError::OutOfBounds { pc: 0, size: 8, .. }));
+            runtime.register_helper(
+                100,
+                HelperProto::new(RetType::Integer, &[]),
+                |_, _, _, _, _| 0,
+            );
+            assert!(unproven(runtime.exec_on(&mut [0; 8], jit)));
+            runtime.verify(&options).unwrap();
+            assert!(short(runtime.exec_on(&mut [0; 8], jit)));
+            runtime.register_map(BpfMap::new(MapDef::new(MapType::Array, 4, 8, 1)).unwrap());
+            assert!(unproven(runtime.exec_on(&mut [0; 8], jit)));
+        }
+        let instructions = Instructions::from_asm("ldxdw r0, [r1+8]\nexit").unwrap();
+        let mut runtime = VirtualMachine::new(instructions.into());
+        runtime.verify(&options).unwrap();
+        assert!(matches!(
+            runtime.debug(&mut [0; 8]),
+            Err(VmError::CtxTooSmall { len: 8, min: 16 })
+        ));
+    }
+
+    #[test]
+    fn test_memory_map() {
+        // copies the input to the output and goes through the heap
+        let prog = "mov r2, 0x1000
+ldxw r3, [r2]
+mov r4, 0x2000
+stxw [r4], r3
+mov r5, 0x3000
+stdw [r5+8], 7
+ldxdw r0, [r5+8]
+add r0, r3
+exit";
+        for (jit, threaded) in [(false, false), (false, true), (true, false)] {
+            let input = 5u32.to_le_bytes();
+            let mut output = [0u8; 4];
+            let mut heap = [0u8; 64];
+            let mut map = MemoryMap::new();
+            map.map_readonly(0x1000, &input).unwrap();
+            map.map(0x2000, &mut output, Perm::ReadWrite).unwrap();
+            map.map(0x3000, &mut heap, Perm::ReadWrite).unwrap();
+
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.set_threaded(threaded);
+            let res = runtime.exec_with_map(&mut [], &mut map, jit);
+            assert_eq!(res.unwrap(), 12);
+            let mut bad = prog.replace("stxw [r4], r3", "stxw [r2], r3");
+            let instructions = Instructions::from_asm(&bad).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.set_threaded(threaded);
+            assert!(matches!(
+                runtime.exec_with_map(&mut [], &mut map, jit),
+                Err(VmError::AccessViolation {
+                    pc: 3,
+                    addr: 0x1000,
+                    size: 4,
+                    write: true
+                })
+            ));
+            bad = prog.replace("[r5+8]", "[r5+64]");
+            let instructions = Instructions::from_asm(&bad).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.set_threaded(threaded);
+            assert!(matches!(
+                runtime.exec_with_map(&mut [], &mut map, jit),
+                Err(VmError::OutOfBounds {
+                    pc: 5,
+                    addr: 0x3040,
+                    size: 8
+                })
+            ));
+            drop(map);
+            assert_eq!(output, input);
+        }
+
+        // the regions are only checked by the bounds check
+        let instructions = Instructions::from_asm(prog).unwrap();
+        let mut runtime = VirtualMachine::new(instructions.into());
+        runtime.set_bound_check(false);
+        let prog = runtime.load_program(false).unwrap();
+        assert!(matches!(
+            Execution::new().run_mapped(&prog, &mut [], &mut MemoryMap::new()),
+            Err(VmError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_guest_addresses() {
+        let config = VmConfig::new().with_guest_addresses(true);
+        // stores the context in a hash map and reads it back from there
+        let prog = "ldxdw r6, [r1]
+stxdw [r10-8], r6
+stb [r1+8], 9
+ldmapfd r1, 0
+mov r2, r10
+add r2, -8
+mov r3, r10
+add r3, -8
+mov r4, 0
+call 2
+ldmapfd r1, 0
+mov r2, r10
+add r2, -8
+call 1
+jeq r0, 0, +1
+ldxdw r0, [r0]
+exit";
+        let vm = |prog: &str, threaded| {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut vm = VirtualMachine::with_config(instructions.into(), config).unwrap();
+            vm.set_threaded(threaded);
+            vm
+        };
+        for (jit, threaded) in [(false, false), (false, true), (true, false)] {
+            let options = VerifierOptions {
+                ctx_size: 16,
+                ..Default::default()
+            };
+            let mut ctx = [0u8; 16];
+            ctx[..8].copy_from_slice(&42u64.to_ne_bytes());
+            for bound_check in [false, true] {
+                let mut runtime = vm(prog, threaded);
+                runtime.register_map(BpfMap::new(MapDef::new(MapType::Hash, 8, 8, 16)).unwrap());
+                runtime.set_bound_check(bound_check);
+                runtime.verify(&options).unwrap();
+                assert_eq!(runtime.exec_on(&mut ctx, jit).unwrap(), 42);
+                assert_eq!(ctx[8], 9);
+            }
+
+            // the map values looked up are the only other memory unproven
+            // accesses may go to
+            let stack_top = STACK_VADDR + STACK_SIZE as u64;
+            let unverified = |prog: &str| {
+                let mut runtime = vm(prog, threaded);
+                runtime.register_map(BpfMap::new(MapDef::new(MapType::Hash, 8, 8, 16)).unwrap());
+                runtime.set_bound_check(false);
+                runtime
+            };
+            assert_eq!(unverified(prog).exec_on(&mut ctx, jit).unwrap(), 42);
+            let past_value = prog.replace("[r0]", "[r0+8]");
+            assert!(matches!(
+                unverified(&past_value).exec_on(&mut ctx, jit),
+                Err(VmError::AccessViolation {
+                    pc: 17,
+                    size: 8,
+                    write: false,
+                    ..
+                })
+            ));
+
+            // the maps and the values looked up have guest addresses too
+            assert_eq!(
+                unverified("ldmapfd r0, 0\nexit").exec(jit).unwrap(),
+                MAP_VADDR as i64
+            );
+            let value = prog.replace("ldxdw r0, [r0]", "mov r0, r0");
+            assert_eq!(
+                unverified(&value).exec_on(&mut ctx, jit).unwrap(),
+                MAP_VALUE_VADDR as i64
+            );
+
+            // every byte of the key a helper reads must be mapped
+            let lookup = "ldmapfd r1, 0\nmov r2, r10\nadd r2, -4\ncall 1\nexit";
+            assert!(matches!(
+                unverified(lookup).exec(jit),
+                Err(VmError::AccessViolation {
+                    pc: 4,
+                    addr,
+                    size: 8,
+                    write: false,
+                }) if addr == stack_top - 4
+            ));
+            assert!(matches!(
+                unverified(&lookup.replace("add r2, -4", "mov r2, 0")).exec(jit),
+                Err(VmError::AccessViolation { pc: 4, addr: 0, .. })
+            ));
+
+            // the same wherever the host memory is
+            assert_eq!(
+                vm("mov r0, r10\nexit", threaded).exec(jit).unwrap(),
+                stack_top as i64
+            );
+            assert_eq!(
+                vm("mov r0, r1\nexit", threaded).exec(jit).unwrap(),
+                INPUT_VADDR as i64
+            );
+
+            let mut runtime = vm("mov r0, 0\nexit", threaded);
+            let mut map = MemoryMap::new();
+            let mut mem = [0u8; 8];
+            map.map(MAP_VADDR - 4, &mut mem, Perm::ReadWrite).unwrap();
+            assert!(matches!(
+                runtime.exec_with_map(&mut [], &mut map, jit),
+                Err(VmError::InvalidRegion(addr)) if addr == MAP_VADDR - 4
+            ));
+
+            let mut runtime = vm("ldxb r0, [r1+16]\nexit", threaded);
+            runtime.set_bound_check(true);
+            assert!(matches!(
+                runtime.exec_on(&mut [0; 16], jit),
+                Err(VmError::OutOfBounds { pc: 0, addr, size: 1 }) if addr == INPUT_VADDR + 16
+            ));
+        }
+    }
+
+    #[test]
+    fn test_vm_config() {
+        let vm = |prog: &str, config| {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            VirtualMachine::with_config(instructions.into(), config)
+        };
+        for config in [
+            VmConfig::new().with_max_call_frames(0),
+            VmConfig::new().with_stack_size(100),
+            VmConfig::new().with_mem_size(64).with_max_ctx_size(32),
+        ] {
+            assert!(matches!(vm("exit", config), Err(VmError::InvalidConfig(_))));
+        }
+
+        // two frames of 128 bytes
+        let config = VmConfig::new().with_stack_size(256).with_max_call_frames(2);
+        let prog = "stdw [r10-256], 5\nldxdw r0, [r10-256]\nexit";
+        for (jit, threaded) in [(false, false), (false, true), (true, false)] {
+            let mut runtime = vm(prog, config).unwrap();
+            runtime.set_bound_check(true);
+            runtime.set_threaded(threaded);
+            assert_eq!(runtime.exec(jit).unwrap(), 5);
+
+            let mut runtime = vm(&prog.replace("256", "264"), config).unwrap();
+            runtime.set_bound_check(true);
+            runtime.set_threaded(threaded);
+            assert!(matches!(
+                runtime.exec(jit),
+                Err(VmError::OutOfBounds { pc: 0, size: 8, .. })
+            ));
+        }
+        // the callee gets the frame below the one of its caller
+        let prog = "lcall +1\nexit\nstdw [r10-128], 1\nmov r0, 3\nexit";
+        let deeper = "lcall +1\nexit\nlcall +1\nexit\nmov r0, 3\nexit";
+        for threaded in [false, true] {
+            let mut runtime = vm(prog, config).unwrap();
+            runtime.set_bound_check(true);
+            runtime.set_threaded(threaded);
+            assert_eq!(runtime.exec(false).unwrap(), 3);
+
+            let mut runtime = vm(&prog.replace("128", "136"), config).unwrap();
+            runtime.set_bound_check(true);
+            runtime.set_threaded(threaded);
+            assert!(matches!(
+                runtime.exec(false),
+                Err(VmError::OutOfBounds { pc: 2, .. })
+            ));
+
+            let mut runtime = vm(deeper, config).unwrap();
+            runtime.set_threaded(threaded);
+            assert!(matches!(
+                runtime.exec(false),
+                Err(VmError::CallStackOverflow(2))
+            ));
+        }
+
+        let config = VmConfig::new().with_mem_size(16).with_max_ctx_size(16);
+        let mut runtime = vm("mov r0, r2\nexit", config).unwrap();
+        assert_eq!(runtime.exec(false).unwrap(), 16);
+        assert!(matches!(
+            runtime.set_mem(0, 32, &[0; 32]),
+            Err(VmError::MemOutOfBound)
+        ));
+        assert!(matches!(
+            runtime.exec_on(&mut [0; 32], true),
+            Err(VmError::CtxTooLarge { len: 32, max: 16 })
+        ));
+    }
+
+    #[test]
+    fn test_isa() {
+        let instructions = Instructions::from_asm("mov r0, 1\njlt r0, 2, +0\nexit").unwrap();
+        let mut vm = VirtualMachine::new(instructions.into());
+        assert!(matches!(
+            vm.set_isa(IsaVersion::V1),
+            Err(VmError::Isa(e)) if e.pc == 1 && e.required == IsaVersion::V2
+        ));
+        assert_eq!(vm.isa(), IsaVersion::V4);
+        vm.set_isa(IsaVersion::V2).unwrap();
+        let prog = vm.load_program(true).unwrap();
+        assert_eq!(prog.isa(), IsaVersion::V2);
+        assert_eq!(vm.exec(true).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_div_by_zero() {
+        // program, pc of the division and what r0 holds after it in the
+        // kernel mode
+        let progs = [
+            ("lddw r0, 0x10000000c\nmov r1, 0\ndiv32 r0, r1\nexit", 3, 0),
+            ("mov r0, 5\ndiv r0, 0\nexit", 1, 0),
+            ("lddw r0, 0x100000007\nmov r1, 0\nmod32 r0, r1\nexit", 3, 7),
+            ("mov r0, -7\nmov r1, 0\nmod r0, r1\nexit", 2, -7),
+            // the divisor is the low half only
+            ("mov r0, 9\nlddw r1, 0x100000000\ndiv32 r0, r1\nexit", 3, 0),
+        ];
+        for (prog, pc, kernel) in progs {
+            for jit in [false, true] {
+                let instructions = Instructions::from_asm(prog).unwrap();
+                let mut runtime = VirtualMachine::new(instructions.into());
+                assert!(matches!(runtime.exec(jit), Err(VmError::DivZero(p)) if p == pc));
+
+                runtime.set_div_by_zero(DivByZero::Kernel);
+                assert_eq!(runtime.exec(jit).unwrap(), kernel, "{prog}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_alu_semantics() {
+        let progs = [
+            ("mov r0, -1\nrsh r0, 60\nexit", 15),
+            ("mov r0, 1\nmov r1, 65\nlsh r0, r1\nexit", 2),
+            ("mov r0, 1\nmov r1, 33\nlsh32 r0, r1\nexit", 2),
+            ("mov32 r0, -8\narsh32 r0, 1\nexit", 0xfffffffc),
+            ("mov r0, -8\nmov r1, 1\narsh32 r0, r1\nexit", 0xfffffffc),
+            ("mov r0, -8\narsh r0, 1\nexit", -4),
+            ("mov r0, -8\nmov r1, 65\narsh r0, r1\nexit", -4),
+            ("mov r0, -1\nmov32 r0, r0\nexit", 0xffffffff),
+            ("lddw r0, 0x7fffffffffffffff\nadd r0, 1\nexit", i64::MIN),
+            ("mov r0, -1\ndiv r0, 2\nexit", i64::MAX),
+            ("mov r0, -1\nmov r1, 2\nmod r0, r1\nexit", 1),
+            ("mov r0, 5\nxor r0, 3\nexit", 6),
+            ("mov r0, 5\nmov r1, 3\nxor r0, r1\nexit", 6),
+            ("lddw r0, 0x1122334455667788\nle16 r0\nexit", 0x7788),
+            ("lddw r0, 0x1122334455667788\nle32 r0\nexit", 0x55667788),
+            ("lddw r0, 0x1122334455667788\nbe16 r0\nexit", 0x8877),
+            ("lddw r0, 0x1122334455667788\nbe32 r0\nexit", 0x88776655),
+            (
+                "lddw r0, 0x1122334455667788\nbe64 r0\nexit",
+                0x8877665544332211u64 as i64,
+            ),
+        ];
+        for (prog, res) in progs {
+            for jit in [false, true] {
+                let instructions = Instructions::from_asm(prog).unwrap();
+                let mut runtime = VirtualMachine::new(instructions.into());
+                assert_eq!(runtime.exec(jit).unwrap(), res, "{prog}, jit: {jit}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_jmp32_semantics() {
+        let progs = [
+            // jmp32 only compares the low 32 bits
+            (
+                "mov r0, 0\nlddw r1, 0x100000001\njeq32 r1, 1, +1\nexit\nmov r0, 1\nexit",
+                1,
+            ),
+            (
+                "mov r0, 0\nlddw r1, 0x100000001\njeq r1, 1, +1\nexit\nmov r0, 1\nexit",
+                0,
+            ),
+            (
+                "mov r0, 0\nmov32 r1, -1\njslt32 r1, 0, +1\nexit\nmov r0, 1\nexit",
+                1,
+            ),
+            (
+                "mov r0, 0\nmov32 r1, -1\njslt r1, 0, +1\nexit\nmov r0, 1\nexit",
+                0,
+            ),
+            (
+                "mov r0, 0\nlddw r1, 0x100000000\nmov r2, 1\njgt32 r2, r1, +1\nexit\nmov r0, 1\nexit",
+                1,
+            ),
+            (
+                "mov r0, 0\nmov r1, 6\nmov r2, 3\njne r1, r2, +1\nexit\nmov r0, 1\nexit",
+                1,
+            ),
+            (
+                "mov r0, 0\nlddw r1, 0x100000000\njset32 r1, -1, +1\nexit\nmov r0, 1\nexit",
+                0,
+            ),
+        ];
+        for (prog, res) in progs {
+            for jit in [false, true] {
+                let instructions = Instructions::from_asm(prog).unwrap();
+                let mut runtime = VirtualMachine::new(instructions.into());
+                assert_eq!(runtime.exec(jit).unwrap(), res, "{prog}, jit: {jit}");
+            }
+        }
+    }
 }
@@ -0,0 +1,358 @@
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::HelperError,
+    helpers::{
+        ArgType, BPF_FUNC_GET_PRANDOM_U32, BPF_FUNC_GET_SMP_PROCESSOR_ID, BPF_FUNC_KTIME_GET_NS,
+        BPF_FUNC_TRACE_PRINTK, HelperProto, RetType,
+    },
+    runtime::{VirtualMachine, run_string},
+};
+
+// like the kernel, `bpf_trace_printk` takes at most 3 arguments
+const TRACE_PRINTK_MAX_ARGS: usize = 3;
+// longest string printed for a `%s`
+const TRACE_PRINTK_MAX_STR: usize = 256;
+
+const NO_ARGS_PROTO: HelperProto = HelperProto::new(RetType::Integer, &[]);
+// format and its size, the arguments are only read if the format uses them
+const TRACE_PRINTK_PROTO: HelperProto =
+    HelperProto::new(RetType::Integer, &[ArgType::PtrToMem, ArgType::ConstSize]);
+
+pub type Clock = dyn Fn() -> u64 + Send + Sync;
+
+/// xorshift64* generator behind `bpf_get_prandom_u32`
+#[derive(Debug, Clone)]
+pub struct Prandom {
+    state: u64,
+}
+
+impl Prandom {
+    pub fn new(seed: u64) -> Self {
+        // splitmix64 step, so that small seeds (and 0) give a usable state
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        Self {
+            state: if z == 0 { 1 } else { z },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as u32
+    }
+}
+
+/// opt-in set of the kernel helpers most programs use, registered with
+/// `VirtualMachine::register_std_helpers`
+pub struct StdHelpers {
+    output: Box<dyn Write + Send>,
+    clock: Box<Clock>,
+    rng: Prandom,
+    processor_id: u32,
+}
+
+impl Default for StdHelpers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StdHelpers {
+    /// trace_printk writes to stdout, ktime counts from now and the rng is
+    /// seeded from the wall clock
+    pub fn new() -> Self {
+        let start = Instant::now();
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        Self {
+            output: Box::new(std::io::stdout()),
+            clock: Box::new(move || start.elapsed().as_nanos() as u64),
+            rng: Prandom::new(seed),
+            processor_id: 0,
+        }
+    }
+
+    /// sink for the text printed by `bpf_trace_printk`
+    pub fn with_output<W: Write + Send + 'static>(mut self, output: W) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// source of `bpf_ktime_get_ns`
+    pub fn with_clock<F: Fn() -> u64 + Send + Sync + 'static>(mut self, clock: F) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// make `bpf_get_prandom_u32` reproducible
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Prandom::new(seed);
+        self
+    }
+
+    /// value of `bpf_get_smp_processor_id`
+    pub fn with_processor_id(mut self, id: u32) -> Self {
+        self.processor_id = id;
+        self
+    }
+}
+
+impl VirtualMachine {
+    /// register `bpf_ktime_get_ns`, `bpf_trace_printk`, `bpf_get_prandom_u32`
+    /// and `bpf_get_smp_processor_id` under their kernel ids
+    pub fn register_std_helpers(&mut self, helpers: StdHelpers) {
+        let StdHelpers {
+            output,
+            clock,
+            rng,
+            processor_id,
+        } = helpers;
+
+        self.register_helper(
+            BPF_FUNC_KTIME_GET_NS,
+            NO_ARGS_PROTO,
+            move |_, _, _, _, _| clock(),
+        );
+
+        let output = Mutex::new(output);
+        self.register_helper(
+            BPF_FUNC_TRACE_PRINTK,
+            TRACE_PRINTK_PROTO,
+            move |fmt, size, a1, a2, a3| {
+                let fmt = unsafe { std::slice::from_raw_parts(fmt as *const u8, size as usize) };
+                let text = match format_trace_printk(fmt, &[a1, a2, a3], run_string) {
+                    Ok(text) => text,
+                    Err(e) => return (-e.errno()) as u64,
+                };
+                let mut output = output.lock().unwrap();
+                match output.write_all(&text).and_then(|_| output.flush()) {
+                    Ok(_) => text.len() as u64,
+                    Err(_) => (-HelperError::InvalidArgument.errno()) as u64,
+                }
+            },
+        );
+
+        let rng = Arc::new(Mutex::new(rng));
+        self.register_helper(
+            BPF_FUNC_GET_PRANDOM_U32,
+            NO_ARGS_PROTO,
+            move |_, _, _, _, _| rng.lock().unwrap().next_u32() as u64,
+        );
+
+        self.register_helper(
+            BPF_FUNC_GET_SMP_PROCESSOR_ID,
+            NO_ARGS_PROTO,
+            move |_, _, _, _, _| processor_id as u64,
+        );
+    }
+}
+
+/// expand `fmt` the way `bpf_trace_printk` does, `fmt` has to be nul
+/// terminated and only `%d %i %u %x %X %p %s %c %%` with optional `l`/`ll`
+/// are accepted, `string(addr, max)` reads at most `max` bytes of the string
+/// of a `%s`, if the program may access them
+pub fn format_trace_printk(
+    fmt: &[u8],
+    args: &[u64],
+    string: impl Fn(u64, usize) -> Option<Vec<u8>>,
+) -> Result<Vec<u8>, HelperError> {
+    let end = fmt
+        .iter()
+        .position(|&c| c == 0)
+        .ok_or(HelperError::InvalidArgument)?;
+    let fmt = &fmt[..end];
+
+    let mut out = Vec::with_capacity(fmt.len());
+    let mut args = args.iter().take(TRACE_PRINTK_MAX_ARGS);
+    let mut i = 0;
+    while i < fmt.len() {
+        let c = fmt[i];
+        i += 1;
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if fmt.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+
+        let mut longs = 0;
+        while fmt.get(i) == Some(&b'l') && longs < 2 {
+            longs += 1;
+            i += 1;
+        }
+        let conv = *fmt.get(i).ok_or(HelperError::InvalidArgument)?;
+        i += 1;
+        let arg = *args.next().ok_or(HelperError::InvalidArgument)?;
+
+        match conv {
+            b'd' | b'i' if longs == 0 => write!(out, "{}", arg as i32),
+            b'd' | b'i' => write!(out, "{}", arg as i64),
+            b'u' if longs == 0 => write!(out, "{}", arg as u32),
+            b'u' => write!(out, "{}", arg),
+            b'x' if longs == 0 => write!(out, "{:x}", arg as u32),
+            b'x' => write!(out, "{:x}", arg),
+            b'X' if longs == 0 => write!(out, "{:X}", arg as u32),
+            b'X' => write!(out, "{:X}", arg),
+            b'p' if longs == 0 => write!(out, "0x{:x}", arg),
+            b'c' if longs == 0 => write!(out, "{}", arg as u8 as char),
+            b's' if longs == 0 => {
+                if arg == 0 {
+                    write!(out, "(null)")
+                } else {
+                    let s =
+                        string(arg, TRACE_PRINTK_MAX_STR).ok_or(HelperError::InvalidArgument)?;
+                    out.write_all(&s)
+                }
+            }
+            _ => return Err(HelperError::InvalidArgument),
+        }
+        .map_err(|_| HelperError::InvalidArgument)?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use assembler::Instructions;
+
+    use super::*;
+    use crate::runtime::VmConfig;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_format_trace_printk() {
+        // the only string is at 0x1000
+        let string = |addr, max: usize| {
+            let s = b"hi %s";
+            (addr == 0x1000).then(|| s[..s.len().min(max)].to_vec())
+        };
+        let format = |fmt: &[u8], args: &[u64]| format_trace_printk(fmt, args, string);
+        let r = format(b"%d %u %x\0", &[-1i64 as u64, u64::MAX, 255]).unwrap();
+        assert_eq!(r, b"-1 4294967295 ff");
+        let r = format(b"%lld %llx %%\0", &[-2i64 as u64, 16]).unwrap();
+        assert_eq!(r, b"-2 10 %");
+        let r = format(b"<%s><%c><%s>\0", &[0x1000, b'z' as u64, 0]).unwrap();
+        assert_eq!(r, b"<hi %s><z><(null)>");
+
+        // missing nul, unknown conversion, too many arguments and a string
+        // the program may not read
+        assert!(format(b"abc", &[]).is_err());
+        assert!(format(b"%f\0", &[1]).is_err());
+        assert!(format(b"%d%d%d%d\0", &[1, 2, 3, 4]).is_err());
+        assert!(format(b"%s\0", &[0x2000]).is_err());
+    }
+
+    #[test]
+    fn test_trace_printk_strings() {
+        // prints the string at ctx+8 with the format at ctx
+        let prog = "mov r3, r1\nadd r3, 8\nmov r2, 5\ncall 6\nexit";
+        let mut ctx = [0u8; 16];
+        ctx[..5].copy_from_slice(b"<%s>\0");
+        ctx[8..11].copy_from_slice(b"hi\0");
+        for guest in [false, true] {
+            for (jit, threaded) in [(false, false), (false, true), (true, false)] {
+                for bound_check in [false, true] {
+                    let vm = |prog: &str| {
+                        let instructions = Instructions::from_asm(prog).unwrap();
+                        let config = VmConfig::new().with_guest_addresses(guest);
+                        let mut vm =
+                            VirtualMachine::with_config(instructions.into(), config).unwrap();
+                        vm.set_threaded(threaded);
+                        vm.set_bound_check(bound_check);
+                        vm
+                    };
+                    let mut runtime = vm(prog);
+                    let output = SharedBuf::default();
+                    runtime.register_std_helpers(StdHelpers::new().with_output(output.clone()));
+                    assert_eq!(runtime.exec_on(&mut ctx, jit).unwrap(), 4);
+                    assert_eq!(output.0.lock().unwrap().as_slice(), b"<hi>");
+
+                    // not a pointer to memory of the run
+                    let mut runtime = vm(&prog.replace("add r3, 8", "mov r3, 8"));
+                    runtime.register_std_helpers(StdHelpers::new().with_output(output.clone()));
+                    let einval = -HelperError::InvalidArgument.errno();
+                    assert_eq!(runtime.exec_on(&mut ctx, jit).unwrap(), einval);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_hello_kern() {
+        let buffer = std::fs::read("../data/hello_kern.o").unwrap();
+        for jit in [false, true] {
+            let instructions = Instructions::from_elf(&buffer, "bpf_prog").unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            let output = SharedBuf::default();
+            runtime.register_std_helpers(StdHelpers::new().with_output(output.clone()));
+
+            assert_eq!(runtime.exec(jit).unwrap(), 0);
+            assert_eq!(output.0.lock().unwrap().as_slice(), b"Hello World\n");
+        }
+    }
+
+    #[test]
+    fn test_time_random_and_cpu() {
+        let prog = "call 5
+mov r6, r0
+call 5
+sub r0, r6
+mov r6, r0
+call 8
+lsh r0, 32
+or r0, r6
+exit";
+        for jit in [false, true] {
+            let now = Arc::new(AtomicU64::new(100));
+            let clock = now.clone();
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.register_std_helpers(
+                StdHelpers::new()
+                    .with_clock(move || clock.fetch_add(25, Ordering::SeqCst))
+                    .with_processor_id(3),
+            );
+            assert_eq!(runtime.exec(jit).unwrap(), (3 << 32) | 25);
+            assert_eq!(now.load(Ordering::SeqCst), 150);
+        }
+
+        // the generator keeps its state across runs
+        let mut rng = Prandom::new(42);
+        let expected: Vec<i64> = (0..4).map(|_| rng.next_u32() as i64).collect();
+        for jit in [false, true] {
+            let instructions = Instructions::from_asm("call 7\ncall 7\nexit").unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.register_std_helpers(StdHelpers::new().with_seed(42));
+            assert_eq!(runtime.exec(jit).unwrap(), expected[1]);
+            assert_eq!(runtime.exec(jit).unwrap(), expected[3]);
+        }
+    }
+}
@@ -0,0 +1,1029 @@
+use std::{collections::HashMap, sync::Arc};
+
+use assembler::{Instruction, op::*};
+
+use crate::{
+    error::VmError,
+    helpers::{BPF_FUNC_TAIL_CALL, Helper},
+    memory::{to_host, translate},
+    runtime::{DivByZero, Program, Regs, enter_tail_call, tail_call_target, with_regions},
+};
+
+/// what makes the threaded interpreter leave its loop
+#[derive(Debug)]
+pub(crate) enum Stop {
+    Exit,
+    Fault(VmError),
+    /// go on with the first instruction of another program
+    TailCall(Arc<Program>),
+}
+
+impl From<VmError> for Stop {
+    fn from(e: VmError) -> Self {
+        Stop::Fault(e)
+    }
+}
+
+type Handler = fn(&mut Cpu<'_>, &Op, &ThreadedProgram) -> Result<(), Stop>;
+
+/// an instruction decoded once, `handler` only does what its opcode needs
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    handler: Handler,
+    // full 64 bits of `lddw`, index in `helpers` of helper calls
+    imm: i64,
+    // pc of the next instruction when a jump or a call is taken
+    target: u32,
+    offset: i16,
+    dst: u8,
+    src: u8,
+}
+
+/// a program pre-decoded for the threaded interpreter, with one op per
+/// instruction so that the pc matches the one of the instructions
+#[derive(Debug)]
+pub(crate) struct ThreadedProgram {
+    ops: Vec<Op>,
+    helpers: Vec<Arc<Helper>>,
+}
+
+/// state of a run
+pub(crate) struct Cpu<'a> {
+    regs: &'a mut Regs,
+    pc: usize,
+    // whether the accesses are checked against the memory of the run, and
+    // translated from guest addresses
+    bounds: bool,
+    guest: bool,
+    div_by_zero: DivByZero,
+    tail_call_cnt: u64,
+    max_call_frames: usize,
+    frame_size: i64,
+    // return pc, r6-r9 and r10 of the callers of the current function
+    frames: Vec<(usize, [i64; 5])>,
+}
+
+impl Cpu<'_> {
+    // pc of the op being run, already moved past it
+    fn cur_pc(&self) -> usize {
+        self.pc - 1
+    }
+
+    fn div_by_zero(&self) -> Result<(), Stop> {
+        Ok(self.div_by_zero.check(self.cur_pc() as i64)?)
+    }
+
+    // host address of an access whose bounds were not proven, or of any
+    // access when the program sees guest addresses
+    fn check(&self, addr: i64, size: usize, write: bool) -> Result<i64, Stop> {
+        let (addr, pc) = (addr as u64, self.cur_pc());
+        let host = with_regions(|regions| match (self.bounds, self.guest) {
+            (true, _) => translate(addr, size, write, pc, regions),
+            (false, true) => to_host(addr, size, write, pc, regions),
+            (false, false) => Ok(addr),
+        })?;
+        Ok(host as i64)
+    }
+
+    #[inline(always)]
+    fn jump_if(&mut self, cond: bool, op: &Op) -> Result<(), Stop> {
+        if cond {
+            self.pc = op.target as usize;
+        }
+        Ok(())
+    }
+}
+
+fn load<T: Into<i64>, const CHECKED: bool>(
+    c: &mut Cpu<'_>,
+    o: &Op,
+    _: &ThreadedProgram,
+) -> Result<(), Stop> {
+    let mut addr = c.regs[o.src as usize].wrapping_add(o.offset as i64);
+    if CHECKED || c.guest {
+        addr = c.check(addr, size_of::<T>(), false)?;
+    }
+    c.regs[o.dst as usize] = unsafe { (addr as *const T).read_unaligned() }.into();
+    Ok(())
+}
+
+// the host is little endian, the low `SIZE` bytes of `value` are stored
+fn store<const SIZE: usize, const CHECKED: bool>(
+    c: &mut Cpu<'_>,
+    o: &Op,
+    value: i64,
+) -> Result<(), Stop> {
+    let mut addr = c.regs[o.dst as usize].wrapping_add(o.offset as i64);
+    if CHECKED || c.guest {
+        addr = c.check(addr, SIZE, true)?;
+    }
+    let bytes = value.to_le_bytes();
+    unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), addr as *mut u8, SIZE) };
+    Ok(())
+}
+
+fn st<const SIZE: usize, const CHECKED: bool>(
+    c: &mut Cpu<'_>,
+    o: &Op,
+    _: &ThreadedProgram,
+) -> Result<(), Stop> {
+    store::<SIZE, CHECKED>(c, o, o.imm)
+}
+
+fn stx<const SIZE: usize, const CHECKED: bool>(
+    c: &mut Cpu<'_>,
+    o: &Op,
+    _: &ThreadedProgram,
+) -> Result<(), Stop> {
+    let value = c.regs[o.src as usize];
+    store::<SIZE, CHECKED>(c, o, value)
+}
+
+/// handler of a load or a store, `checked` when its bounds were not proven
+fn memory_handler(op: u8, checked: bool) -> Option<Handler> {
+    let handler: Handler = match (op, checked) {
+        (LDXW, false) => load::<u32, false>,
+        (LDXW, true) => load::<u32, true>,
+        (LDXH, false) => load::<u16, false>,
+        (LDXH, true) => load::<u16, true>,
+        (LDXB, false) => load::<u8, false>,
+        (LDXB, true) => load::<u8, true>,
+        (LDXDW, false) => load::<i64, false>,
+        (LDXDW, true) => load::<i64, true>,
+        (LDXSW, false) => load::<i32, false>,
+        (LDXSW, true) => load::<i32, true>,
+        (LDXSH, false) => load::<i16, false>,
+        (LDXSH, true) => load::<i16, true>,
+        (LDXSB, false) => load::<i8, false>,
+        (LDXSB, true) => load::<i8, true>,
+        (STW, false) => st::<4, false>,
+        (STW, true) => st::<4, true>,
+        (STH, false) => st::<2, false>,
+        (STH, true) => st::<2, true>,
+        (STB, false) => st::<1, false>,
+        (STB, true) => st::<1, true>,
+        (STDW, false) => st::<8, false>,
+        (STDW, true) => st::<8, true>,
+        (STXW, false) => stx::<4, false>,
+        (STXW, true) => stx::<4, true>,
+        (STXH, false) => stx::<2, false>,
+        (STXH, true) => stx::<2, true>,
+        (STXB, false) => stx::<1, false>,
+        (STXB, true) => stx::<1, true>,
+        (STXDW, false) => stx::<8, false>,
+        (STXDW, true) => stx::<8, true>,
+        _ => return None,
+    };
+    Some(handler)
+}
+
+impl ThreadedProgram {
+    /// decode `instructions` with their map fds already resolved, the helpers
+    /// are looked up in `helpers`, the accesses `safe_accesses` does not mark
+    /// are checked when the run asks for it
+    pub(crate) fn decode(
+        instructions: &[Instruction],
+        helpers: &HashMap<u32, Arc<Helper>>,
+        safe_accesses: &[bool],
+    ) -> Self {
+        let mut prog = ThreadedProgram {
+            ops: Vec::with_capacity(instructions.len()),
+            helpers: Vec::new(),
+        };
+        for (pc, ins) in instructions.iter().enumerate() {
+            let op = prog.decode_one(pc, ins, instructions, helpers, safe_accesses);
+            prog.ops.push(op);
+        }
+        prog
+    }
+
+    fn decode_one(
+        &mut self,
+        pc: usize,
+        ins: &Instruction,
+        instructions: &[Instruction],
+        helpers: &HashMap<u32, Arc<Helper>>,
+        safe_accesses: &[bool],
+    ) -> Op {
+        let mut op = Op {
+            handler: |_, _, _| unreachable!(),
+            imm: ins.imm,
+            target: (pc as i64 + 1 + ins.jump_offset()) as u32,
+            offset: ins.offset,
+            dst: ins.dst_reg() & 0x0f,
+            src: ins.src_reg() & 0x0f,
+        };
+        let checked = !safe_accesses.get(pc).is_some_and(|&s| s);
+        if let Some(handler) = memory_handler(ins.op, checked) {
+            op.handler = handler;
+            return op;
+        }
+
+        op.handler = match ins.op {
+            // fused with its second half, which is never run
+            LDDW => {
+                let Some(next) = instructions.get(pc + 1) else {
+                    return op;
+                };
+                op.imm = ins.imm | (next.imm << 32);
+                |c, o, _| {
+                    c.regs[o.dst as usize] = o.imm;
+                    c.pc += 1;
+                    Ok(())
+                }
+            }
+            ADD_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_add(o.imm) & U32_MASK;
+                Ok(())
+            },
+            ADD_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_add(s) & U32_MASK;
+                Ok(())
+            },
+            SUB_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_sub(o.imm) & U32_MASK;
+                Ok(())
+            },
+            SUB_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_sub(s) & U32_MASK;
+                Ok(())
+            },
+            MUL_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_mul(o.imm) & U32_MASK;
+                Ok(())
+            },
+            MUL_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_mul(s) & U32_MASK;
+                Ok(())
+            },
+            DIV_IMM | DIV_REG | MOD_IMM | MOD_REG if ins.offset == EBPF_OFF_SIGNED => {
+                match ins.op {
+                    DIV_IMM => |c, o, _| {
+                        let a = c.regs[o.dst as usize] as i32;
+                        let r = match o.imm as i32 {
+                            0 => c.div_by_zero().map(|_| 0)?,
+                            b => a.wrapping_div(b),
+                        };
+                        c.regs[o.dst as usize] = r as u32 as i64;
+                        Ok(())
+                    },
+                    DIV_REG => |c, o, _| {
+                        let a = c.regs[o.dst as usize] as i32;
+                        let r = match c.regs[o.src as usize] as i32 {
+                            0 => c.div_by_zero().map(|_| 0)?,
+                            b => a.wrapping_div(b),
+                        };
+                        c.regs[o.dst as usize] = r as u32 as i64;
+                        Ok(())
+                    },
+                    MOD_IMM => |c, o, _| {
+                        let a = c.regs[o.dst as usize] as i32;
+                        let r = match o.imm as i32 {
+                            0 => c.div_by_zero().map(|_| a)?,
+                            b => a.wrapping_rem(b),
+                        };
+                        c.regs[o.dst as usize] = r as u32 as i64;
+                        Ok(())
+                    },
+                    _ => |c, o, _| {
+                        let a = c.regs[o.dst as usize] as i32;
+                        let r = match c.regs[o.src as usize] as i32 {
+                            0 => c.div_by_zero().map(|_| a)?,
+                            b => a.wrapping_rem(b),
+                        };
+                        c.regs[o.dst as usize] = r as u32 as i64;
+                        Ok(())
+                    },
+                }
+            }
+            // the divisor is known when decoding
+            DIV_IMM if ins.imm & U32_MASK == 0 => |c, o, _| {
+                c.div_by_zero()?;
+                c.regs[o.dst as usize] = 0;
+                Ok(())
+            },
+            DIV_IMM => {
+                op.imm &= U32_MASK;
+                |c, o, _| {
+                    let r = &mut c.regs[o.dst as usize];
+                    *r = (*r & U32_MASK) / o.imm;
+                    Ok(())
+                }
+            }
+            DIV_REG => |c, o, _| {
+                let b = c.regs[o.src as usize] & U32_MASK;
+                if b == 0 {
+                    c.div_by_zero()?;
+                    c.regs[o.dst as usize] = 0;
+                } else {
+                    let r = &mut c.regs[o.dst as usize];
+                    *r = (*r & U32_MASK) / b;
+                }
+                Ok(())
+            },
+            OR_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = (*r | o.imm) & U32_MASK;
+                Ok(())
+            },
+            OR_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = (*r | s) & U32_MASK;
+                Ok(())
+            },
+            AND_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r &= o.imm & U32_MASK;
+                Ok(())
+            },
+            AND_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r &= s & U32_MASK;
+                Ok(())
+            },
+            LSH_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = ((*r & U32_MASK) << (o.imm & 31)) & U32_MASK;
+                Ok(())
+            },
+            LSH_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = ((*r & U32_MASK) << (s & 31)) & U32_MASK;
+                Ok(())
+            },
+            RSH_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = (*r & U32_MASK) >> (o.imm & 31);
+                Ok(())
+            },
+            RSH_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = (*r & U32_MASK) >> (s & 31);
+                Ok(())
+            },
+            NEG32 => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_neg() & U32_MASK;
+                Ok(())
+            },
+            MOD_IMM if ins.imm & U32_MASK == 0 => |c, o, _| {
+                c.div_by_zero()?;
+                c.regs[o.dst as usize] &= U32_MASK;
+                Ok(())
+            },
+            MOD_IMM => {
+                op.imm &= U32_MASK;
+                |c, o, _| {
+                    let r = &mut c.regs[o.dst as usize];
+                    *r = (*r & U32_MASK) % o.imm;
+                    Ok(())
+                }
+            }
+            MOD_REG => |c, o, _| {
+                let b = c.regs[o.src as usize] & U32_MASK;
+                if b == 0 {
+                    c.div_by_zero()?;
+                    c.regs[o.dst as usize] &= U32_MASK;
+                } else {
+                    let r = &mut c.regs[o.dst as usize];
+                    *r = (*r & U32_MASK) % b;
+                }
+                Ok(())
+            },
+            XOR_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = (*r ^ o.imm) & U32_MASK;
+                Ok(())
+            },
+            XOR_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = (*r ^ s) & U32_MASK;
+                Ok(())
+            },
+            MOV_IMM => {
+                op.imm &= U32_MASK;
+                |c, o, _| {
+                    c.regs[o.dst as usize] = o.imm;
+                    Ok(())
+                }
+            }
+            MOV_REG if ins.offset == 8 => |c, o, _| {
+                c.regs[o.dst as usize] = c.regs[o.src as usize] as i8 as i32 as u32 as i64;
+                Ok(())
+            },
+            MOV_REG if ins.offset != 0 => |c, o, _| {
+                c.regs[o.dst as usize] = c.regs[o.src as usize] as i16 as i32 as u32 as i64;
+                Ok(())
+            },
+            MOV_REG => |c, o, _| {
+                c.regs[o.dst as usize] = c.regs[o.src as usize] & U32_MASK;
+                Ok(())
+            },
+            ARSH_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = ((*r as i32) >> (o.imm & 31)) as i64 & U32_MASK;
+                Ok(())
+            },
+            ARSH_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = ((*r as i32) >> (s & 31)) as i64 & U32_MASK;
+                Ok(())
+            },
+            // the host is little endian
+            LE => match ins.imm {
+                16 => |c, o, _| {
+                    let r = &mut c.regs[o.dst as usize];
+                    *r = *r as u16 as i64;
+                    Ok(())
+                },
+                32 => |c, o, _| {
+                    let r = &mut c.regs[o.dst as usize];
+                    *r = *r as u32 as i64;
+                    Ok(())
+                },
+                _ => |_, _, _| Ok(()),
+            },
+            BE | BSWAP => match ins.imm {
+                16 => |c, o, _| {
+                    let r = &mut c.regs[o.dst as usize];
+                    *r = (*r as u16).swap_bytes() as i64;
+                    Ok(())
+                },
+                32 => |c, o, _| {
+                    let r = &mut c.regs[o.dst as usize];
+                    *r = (*r as u32).swap_bytes() as i64;
+                    Ok(())
+                },
+                _ => |c, o, _| {
+                    let r = &mut c.regs[o.dst as usize];
+                    *r = r.swap_bytes();
+                    Ok(())
+                },
+            },
+            ADD64_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_add(o.imm);
+                Ok(())
+            },
+            ADD64_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_add(s);
+                Ok(())
+            },
+            SUB64_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_sub(o.imm);
+                Ok(())
+            },
+            SUB64_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_sub(s);
+                Ok(())
+            },
+            MUL64_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_mul(o.imm);
+                Ok(())
+            },
+            MUL64_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_mul(s);
+                Ok(())
+            },
+            DIV64_IMM | DIV64_REG | MOD64_IMM | MOD64_REG if ins.offset == EBPF_OFF_SIGNED => {
+                match ins.op {
+                    DIV64_IMM => |c, o, _| {
+                        let a = c.regs[o.dst as usize];
+                        c.regs[o.dst as usize] = match o.imm {
+                            0 => c.div_by_zero().map(|_| 0)?,
+                            b => a.wrapping_div(b),
+                        };
+                        Ok(())
+                    },
+                    DIV64_REG => |c, o, _| {
+                        let a = c.regs[o.dst as usize];
+                        c.regs[o.dst as usize] = match c.regs[o.src as usize] {
+                            0 => c.div_by_zero().map(|_| 0)?,
+                            b => a.wrapping_div(b),
+                        };
+                        Ok(())
+                    },
+                    MOD64_IMM => |c, o, _| {
+                        let a = c.regs[o.dst as usize];
+                        c.regs[o.dst as usize] = match o.imm {
+                            0 => c.div_by_zero().map(|_| a)?,
+                            b => a.wrapping_rem(b),
+                        };
+                        Ok(())
+                    },
+                    _ => |c, o, _| {
+                        let a = c.regs[o.dst as usize];
+                        c.regs[o.dst as usize] = match c.regs[o.src as usize] {
+                            0 => c.div_by_zero().map(|_| a)?,
+                            b => a.wrapping_rem(b),
+                        };
+                        Ok(())
+                    },
+                }
+            }
+            DIV64_IMM if ins.imm == 0 => |c, o, _| {
+                c.div_by_zero()?;
+                c.regs[o.dst as usize] = 0;
+                Ok(())
+            },
+            DIV64_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = (*r as u64 / o.imm as u64) as i64;
+                Ok(())
+            },
+            DIV64_REG => |c, o, _| {
+                let b = c.regs[o.src as usize] as u64;
+                let r = (c.regs[o.dst as usize] as u64).checked_div(b);
+                c.regs[o.dst as usize] = match r {
+                    Some(r) => r as i64,
+                    None => c.div_by_zero().map(|_| 0)?,
+                };
+                Ok(())
+            },
+            OR64_IMM => |c, o, _| {
+                c.regs[o.dst as usize] |= o.imm;
+                Ok(())
+            },
+            OR64_REG => |c, o, _| {
+                c.regs[o.dst as usize] |= c.regs[o.src as usize];
+                Ok(())
+            },
+            AND64_IMM => |c, o, _| {
+                c.regs[o.dst as usize] &= o.imm;
+                Ok(())
+            },
+            AND64_REG => |c, o, _| {
+                c.regs[o.dst as usize] &= c.regs[o.src as usize];
+                Ok(())
+            },
+            LSH64_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_shl(o.imm as u32);
+                Ok(())
+            },
+            LSH64_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_shl(s as u32);
+                Ok(())
+            },
+            RSH64_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = (*r as u64).wrapping_shr(o.imm as u32) as i64;
+                Ok(())
+            },
+            RSH64_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = (*r as u64).wrapping_shr(s as u32) as i64;
+                Ok(())
+            },
+            NEG64 => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_neg();
+                Ok(())
+            },
+            MOD64_IMM if ins.imm == 0 => |c, _, _| c.div_by_zero(),
+            MOD64_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = (*r as u64 % o.imm as u64) as i64;
+                Ok(())
+            },
+            MOD64_REG => |c, o, _| {
+                let b = c.regs[o.src as usize] as u64;
+                if b == 0 {
+                    c.div_by_zero()?;
+                } else {
+                    let r = &mut c.regs[o.dst as usize];
+                    *r = (*r as u64 % b) as i64;
+                }
+                Ok(())
+            },
+            XOR64_IMM => |c, o, _| {
+                c.regs[o.dst as usize] ^= o.imm;
+                Ok(())
+            },
+            XOR64_REG => |c, o, _| {
+                c.regs[o.dst as usize] ^= c.regs[o.src as usize];
+                Ok(())
+            },
+            MOV64_IMM => |c, o, _| {
+                c.regs[o.dst as usize] = o.imm;
+                Ok(())
+            },
+            MOV64_REG if ins.offset == 8 => |c, o, _| {
+                c.regs[o.dst as usize] = c.regs[o.src as usize] as i8 as i64;
+                Ok(())
+            },
+            MOV64_REG if ins.offset == 16 => |c, o, _| {
+                c.regs[o.dst as usize] = c.regs[o.src as usize] as i16 as i64;
+                Ok(())
+            },
+            MOV64_REG if ins.offset != 0 => |c, o, _| {
+                c.regs[o.dst as usize] = c.regs[o.src as usize] as i32 as i64;
+                Ok(())
+            },
+            MOV64_REG => |c, o, _| {
+                c.regs[o.dst as usize] = c.regs[o.src as usize];
+                Ok(())
+            },
+            ARSH64_IMM => |c, o, _| {
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_shr(o.imm as u32);
+                Ok(())
+            },
+            ARSH64_REG => |c, o, _| {
+                let s = c.regs[o.src as usize];
+                let r = &mut c.regs[o.dst as usize];
+                *r = r.wrapping_shr(s as u32);
+                Ok(())
+            },
+            JA | JA32 => |c, o, _| {
+                c.pc = o.target as usize;
+                Ok(())
+            },
+            JEQ_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] == o.imm, o),
+            JEQ_REG => |c, o, _| c.jump_if(c.regs[o.dst as usize] == c.regs[o.src as usize], o),
+            JGT_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as u64 > o.imm as u64, o),
+            JGT_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as u64 > c.regs[o.src as usize] as u64,
+                    o,
+                )
+            },
+            JGE_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as u64 >= o.imm as u64, o),
+            JGE_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as u64 >= c.regs[o.src as usize] as u64,
+                    o,
+                )
+            },
+            JSET_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] & o.imm != 0, o),
+            JSET_REG => {
+                |c, o, _| c.jump_if(c.regs[o.dst as usize] & c.regs[o.src as usize] != 0, o)
+            }
+            JNE_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] != o.imm, o),
+            JNE_REG => |c, o, _| c.jump_if(c.regs[o.dst as usize] != c.regs[o.src as usize], o),
+            JSGT_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] > o.imm, o),
+            JSGT_REG => |c, o, _| c.jump_if(c.regs[o.dst as usize] > c.regs[o.src as usize], o),
+            JSGE_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] >= o.imm, o),
+            JSGE_REG => |c, o, _| c.jump_if(c.regs[o.dst as usize] >= c.regs[o.src as usize], o),
+            JLT_IMM => |c, o, _| c.jump_if((c.regs[o.dst as usize] as u64) < o.imm as u64, o),
+            JLT_REG => |c, o, _| {
+                c.jump_if(
+                    (c.regs[o.dst as usize] as u64) < c.regs[o.src as usize] as u64,
+                    o,
+                )
+            },
+            JLE_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as u64 <= o.imm as u64, o),
+            JLE_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as u64 <= c.regs[o.src as usize] as u64,
+                    o,
+                )
+            },
+            JSLT_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] < o.imm, o),
+            JSLT_REG => |c, o, _| c.jump_if(c.regs[o.dst as usize] < c.regs[o.src as usize], o),
+            JSLE_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] <= o.imm, o),
+            JSLE_REG => |c, o, _| c.jump_if(c.regs[o.dst as usize] <= c.regs[o.src as usize], o),
+            JEQ32_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as u32 == o.imm as u32, o),
+            JEQ32_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as u32 == c.regs[o.src as usize] as u32,
+                    o,
+                )
+            },
+            JGT32_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as u32 > o.imm as u32, o),
+            JGT32_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as u32 > c.regs[o.src as usize] as u32,
+                    o,
+                )
+            },
+            JGE32_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as u32 >= o.imm as u32, o),
+            JGE32_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as u32 >= c.regs[o.src as usize] as u32,
+                    o,
+                )
+            },
+            JSET32_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as u32 & o.imm as u32 != 0, o),
+            JSET32_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as u32 & c.regs[o.src as usize] as u32 != 0,
+                    o,
+                )
+            },
+            JNE32_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as u32 != o.imm as u32, o),
+            JNE32_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as u32 != c.regs[o.src as usize] as u32,
+                    o,
+                )
+            },
+            JSGT32_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as i32 > o.imm as i32, o),
+            JSGT32_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as i32 > c.regs[o.src as usize] as i32,
+                    o,
+                )
+            },
+            JSGE32_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as i32 >= o.imm as i32, o),
+            JSGE32_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as i32 >= c.regs[o.src as usize] as i32,
+                    o,
+                )
+            },
+            JLT32_IMM => |c, o, _| c.jump_if((c.regs[o.dst as usize] as u32) < o.imm as u32, o),
+            JLT32_REG => |c, o, _| {
+                c.jump_if(
+                    (c.regs[o.dst as usize] as u32) < c.regs[o.src as usize] as u32,
+                    o,
+                )
+            },
+            JLE32_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as u32 <= o.imm as u32, o),
+            JLE32_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as u32 <= c.regs[o.src as usize] as u32,
+                    o,
+                )
+            },
+            JSLT32_IMM => |c, o, _| c.jump_if((c.regs[o.dst as usize] as i32) < o.imm as i32, o),
+            JSLT32_REG => |c, o, _| {
+                c.jump_if(
+                    (c.regs[o.dst as usize] as i32) < c.regs[o.src as usize] as i32,
+                    o,
+                )
+            },
+            JSLE32_IMM => |c, o, _| c.jump_if(c.regs[o.dst as usize] as i32 <= o.imm as i32, o),
+            JSLE32_REG => |c, o, _| {
+                c.jump_if(
+                    c.regs[o.dst as usize] as i32 <= c.regs[o.src as usize] as i32,
+                    o,
+                )
+            },
+            CALL if ins.src_reg() == EBPF_PSEUDO_CALL => {
+                op.target = (pc as i64 + 1 + ins.imm) as u32;
+                |c, o, _| {
+                    if c.frames.len() + 1 >= c.max_call_frames {
+                        return Err(VmError::CallStackOverflow(c.cur_pc()).into());
+                    }
+                    let r = &c.regs;
+                    let saved = [r[6], r[7], r[8], r[9], r[10]];
+                    c.frames.push((c.pc, saved));
+                    c.regs[10] -= c.frame_size;
+                    c.pc = o.target as usize;
+                    Ok(())
+                }
+            }
+            CALL if ins.imm == BPF_FUNC_TAIL_CALL as i64 => |c, _, _| {
+                match tail_call_target(c.regs[2] as u64, c.regs[3] as u64, c.tail_call_cnt) {
+                    Ok(prog) => {
+                        // r1 and the stack are handed over as they are, the
+                        // new program never returns to the callers
+                        c.tail_call_cnt += 1;
+                        if let Some((_, saved)) = c.frames.first() {
+                            c.regs[10] = saved[4];
+                        }
+                        c.frames.clear();
+                        Err(Stop::TailCall(prog))
+                    }
+                    Err(e) => {
+                        c.regs[0] = -e.errno();
+                        Ok(())
+                    }
+                }
+            },
+            CALL => match helpers.get(&(ins.imm as u32)) {
+                Some(helper) => {
+                    op.imm = self.helpers.len() as i64;
+                    self.helpers.push(helper.clone());
+                    |c, o, p| {
+                        let r = &c.regs;
+                        let args = [r[1], r[2], r[3], r[4], r[5]].map(|r| r as u64);
+                        let helper = &p.helpers[o.imm as usize];
+                        let res = match c.guest {
+                            true => helper.call_guest(args, c.cur_pc())?,
+                            false => {
+                                let [r1, r2, r3, r4, r5] = args;
+                                helper.call(r1, r2, r3, r4, r5)
+                            }
+                        };
+                        c.regs[0] = res as i64;
+                        Ok(())
+                    }
+                }
+                None => |_, o, _| Err(VmError::UnknownHelper(o.imm).into()),
+            },
+            EXIT => |c, _, _| match c.frames.pop() {
+                Some((pc, saved)) => {
+                    c.regs[6..=10].copy_from_slice(&saved);
+                    c.pc = pc;
+                    Ok(())
+                }
+                None => Err(Stop::Exit),
+            },
+            _ => |c, _, _| unreachable!("invalid instruction at pc {}", c.cur_pc()),
+        };
+        op
+    }
+}
+
+/// run the decoded `prog` from its first op, `regs` holds the arguments and the
+/// stack pointer, the run must be the one of this thread
+pub(crate) fn run(
+    prog: &Arc<Program>,
+    regs: &mut Regs,
+    div_by_zero: DivByZero,
+) -> Result<i64, VmError> {
+    let config = prog.config;
+    let mut cpu = Cpu {
+        regs,
+        pc: 0,
+        bounds: prog.memory_bound_check,
+        guest: config.guest_addresses(),
+        div_by_zero,
+        tail_call_cnt: 0,
+        max_call_frames: config.max_call_frames(),
+        frame_size: config.frame_size() as i64,
+        frames: Vec::new(),
+    };
+    let mut prog = prog.clone();
+
+    loop {
+        let decoded = &prog.decoded;
+        let op = &decoded.ops[cpu.pc];
+        cpu.pc += 1;
+        if let Err(stop) = (op.handler)(&mut cpu, op, decoded) {
+            match stop {
+                Stop::Exit => return Ok(cpu.regs[0]),
+                Stop::Fault(e) => return Err(e),
+                Stop::TailCall(next) => {
+                    enter_tail_call(&next);
+                    prog = next;
+                    cpu.pc = 0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use assembler::Instructions;
+
+    use crate::{
+        maps::{BpfMap, MapDef, MapType},
+        runtime::{DivByZero, VirtualMachine},
+        verifier::VerifierOptions,
+    };
+
+    // results of the matching and the threaded interpreters, which must agree
+    fn run_both(vm: &mut VirtualMachine, ctx: &mut [u8]) -> (String, String) {
+        vm.set_threaded(false);
+        let expected = vm.exec_on(ctx, false);
+        vm.set_threaded(true);
+        let res = vm.exec_on(ctx, false);
+        (format!("{:?}", expected), format!("{:?}", res))
+    }
+
+    #[test]
+    fn test_data_suite() {
+        let mut names: Vec<_> = fs::read_dir("../data")
+            .unwrap()
+            .filter_map(|e| {
+                let path = e.unwrap().path();
+                let name = path.file_name()?.to_str()?.strip_suffix(".data")?;
+                Some(name.to_string())
+            })
+            .collect();
+        names.sort();
+        assert!(!names.is_empty());
+
+        for name in names {
+            let prog = fs::read_to_string(format!("../data/{}.data", name)).unwrap();
+            let instructions = Instructions::from_asm(&prog).unwrap();
+            let mut vm = VirtualMachine::new(instructions.into());
+            let mut mem = [
+                0xaa, 0xbb, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0xcc, 0xdd,
+            ];
+            let (expected, res) = run_both(&mut vm, &mut mem);
+            assert_eq!(res, expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_faults_and_calls() {
+        let progs = [
+            "mov r0, 5\ndiv r0, 0\nexit",
+            "lddw r0, 0x100000007\nmov r1, 0\nmod32 r0, r1\nexit",
+            "mov r0, -7\nsmod r0, 0\nexit",
+            "ldmapfd r1, 3\nexit",
+            "call 1000\nexit",
+            "lcall -1\nexit",
+            // the callee writes 7 to the stack of its caller, r6 survives
+            "mov r6, 35
+mov r1, r10
+add r1, -8
+lcall +3
+ldxdw r0, [r10-8]
+add r0, r6
+exit
+stdw [r1], 7
+mov r6, 0
+stdw [r10-8], 1
+mov r0, 0
+exit",
+            // out of the context when its first byte is > 15
+            "ldxb r2, [r1]\nadd r1, r2\nstb [r10-1], 0\nldxb r0, [r1]\nexit",
+        ];
+        for prog in progs {
+            for (bound_check, div_by_zero) in [(false, DivByZero::Error), (true, DivByZero::Kernel)]
+            {
+                let instructions = Instructions::from_asm(prog).unwrap();
+                let mut vm = VirtualMachine::new(instructions.into());
+                vm.set_bound_check(bound_check);
+                vm.set_div_by_zero(div_by_zero);
+                let mut ctx = [0u8; 16];
+                ctx[0] = 5;
+                ctx[5] = 42;
+                let (expected, res) = run_both(&mut vm, &mut ctx);
+                assert_eq!(res, expected, "{prog}");
+                if prog.starts_with("ldxb") {
+                    ctx[0] = 16;
+                    let (expected, res) = run_both(&mut vm, &mut ctx);
+                    assert_eq!(res, expected, "{prog}");
+                    assert_eq!(res.starts_with("Err(OutOfBounds"), bound_check);
+                }
+            }
+        }
+
+        // accesses proven in bounds are not checked
+        let prog = "stb [r10-1], 0\nldxb r0, [r1]\nexit";
+        let instructions = Instructions::from_asm(prog).unwrap();
+        let mut vm = VirtualMachine::new(instructions.into());
+        let options = VerifierOptions {
+            ctx_size: 16,
+            runtime_checks: true,
+            ..Default::default()
+        };
+        vm.verify(&options).unwrap();
+        vm.set_bound_check(true);
+        vm.set_threaded(true);
+        let mut ctx = [7u8; 16];
+        assert_eq!(vm.exec_on(&mut ctx, false).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_tail_call() {
+        let prog = "mov r6, r1
+ldmapfd r2, 0
+ldxb r3, [r6]
+call 12
+exit";
+        let progs =
+            std::sync::Arc::new(BpfMap::new(MapDef::new(MapType::ProgArray, 4, 4, 4)).unwrap());
+        let target = Instructions::from_asm("ldxb r0, [r1+1]\nexit").unwrap();
+        let mut target = VirtualMachine::new(target.into());
+        progs
+            .set_program(1, target.load_program(false).unwrap())
+            .unwrap();
+
+        let instructions = Instructions::from_asm(prog).unwrap();
+        let mut vm = VirtualMachine::new(instructions.into());
+        vm.register_shared_map(progs);
+        for index in [1, 0, 7] {
+            let mut ctx = [index, 42];
+            let (expected, res) = run_both(&mut vm, &mut ctx);
+            assert_eq!(res, expected);
+        }
+        vm.set_threaded(true);
+        assert_eq!(vm.exec_on(&mut [1, 42], false).unwrap(), 42);
+    }
+}
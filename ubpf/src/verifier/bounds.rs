@@ -0,0 +1,380 @@
+use assembler::op::*;
+
+use super::{
+    state::{RegState, RegType},
+    tnum::Tnum,
+};
+
+fn bounded(var_off: Tnum, (smin, smax): (i64, i64), (umin, umax): (u64, u64)) -> RegState {
+    let mut reg = RegState {
+        var_off,
+        smin,
+        smax,
+        umin,
+        umax,
+        ..RegState::scalar()
+    };
+    reg.sync_bounds();
+    reg
+}
+
+const SIGNED: (i64, i64) = (i64::MIN, i64::MAX);
+const UNSIGNED: (u64, u64) = (0, u64::MAX);
+
+/// result of the alu operation `op` on two scalars, `src` is the immediate
+/// as a constant for the `_IMM` variants
+pub(super) fn scalar_alu(op: u8, is64: bool, dst: &RegState, src: &RegState) -> RegState {
+    // alu32 works on the zero extended low halves, most operations then give
+    // the same low 32 bits as on 64 bits
+    let (dst, src) = if is64 {
+        (*dst, *src)
+    } else {
+        (dst.cast32(), src.cast32())
+    };
+    let shift = src
+        .is_const()
+        .then_some((src.var_off.value & if is64 { 63 } else { 31 }) as u32);
+
+    let result = match (op, shift) {
+        (EBPF_ADD, _) => bounded(
+            dst.var_off + src.var_off,
+            dst.smin
+                .checked_add(src.smin)
+                .zip(dst.smax.checked_add(src.smax))
+                .unwrap_or(SIGNED),
+            dst.umin
+                .checked_add(src.umin)
+                .zip(dst.umax.checked_add(src.umax))
+                .unwrap_or(UNSIGNED),
+        ),
+        (EBPF_SUB, _) => bounded(
+            dst.var_off - src.var_off,
+            dst.smin
+                .checked_sub(src.smax)
+                .zip(dst.smax.checked_sub(src.smin))
+                .unwrap_or(SIGNED),
+            if dst.umin >= src.umax {
+                (dst.umin - src.umax, dst.umax - src.umin)
+            } else {
+                UNSIGNED
+            },
+        ),
+        (EBPF_MUL, _) => {
+            let small = dst.umax <= u32::MAX as u64 && src.umax <= u32::MAX as u64;
+            bounded(
+                dst.var_off * src.var_off,
+                SIGNED,
+                if small {
+                    (dst.umin * src.umin, dst.umax * src.umax)
+                } else {
+                    UNSIGNED
+                },
+            )
+        }
+        // a division by zero gives 0 and a modulo by zero leaves dst as is
+        (EBPF_DIV, _) => bounded(
+            Tnum::unknown(),
+            SIGNED,
+            match dst.umax.checked_div(src.umin) {
+                Some(max) => (dst.umin / src.umax, max),
+                None => (0, dst.umax),
+            },
+        ),
+        (EBPF_MOD, _) => bounded(
+            Tnum::unknown(),
+            SIGNED,
+            if src.umin > 0 {
+                (0, dst.umax.min(src.umax - 1))
+            } else {
+                (0, dst.umax)
+            },
+        ),
+        (EBPF_AND, _) => bounded(
+            dst.var_off & src.var_off,
+            SIGNED,
+            (0, dst.umax.min(src.umax)),
+        ),
+        (EBPF_OR, _) => bounded(
+            dst.var_off | src.var_off,
+            SIGNED,
+            (dst.umin.max(src.umin), u64::MAX),
+        ),
+        (EBPF_XOR, _) => RegState::from_tnum(dst.var_off ^ src.var_off),
+        (EBPF_LSH, Some(shift)) => bounded(
+            dst.var_off << shift,
+            SIGNED,
+            if shift == 0 || dst.umax >> (64 - shift) == 0 {
+                (dst.umin << shift, dst.umax << shift)
+            } else {
+                UNSIGNED
+            },
+        ),
+        (EBPF_RSH, Some(shift)) => bounded(
+            dst.var_off >> shift,
+            SIGNED,
+            (dst.umin >> shift, dst.umax >> shift),
+        ),
+        // the sign of a 32 bits value is bit 31, not bit 63
+        (EBPF_ARSH, Some(shift)) if is64 => bounded(
+            dst.var_off.arshift(shift),
+            (dst.smin >> shift, dst.smax >> shift),
+            UNSIGNED,
+        ),
+        (EBPF_NEG, _) if dst.is_const() => RegState::constant(dst.var_off.value.wrapping_neg()),
+        _ => RegState::scalar(),
+    };
+
+    if is64 { result } else { result.cast32() }
+}
+
+/// `refine_jmp` for a jump on the low 32 bits, only the registers that fit
+/// in them are narrowed
+pub(super) fn refine_jmp32(
+    op: u8,
+    taken: bool,
+    dst: &RegState,
+    src: &RegState,
+) -> Option<(RegState, RegState)> {
+    if dst.ty != RegType::Scalar || src.ty != RegType::Scalar {
+        return Some((*dst, *src));
+    }
+    let (a, b) = (dst.cast32(), src.cast32());
+    // bit 31 is the sign, the 64 bits bounds only agree below it
+    let signed = matches!(op, EBPF_JSGT | EBPF_JSGE | EBPF_JSLT | EBPF_JSLE);
+    if signed && (a.umax > i32::MAX as u64 || b.umax > i32::MAX as u64) {
+        return Some((*dst, *src));
+    }
+    let (a, b) = refine_jmp(op, taken, &a, &b)?;
+    let narrowed = |old: &RegState, new: RegState| {
+        if old.umax <= u32::MAX as u64 {
+            new
+        } else {
+            *old
+        }
+    };
+    Some((narrowed(dst, a), narrowed(src, b)))
+}
+
+/// `dst` and `src` once the jump `op` between them is known to be `taken` or
+/// not, `None` when that never happens
+pub(super) fn refine_jmp(
+    op: u8,
+    taken: bool,
+    dst: &RegState,
+    src: &RegState,
+) -> Option<(RegState, RegState)> {
+    let (mut a, mut b) = (*dst, *src);
+    if a.ty != RegType::Scalar || b.ty != RegType::Scalar {
+        return Some((a, b));
+    }
+
+    // the condition that holds on this branch
+    let op = if taken {
+        op
+    } else {
+        match op {
+            EBPF_JEQ => EBPF_JNE,
+            EBPF_JNE => EBPF_JEQ,
+            EBPF_JGT => EBPF_JLE,
+            EBPF_JLE => EBPF_JGT,
+            EBPF_JGE => EBPF_JLT,
+            EBPF_JLT => EBPF_JGE,
+            EBPF_JSGT => EBPF_JSLE,
+            EBPF_JSLE => EBPF_JSGT,
+            EBPF_JSGE => EBPF_JSLT,
+            EBPF_JSLT => EBPF_JSGE,
+            // no bit of src set in dst
+            _ => {
+                if b.is_const() {
+                    let bits = b.var_off.value;
+                    if a.var_off.value & bits != 0 {
+                        return None;
+                    }
+                    a.var_off = Tnum {
+                        value: a.var_off.value & !bits,
+                        mask: a.var_off.mask & !bits,
+                    };
+                    a.sync_bounds();
+                }
+                return Some((a, b));
+            }
+        }
+    };
+
+    // a < b is b > a
+    let (op, swapped) = match op {
+        EBPF_JLT => (EBPF_JGT, true),
+        EBPF_JLE => (EBPF_JGE, true),
+        EBPF_JSLT => (EBPF_JSGT, true),
+        EBPF_JSLE => (EBPF_JSGE, true),
+        op => (op, false),
+    };
+    let (x, y) = if swapped {
+        (&mut b, &mut a)
+    } else {
+        (&mut a, &mut b)
+    };
+
+    match op {
+        EBPF_JEQ => {
+            let known = !(x.var_off.mask | y.var_off.mask);
+            if (x.var_off.value ^ y.var_off.value) & known != 0 {
+                return None;
+            }
+            x.var_off = x.var_off.intersect(y.var_off);
+            x.smin = x.smin.max(y.smin);
+            x.smax = x.smax.min(y.smax);
+            x.umin = x.umin.max(y.umin);
+            x.umax = x.umax.min(y.umax);
+            x.sync_bounds();
+            *y = *x;
+        }
+        EBPF_JNE => {
+            if !exclude(x, y) || !exclude(y, x) {
+                return None;
+            }
+        }
+        EBPF_JGT => {
+            x.umin = x.umin.max(y.umin.checked_add(1)?);
+            y.umax = y.umax.min(x.umax.checked_sub(1)?);
+        }
+        EBPF_JGE => {
+            x.umin = x.umin.max(y.umin);
+            y.umax = y.umax.min(x.umax);
+        }
+        EBPF_JSGT => {
+            x.smin = x.smin.max(y.smin.checked_add(1)?);
+            y.smax = y.smax.min(x.smax.checked_sub(1)?);
+        }
+        EBPF_JSGE => {
+            x.smin = x.smin.max(y.smin);
+            y.smax = y.smax.min(x.smax);
+        }
+        // some bit of src set in dst
+        _ => {
+            if y.is_const() && x.var_off.max() & y.var_off.value == 0 {
+                return None;
+            }
+        }
+    }
+
+    for reg in [&mut a, &mut b] {
+        if reg.is_empty() {
+            return None;
+        }
+        reg.sync_bounds();
+        if reg.is_empty() {
+            return None;
+        }
+    }
+    Some((a, b))
+}
+
+// `x` is not the constant `y`, only a constant at the edge of the range of
+// `x` narrows it, false when `x` is that constant
+fn exclude(x: &mut RegState, y: &RegState) -> bool {
+    if !y.is_const() {
+        return true;
+    }
+    let k = y.var_off.value;
+    if x.is_const() || (x.umin == x.umax && x.umin == k) {
+        return x.var_off.value != k && x.umin != k;
+    }
+    if x.umin == k {
+        x.umin += 1;
+    } else if x.umax == k {
+        x.umax -= 1;
+    }
+    if x.smin == k as i64 {
+        x.smin += 1;
+    } else if x.smax == k as i64 {
+        x.smax -= 1;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(umin: u64, umax: u64) -> RegState {
+        bounded(Tnum::unknown(), SIGNED, (umin, umax))
+    }
+
+    #[test]
+    fn test_scalar_alu() {
+        let byte = RegState::sized(1);
+        assert_eq!((byte.umin, byte.umax), (0, 255));
+
+        let r = scalar_alu(EBPF_ADD, true, &byte, &RegState::constant(8));
+        assert_eq!((r.umin, r.umax, r.smin, r.smax), (8, 263, 8, 263));
+        let r = scalar_alu(EBPF_LSH, true, &byte, &RegState::constant(2));
+        assert_eq!((r.umin, r.umax), (0, 1020));
+        assert_eq!(
+            r.var_off,
+            Tnum {
+                value: 0,
+                mask: 0x3fc
+            }
+        );
+        let r = scalar_alu(EBPF_AND, true, &RegState::scalar(), &RegState::constant(7));
+        assert_eq!((r.umin, r.umax), (0, 7));
+        let r = scalar_alu(EBPF_MOD, true, &RegState::scalar(), &RegState::constant(10));
+        assert_eq!((r.umin, r.umax), (0, 9));
+
+        // 1 - 2 wraps, only on 32 bits is it still below 2^32
+        let r = scalar_alu(
+            EBPF_SUB,
+            true,
+            &RegState::constant(1),
+            &RegState::constant(2),
+        );
+        assert_eq!(r, RegState::constant(u64::MAX));
+        let r = scalar_alu(
+            EBPF_SUB,
+            false,
+            &RegState::constant(1),
+            &RegState::constant(2),
+        );
+        assert_eq!(r, RegState::constant(u32::MAX as u64));
+        let r = scalar_alu(EBPF_ADD, false, &RegState::scalar(), &RegState::constant(1));
+        assert_eq!((r.umin, r.umax), (0, u32::MAX as u64));
+    }
+
+    #[test]
+    fn test_refine_jmp() {
+        let k = RegState::constant(16);
+        let (r, _) = refine_jmp(EBPF_JGT, false, &RegState::scalar(), &k).unwrap();
+        assert_eq!((r.umin, r.umax), (0, 16));
+        let (r, _) = refine_jmp(EBPF_JGE, true, &range(0, 100), &k).unwrap();
+        assert_eq!((r.umin, r.umax), (16, 100));
+        let (r, _) = refine_jmp(EBPF_JSLT, true, &RegState::scalar(), &k).unwrap();
+        assert_eq!((r.smin, r.smax), (i64::MIN, 15));
+        let (r, _) = refine_jmp(EBPF_JEQ, true, &RegState::scalar(), &k).unwrap();
+        assert_eq!(r, k);
+        let (r, _) = refine_jmp(EBPF_JNE, true, &range(16, 20), &k).unwrap();
+        assert_eq!((r.umin, r.umax), (17, 20));
+        let (r, _) = refine_jmp(EBPF_JSET, false, &RegState::sized(1), &k).unwrap();
+        assert_eq!(
+            r.var_off,
+            Tnum {
+                value: 0,
+                mask: 0xef
+            }
+        );
+
+        // branches that are never taken
+        assert!(refine_jmp(EBPF_JGT, true, &range(0, 16), &k).is_none());
+        assert!(refine_jmp(EBPF_JEQ, false, &k, &k).is_none());
+        assert!(
+            refine_jmp(
+                EBPF_JEQ,
+                true,
+                &RegState::sized(1),
+                &RegState::constant(256)
+            )
+            .is_none()
+        );
+        assert!(refine_jmp(EBPF_JSET, true, &RegState::constant(1), &k).is_none());
+    }
+}
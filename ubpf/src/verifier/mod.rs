@@ -0,0 +1,1832 @@
+mod bounds;
+mod state;
+mod tnum;
+
+use std::{cell::Cell, collections::HashMap, fmt::Write, rc::Rc};
+
+use assembler::{Instruction, IsaVersion, class::*, op::*};
+pub use state::{CallFrame, RegState, RegType, SlotType, StackSlot, VerifierState};
+pub use tnum::Tnum;
+
+use crate::{
+    error::VerifierError,
+    helpers::{ArgType, HelperProto, RetType},
+    maps::MapDef,
+    runtime::{MEM_SIZE, VirtualMachine, VmConfig},
+};
+
+// same limit as the kernel's BPF_COMPLEXITY_LIMIT_INSNS
+const COMPLEXITY_LIMIT_INSNS: usize = 1_000_000;
+// states kept at a loop header, that is about as many iterations of the loop
+const MAX_LOOP_STATES: usize = 1024;
+// pointers never move further than this from what they point to
+const MAX_POINTER_OFF: i64 = 1 << 29;
+
+#[derive(Debug, Clone)]
+pub struct VerifierOptions {
+    /// bytes of context r1 points to, accesses past it are rejected
+    pub ctx_size: usize,
+    /// accept the context accesses that cannot be proven in bounds instead,
+    /// leaving them to the runtime bounds check
+    pub runtime_checks: bool,
+    /// accept backward jumps, as long as every loop is proven to end
+    pub bounded_loops: bool,
+    /// 0 for no log, 1 logs the instructions walked and the states where
+    /// paths start, 2 the state before every instruction too
+    pub log_level: u32,
+    /// instructions newer than this version are rejected
+    pub isa: IsaVersion,
+}
+
+impl Default for VerifierOptions {
+    fn default() -> Self {
+        Self {
+            ctx_size: MEM_SIZE,
+            runtime_checks: false,
+            bounded_loops: true,
+            log_level: 0,
+            isa: IsaVersion::default(),
+        }
+    }
+}
+
+/// bytes of stack a function uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackUsage {
+    /// first instruction of the function
+    pub func: usize,
+    /// deepest byte of its own frame it reads or writes
+    pub depth: usize,
+    /// with the frames of the functions it calls
+    pub total: usize,
+}
+
+/// state saved at a prune point, with the number of paths walked from it
+/// that have not ended yet, as the kernel's `branches`
+struct Checkpoint {
+    state: VerifierState,
+    branches: Cell<u32>,
+    parent: Option<Rc<Checkpoint>>,
+}
+
+/// a path ended, the checkpoints it went through may now be complete
+fn end_path(mut parent: Option<Rc<Checkpoint>>) {
+    while let Some(checkpoint) = parent {
+        let branches = checkpoint.branches.get() - 1;
+        checkpoint.branches.set(branches);
+        if branches > 0 {
+            break;
+        }
+        parent = checkpoint.parent.clone();
+    }
+}
+
+/// walks every path of a program, tracking the type of each register and
+/// stack slot, to reject the programs that could misbehave at runtime
+pub struct Verifier<'a> {
+    insns: &'a [Instruction],
+    maps: Vec<MapDef>,
+    helpers: HashMap<u32, HelperProto>,
+    options: VerifierOptions,
+    config: VmConfig,
+    // states already reached at each jump target
+    explored: Vec<Vec<Rc<Checkpoint>>>,
+    prune_points: Vec<bool>,
+    // targets of backward jumps
+    loop_headers: Vec<bool>,
+    // accesses left to the runtime bounds check on some path
+    runtime_checked: Vec<bool>,
+    // first instruction of each function, then the deepest stack byte each
+    // of them uses and the functions each of them calls
+    funcs: Vec<usize>,
+    stack_depth: Vec<usize>,
+    callees: Vec<Vec<usize>>,
+    insn_processed: usize,
+    next_id: u32,
+    log: String,
+}
+
+impl<'a> Verifier<'a> {
+    /// `maps` is indexed by the fd of `ldmapfd`, `helpers` are the prototypes
+    /// of the ids `call` may use
+    pub fn new(
+        insns: &'a [Instruction],
+        maps: Vec<MapDef>,
+        helpers: HashMap<u32, HelperProto>,
+        options: VerifierOptions,
+    ) -> Self {
+        Self {
+            insns,
+            maps,
+            helpers,
+            options,
+            config: VmConfig::default(),
+            explored: vec![Vec::new(); insns.len()],
+            prune_points: vec![false; insns.len()],
+            loop_headers: vec![false; insns.len()],
+            runtime_checked: vec![false; insns.len()],
+            funcs: vec![0],
+            stack_depth: vec![0],
+            callees: vec![Vec::new()],
+            insn_processed: 0,
+            next_id: 0,
+            log: String::new(),
+        }
+    }
+
+    /// check the stack and the calls against the sizes of `config` instead
+    /// of the default ones
+    pub fn with_config(mut self, config: VmConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn verify(&mut self) -> Result<(), VerifierError> {
+        let res = self
+            .check_cfg()
+            .and_then(|_| self.do_check())
+            .and_then(|_| self.check_stack_depth());
+        let processed = self.insn_processed;
+        // as the kernel, the stack of each function
+        let depths = self.stack_depth.iter().map(|d| d.to_string());
+        let depths = depths.collect::<Vec<_>>().join("+");
+        match &res {
+            Ok(()) => self.log(
+                1,
+                format_args!(
+                    "processed {} insns (limit {}) stack depth {}",
+                    processed, COMPLEXITY_LIMIT_INSNS, depths
+                ),
+            ),
+            Err(e) => self.log(1, format_args!("{}", e)),
+        }
+        res
+    }
+
+    /// what `verify` went through, as much as `log_level` asks for
+    pub fn log_text(&self) -> &str {
+        &self.log
+    }
+
+    fn log(&mut self, level: u32, line: std::fmt::Arguments) {
+        if self.options.log_level >= level {
+            let _ = writeln!(self.log, "{}", line);
+        }
+    }
+
+    /// for each instruction, whether it is a load or a store proven to stay
+    /// in bounds on every path, meaningful once `verify` succeeded
+    pub fn safe_accesses(&self) -> Vec<bool> {
+        self.insns
+            .iter()
+            .zip(self.runtime_checked.iter())
+            .map(|(ins, &checked)| {
+                matches!(ins.class(), EBPF_CLS_LDX | EBPF_CLS_ST | EBPF_CLS_STX) && !checked
+            })
+            .collect()
+    }
+
+    /// stack used by each function, meaningful once `verify` succeeded
+    pub fn stack_usage(&self) -> Vec<StackUsage> {
+        let mut usage: Vec<Option<StackUsage>> = vec![None; self.funcs.len()];
+        for func in 0..self.funcs.len() {
+            self.compute_stack_usage(func, &mut usage);
+        }
+        usage.into_iter().flatten().collect()
+    }
+
+    fn compute_stack_usage(&self, func: usize, usage: &mut [Option<StackUsage>]) -> usize {
+        if let Some(u) = usage[func] {
+            return u.total;
+        }
+        let depth = self.stack_depth[func];
+        // no recursion, `verify` made sure of it
+        let total = self.callees[func]
+            .iter()
+            .map(|&callee| self.config.frame_size() + self.compute_stack_usage(callee, usage))
+            .fold(depth, usize::max);
+        usage[func] = Some(StackUsage {
+            func: self.funcs[func],
+            depth,
+            total,
+        });
+        total
+    }
+
+    // a function making calls only has its own frame, the stack below it is
+    // for the callees
+    fn check_stack_depth(&self) -> Result<(), VerifierError> {
+        let limit = self.config.frame_size();
+        for (func, &depth) in self.stack_depth.iter().enumerate() {
+            if !self.callees[func].is_empty() && depth > limit {
+                return Err(VerifierError::StackTooDeep {
+                    func: self.funcs[func],
+                    depth,
+                    limit,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// checks that do not depend on register states: opcodes, registers,
+    /// jump targets, loops only if allowed, no recursion and no unreachable
+    /// code
+    fn check_cfg(&mut self) -> Result<(), VerifierError> {
+        let len = self.insns.len();
+        if len == 0 {
+            return Err(VerifierError::FallThrough);
+        }
+        let in_range = |start: usize, end: usize, target: i64| {
+            target >= start as i64
+                && target < end as i64
+                && (target == 0 || self.insns[target as usize - 1].op != LDDW)
+        };
+
+        // functions start at 0 and at the targets of local calls
+        for (pc, ins) in self.insns.iter().enumerate() {
+            if ins.op == CALL && ins.src_reg() == EBPF_PSEUDO_CALL {
+                let target = pc as i64 + ins.imm + 1;
+                if !in_range(0, len, target) {
+                    return Err(VerifierError::JumpOutOfRange { pc, target });
+                }
+                self.funcs.push(target as usize);
+            }
+        }
+        self.funcs.sort_unstable();
+        self.funcs.dedup();
+        self.stack_depth = vec![0; self.funcs.len()];
+        self.callees = vec![Vec::new(); self.funcs.len()];
+        // the call instructions, to report recursions
+        let mut calls = vec![Vec::new(); self.funcs.len()];
+
+        let mut successors = vec![Vec::new(); len];
+        let mut pc = 0;
+        while pc < len {
+            let ins = &self.insns[pc];
+            check_insn(pc, ins, self.options.isa)?;
+            if ins.op == LDDW {
+                let next = self.insns.get(pc + 1);
+                if !next.is_some_and(|n| n.op == 0 && n.regs == 0 && n.offset == 0) {
+                    return Err(VerifierError::InvalidLddw { pc });
+                }
+            }
+            let next = pc + if ins.op == LDDW { 2 } else { 1 };
+            // jumps stay in their function, which ends with an exit or a ja
+            let func = self.func_index(pc);
+            let (start, end) = (
+                self.funcs[func],
+                self.funcs.get(func + 1).map_or(len, |&f| f),
+            );
+
+            if ins.op == CALL && ins.src_reg() == EBPF_PSEUDO_CALL {
+                let callee = self.func_index((pc as i64 + ins.imm + 1) as usize);
+                self.callees[func].push(callee);
+                calls[func].push(pc);
+                successors[pc].push(self.funcs[callee]);
+            }
+
+            if matches!(ins.class(), EBPF_CLS_JMP | EBPF_CLS_JMP32)
+                && ins.op != CALL
+                && ins.op != EXIT
+            {
+                let target = pc as i64 + ins.jump_offset() + 1;
+                if !in_range(start, end, target) {
+                    return Err(VerifierError::JumpOutOfRange { pc, target });
+                }
+                let unconditional = matches!(ins.op, JA | JA32);
+                if !unconditional && next >= end {
+                    return Err(VerifierError::FallThrough);
+                }
+                let target = target as usize;
+                if target <= pc {
+                    if !self.options.bounded_loops {
+                        return Err(VerifierError::BackEdge {
+                            from: pc,
+                            to: target,
+                        });
+                    }
+                    self.loop_headers[target] = true;
+                }
+                self.prune_points[target] = true;
+                successors[pc].push(target);
+                if !unconditional {
+                    self.prune_points[next] = true;
+                    successors[pc].push(next);
+                }
+            } else if ins.op != EXIT {
+                if next >= end {
+                    return Err(VerifierError::FallThrough);
+                }
+                successors[pc].push(next);
+            }
+            pc = next;
+        }
+
+        // a function may not call itself, even through others
+        let mut visited = vec![false; self.funcs.len()];
+        for func in 0..self.funcs.len() {
+            self.check_recursion(func, &calls, &mut Vec::new(), &mut visited)?;
+        }
+
+        // every instruction has to be reachable from the first one
+        let mut reachable = vec![false; len];
+        let mut pending = vec![0];
+        while let Some(pc) = pending.pop() {
+            if !reachable[pc] {
+                reachable[pc] = true;
+                pending.extend(successors[pc].iter());
+            }
+        }
+        let mut pc = 0;
+        while pc < len {
+            if !reachable[pc] {
+                return Err(VerifierError::Unreachable { pc });
+            }
+            pc += if self.insns[pc].op == LDDW { 2 } else { 1 };
+        }
+        Ok(())
+    }
+
+    /// index in `funcs` of the function `pc` belongs to
+    fn func_index(&self, pc: usize) -> usize {
+        self.funcs.partition_point(|&start| start <= pc) - 1
+    }
+
+    // depth first walk of the calls from `func`, `path` are the functions
+    // being walked
+    fn check_recursion(
+        &self,
+        func: usize,
+        calls: &[Vec<usize>],
+        path: &mut Vec<usize>,
+        visited: &mut [bool],
+    ) -> Result<(), VerifierError> {
+        if visited[func] {
+            return Ok(());
+        }
+        path.push(func);
+        for (&callee, &pc) in self.callees[func].iter().zip(calls[func].iter()) {
+            if path.contains(&callee) {
+                return Err(VerifierError::RecursiveCall { pc });
+            }
+            self.check_recursion(callee, calls, path, visited)?;
+        }
+        path.pop();
+        visited[func] = true;
+        Ok(())
+    }
+
+    /// depth first walk of every path, with the state of the registers and
+    /// the stack, loops are walked one iteration after the other until they
+    /// exit
+    fn do_check(&mut self) -> Result<(), VerifierError> {
+        // the jump a path starts from, if any
+        let state = VerifierState::with_stack_size(self.config.stack_size());
+        let mut pending = vec![(None, 0, state, None)];
+        'paths: while let Some((from, mut pc, mut state, mut parent)) = pending.pop() {
+            match from {
+                Some(from) => self.log(1, format_args!("from {} to {}: {}", from, pc, state)),
+                None => self.log(1, format_args!("{}: {}", pc, state)),
+            }
+            // the state was just logged
+            let mut path_start = true;
+            loop {
+                self.insn_processed += 1;
+                if self.insn_processed > COMPLEXITY_LIMIT_INSNS {
+                    return Err(VerifierError::TooComplex(self.insn_processed));
+                }
+                if self.prune_points[pc] {
+                    let mut same = self.explored[pc].iter().filter(|c| c.state == state);
+                    // a path reaching here in the same state was already
+                    // found safe
+                    if same.clone().any(|c| c.branches.get() == 0) {
+                        self.log(1, format_args!("{}: safe", pc));
+                        end_path(parent);
+                        continue 'paths;
+                    }
+                    // back in the state a loop iteration started from, that
+                    // is still being walked, the loop may never exit
+                    if self.loop_headers[pc] && same.next().is_some() {
+                        return Err(VerifierError::InfiniteLoop { pc });
+                    }
+                    if self.loop_headers[pc] && self.explored[pc].len() >= MAX_LOOP_STATES {
+                        return Err(VerifierError::LoopTooComplex { pc });
+                    }
+                    let checkpoint = Rc::new(Checkpoint {
+                        state: state.clone(),
+                        branches: Cell::new(1),
+                        parent: parent.take(),
+                    });
+                    self.explored[pc].push(checkpoint.clone());
+                    parent = Some(checkpoint);
+                }
+
+                let ins = self.insns[pc];
+                if !std::mem::take(&mut path_start) {
+                    self.log(2, format_args!("{}: {}", pc, state));
+                }
+                self.log(1, format_args!("{}: ({:02x}) {:?}", pc, ins.op, ins));
+                match ins.class() {
+                    EBPF_CLS_ALU | EBPF_CLS_ALU64 => self.check_alu(pc, &ins, &mut state)?,
+                    EBPF_CLS_LDX => {
+                        let dst = self.check_mem_access(
+                            pc,
+                            &mut state,
+                            ins.src_reg(),
+                            ins.offset,
+                            mem_size(&ins),
+                            None,
+                        )?;
+                        state.regs[ins.dst_reg() as usize] = if ins.op & 0xe0 == EBPF_MODE_MEMSX {
+                            sign_extend(dst, mem_size(&ins) as u32 * 8)
+                        } else {
+                            dst
+                        };
+                    }
+                    EBPF_CLS_ST | EBPF_CLS_STX => {
+                        let value = if ins.class() == EBPF_CLS_STX {
+                            check_reg_init(pc, &state, ins.src_reg())?
+                        } else {
+                            RegState::constant(ins.imm as u64)
+                        };
+                        self.check_mem_access(
+                            pc,
+                            &mut state,
+                            ins.dst_reg(),
+                            ins.offset,
+                            mem_size(&ins),
+                            Some(value),
+                        )?;
+                    }
+                    EBPF_CLS_LD => {
+                        state.regs[ins.dst_reg() as usize] = self.check_lddw(pc, &ins)?;
+                        pc += 2;
+                        continue;
+                    }
+                    _ => match ins.op {
+                        JA | JA32 => {
+                            pc = (pc as i64 + ins.jump_offset() + 1) as usize;
+                            continue;
+                        }
+                        EXIT => {
+                            let r0 = check_reg_init(pc, &state, 0)?;
+                            let frameno = state.frameno();
+                            // what a function returns may be a pointer, but
+                            // not to its own stack
+                            let dangling = r0.ty == RegType::PtrToStack && r0.frameno == frameno;
+                            if (frameno == 0 && r0.ty.is_pointer()) || dangling {
+                                return Err(VerifierError::PointerLeak { pc, reg: 0 });
+                            }
+                            match state.pop_frame() {
+                                Some(ret_pc) => {
+                                    pc = ret_pc;
+                                    continue;
+                                }
+                                None => {
+                                    end_path(parent);
+                                    continue 'paths;
+                                }
+                            }
+                        }
+                        CALL if ins.src_reg() == EBPF_PSEUDO_CALL => {
+                            let frames = state.callers.len() + 2;
+                            if frames > self.config.max_call_frames() {
+                                return Err(VerifierError::CallStackTooDeep { pc, frames });
+                            }
+                            let target = (pc as i64 + ins.imm + 1) as usize;
+                            state.push_frame(target, pc + 1);
+                            pc = target;
+                            continue;
+                        }
+                        CALL => self.check_call(pc, &ins, &mut state)?,
+                        _ => {
+                            let target = (pc as i64 + ins.offset as i64 + 1) as usize;
+                            match self.check_cond_jmp(pc, &ins, &state)? {
+                                (Some(branch), Some(fall_through)) => {
+                                    if let Some(parent) = &parent {
+                                        parent.branches.set(parent.branches.get() + 1);
+                                    }
+                                    pending.push((Some(pc), target, branch, parent.clone()));
+                                    state = fall_through;
+                                }
+                                (Some(branch), None) => {
+                                    pc = target;
+                                    state = branch;
+                                    continue;
+                                }
+                                (None, Some(fall_through)) => state = fall_through,
+                                (None, None) => {
+                                    end_path(parent);
+                                    continue 'paths;
+                                }
+                            }
+                        }
+                    },
+                }
+                pc += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn new_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn check_lddw(&self, pc: usize, ins: &Instruction) -> Result<RegState, VerifierError> {
+        if ins.src_reg() != EBPF_PSEUDO_MAP_FD {
+            return Ok(RegState::scalar());
+        }
+        if usize::try_from(ins.imm)
+            .ok()
+            .is_none_or(|fd| fd >= self.maps.len())
+        {
+            return Err(VerifierError::InvalidMapFd { pc, fd: ins.imm });
+        }
+        Ok(RegState {
+            map: Some(ins.imm as u32),
+            ..RegState::pointer(RegType::ConstPtrToMap, 0)
+        })
+    }
+
+    fn check_alu(
+        &mut self,
+        pc: usize,
+        ins: &Instruction,
+        state: &mut VerifierState,
+    ) -> Result<(), VerifierError> {
+        let is64 = ins.class() == EBPF_CLS_ALU64;
+        let op = ins.op & ALU_OP_MASK;
+        let dst = ins.dst_reg();
+        let src = if ins.op & EBPF_SRC_REG != 0 && op != EBPF_END {
+            Some(check_reg_init(pc, state, ins.src_reg())?)
+        } else {
+            None
+        };
+
+        let imm = if is64 {
+            RegState::constant(ins.imm as u64)
+        } else {
+            RegState::constant(ins.imm as u32 as u64)
+        };
+        if op == EBPF_MOV {
+            state.regs[dst as usize] = match src {
+                Some(src) if ins.offset != 0 => {
+                    let value = sign_extend(src, ins.offset as u32);
+                    if is64 { value } else { value.cast32() }
+                }
+                Some(src) if is64 => src,
+                Some(src) if src.ty == RegType::Scalar => src.cast32(),
+                // a 32 bits copy of a pointer is just a number
+                Some(_) => RegState::scalar(),
+                None => imm,
+            };
+            return Ok(());
+        }
+
+        let dst_reg = check_reg_init(pc, state, dst)?;
+        let src_ty = src.map_or(RegType::Scalar, |s| s.ty);
+        if !dst_reg.ty.is_pointer() && !src_ty.is_pointer() {
+            state.regs[dst as usize] = if op == EBPF_END {
+                RegState::scalar()
+            } else if ins.offset == EBPF_OFF_SIGNED {
+                // signed division and modulo are not tracked
+                let value = RegState::scalar();
+                if is64 { value } else { value.cast32() }
+            } else {
+                bounds::scalar_alu(op, is64, &dst_reg, &src.unwrap_or(imm))
+            };
+            return Ok(());
+        }
+
+        // pointer arithmetic
+        let (reg, ty) = if dst_reg.ty.is_pointer() {
+            (dst, dst_reg.ty)
+        } else {
+            (ins.src_reg(), src_ty)
+        };
+        let prohibited = Err(VerifierError::PointerArithmetic { pc, reg, ty });
+        if !is64 || !dst_reg.ty.is_pointer() {
+            return prohibited;
+        }
+        let result = match (op, src) {
+            // difference of two pointers to the same memory
+            (EBPF_SUB, Some(src))
+                if src.ty == dst_reg.ty
+                    && src.map == dst_reg.map
+                    && matches!(
+                        src.ty,
+                        RegType::PtrToCtx | RegType::PtrToStack | RegType::PtrToMapValue
+                    ) =>
+            {
+                RegState::scalar()
+            }
+            // pointer moved by a number, constant or within bounds
+            (EBPF_ADD | EBPF_SUB, _)
+                if src_ty == RegType::Scalar
+                    && matches!(
+                        dst_reg.ty,
+                        RegType::PtrToCtx | RegType::PtrToStack | RegType::PtrToMapValue
+                    ) =>
+            {
+                let delta = src.unwrap_or(RegState::constant(ins.imm as u64));
+                let add = op == EBPF_ADD;
+                if delta.is_const() {
+                    let value = delta.var_off.value as i64;
+                    let off = if add {
+                        dst_reg.off.checked_add(value)
+                    } else {
+                        dst_reg.off.checked_sub(value)
+                    };
+                    match off {
+                        Some(off) if off.abs() < MAX_POINTER_OFF => RegState { off, ..dst_reg },
+                        _ => return prohibited,
+                    }
+                } else {
+                    if delta.smin <= -MAX_POINTER_OFF || delta.smax >= MAX_POINTER_OFF {
+                        return prohibited;
+                    }
+                    let (var_off, smin, smax) = if add {
+                        (
+                            dst_reg.var_off + delta.var_off,
+                            dst_reg.smin + delta.smin,
+                            dst_reg.smax + delta.smax,
+                        )
+                    } else {
+                        (
+                            dst_reg.var_off - delta.var_off,
+                            dst_reg.smin - delta.smax,
+                            dst_reg.smax - delta.smin,
+                        )
+                    };
+                    if smin <= -MAX_POINTER_OFF || smax >= MAX_POINTER_OFF {
+                        return prohibited;
+                    }
+                    let mut ptr = RegState {
+                        var_off,
+                        smin,
+                        smax,
+                        umin: 0,
+                        umax: u64::MAX,
+                        ..dst_reg
+                    };
+                    ptr.sync_bounds();
+                    ptr
+                }
+            }
+            _ => return prohibited,
+        };
+        state.regs[dst as usize] = result;
+        Ok(())
+    }
+
+    /// check an access of `size` bytes at `reg + off`, `value` is what gets
+    /// stored, the loaded value is returned for loads
+    fn check_mem_access(
+        &mut self,
+        pc: usize,
+        state: &mut VerifierState,
+        reg: u8,
+        off: i16,
+        size: usize,
+        value: Option<RegState>,
+    ) -> Result<RegState, VerifierError> {
+        let ptr = check_reg_init(pc, state, reg)?;
+        // range of offsets the access may start at
+        let min_off = ptr.off.saturating_add(ptr.smin).saturating_add(off as i64);
+        let max_off = ptr.off.saturating_add(ptr.smax).saturating_add(off as i64);
+        let in_bounds = |(lo, hi): (i64, i64)| {
+            if min_off < lo {
+                Err(min_off)
+            } else if max_off + size as i64 > hi {
+                Err(max_off)
+            } else {
+                Ok(())
+            }
+        };
+        let out_of_bounds = |off| VerifierError::OutOfBounds {
+            pc,
+            ty: ptr.ty,
+            off,
+            size,
+        };
+        let leak = value.is_some_and(|v| v.ty.is_pointer());
+
+        match ptr.ty {
+            RegType::PtrToCtx => {
+                if let Err(off) = in_bounds((0, self.options.ctx_size as i64)) {
+                    if !self.options.runtime_checks {
+                        return Err(out_of_bounds(off));
+                    }
+                    self.runtime_checked[pc] = true;
+                }
+            }
+            RegType::PtrToMapValue => {
+                let value_size = self.maps[ptr.map.unwrap() as usize].value_size;
+                in_bounds((0, value_size as i64)).map_err(out_of_bounds)?;
+            }
+            RegType::PtrToStack => {
+                // the frames of the callers are above this one
+                let limit =
+                    self.config.stack_size() - ptr.frameno as usize * self.config.frame_size();
+                in_bounds((-(limit as i64), 0)).map_err(out_of_bounds)?;
+                let func = match state.callers.get(ptr.frameno as usize) {
+                    Some(frame) => frame.func,
+                    None => state.func,
+                };
+                let func = self.func_index(func);
+                self.stack_depth[func] = self.stack_depth[func].max(-min_off as usize);
+
+                let src = self.insns[pc].src_reg();
+                let end = max_off + size as i64;
+                let stack = state.stack_of(ptr.frameno);
+                return match value {
+                    Some(value) if ptr.is_const() => {
+                        store_stack(pc, stack, min_off, size, value, src)?;
+                        Ok(RegState::scalar())
+                    }
+                    Some(value) => {
+                        // any byte of the range may be written
+                        if value.ty.is_pointer() {
+                            return Err(VerifierError::PointerLeak { pc, reg: src });
+                        }
+                        for off in min_off..end {
+                            let (slot, _) = VerifierState::stack_byte(off);
+                            destroy_spill(&mut stack[slot]);
+                        }
+                        Ok(RegState::scalar())
+                    }
+                    None => {
+                        check_stack_init(pc, stack, min_off, end)?;
+                        if ptr.is_const() {
+                            Ok(load_stack(stack, min_off, size))
+                        } else {
+                            Ok(RegState::sized(size))
+                        }
+                    }
+                };
+            }
+            ty => return Err(VerifierError::InvalidMemAccess { pc, reg, ty }),
+        }
+        if leak {
+            return Err(VerifierError::PointerLeak {
+                pc,
+                reg: self.insns[pc].src_reg(),
+            });
+        }
+        Ok(RegState::sized(size))
+    }
+
+    fn check_call(
+        &mut self,
+        pc: usize,
+        ins: &Instruction,
+        state: &mut VerifierState,
+    ) -> Result<(), VerifierError> {
+        let proto = match self.helpers.get(&(ins.imm as u32)) {
+            Some(&proto) if ins.src_reg() == 0 => proto,
+            _ => return Err(VerifierError::UnknownHelper { pc, id: ins.imm }),
+        };
+
+        // map of the `ConstMapPtr` argument
+        let mut map = None;
+        for (i, &arg) in proto.args.iter().enumerate() {
+            if arg == ArgType::DontCare {
+                continue;
+            }
+            let regno = i as u8 + 1;
+            let reg = check_reg_init(pc, state, regno)?;
+            let invalid = || VerifierError::InvalidHelperArg {
+                pc,
+                reg: regno,
+                ty: reg.ty,
+            };
+            match arg {
+                ArgType::DontCare | ArgType::Anything => {}
+                ArgType::Scalar | ArgType::ConstSize => {
+                    if reg.ty != RegType::Scalar {
+                        return Err(invalid());
+                    }
+                }
+                ArgType::PtrToCtx => {
+                    if reg.ty != RegType::PtrToCtx {
+                        return Err(invalid());
+                    }
+                }
+                ArgType::ConstMapPtr => {
+                    if reg.ty != RegType::ConstPtrToMap {
+                        return Err(invalid());
+                    }
+                    map = reg.map;
+                }
+                ArgType::PtrToMapKey | ArgType::PtrToMapValue => {
+                    let def = &self.maps[map.ok_or_else(invalid)? as usize];
+                    let size = if arg == ArgType::PtrToMapKey {
+                        def.key_size
+                    } else {
+                        def.value_size
+                    };
+                    self.check_helper_mem(pc, state, regno, size as usize)?;
+                }
+                ArgType::PtrToMem => {
+                    // the size is the next argument, it has to be bounded
+                    let size = check_reg_init(pc, state, regno + 1)?;
+                    if size.ty != RegType::Scalar
+                        || size.umin == 0
+                        || size.umax > self.config.mem_size() as u64
+                    {
+                        return Err(VerifierError::InvalidHelperArg {
+                            pc,
+                            reg: regno + 1,
+                            ty: size.ty,
+                        });
+                    }
+                    self.check_helper_mem(pc, state, regno, size.umax as usize)?;
+                }
+            }
+        }
+
+        let r0 = match proto.ret {
+            RetType::Integer => RegState::scalar(),
+            RetType::MapValueOrNull => RegState {
+                map,
+                id: self.new_id(),
+                ..RegState::pointer(RegType::PtrToMapValueOrNull, 0)
+            },
+        };
+
+        // r1-r5 are caller saved
+        for reg in 1..=5 {
+            state.regs[reg] = RegState::not_init();
+        }
+        state.regs[0] = r0;
+        Ok(())
+    }
+
+    // `size` bytes the helper reads through `regno`
+    fn check_helper_mem(
+        &mut self,
+        pc: usize,
+        state: &mut VerifierState,
+        regno: u8,
+        size: usize,
+    ) -> Result<(), VerifierError> {
+        let ty = state.regs[regno as usize].ty;
+        if !matches!(
+            ty,
+            RegType::PtrToStack | RegType::PtrToMapValue | RegType::PtrToCtx
+        ) {
+            return Err(VerifierError::InvalidHelperArg { pc, reg: regno, ty });
+        }
+        self.check_mem_access(pc, state, regno, 0, size, None)?;
+        Ok(())
+    }
+
+    /// check the operands and return the states of the jump and of the fall
+    /// through, `None` for a branch that is never taken
+    fn check_cond_jmp(
+        &self,
+        pc: usize,
+        ins: &Instruction,
+        state: &VerifierState,
+    ) -> Result<(Option<VerifierState>, Option<VerifierState>), VerifierError> {
+        let dst = check_reg_init(pc, state, ins.dst_reg())?;
+        let src = if ins.op & EBPF_SRC_REG != 0 {
+            Some(check_reg_init(pc, state, ins.src_reg())?)
+        } else {
+            None
+        };
+
+        let op = ins.op & ALU_OP_MASK;
+        let is64 = ins.class() == EBPF_CLS_JMP;
+        let imm = if is64 {
+            ins.imm as u64
+        } else {
+            ins.imm as u32 as u64
+        };
+        let [branch, fall_through] = [true, false].map(|taken| {
+            let mut next = state.clone();
+            let other = src.unwrap_or(RegState::constant(imm));
+            let (dst, src) = if is64 {
+                bounds::refine_jmp(op, taken, &dst, &other)?
+            } else {
+                bounds::refine_jmp32(op, taken, &dst, &other)?
+            };
+            next.regs[ins.dst_reg() as usize] = dst;
+            if ins.op & EBPF_SRC_REG != 0 {
+                next.regs[ins.src_reg() as usize] = src;
+            }
+            Some(next)
+        });
+        let (mut branch, mut fall_through) = (branch, fall_through);
+
+        if dst.ty == RegType::PtrToMapValueOrNull
+            && is64
+            && src.is_none()
+            && ins.imm == 0
+            && (op == EBPF_JEQ || op == EBPF_JNE)
+        {
+            if let Some(branch) = branch.as_mut() {
+                branch.mark_map_value(dst.id, op == EBPF_JEQ);
+            }
+            if let Some(fall_through) = fall_through.as_mut() {
+                fall_through.mark_map_value(dst.id, op == EBPF_JNE);
+            }
+        }
+        Ok((branch, fall_through))
+    }
+}
+
+impl VirtualMachine {
+    /// statically check the program against the maps and helpers registered
+    /// so far, the accesses proven in bounds then skip the runtime bounds
+    /// check
+    pub fn verify(&mut self, options: &VerifierOptions) -> Result<(), VerifierError> {
+        self.verify_with_log(options).0
+    }
+
+    /// `verify`, along with the log of the verifier
+    pub fn verify_with_log(
+        &mut self,
+        options: &VerifierOptions,
+    ) -> (Result<(), VerifierError>, String) {
+        let maps = self.maps().iter().map(|map| *map.def()).collect();
+        let helpers = self.helper_protos();
+        // the context is never larger than the vm accepts
+        let mut options = options.clone();
+        options.ctx_size = options.ctx_size.min(self.config().max_ctx_size());
+        let ctx_size = options.ctx_size;
+        let mut verifier =
+            Verifier::new(self.instructions(), maps, helpers, options).with_config(*self.config());
+        let res = verifier.verify();
+        let safe_accesses = res.is_ok().then(|| verifier.safe_accesses());
+        let log = verifier.log;
+        if let Some(safe_accesses) = safe_accesses {
+            self.set_safe_accesses(safe_accesses, ctx_size);
+        }
+        (res, log)
+    }
+}
+
+fn check_reg_init(pc: usize, state: &VerifierState, reg: u8) -> Result<RegState, VerifierError> {
+    let r = state.regs[reg as usize];
+    if r.ty == RegType::NotInit {
+        return Err(VerifierError::UninitRegister { pc, reg });
+    }
+    Ok(r)
+}
+
+fn mem_size(ins: &Instruction) -> usize {
+    match ins.op & 0x18 {
+        EBPF_SIZE_B => 1,
+        EBPF_SIZE_H => 2,
+        EBPF_SIZE_W => 4,
+        _ => 8,
+    }
+}
+
+// `value` extended to 64 bits from its low `bits` bits as a signed number,
+// only known when its sign bit is clear
+fn sign_extend(value: RegState, bits: u32) -> RegState {
+    if value.ty == RegType::Scalar && value.umax < 1 << (bits - 1) {
+        value
+    } else {
+        RegState::scalar()
+    }
+}
+
+// `reg` holds `value` for `stx`
+fn store_stack(
+    pc: usize,
+    stack: &mut [StackSlot],
+    off: i64,
+    size: usize,
+    value: RegState,
+    reg: u8,
+) -> Result<(), VerifierError> {
+    let aligned = size == 8 && off % 8 == 0;
+    if value.ty.is_pointer() && !aligned {
+        // pointers can only be spilled whole to an aligned slot
+        return Err(VerifierError::PointerLeak { pc, reg });
+    }
+    if aligned {
+        // numbers are spilled too, to keep their bounds
+        let (slot, _) = VerifierState::stack_byte(off);
+        stack[slot] = StackSlot {
+            bytes: [SlotType::Spill; 8],
+            spilled: value,
+        };
+        return Ok(());
+    }
+
+    for i in 0..size as i64 {
+        let (slot, byte) = VerifierState::stack_byte(off + i);
+        let slot = &mut stack[slot];
+        destroy_spill(slot);
+        slot.bytes[byte] = SlotType::Misc;
+    }
+    Ok(())
+}
+
+// what is left of a partly overwritten spill is no longer a register
+fn destroy_spill(slot: &mut StackSlot) {
+    if slot.bytes.contains(&SlotType::Spill) {
+        slot.bytes = slot.bytes.map(|b| {
+            if b == SlotType::Spill {
+                SlotType::Misc
+            } else {
+                b
+            }
+        });
+        slot.spilled = RegState::not_init();
+    }
+}
+
+fn load_stack(stack: &[StackSlot], off: i64, size: usize) -> RegState {
+    let (slot, _) = VerifierState::stack_byte(off);
+    let slot = &stack[slot];
+    if size == 8 && off % 8 == 0 && slot.bytes == [SlotType::Spill; 8] {
+        return slot.spilled;
+    }
+    RegState::sized(size)
+}
+
+// every byte of `[start, end)` has been written
+fn check_stack_init(
+    pc: usize,
+    stack: &[StackSlot],
+    start: i64,
+    end: i64,
+) -> Result<(), VerifierError> {
+    let never_written = (start..end).any(|off| {
+        let (slot, byte) = VerifierState::stack_byte(off);
+        stack[slot].bytes[byte] == SlotType::Invalid
+    });
+    if never_written {
+        return Err(VerifierError::InvalidStackRead {
+            pc,
+            off: start,
+            size: (end - start) as usize,
+        });
+    }
+    Ok(())
+}
+
+/// opcode and registers of a single instruction
+fn check_insn(pc: usize, ins: &Instruction, isa: IsaVersion) -> Result<(), VerifierError> {
+    const KNOWN_OPS: &[u8] = &[
+        ADD_IMM, ADD_REG, SUB_IMM, SUB_REG, MUL_IMM, MUL_REG, DIV_IMM, DIV_REG, OR_IMM, OR_REG,
+        AND_IMM, AND_REG, LSH_IMM, LSH_REG, RSH_IMM, RSH_REG, NEG32, MOD_IMM, MOD_REG, XOR_IMM,
+        XOR_REG, MOV_IMM, MOV_REG, ARSH_IMM, ARSH_REG, LE, BE, ADD64_IMM, ADD64_REG, SUB64_IMM,
+        SUB64_REG, MUL64_IMM, MUL64_REG, DIV64_IMM, DIV64_REG, OR64_IMM, OR64_REG, AND64_IMM,
+        AND64_REG, LSH64_IMM, LSH64_REG, RSH64_IMM, RSH64_REG, NEG64, MOD64_IMM, MOD64_REG,
+        XOR64_IMM, XOR64_REG, MOV64_IMM, MOV64_REG, ARSH64_IMM, ARSH64_REG, LDXW, LDXH, LDXB,
+        LDXDW, STW, STH, STB, STDW, STXW, STXH, STXB, STXDW, LDDW, JA, JEQ_IMM, JEQ_REG, JGT_IMM,
+        JGT_REG, JGE_IMM, JGE_REG, JSET_REG, JSET_IMM, JNE_IMM, JNE_REG, JSGT_IMM, JSGT_REG,
+        JSGE_IMM, JSGE_REG, CALL, EXIT, JLT_IMM, JLT_REG, JLE_IMM, JLE_REG, JSLT_IMM, JSLT_REG,
+        JSLE_IMM, JSLE_REG, JEQ32_IMM, JEQ32_REG, JGT32_IMM, JGT32_REG, JGE32_IMM, JGE32_REG,
+        JSET32_IMM, JSET32_REG, JNE32_IMM, JNE32_REG, JSGT32_IMM, JSGT32_REG, JSGE32_IMM,
+        JSGE32_REG, JLT32_IMM, JLT32_REG, JLE32_IMM, JLE32_REG, JSLT32_IMM, JSLT32_REG, JSLE32_IMM,
+        JSLE32_REG, BSWAP, LDXSW, LDXSH, LDXSB, JA32,
+    ];
+    if !KNOWN_OPS.contains(&ins.op) {
+        return Err(VerifierError::UnknownOpcode { pc, op: ins.op });
+    }
+    let required = IsaVersion::of(ins);
+    if required > isa {
+        return Err(VerifierError::UnsupportedInsn { pc, required, isa });
+    }
+    // the offset of an alu instruction picks its signed or sign extending
+    // variant
+    if matches!(ins.class(), EBPF_CLS_ALU | EBPF_CLS_ALU64) {
+        let valid = match ins.op & ALU_OP_MASK {
+            EBPF_DIV | EBPF_MOD => matches!(ins.offset, 0 | EBPF_OFF_SIGNED),
+            EBPF_MOV if ins.op & EBPF_SRC_REG != 0 => {
+                matches!(ins.offset, 0 | 8 | 16)
+                    || (ins.offset == 32 && ins.class() == EBPF_CLS_ALU64)
+            }
+            _ => ins.offset == 0,
+        };
+        if !valid {
+            return Err(VerifierError::InvalidOffset {
+                pc,
+                off: ins.offset,
+            });
+        }
+    }
+    for reg in [ins.dst_reg(), ins.src_reg()] {
+        if reg > 10 {
+            return Err(VerifierError::InvalidRegister { pc, reg });
+        }
+    }
+    let writes_dst = matches!(
+        ins.class(),
+        EBPF_CLS_ALU | EBPF_CLS_ALU64 | EBPF_CLS_LDX | EBPF_CLS_LD
+    );
+    if writes_dst && ins.dst_reg() == 10 {
+        return Err(VerifierError::ReadOnlyFramePointer { pc });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use assembler::Instructions;
+
+    use super::*;
+    use crate::{
+        maps::{BpfMap, MapType},
+        runtime::{STACK_FRAME_SIZE, STACK_SIZE},
+        std_helpers::StdHelpers,
+    };
+
+    // one hash map, 4 bytes keys and 8 bytes values
+    fn verify_with(prog: &str, options: VerifierOptions) -> Result<(), VerifierError> {
+        let instructions = Instructions::from_asm(prog).unwrap();
+        let mut vm = VirtualMachine::new(instructions.into());
+        vm.register_map(BpfMap::new(MapDef::new(MapType::Hash, 4, 8, 16)).unwrap());
+        vm.verify(&options)
+    }
+
+    fn verify(prog: &str) -> Result<(), VerifierError> {
+        verify_with(prog, VerifierOptions::default())
+    }
+
+    const LOOKUP: &str = "stw [r10-4], 0
+ldmapfd r1, 0
+mov r2, r10
+add r2, -4
+call 1
+";
+
+    #[test]
+    fn test_accept() {
+        // null checked lookup through a copy of r0
+        let prog = format!("{LOOKUP}mov r6, r0\nmov r0, 0\njeq r6, 0, +1\nldxdw r0, [r6+0]\nexit");
+        assert_eq!(verify(&prog), Ok(()));
+
+        // a spilled ctx pointer is still one once filled
+        let prog = "stxdw [r10-8], r1
+ldxdw r2, [r10-8]
+ldxw r0, [r2+12]
+exit";
+        assert_eq!(verify(prog), Ok(()));
+
+        let buffer = std::fs::read("../data/hello_kern.o").unwrap();
+        let instructions = Instructions::from_elf(&buffer, "bpf_prog").unwrap();
+        let mut vm = VirtualMachine::new(instructions.into());
+        vm.register_std_helpers(StdHelpers::new());
+        assert_eq!(vm.verify(&VerifierOptions::default()), Ok(()));
+    }
+
+    #[test]
+    fn test_reject_registers() {
+        assert_eq!(
+            verify("mov r0, r3\nexit"),
+            Err(VerifierError::UninitRegister { pc: 0, reg: 3 })
+        );
+        assert_eq!(
+            verify("exit"),
+            Err(VerifierError::UninitRegister { pc: 0, reg: 0 })
+        );
+        assert_eq!(
+            verify("mov r0, r1\nexit"),
+            Err(VerifierError::PointerLeak { pc: 1, reg: 0 })
+        );
+        assert_eq!(
+            verify("mov r10, 0\nmov r0, 0\nexit"),
+            Err(VerifierError::ReadOnlyFramePointer { pc: 0 })
+        );
+        // r1-r5 are gone after a call
+        assert_eq!(
+            verify(&format!("{LOOKUP}ldxw r0, [r1]\nexit")),
+            Err(VerifierError::UninitRegister { pc: 6, reg: 1 })
+        );
+        assert_eq!(
+            verify("call 1000\nexit"),
+            Err(VerifierError::UnknownHelper { pc: 0, id: 1000 })
+        );
+        assert_eq!(
+            verify("mov r1, 0\ncall 1\nmov r0, 0\nexit"),
+            Err(VerifierError::InvalidHelperArg {
+                pc: 1,
+                reg: 1,
+                ty: RegType::Scalar
+            })
+        );
+    }
+
+    #[test]
+    fn test_helper_args() {
+        let prog = "stw [r10-4], 0
+stdw [r10-16], 0
+ldmapfd r1, 0
+mov r2, r10
+add r2, -4
+mov r3, r10
+add r3, -16
+mov r4, 0
+call 2
+exit";
+        assert_eq!(verify(prog), Ok(()));
+        assert_eq!(
+            verify("ldmapfd r2, 0\nmov r3, 0\ncall 12\nmov r0, 0\nexit"),
+            Ok(())
+        );
+
+        // the key has never been written
+        assert_eq!(
+            verify("ldmapfd r1, 0\nmov r2, r10\nadd r2, -4\ncall 1\nmov r0, 0\nexit"),
+            Err(VerifierError::InvalidStackRead {
+                pc: 4,
+                off: -4,
+                size: 4
+            })
+        );
+        // 4 bytes key past the top of the stack
+        assert_eq!(
+            verify("stw [r10-4], 0\nldmapfd r1, 0\nmov r2, r10\nadd r2, -2\ncall 1\nexit"),
+            Err(VerifierError::OutOfBounds {
+                pc: 5,
+                ty: RegType::PtrToStack,
+                off: -2,
+                size: 4
+            })
+        );
+        assert_eq!(
+            verify("ldmapfd r1, 0\nmov r2, 0\ncall 1\nexit"),
+            Err(VerifierError::InvalidHelperArg {
+                pc: 3,
+                reg: 2,
+                ty: RegType::Scalar
+            })
+        );
+        assert_eq!(
+            verify("ldmapfd r2, 0\nmov r1, 0\nmov r3, 0\ncall 12\nexit"),
+            Err(VerifierError::InvalidHelperArg {
+                pc: 4,
+                reg: 1,
+                ty: RegType::Scalar
+            })
+        );
+        // no flags
+        assert_eq!(
+            verify(&prog.replace("mov r4, 0\n", "")),
+            Err(VerifierError::UninitRegister { pc: 8, reg: 4 })
+        );
+    }
+
+    #[test]
+    fn test_isa_v4() {
+        let prog = "ldxsb r2, [r1]
+sdiv r2, 3
+movsx8 r3, r2
+bswap16 r3
+mov r0, 0
+jeq r3, 0, +1
+gotol +1
+mov r0, 1
+exit";
+        assert_eq!(verify(prog), Ok(()));
+
+        // a sign extended pointer is a number
+        assert_eq!(
+            verify("movsx32 r2, r10\nldxdw r0, [r2]\nexit"),
+            Err(VerifierError::InvalidMemAccess {
+                pc: 1,
+                reg: 2,
+                ty: RegType::Scalar
+            })
+        );
+        // with a clear sign bit, a byte keeps its bounds
+        let prog = "ldxb r2, [r1]
+and r2, 0x7f
+movsx8 r2, r2
+add r1, r2
+ldxb r0, [r1]
+exit";
+        let ctx = |ctx_size| VerifierOptions {
+            ctx_size,
+            ..Default::default()
+        };
+        assert_eq!(verify_with(prog, ctx(128)), Ok(()));
+        assert!(matches!(
+            verify_with(&prog.replace("and r2, 0x7f", "and r2, 0xff"), ctx(256)),
+            Err(VerifierError::PointerArithmetic { pc: 3, .. })
+        ));
+
+        // offsets other than the signed and sign extending ones
+        let invalid = |pc: usize, op: u8, off: i16| {
+            let mut insns: Vec<Instruction> = Instructions::from_asm("mov r0, 0\nmov r1, 0\nexit")
+                .unwrap()
+                .into();
+            insns[pc].op = op;
+            insns[pc].offset = off;
+            let mut vm = VirtualMachine::new(insns);
+            vm.verify(&VerifierOptions::default())
+        };
+        assert_eq!(
+            invalid(1, ADD64_IMM, 1),
+            Err(VerifierError::InvalidOffset { pc: 1, off: 1 })
+        );
+        assert_eq!(
+            invalid(1, MOV_REG, 32),
+            Err(VerifierError::InvalidOffset { pc: 1, off: 32 })
+        );
+        assert_eq!(invalid(1, MOV64_REG, 32), Ok(()));
+
+        let v3 = VerifierOptions {
+            isa: IsaVersion::V3,
+            ..Default::default()
+        };
+        assert_eq!(
+            verify_with("mov r0, 0\nbswap16 r0\nexit", v3.clone()),
+            Err(VerifierError::UnsupportedInsn {
+                pc: 1,
+                required: IsaVersion::V4,
+                isa: IsaVersion::V3
+            })
+        );
+        assert_eq!(verify_with("mov r0, 0\nbe16 r0\nexit", v3), Ok(()));
+    }
+
+    #[test]
+    fn test_reject_memory() {
+        // no null check
+        let prog = format!("{LOOKUP}ldxdw r0, [r0+0]\nexit");
+        assert_eq!(
+            verify(&prog),
+            Err(VerifierError::InvalidMemAccess {
+                pc: 6,
+                reg: 0,
+                ty: RegType::PtrToMapValueOrNull
+            })
+        );
+        // null checked, past the value
+        let prog = format!("{LOOKUP}jeq r0, 0, +1\nldxdw r0, [r0+4]\nexit");
+        assert_eq!(
+            verify(&prog),
+            Err(VerifierError::OutOfBounds {
+                pc: 7,
+                ty: RegType::PtrToMapValue,
+                off: 4,
+                size: 8
+            })
+        );
+        // only the null branch is a scalar
+        let prog = format!("{LOOKUP}jne r0, 0, +1\nldxb r0, [r0+0]\nmov r0, 0\nexit");
+        assert!(matches!(
+            verify(&prog),
+            Err(VerifierError::InvalidMemAccess {
+                ty: RegType::Scalar,
+                ..
+            })
+        ));
+
+        let small_ctx = VerifierOptions {
+            ctx_size: 16,
+            ..Default::default()
+        };
+        assert_eq!(
+            verify_with("ldxdw r0, [r1+12]\nexit", small_ctx),
+            Err(VerifierError::OutOfBounds {
+                pc: 0,
+                ty: RegType::PtrToCtx,
+                off: 12,
+                size: 8
+            })
+        );
+        assert!(matches!(
+            verify("stb [r10], 1\nmov r0, 0\nexit"),
+            Err(VerifierError::OutOfBounds { off: 0, .. })
+        ));
+        assert!(matches!(
+            verify("stb [r10-4097], 1\nmov r0, 0\nexit"),
+            Err(VerifierError::OutOfBounds { off: -4097, .. })
+        ));
+
+        // pointers do not go to the context, nor half to the stack
+        assert_eq!(
+            verify("stxdw [r1], r10\nmov r0, 0\nexit"),
+            Err(VerifierError::PointerLeak { pc: 0, reg: 10 })
+        );
+        assert_eq!(
+            verify("stxw [r10-8], r1\nmov r0, 0\nexit"),
+            Err(VerifierError::PointerLeak { pc: 0, reg: 1 })
+        );
+        // a partly overwritten spill is no longer a pointer
+        let prog = "stxdw [r10-8], r1
+stb [r10-8], 0
+ldxdw r2, [r10-8]
+ldxw r0, [r2]
+exit";
+        assert!(matches!(
+            verify(prog),
+            Err(VerifierError::InvalidMemAccess {
+                pc: 3,
+                ty: RegType::Scalar,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_reject_pointer_arithmetic() {
+        let prog = format!("{LOOKUP}add r0, 4\nexit");
+        assert_eq!(
+            verify(&prog),
+            Err(VerifierError::PointerArithmetic {
+                pc: 6,
+                reg: 0,
+                ty: RegType::PtrToMapValueOrNull
+            })
+        );
+        assert_eq!(
+            verify("mul r1, 2\nmov r0, 0\nexit"),
+            Err(VerifierError::PointerArithmetic {
+                pc: 0,
+                reg: 1,
+                ty: RegType::PtrToCtx
+            })
+        );
+        assert_eq!(
+            verify("add32 r1, 2\nmov r0, 0\nexit"),
+            Err(VerifierError::PointerArithmetic {
+                pc: 0,
+                reg: 1,
+                ty: RegType::PtrToCtx
+            })
+        );
+        // scalar + pointer and pointer - other pointer
+        assert!(verify("mov r0, 0\nadd r0, r1\nmov r0, 0\nexit").is_err());
+        assert!(verify("sub r1, r10\nmov r0, 0\nexit").is_err());
+        assert_eq!(
+            verify("mov r2, r10\nadd r2, -8\nsub r2, r10\nmov r0, r2\nexit"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_bounds() {
+        // a byte of the context, checked against 59, indexes 64 bytes of it
+        let prog = "mov r0, 0
+ldxb r2, [r1]
+jgt r2, 59, +3
+mov r3, r1
+add r3, r2
+ldxb r0, [r3+4]
+exit";
+        let ctx = |ctx_size| VerifierOptions {
+            ctx_size,
+            ..Default::default()
+        };
+        assert_eq!(verify_with(prog, ctx(64)), Ok(()));
+        assert_eq!(
+            verify_with(prog, ctx(63)),
+            Err(VerifierError::OutOfBounds {
+                pc: 5,
+                ty: RegType::PtrToCtx,
+                off: 63,
+                size: 1
+            })
+        );
+        // a byte fits in 32 bits, the 32 bits check bounds it too
+        assert_eq!(verify_with(&prog.replace("jgt", "jgt32"), ctx(64)), Ok(()));
+        // not the high bits of 64 bits
+        assert!(matches!(
+            verify_with(
+                &prog.replace("jgt", "jgt32").replace("ldxb r2", "ldxdw r2"),
+                ctx(64)
+            ),
+            Err(VerifierError::PointerArithmetic { pc: 4, .. })
+        ));
+
+        // the low 3 bits index a stack slot
+        let prog = "ldxdw r2, [r1]
+and r2, 7
+mov r3, r10
+add r3, -16
+add r3, r2
+stb [r3], 1
+mov r0, 0
+exit";
+        assert_eq!(verify(prog), Ok(()));
+        assert!(matches!(
+            verify(&prog.replace("and r2, 7", "and r2, 31")),
+            Err(VerifierError::OutOfBounds { pc: 5, off: 15, .. })
+        ));
+        // unbounded
+        assert!(matches!(
+            verify(&prog.replace("and r2, 7", "add r2, 1")),
+            Err(VerifierError::PointerArithmetic { pc: 4, .. })
+        ));
+
+        // the dereference of a number is never reached
+        let prog = "mov r0, 0
+mov r2, 5
+jgt r2, 10, +1
+exit
+ldxdw r0, [r2]
+exit";
+        assert_eq!(verify(prog), Ok(()));
+        // a spilled number keeps its bounds
+        let prog = "mov r2, 8
+stxdw [r10-8], r2
+ldxdw r3, [r10-8]
+add r1, r3
+ldxdw r0, [r1+0]
+exit";
+        assert_eq!(verify_with(prog, ctx(16)), Ok(()));
+    }
+
+    #[test]
+    fn test_safe_accesses() {
+        let prog = "ldxb r2, [r1]
+add r1, r2
+stb [r10-1], 0
+ldxb r0, [r1]
+exit";
+        let small_ctx = VerifierOptions {
+            ctx_size: 16,
+            ..Default::default()
+        };
+        assert!(matches!(
+            verify_with(prog, small_ctx.clone()),
+            Err(VerifierError::OutOfBounds { pc: 3, .. })
+        ));
+
+        let runtime_checks = VerifierOptions {
+            runtime_checks: true,
+            ..small_ctx
+        };
+        let insns: Vec<Instruction> = Instructions::from_asm(prog).unwrap().into();
+        let mut verifier = Verifier::new(&insns, Vec::new(), HashMap::new(), runtime_checks);
+        assert_eq!(verifier.verify(), Ok(()));
+        assert_eq!(
+            verifier.safe_accesses(),
+            vec![true, false, true, false, false]
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        // for (i = 0; i < 16; i++) r0 += ctx[i]
+        let prog = "mov r0, 0
+mov r2, 0
+mov r3, r1
+add r3, r2
+ldxb r4, [r3]
+add r0, r4
+add r2, 1
+jlt r2, 16, -6
+exit";
+        let ctx = |ctx_size| VerifierOptions {
+            ctx_size,
+            ..Default::default()
+        };
+        assert_eq!(verify_with(prog, ctx(16)), Ok(()));
+        assert!(matches!(
+            verify_with(prog, ctx(15)),
+            Err(VerifierError::OutOfBounds { pc: 4, off: 15, .. })
+        ));
+
+        // the same loop tested at the top
+        let prog = "mov r0, 0
+mov r2, 0
+jge r2, 8, +3
+add r0, r2
+add r2, 1
+ja -4
+exit";
+        assert_eq!(verify(prog), Ok(()));
+
+        assert_eq!(
+            verify("mov r0, 0\njeq r0, 0, -1\nexit"),
+            Err(VerifierError::InfiniteLoop { pc: 1 })
+        );
+        // nothing changes once the loop went through once
+        assert_eq!(
+            verify("ldxdw r0, [r1]\njne r0, 0, -1\nexit"),
+            Err(VerifierError::InfiniteLoop { pc: 1 })
+        );
+        // i only grows, i > 0 holds forever
+        assert_eq!(
+            verify("mov r0, 1\nadd r0, 1\njgt r0, 0, -2\nexit"),
+            Err(VerifierError::LoopTooComplex { pc: 1 })
+        );
+    }
+
+    #[test]
+    fn test_log() {
+        let prog = "ldxb r2, [r1]
+mov r0, 0
+jgt r2, 7, +1
+ldxdw r0, [r2]
+exit";
+        let verify_log = |log_level| {
+            let mut vm = VirtualMachine::new(Instructions::from_asm(prog).unwrap().into());
+            let options = VerifierOptions {
+                log_level,
+                ..Default::default()
+            };
+            vm.verify_with_log(&options)
+        };
+
+        let (res, log) = verify_log(0);
+        assert!(res.is_err() && log.is_empty());
+        let (res, log) = verify_log(1);
+        assert_eq!(
+            res,
+            Err(VerifierError::InvalidMemAccess {
+                pc: 3,
+                reg: 2,
+                ty: RegType::Scalar
+            })
+        );
+        assert_eq!(
+            log,
+            "0: R1=ctx() R10=fp0
+0: (71) ldxb r2, [r1]
+1: (b7) mov r0, 0
+2: (25) jgt r2, 7, +1
+3: (79) ldxdw r0, [r2]
+3: R2 invalid mem access 'scalar'
+"
+        );
+        let (_, log) = verify_log(2);
+        assert!(log.contains("3: R0=0 R1=ctx() R2=scalar(umax=7,var_off=(0x0; 0x7)) R10=fp0\n"));
+    }
+
+    #[test]
+    fn test_stack() {
+        assert_eq!(
+            verify("ldxdw r0, [r10-8]\nexit"),
+            Err(VerifierError::InvalidStackRead {
+                pc: 0,
+                off: -8,
+                size: 8
+            })
+        );
+        assert!(matches!(
+            verify("stw [r10-8], 0\nldxdw r0, [r10-8]\nexit"),
+            Err(VerifierError::InvalidStackRead { pc: 1, .. })
+        ));
+        // only written on one path
+        let prog = "ldxdw r2, [r1]
+jeq r2, 0, +1
+stdw [r10-8], 1
+ldxdw r0, [r10-8]
+exit";
+        assert!(matches!(
+            verify(prog),
+            Err(VerifierError::InvalidStackRead { pc: 3, .. })
+        ));
+
+        // the callee writes to the stack of its caller through r1
+        let prog = "mov r1, r10
+add r1, -8
+lcall +2
+ldxdw r0, [r10-8]
+exit
+stdw [r1], 7
+mov r0, 0
+exit";
+        let insns: Vec<Instruction> = Instructions::from_asm(prog).unwrap().into();
+        let mut verifier = Verifier::new(&insns, Vec::new(), HashMap::new(), Default::default());
+        assert_eq!(verifier.verify(), Ok(()));
+        assert_eq!(
+            verifier.stack_usage(),
+            vec![
+                StackUsage {
+                    func: 0,
+                    depth: 8,
+                    total: STACK_FRAME_SIZE
+                },
+                StackUsage {
+                    func: 5,
+                    depth: 0,
+                    total: 0
+                }
+            ]
+        );
+        assert!(matches!(
+            verify(&prog.replace("stdw [r1], 7", "mov r1, 0")),
+            Err(VerifierError::InvalidStackRead { pc: 3, .. })
+        ));
+        assert_eq!(
+            verify(&prog.replace("mov r0, 0", "mov r0, r10")),
+            Err(VerifierError::PointerLeak { pc: 7, reg: 0 })
+        );
+        // r6-r9 survive the call, r1-r5 do not
+        assert_eq!(
+            verify(&prog.replace("ldxdw r0, [r10-8]", "mov r0, r2")),
+            Err(VerifierError::UninitRegister { pc: 3, reg: 2 })
+        );
+
+        // a function making calls only has its own frame
+        let prog = "stdw [r10-520], 0
+lcall +2
+mov r0, 0
+exit
+mov r0, 0
+exit";
+        assert_eq!(
+            verify(prog),
+            Err(VerifierError::StackTooDeep {
+                func: 0,
+                depth: 520,
+                limit: STACK_FRAME_SIZE,
+            })
+        );
+        assert_eq!(verify(&prog.replace("520", "512")), Ok(()));
+        // the callee gets what is left below
+        let deep = |off| format!("lcall +1\nexit\nstdw [r10-{off}], 0\nmov r0, 0\nexit");
+        assert_eq!(verify(&deep(STACK_SIZE - STACK_FRAME_SIZE)), Ok(()));
+        assert!(matches!(
+            verify(&deep(STACK_SIZE - STACK_FRAME_SIZE + 8)),
+            Err(VerifierError::OutOfBounds { pc: 2, .. })
+        ));
+
+        assert_eq!(
+            verify("mov r0, 0\nlcall -2\nexit"),
+            Err(VerifierError::RecursiveCall { pc: 1 })
+        );
+        assert_eq!(
+            verify("mov r0, 0\nlcall +1\nja +1\nexit"),
+            Err(VerifierError::JumpOutOfRange { pc: 2, target: 4 })
+        );
+    }
+
+    #[test]
+    fn test_vm_config() {
+        // two frames of 128 bytes
+        let config = VmConfig::new().with_stack_size(256).with_max_call_frames(2);
+        let verify = |prog: &str| {
+            let insns: Vec<Instruction> = Instructions::from_asm(prog).unwrap().into();
+            Verifier::new(&insns, Vec::new(), HashMap::new(), Default::default())
+                .with_config(config)
+                .verify()
+        };
+        assert_eq!(verify("stdw [r10-256], 0\nmov r0, 0\nexit"), Ok(()));
+        assert!(matches!(
+            verify("stdw [r10-264], 0\nmov r0, 0\nexit"),
+            Err(VerifierError::OutOfBounds { pc: 0, .. })
+        ));
+        assert_eq!(
+            verify("stdw [r10-136], 0\nlcall +1\nexit\nmov r0, 0\nexit"),
+            Err(VerifierError::StackTooDeep {
+                func: 0,
+                depth: 136,
+                limit: 128
+            })
+        );
+        assert_eq!(
+            verify("lcall +1\nexit\nlcall +1\nexit\nmov r0, 0\nexit"),
+            Err(VerifierError::CallStackTooDeep { pc: 2, frames: 3 })
+        );
+
+        // the context is no larger than the vm accepts
+        let mut vm = VirtualMachine::with_config(
+            Instructions::from_asm("ldxdw r0, [r1+64]\nexit")
+                .unwrap()
+                .into(),
+            VmConfig::new().with_mem_size(64).with_max_ctx_size(64),
+        )
+        .unwrap();
+        assert!(matches!(
+            vm.verify(&Default::default()),
+            Err(VerifierError::OutOfBounds { pc: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_reject_cfg() {
+        let no_loops = VerifierOptions {
+            bounded_loops: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            verify_with("mov r0, 0\njlt r0, 4, -2\nexit", no_loops),
+            Err(VerifierError::BackEdge { from: 1, to: 0 })
+        );
+        assert_eq!(
+            verify("mov r0, 0\nja +2\nexit"),
+            Err(VerifierError::JumpOutOfRange { pc: 1, target: 4 })
+        );
+        assert_eq!(
+            verify("mov r0, 0\nexit\nexit"),
+            Err(VerifierError::Unreachable { pc: 2 })
+        );
+        assert_eq!(verify("mov r0, 0"), Err(VerifierError::FallThrough));
+        assert_eq!(
+            verify("ja +1\nlddw r0, 1\nexit"),
+            Err(VerifierError::JumpOutOfRange { pc: 0, target: 2 })
+        );
+        assert_eq!(
+            verify("ldmapfd r1, 1\nmov r0, 0\nexit"),
+            Err(VerifierError::InvalidMapFd { pc: 0, fd: 1 })
+        );
+    }
+}
@@ -0,0 +1,422 @@
+use std::fmt::Display;
+
+use super::tnum::Tnum;
+use crate::runtime::STACK_SIZE;
+
+/// what the verifier knows a register (or a spilled stack slot) holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegType {
+    NotInit,
+    Scalar,
+    PtrToCtx,
+    /// pointer relative to r10
+    PtrToStack,
+    /// map loaded by `ldmapfd`, only usable as a helper argument
+    ConstPtrToMap,
+    PtrToMapValue,
+    /// result of `bpf_map_lookup_elem` until it has been compared to 0
+    PtrToMapValueOrNull,
+}
+
+impl RegType {
+    pub fn is_pointer(&self) -> bool {
+        !matches!(self, RegType::NotInit | RegType::Scalar)
+    }
+}
+
+impl Display for RegType {
+    // the names the kernel verifier uses
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            RegType::NotInit => "?",
+            RegType::Scalar => "scalar",
+            RegType::PtrToCtx => "ctx",
+            RegType::PtrToStack => "fp",
+            RegType::ConstPtrToMap => "map_ptr",
+            RegType::PtrToMapValue => "map_value",
+            RegType::PtrToMapValueOrNull => "map_value_or_null",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RegState {
+    pub ty: RegType,
+    /// constant offset of a pointer from where it points to
+    pub off: i64,
+    /// fd of the map for map pointers
+    pub map: Option<u32>,
+    /// shared by the copies of a `PtrToMapValueOrNull`, so that a null check
+    /// on one of them applies to all
+    pub id: u32,
+    /// call depth of the function a `PtrToStack` points to the stack of
+    pub frameno: u32,
+    /// known bits and bounds of a scalar, or of the variable part of a
+    /// pointer offset
+    pub var_off: Tnum,
+    pub smin: i64,
+    pub smax: i64,
+    pub umin: u64,
+    pub umax: u64,
+}
+
+impl RegState {
+    pub fn not_init() -> Self {
+        Self::pointer(RegType::NotInit, 0)
+    }
+
+    /// any number
+    pub fn scalar() -> Self {
+        Self::from_tnum(Tnum::unknown())
+    }
+
+    pub fn constant(value: u64) -> Self {
+        Self::from_tnum(Tnum::constant(value))
+    }
+
+    /// number made of the known bits of `var_off`
+    pub fn from_tnum(var_off: Tnum) -> Self {
+        let mut reg = Self {
+            ty: RegType::Scalar,
+            var_off,
+            smin: i64::MIN,
+            smax: i64::MAX,
+            umin: 0,
+            umax: u64::MAX,
+            ..Self::pointer(RegType::Scalar, 0)
+        };
+        reg.sync_bounds();
+        reg
+    }
+
+    /// number of at most `size` bytes, what a load of `size` bytes gives
+    pub fn sized(size: usize) -> Self {
+        Self::from_tnum(Tnum::unknown().cast(size))
+    }
+
+    pub fn pointer(ty: RegType, off: i64) -> Self {
+        Self {
+            ty,
+            off,
+            map: None,
+            id: 0,
+            frameno: 0,
+            var_off: Tnum::constant(0),
+            smin: 0,
+            smax: 0,
+            umin: 0,
+            umax: 0,
+        }
+    }
+
+    pub fn is_const(&self) -> bool {
+        self.var_off.is_const()
+    }
+
+    /// no number fits the bounds, as on a branch that is never taken
+    pub fn is_empty(&self) -> bool {
+        self.smin > self.smax
+            || self.umin > self.umax
+            || self.var_off.min() > self.umax
+            || self.var_off.max() < self.umin
+            || (self.umin == self.umax && !self.var_off.contains(self.umin))
+    }
+
+    /// the low 32 bits, zero extended, as left by an alu32 operation
+    pub fn cast32(&self) -> Self {
+        if self.umax <= u32::MAX as u64 {
+            return *self;
+        }
+        Self::from_tnum(self.var_off.cast(4))
+    }
+
+    /// tighten the bounds and the known bits with what each of them implies
+    /// on the others, as the kernel's `reg_bounds_sync`
+    pub fn sync_bounds(&mut self) {
+        const SIGN: u64 = 1 << 63;
+        let t = self.var_off;
+        // bounds from the known bits
+        self.smin = self.smin.max((t.value | (t.mask & SIGN)) as i64);
+        self.smax = self.smax.min((t.value | (t.mask & !SIGN)) as i64);
+        self.umin = self.umin.max(t.min());
+        self.umax = self.umax.min(t.max());
+
+        // signed bounds from unsigned ones and the reverse
+        if self.smin >= 0 || self.smax < 0 {
+            // both halves agree when the sign is known
+            self.umin = self.umin.max(self.smin as u64);
+            self.umax = self.umax.min(self.smax as u64);
+            self.smin = self.umin as i64;
+            self.smax = self.umax as i64;
+        } else if (self.umax as i64) >= 0 {
+            // positive
+            self.smin = self.umin as i64;
+            self.umax = self.umax.min(self.smax as u64);
+            self.smax = self.umax as i64;
+        } else if (self.umin as i64) < 0 {
+            // negative
+            self.umin = self.umin.max(self.smin as u64);
+            self.smin = self.umin as i64;
+            self.smax = self.umax as i64;
+        }
+
+        // known bits from the bounds
+        if self.umin <= self.umax {
+            self.var_off = self.var_off.intersect(Tnum::range(self.umin, self.umax));
+        }
+    }
+}
+
+impl Display for RegState {
+    // close to the kernel's verifier log, `scalar(umax=255)`, `fp-8`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.ty {
+            RegType::NotInit => return write!(f, "?"),
+            RegType::Scalar if self.is_const() => {
+                return write!(f, "{}", self.var_off.value as i64);
+            }
+            RegType::PtrToStack if self.is_const() => return write!(f, "fp{}", self.off),
+            _ => {}
+        }
+
+        let mut fields = Vec::new();
+        if let Some(map) = self.map {
+            fields.push(format!("map={}", map));
+        }
+        if self.id != 0 {
+            fields.push(format!("id={}", self.id));
+        }
+        if self.off != 0 {
+            fields.push(format!("off={}", self.off));
+        }
+        if !self.is_const() {
+            // the signed bounds only when they say something else
+            if (self.smin, self.smax) != (self.umin as i64, self.umax as i64) {
+                if self.smin != i64::MIN {
+                    fields.push(format!("smin={}", self.smin));
+                }
+                if self.smax != i64::MAX {
+                    fields.push(format!("smax={}", self.smax));
+                }
+            }
+            if self.umin != 0 {
+                fields.push(format!("umin={}", self.umin));
+            }
+            if self.umax != u64::MAX {
+                fields.push(format!("umax={}", self.umax));
+            }
+            if self.var_off != Tnum::unknown() {
+                fields.push(format!("var_off={}", self.var_off));
+            }
+        }
+        write!(f, "{}({})", self.ty, fields.join(","))
+    }
+}
+
+/// content of a stack byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SlotType {
+    /// never written
+    Invalid,
+    /// written with something that is not a spilled pointer
+    Misc,
+    /// part of a register spilled by an aligned 8 bytes store
+    Spill,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StackSlot {
+    pub bytes: [SlotType; 8],
+    pub spilled: RegState,
+}
+
+impl Default for StackSlot {
+    fn default() -> Self {
+        Self {
+            bytes: [SlotType::Invalid; 8],
+            spilled: RegState::not_init(),
+        }
+    }
+}
+
+impl Display for StackSlot {
+    // a spilled register, or a char per byte: `?` never written, `m` misc
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.bytes == [SlotType::Spill; 8] {
+            return write!(f, "{}", self.spilled);
+        }
+        for byte in self.bytes {
+            let c = match byte {
+                SlotType::Invalid => '?',
+                SlotType::Misc => 'm',
+                SlotType::Spill => 'r',
+            };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// what a function that made a local call gets back on return
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CallFrame {
+    pub regs: [RegState; 11],
+    pub stack: Vec<StackSlot>,
+    /// first instruction of the function
+    pub func: usize,
+    /// instruction after the call
+    pub ret_pc: usize,
+}
+
+/// registers and stack along one path of the program
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VerifierState {
+    pub regs: [RegState; 11],
+    /// slot `i` covers `[r10 - 8 * (i + 1), r10 - 8 * i)`
+    pub stack: Vec<StackSlot>,
+    /// first instruction of the function being walked
+    pub func: usize,
+    /// functions waiting for the current one to return, outermost first
+    pub callers: Vec<CallFrame>,
+}
+
+impl Default for VerifierState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VerifierState {
+    /// r1 points to the context and r10 to the top of the stack, nothing
+    /// else is initialized
+    pub fn new() -> Self {
+        Self::with_stack_size(STACK_SIZE)
+    }
+
+    /// state at the start of a program run with `stack_size` bytes of stack
+    pub fn with_stack_size(stack_size: usize) -> Self {
+        let mut regs = [RegState::not_init(); 11];
+        regs[1] = RegState::pointer(RegType::PtrToCtx, 0);
+        regs[10] = RegState::pointer(RegType::PtrToStack, 0);
+        Self {
+            regs,
+            stack: vec![StackSlot::default(); stack_size / 8],
+            func: 0,
+            callers: Vec::new(),
+        }
+    }
+
+    /// call depth of the current function
+    pub fn frameno(&self) -> u32 {
+        self.callers.len() as u32
+    }
+
+    /// stack of the function at call depth `frameno`
+    pub fn stack_of(&mut self, frameno: u32) -> &mut Vec<StackSlot> {
+        match self.callers.get_mut(frameno as usize) {
+            Some(frame) => &mut frame.stack,
+            None => &mut self.stack,
+        }
+    }
+
+    /// enter the function at `func`, which gets r1-r5, a new stack and r10
+    /// pointing to it
+    pub fn push_frame(&mut self, func: usize, ret_pc: usize) {
+        let mut regs = [RegState::not_init(); 11];
+        regs[1..=5].copy_from_slice(&self.regs[1..=5]);
+        regs[10] = RegState {
+            frameno: self.frameno() + 1,
+            ..RegState::pointer(RegType::PtrToStack, 0)
+        };
+        // as many slots in every frame
+        let stack = vec![StackSlot::default(); self.stack.len()];
+        let caller = CallFrame {
+            regs: std::mem::replace(&mut self.regs, regs),
+            stack: std::mem::replace(&mut self.stack, stack),
+            func: std::mem::replace(&mut self.func, func),
+            ret_pc,
+        };
+        self.callers.push(caller);
+    }
+
+    /// back to the caller with r0, `None` in the outermost function, the
+    /// stack pointers to the frame that is gone are no longer usable
+    pub fn pop_frame(&mut self) -> Option<usize> {
+        let caller = self.callers.pop()?;
+        let r0 = self.regs[0];
+        let frameno = self.frameno() + 1;
+        self.regs = caller.regs;
+        self.stack = caller.stack;
+        self.func = caller.func;
+        self.regs[0] = r0;
+        for reg in 1..=5 {
+            self.regs[reg] = RegState::not_init();
+        }
+        let dangling = |reg: &RegState| reg.ty == RegType::PtrToStack && reg.frameno >= frameno;
+        for slot in self
+            .callers
+            .iter_mut()
+            .flat_map(|frame| frame.stack.iter_mut())
+            .chain(self.stack.iter_mut())
+        {
+            if slot.bytes == [SlotType::Spill; 8] && dangling(&slot.spilled) {
+                *slot = StackSlot {
+                    bytes: [SlotType::Misc; 8],
+                    spilled: RegState::not_init(),
+                };
+            }
+        }
+        Some(caller.ret_pc)
+    }
+
+    /// slot and byte index of the stack byte at `r10 + off`, `off` < 0
+    pub fn stack_byte(off: i64) -> (usize, usize) {
+        let pos = (-off - 1) as usize;
+        (pos / 8, 7 - pos % 8)
+    }
+
+    /// what a null check of the pointer `id` proved, on every copy of it
+    pub fn mark_map_value(&mut self, id: u32, is_null: bool) {
+        let mark = |reg: &mut RegState| {
+            if reg.ty == RegType::PtrToMapValueOrNull && reg.id == id {
+                *reg = if is_null {
+                    RegState::scalar()
+                } else {
+                    RegState {
+                        ty: RegType::PtrToMapValue,
+                        id: 0,
+                        ..*reg
+                    }
+                };
+            }
+        };
+        self.regs.iter_mut().for_each(mark);
+        self.stack
+            .iter_mut()
+            .map(|slot| &mut slot.spilled)
+            .for_each(mark);
+    }
+}
+
+impl Display for VerifierState {
+    // the initialized registers and the written stack slots
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let regs = self
+            .regs
+            .iter()
+            .enumerate()
+            .filter(|(_, reg)| reg.ty != RegType::NotInit)
+            .map(|(i, reg)| format!("R{}={}", i, reg));
+        let stack = self
+            .stack
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.bytes != [SlotType::Invalid; 8])
+            .map(|(i, slot)| format!("fp-{}={}", 8 * (i + 1), slot));
+        if !self.callers.is_empty() {
+            write!(f, "frame{}: ", self.callers.len())?;
+        }
+        write!(f, "{}", regs.chain(stack).collect::<Vec<_>>().join(" "))
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    fmt::Display,
+    ops::{Add, BitAnd, BitOr, BitXor, Mul, Shl, Shr, Sub},
+};
+
+/// tracked number, the bits set in `mask` are unknown, the others are those
+/// of `value`, same as the kernel's `struct tnum`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tnum {
+    pub value: u64,
+    pub mask: u64,
+}
+
+impl Tnum {
+    pub const fn constant(value: u64) -> Self {
+        Self { value, mask: 0 }
+    }
+
+    pub const fn unknown() -> Self {
+        Self {
+            value: 0,
+            mask: u64::MAX,
+        }
+    }
+
+    /// smallest tnum holding every number of `[min, max]`
+    pub fn range(min: u64, max: u64) -> Self {
+        let chi = min ^ max;
+        let bits = 64 - chi.leading_zeros();
+        if bits > 63 || min > max {
+            return Self::unknown();
+        }
+        let delta = (1u64 << bits) - 1;
+        Self {
+            value: min & !delta,
+            mask: delta,
+        }
+    }
+
+    pub fn is_const(&self) -> bool {
+        self.mask == 0
+    }
+
+    /// whether `value` is one of the numbers of `self`
+    pub fn contains(&self, value: u64) -> bool {
+        value & !self.mask == self.value
+    }
+
+    /// smallest and largest unsigned values
+    pub fn min(&self) -> u64 {
+        self.value
+    }
+
+    pub fn max(&self) -> u64 {
+        self.value | self.mask
+    }
+
+    pub fn arshift(self, shift: u32) -> Self {
+        Self {
+            value: ((self.value as i64) >> shift) as u64,
+            mask: ((self.mask as i64) >> shift) as u64,
+        }
+    }
+
+    /// numbers known to be in both `self` and `other`
+    pub fn intersect(self, other: Self) -> Self {
+        let mu = self.mask & other.mask;
+        Self {
+            value: (self.value | other.value) & !mu,
+            mask: mu,
+        }
+    }
+
+    /// the low `size` bytes
+    pub fn cast(self, size: usize) -> Self {
+        if size >= 8 {
+            return self;
+        }
+        let keep = (1u64 << (size * 8)) - 1;
+        Self {
+            value: self.value & keep,
+            mask: self.mask & keep,
+        }
+    }
+}
+
+impl Add for Tnum {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        let sm = self.mask.wrapping_add(other.mask);
+        let sv = self.value.wrapping_add(other.value);
+        let chi = sm.wrapping_add(sv) ^ sv;
+        let mu = chi | self.mask | other.mask;
+        Self {
+            value: sv & !mu,
+            mask: mu,
+        }
+    }
+}
+
+impl Sub for Tnum {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        let dv = self.value.wrapping_sub(other.value);
+        let alpha = dv.wrapping_add(self.mask);
+        let beta = dv.wrapping_sub(other.mask);
+        let mu = (alpha ^ beta) | self.mask | other.mask;
+        Self {
+            value: dv & !mu,
+            mask: mu,
+        }
+    }
+}
+
+impl Mul for Tnum {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        let value = self.value.wrapping_mul(other.value);
+        let mut acc = Self::constant(0);
+        let (mut a, mut b) = (self, other);
+        while a.value != 0 || a.mask != 0 {
+            if a.value & 1 != 0 {
+                acc = acc
+                    + Self {
+                        value: 0,
+                        mask: b.mask,
+                    };
+            } else if a.mask & 1 != 0 {
+                acc = acc
+                    + Self {
+                        value: 0,
+                        mask: b.value | b.mask,
+                    };
+            }
+            a = a >> 1;
+            b = b << 1;
+        }
+        Self::constant(value) + acc
+    }
+}
+
+impl BitAnd for Tnum {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        let value = self.value & other.value;
+        Self {
+            value,
+            mask: self.max() & other.max() & !value,
+        }
+    }
+}
+
+impl BitOr for Tnum {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        let value = self.value | other.value;
+        Self {
+            value,
+            mask: (self.mask | other.mask) & !value,
+        }
+    }
+}
+
+impl BitXor for Tnum {
+    type Output = Self;
+
+    fn bitxor(self, other: Self) -> Self {
+        let mu = self.mask | other.mask;
+        Self {
+            value: (self.value ^ other.value) & !mu,
+            mask: mu,
+        }
+    }
+}
+
+impl Shl<u32> for Tnum {
+    type Output = Self;
+
+    fn shl(self, shift: u32) -> Self {
+        Self {
+            value: self.value << shift,
+            mask: self.mask << shift,
+        }
+    }
+}
+
+impl Shr<u32> for Tnum {
+    type Output = Self;
+
+    fn shr(self, shift: u32) -> Self {
+        Self {
+            value: self.value >> shift,
+            mask: self.mask >> shift,
+        }
+    }
+}
+
+impl Display for Tnum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({:#x}; {:#x})", self.value, self.mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tnum() {
+        let t = Tnum::range(16, 31);
+        assert_eq!(
+            t,
+            Tnum {
+                value: 16,
+                mask: 15
+            }
+        );
+        assert!(t.contains(20) && !t.contains(32));
+        assert_eq!(Tnum::range(0, u64::MAX), Tnum::unknown());
+
+        // x & 0xf0 only keeps the bits x may have there
+        let x = Tnum::unknown() & Tnum::constant(0xf0);
+        assert_eq!((x.min(), x.max()), (0, 0xf0));
+        assert_eq!(Tnum::constant(3) + Tnum::constant(4), Tnum::constant(7));
+        assert_eq!(
+            Tnum::constant(3) - Tnum::constant(4),
+            Tnum::constant(u64::MAX)
+        );
+        assert_eq!(Tnum::constant(6) * Tnum::constant(7), Tnum::constant(42));
+
+        // {0, 1} + {0, 1} is somewhere in [0, 3]
+        let bit = Tnum { value: 0, mask: 1 };
+        assert_eq!(bit + bit, Tnum { value: 0, mask: 3 });
+        assert_eq!(((bit << 4) | Tnum::constant(1)).max(), 0x11);
+        assert_eq!(Tnum::constant(0x1234).cast(1), Tnum::constant(0x34));
+        assert_eq!(
+            Tnum::constant(1 << 63).arshift(63),
+            Tnum::constant(u64::MAX)
+        );
+        assert_eq!(
+            Tnum::range(0, 255).intersect(Tnum::range(0, 15)),
+            Tnum::range(0, 15)
+        );
+    }
+}
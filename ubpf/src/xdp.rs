@@ -0,0 +1,438 @@
+use crate::{
+    error::{HelperError, VmError},
+    helpers::{
+        ArgType, BPF_FUNC_XDP_ADJUST_HEAD, BPF_FUNC_XDP_ADJUST_META, BPF_FUNC_XDP_ADJUST_TAIL,
+        HelperProto, RetType,
+    },
+    memory::{MemoryMap, Perm},
+    runtime::VirtualMachine,
+};
+
+// same defaults as the kernel
+pub const XDP_PACKET_HEADROOM: usize = 256;
+pub const XDP_FRAME_SIZE: usize = 4096;
+/// guest address of the frame, the pointers of `XdpMd` are 32 bits wide
+pub const XDP_FRAME_VADDR: u64 = 0x8000_0000;
+const ETH_HLEN: u64 = 14;
+const XDP_META_MAX: u64 = 32;
+
+// the adjust helpers all take the context and a delta
+const XDP_ADJUST_PROTO: HelperProto =
+    HelperProto::new(RetType::Integer, &[ArgType::PtrToCtx, ArgType::Scalar]);
+
+/// verdict of an xdp program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum XdpAction {
+    Aborted = 0,
+    Drop = 1,
+    Pass = 2,
+    Tx = 3,
+    Redirect = 4,
+}
+
+impl From<i64> for XdpAction {
+    /// unknown return codes are handled as `XDP_ABORTED`, like the kernel does
+    fn from(ret: i64) -> Self {
+        match ret as u32 {
+            1 => XdpAction::Drop,
+            2 => XdpAction::Pass,
+            3 => XdpAction::Tx,
+            4 => XdpAction::Redirect,
+            _ => XdpAction::Aborted,
+        }
+    }
+}
+
+/// `struct xdp_md` as seen by programs, the pointers are guest addresses in
+/// the frame mapped at `XDP_FRAME_VADDR`
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct XdpMd {
+    pub data: u32,
+    pub data_end: u32,
+    pub data_meta: u32,
+    pub ingress_ifindex: u32,
+    pub rx_queue_index: u32,
+    pub egress_ifindex: u32,
+}
+
+// what r1 points to, the program may write over `ctx` so the helpers work on
+// `md` and copy it back to `ctx`
+#[derive(Debug)]
+#[repr(C)]
+struct XdpBuff {
+    ctx: XdpMd,
+    md: XdpMd,
+    hard_start: u64,
+    hard_end: u64,
+    frame: *mut u8,
+}
+
+impl XdpBuff {
+    /// host address of `addr` in the frame
+    fn host(&self, addr: u64) -> *mut u8 {
+        unsafe { self.frame.add((addr - XDP_FRAME_VADDR) as usize) }
+    }
+
+    /// undo what the program wrote to its `xdp_md`
+    fn sync(&mut self) {
+        self.ctx = self.md;
+    }
+}
+
+/// a packet in a frame with headroom, handed to `VirtualMachine::exec_xdp`
+#[derive(Debug)]
+pub struct XdpPacket {
+    frame: Box<[u8]>,
+    buff: Box<XdpBuff>,
+}
+
+impl XdpPacket {
+    /// copy `data` after `XDP_PACKET_HEADROOM` bytes of headroom
+    pub fn new(data: &[u8]) -> Result<Self, VmError> {
+        Self::with_headroom(data, XDP_PACKET_HEADROOM)
+    }
+
+    pub fn with_headroom(data: &[u8], headroom: usize) -> Result<Self, VmError> {
+        let page = XDP_FRAME_SIZE;
+        let frame_size = (headroom + data.len()).div_ceil(page).max(1) * page;
+        // the whole frame has to be addressable by the 32 bits pointers
+        if frame_size as u64 > u32::MAX as u64 - XDP_FRAME_VADDR {
+            return Err(VmError::PacketAlloc);
+        }
+        let mut frame = vec![0u8; frame_size].into_boxed_slice();
+        frame[headroom..headroom + data.len()].copy_from_slice(data);
+
+        let hard_start = XDP_FRAME_VADDR;
+        let data_start = (hard_start + headroom as u64) as u32;
+        let md = XdpMd {
+            data: data_start,
+            data_end: data_start + data.len() as u32,
+            data_meta: data_start,
+            ..Default::default()
+        };
+        let buff = Box::new(XdpBuff {
+            ctx: md,
+            md,
+            hard_start,
+            hard_end: hard_start + frame_size as u64,
+            frame: frame.as_mut_ptr(),
+        });
+        Ok(Self { frame, buff })
+    }
+
+    pub fn with_ingress_ifindex(mut self, ifindex: u32) -> Self {
+        self.buff.md.ingress_ifindex = ifindex;
+        self.buff.sync();
+        self
+    }
+
+    pub fn with_rx_queue_index(mut self, index: u32) -> Self {
+        self.buff.md.rx_queue_index = index;
+        self.buff.sync();
+        self
+    }
+
+    pub fn md(&self) -> &XdpMd {
+        &self.buff.md
+    }
+
+    /// the packet, between `data` and `data_end`
+    pub fn data(&self) -> &[u8] {
+        let md = &self.buff.md;
+        self.slice(md.data, md.data_end)
+    }
+
+    /// the metadata, between `data_meta` and `data`
+    pub fn meta(&self) -> &[u8] {
+        let md = &self.buff.md;
+        self.slice(md.data_meta, md.data)
+    }
+
+    /// bytes left in front of the metadata
+    pub fn headroom(&self) -> usize {
+        self.buff.md.data_meta as usize - self.buff.hard_start as usize
+    }
+
+    fn slice(&self, start: u32, end: u32) -> &[u8] {
+        let offset = start as usize - XDP_FRAME_VADDR as usize;
+        &self.frame[offset..offset + (end - start) as usize]
+    }
+}
+
+unsafe impl Send for XdpPacket {}
+
+impl VirtualMachine {
+    /// run the program as an xdp program on `packet`, which is updated in
+    /// place, the xdp helpers get registered on the first call
+    pub fn exec_xdp(
+        &mut self,
+        packet: &mut XdpPacket,
+        jit_enable: bool,
+    ) -> Result<XdpAction, VmError> {
+        if !self.has_helper(BPF_FUNC_XDP_ADJUST_HEAD) {
+            self.register_xdp_helpers();
+        }
+        let ctx = packet.buff.as_mut() as *mut XdpBuff as *mut u8;
+        let ctx = unsafe { std::slice::from_raw_parts_mut(ctx, size_of::<XdpMd>()) };
+        // the packet and its metadata are somewhere in the frame
+        let mut map = MemoryMap::new();
+        map.map(XDP_FRAME_VADDR, &mut packet.frame, Perm::ReadWrite)?;
+        let ret = self.exec_with_map(ctx, &mut map, jit_enable);
+        packet.buff.sync();
+        Ok(XdpAction::from(ret?))
+    }
+
+    /// register `bpf_xdp_adjust_head`, `bpf_xdp_adjust_meta` and
+    /// `bpf_xdp_adjust_tail`, which expect an `XdpPacket` context in r1
+    pub fn register_xdp_helpers(&mut self) {
+        self.register_helper(
+            BPF_FUNC_XDP_ADJUST_HEAD,
+            XDP_ADJUST_PROTO,
+            |ctx, delta, _, _, _| errno(xdp_adjust_head(ctx, delta as i32 as i64)),
+        );
+        self.register_helper(
+            BPF_FUNC_XDP_ADJUST_META,
+            XDP_ADJUST_PROTO,
+            |ctx, delta, _, _, _| errno(xdp_adjust_meta(ctx, delta as i32 as i64)),
+        );
+        self.register_helper(
+            BPF_FUNC_XDP_ADJUST_TAIL,
+            XDP_ADJUST_PROTO,
+            |ctx, delta, _, _, _| errno(xdp_adjust_tail(ctx, delta as i32 as i64)),
+        );
+    }
+}
+
+fn errno(r: Result<(), HelperError>) -> u64 {
+    match r {
+        Ok(_) => 0,
+        Err(e) => (-e.errno()) as u64,
+    }
+}
+
+fn xdp_buff<'a>(ctx: u64) -> &'a mut XdpBuff {
+    unsafe { &mut *(ctx as *mut XdpBuff) }
+}
+
+fn xdp_adjust_head(ctx: u64, delta: i64) -> Result<(), HelperError> {
+    let buff = xdp_buff(ctx);
+    let meta_len = (buff.md.data - buff.md.data_meta) as u64;
+    let data = (buff.md.data as i64 + delta) as u64;
+    if data < buff.hard_start + meta_len || data + ETH_HLEN > buff.md.data_end as u64 {
+        return Err(HelperError::InvalidArgument);
+    }
+
+    // the metadata stays right in front of the packet
+    let meta = data - meta_len;
+    if meta_len != 0 {
+        let (from, to) = (buff.host(buff.md.data_meta as u64), buff.host(meta));
+        unsafe { std::ptr::copy(from, to, meta_len as usize) };
+    }
+    buff.md.data_meta = meta as u32;
+    buff.md.data = data as u32;
+    buff.sync();
+    Ok(())
+}
+
+fn xdp_adjust_meta(ctx: u64, delta: i64) -> Result<(), HelperError> {
+    let buff = xdp_buff(ctx);
+    let md = &mut buff.md;
+    let meta = (md.data_meta as i64 + delta) as u64;
+    let data = md.data as u64;
+    if meta < buff.hard_start || meta > data {
+        return Err(HelperError::InvalidArgument);
+    }
+    let meta_len = data - meta;
+    if !meta_len.is_multiple_of(4) || meta_len > XDP_META_MAX {
+        return Err(HelperError::InvalidArgument);
+    }
+    md.data_meta = meta as u32;
+    buff.sync();
+    Ok(())
+}
+
+fn xdp_adjust_tail(ctx: u64, delta: i64) -> Result<(), HelperError> {
+    let buff = xdp_buff(ctx);
+    let old_end = buff.md.data_end as u64;
+    let data_end = (old_end as i64 + delta) as u64;
+    if data_end > buff.hard_end || data_end < buff.md.data as u64 + ETH_HLEN {
+        return Err(HelperError::InvalidArgument);
+    }
+
+    // the grown part must not leak what was there before
+    if data_end > old_end {
+        let end = buff.host(old_end);
+        unsafe { std::ptr::write_bytes(end, 0, (data_end - old_end) as usize) };
+    }
+    buff.md.data_end = data_end as u32;
+    buff.sync();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use assembler::Instructions;
+
+    use super::*;
+    use crate::verifier::VerifierOptions;
+
+    fn packet_bytes() -> Vec<u8> {
+        (0..64).collect()
+    }
+
+    #[test]
+    fn test_xdp_context() {
+        // XDP_PASS if the packet is longer than 60 bytes and came from
+        // ifindex 7, XDP_DROP otherwise
+        let prog = "ldxw r2, [r1]
+ldxw r3, [r1+4]
+ldxw r4, [r1+12]
+mov r0, 1
+jne r4, 7, +4
+sub r3, r2
+jle r3, 60, +2
+ldxb r5, [r2+63]
+mov r0, r5
+exit";
+        // the packet is outside of the context, in a region of its own
+        for jit in [false, true] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+
+            let mut packet = XdpPacket::new(&packet_bytes())
+                .unwrap()
+                .with_ingress_ifindex(7);
+            assert_eq!(packet.headroom(), XDP_PACKET_HEADROOM);
+            let data = XDP_FRAME_VADDR + XDP_PACKET_HEADROOM as u64;
+            assert_eq!(packet.md().data as u64, data);
+            // the last byte of the packet is returned
+            assert_eq!(
+                runtime.exec_xdp(&mut packet, jit).unwrap(),
+                XdpAction::Aborted
+            );
+
+            let mut data = packet_bytes();
+            data[63] = 3;
+            let mut packet = XdpPacket::new(&data).unwrap().with_ingress_ifindex(7);
+            assert_eq!(runtime.exec_xdp(&mut packet, jit).unwrap(), XdpAction::Tx);
+            data[63] = 0xff;
+            let mut packet = XdpPacket::new(&data).unwrap().with_ingress_ifindex(7);
+            assert_eq!(
+                runtime.exec_xdp(&mut packet, jit).unwrap(),
+                XdpAction::Aborted
+            );
+
+            let mut packet = XdpPacket::new(&data).unwrap();
+            assert_eq!(runtime.exec_xdp(&mut packet, jit).unwrap(), XdpAction::Drop);
+            let mut packet = XdpPacket::new(&data[..60]).unwrap().with_ingress_ifindex(7);
+            assert_eq!(runtime.exec_xdp(&mut packet, jit).unwrap(), XdpAction::Drop);
+        }
+
+        // nothing past the end of the frame
+        let prog = "ldxw r2, [r1+4]\nldxb r0, [r2+4096]\nexit";
+        for jit in [false, true] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            let mut packet = XdpPacket::new(&packet_bytes()).unwrap();
+            assert!(runtime.exec_xdp(&mut packet, jit).is_err());
+        }
+    }
+
+    #[test]
+    fn test_xdp_adjust() {
+        // strip the first 14 bytes, shrink the tail by 10 bytes, reserve 4
+        // bytes of metadata and write the old first byte of the packet there
+        let prog = "mov r6, r1
+mov r2, 14
+call 44
+jne r0, 0, +14
+mov r1, r6
+mov r2, -10
+call 65
+jne r0, 0, +10
+mov r1, r6
+mov r2, -4
+call 54
+jne r0, 0, +6
+ldxw r2, [r6+8]
+ldxw r3, [r6]
+ldxb r4, [r3-14]
+stxw [r2], r4
+mov r0, 2
+exit
+mov r0, 0
+exit";
+        for jit in [false, true] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            let mut data = packet_bytes();
+            data[0] = 0xaa;
+            let mut packet = XdpPacket::new(&data).unwrap();
+
+            assert_eq!(runtime.exec_xdp(&mut packet, jit).unwrap(), XdpAction::Pass);
+            assert_eq!(packet.data(), &data[14..54]);
+            assert_eq!(packet.meta(), &0xaau32.to_ne_bytes());
+            assert_eq!(packet.headroom(), XDP_PACKET_HEADROOM + 14 - 4);
+        }
+    }
+
+    #[test]
+    fn test_xdp_forged_md() {
+        // the helpers do not trust what the program wrote to its xdp_md
+        let prog = "stw [r1], 0x7fff0000
+stw [r1+4], 0x7fff0000
+mov r2, 0x10000
+call 65
+exit";
+        let options = VerifierOptions {
+            ctx_size: size_of::<XdpMd>(),
+            ..Default::default()
+        };
+        for jit in [false, true] {
+            let instructions = Instructions::from_asm(prog).unwrap();
+            let mut runtime = VirtualMachine::new(instructions.into());
+            runtime.register_xdp_helpers();
+            runtime.verify(&options).unwrap();
+            let data = packet_bytes();
+            let mut packet = XdpPacket::new(&data).unwrap();
+            assert_eq!(
+                runtime.exec_xdp(&mut packet, jit).unwrap(),
+                XdpAction::Aborted
+            );
+            assert_eq!(packet.data(), &data[..]);
+            assert_eq!(packet.headroom(), XDP_PACKET_HEADROOM);
+        }
+    }
+
+    #[test]
+    fn test_xdp_adjust_bounds() {
+        let data = packet_bytes();
+        let mut packet = XdpPacket::with_headroom(&data, 16).unwrap();
+        let ctx = packet.buff.as_mut() as *mut XdpBuff as u64;
+
+        // no room in front of the packet, less than an ethernet header left
+        assert!(xdp_adjust_head(ctx, -17).is_err());
+        assert!(xdp_adjust_head(ctx, 64 - 13).is_err());
+        assert!(xdp_adjust_head(ctx, -16).is_ok());
+
+        // metadata has to be 4 bytes aligned and at most 32 bytes, it moves
+        // along with the packet
+        assert!(xdp_adjust_meta(ctx, -4).is_err());
+        assert!(xdp_adjust_head(ctx, 40).is_ok());
+        assert!(xdp_adjust_meta(ctx, -3).is_err());
+        assert!(xdp_adjust_meta(ctx, -36).is_err());
+        assert!(xdp_adjust_meta(ctx, -8).is_ok());
+        assert_eq!(packet.meta(), &data[16..24]);
+        assert!(xdp_adjust_head(ctx, -8).is_ok());
+        assert_eq!(packet.meta(), &data[16..24]);
+        assert_eq!(packet.data(), &data[16..]);
+
+        // growing the tail zeroes the new bytes, up to the end of the frame
+        assert!(xdp_adjust_tail(ctx, 4).is_ok());
+        assert_eq!(&packet.data()[48..], &[0; 4]);
+        assert!(xdp_adjust_tail(ctx, XDP_FRAME_SIZE as i64).is_err());
+        assert!(xdp_adjust_tail(ctx, -(52 - 13)).is_err());
+    }
+}